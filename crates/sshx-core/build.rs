@@ -1,10 +1,14 @@
-use std::{env, path::PathBuf};
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let descriptor_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("sshx.bin");
-    tonic_build::configure()
-        .file_descriptor_set_path(descriptor_path)
-        .bytes(["."])
-        .compile(&["proto/sshx.proto"], &["proto/"])?;
+    // Protocol buffers are only compiled for gRPC, which `wasm32` builds omit.
+    #[cfg(feature = "grpc")]
+    {
+        use std::{env, path::PathBuf};
+
+        let descriptor_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("sshx.bin");
+        tonic_build::configure()
+            .file_descriptor_set_path(descriptor_path)
+            .bytes(["."])
+            .compile(&["proto/sshx.proto", "proto/health.proto"], &["proto/"])?;
+    }
     Ok(())
 }
@@ -0,0 +1,226 @@
+//! Machine-readable error codes, shared by every API that reports errors.
+//!
+//! The same code is attached to gRPC statuses, WebSocket close frames, and
+//! error messages sent over WebSocket, so that clients can tell errors apart
+//! without parsing human-readable text.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "grpc")]
+use tonic::{metadata::MetadataValue, Code, Status};
+
+/// Name of the gRPC metadata key that carries the error code of a status.
+#[cfg(feature = "grpc")]
+pub const ERROR_CODE_METADATA: &str = "sshx-error-code";
+
+/// Category of an error reported by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request was malformed or had invalid arguments.
+    InvalidRequest,
+    /// The encryption key, token, or authentication code was wrong.
+    InvalidAuth,
+    /// The client is not allowed to connect, due to its origin or address.
+    PermissionDenied,
+    /// The requested session does not exist.
+    NotFound,
+    /// The requested session name is already in use.
+    NameTaken,
+    /// The session was closed by its host.
+    SessionClosed,
+    /// The session was closed after reaching its time limit.
+    SessionExpired,
+    /// The client is sending too much, or has been temporarily banned.
+    RateLimited,
+    /// The namespace has used up one of its limits, like its open sessions.
+    QuotaExceeded,
+    /// The client and server protocol versions are incompatible.
+    Incompatible,
+    /// An error reported by the host's command-line client.
+    ClientReported,
+    /// The server is shutting down, so clients should reconnect later.
+    ShuttingDown,
+    /// Another client adopted the session, so this one is no longer its host.
+    HostReplaced,
+    /// The viewer sent no messages for too long, so it was disconnected.
+    IdleTimeout,
+    /// The shell that a request refers to does not exist, or has closed.
+    ShellNotFound,
+    /// The session has reached its limit of shells or viewers.
+    SessionFull,
+    /// An unexpected error inside the server.
+    Internal,
+}
+
+impl ErrorCode {
+    /// All error codes, in a stable order.
+    pub const ALL: [ErrorCode; 17] = [
+        Self::InvalidRequest,
+        Self::InvalidAuth,
+        Self::PermissionDenied,
+        Self::NotFound,
+        Self::NameTaken,
+        Self::SessionClosed,
+        Self::SessionExpired,
+        Self::RateLimited,
+        Self::QuotaExceeded,
+        Self::Incompatible,
+        Self::ClientReported,
+        Self::ShuttingDown,
+        Self::HostReplaced,
+        Self::IdleTimeout,
+        Self::ShellNotFound,
+        Self::SessionFull,
+        Self::Internal,
+    ];
+
+    /// Returns the name of this code, as used in serialized messages.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::InvalidRequest => "invalid_request",
+            Self::InvalidAuth => "invalid_auth",
+            Self::PermissionDenied => "permission_denied",
+            Self::NotFound => "not_found",
+            Self::NameTaken => "name_taken",
+            Self::SessionClosed => "session_closed",
+            Self::SessionExpired => "session_expired",
+            Self::RateLimited => "rate_limited",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::Incompatible => "incompatible",
+            Self::ClientReported => "client_reported",
+            Self::ShuttingDown => "shutting_down",
+            Self::HostReplaced => "host_replaced",
+            Self::IdleTimeout => "idle_timeout",
+            Self::ShellNotFound => "shell_not_found",
+            Self::SessionFull => "session_full",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// Returns the WebSocket close code used for this error.
+    ///
+    /// These are in the range reserved for applications, and end in the
+    /// closest HTTP status code.
+    pub fn close_code(self) -> u16 {
+        match self {
+            Self::InvalidRequest => 4400,
+            Self::InvalidAuth => 4401,
+            Self::PermissionDenied => 4403,
+            Self::NotFound => 4404,
+            Self::NameTaken => 4409,
+            Self::SessionClosed => 4410,
+            Self::SessionExpired => 4408,
+            Self::RateLimited => 4429,
+            Self::QuotaExceeded => 4402,
+            Self::Incompatible => 4426,
+            Self::ClientReported => 4422,
+            Self::ShuttingDown => 4503,
+            Self::HostReplaced => 4423,
+            Self::IdleTimeout => 4440,
+            Self::ShellNotFound => 4414,
+            Self::SessionFull => 4413,
+            Self::Internal => 4500,
+        }
+    }
+
+    /// Find the error code for a WebSocket close code, if it is one of ours.
+    pub fn from_close_code(code: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.close_code() == code)
+    }
+
+    /// Returns the closest standard gRPC status code for this error.
+    #[cfg(feature = "grpc")]
+    pub fn grpc_code(self) -> Code {
+        match self {
+            Self::InvalidRequest => Code::InvalidArgument,
+            Self::InvalidAuth => Code::Unauthenticated,
+            Self::PermissionDenied => Code::PermissionDenied,
+            Self::NameTaken => Code::AlreadyExists,
+            Self::NotFound | Self::SessionClosed | Self::SessionExpired => Code::NotFound,
+            Self::ShellNotFound => Code::NotFound,
+            Self::RateLimited | Self::QuotaExceeded | Self::SessionFull => Code::ResourceExhausted,
+            Self::Incompatible | Self::HostReplaced => Code::FailedPrecondition,
+            Self::ClientReported => Code::Aborted,
+            Self::ShuttingDown => Code::Unavailable,
+            Self::IdleTimeout => Code::DeadlineExceeded,
+            Self::Internal => Code::Internal,
+        }
+    }
+
+    /// Returns the closest code known to clients that predate specific codes.
+    ///
+    /// Viewers only see codes like [`ErrorCode::ShellNotFound`] if they
+    /// negotiate the [`DETAILED_ERRORS`](crate::feature::DETAILED_ERRORS)
+    /// feature, and get this one otherwise, so they can still decode it.
+    pub fn fallback(self) -> Self {
+        match self {
+            Self::ShellNotFound => Self::InvalidRequest,
+            Self::SessionFull => Self::QuotaExceeded,
+            code => code,
+        }
+    }
+
+    /// Create a gRPC status with this error code attached as metadata.
+    #[cfg(feature = "grpc")]
+    pub fn status(self, message: impl Into<String>) -> Status {
+        let mut status = Status::new(self.grpc_code(), message);
+        let value = MetadataValue::from_static(self.as_str());
+        status.metadata_mut().insert(ERROR_CODE_METADATA, value);
+        status
+    }
+
+    /// Find the error code of a gRPC status.
+    ///
+    /// Statuses from older servers without the metadata are categorized by
+    /// their standard code, where possible.
+    #[cfg(feature = "grpc")]
+    pub fn from_status(status: &Status) -> Option<Self> {
+        let attached = status.metadata().get(ERROR_CODE_METADATA);
+        if let Some(code) = attached.and_then(|value| value.to_str().ok()?.parse().ok()) {
+            return Some(code);
+        }
+        match status.code() {
+            Code::InvalidArgument => Some(Self::InvalidRequest),
+            Code::Unauthenticated => Some(Self::InvalidAuth),
+            Code::PermissionDenied => Some(Self::PermissionDenied),
+            Code::NotFound => Some(Self::NotFound),
+            Code::AlreadyExists => Some(Self::NameTaken),
+            Code::ResourceExhausted => Some(Self::RateLimited),
+            Code::FailedPrecondition => Some(Self::Incompatible),
+            Code::Internal => Some(Self::Internal),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = UnknownErrorCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|code| code.as_str() == s)
+            .ok_or(UnknownErrorCode)
+    }
+}
+
+/// Returned when parsing an unrecognized error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownErrorCode;
+
+impl fmt::Display for UnknownErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unknown error code")
+    }
+}
+
+impl std::error::Error for UnknownErrorCode {}
@@ -0,0 +1,42 @@
+//! Optional protocol features, negotiated when peers connect.
+//!
+//! Each side lists the features it supports, and a feature is only used if
+//! both sides listed it. Peers that predate negotiation list nothing, so they
+//! keep seeing the protocol as it was before these features were added.
+
+/// The host reports the line number of its output, for fetching line ranges.
+pub const LINE_INDEX: &str = "line-index";
+
+/// The server tells hosts to reconnect elsewhere before it shuts down.
+pub const SHUTDOWN_NOTICE: &str = "shutdown-notice";
+
+/// The server asks hosts to resend output after a gap, and hosts mark output
+/// as discontinuous when they no longer have it.
+pub const RESYNC: &str = "resync";
+
+/// Features of the gRPC protocol between hosts and the server.
+pub const HOST_FEATURES: &[&str] = &[LINE_INDEX, SHUTDOWN_NOTICE, RESYNC];
+
+/// The server compresses large messages of output with Zstandard.
+pub const COMPRESSION: &str = "compression";
+
+/// The viewer acknowledges output, for a flow-control window.
+pub const FLOW_CONTROL: &str = "flow-control";
+
+/// The server sends changes to other users and chat messages.
+pub const PRESENCE: &str = "presence";
+
+/// The server sends specific error codes, like `shell_not_found`, instead of
+/// the generic codes that older viewers know.
+pub const DETAILED_ERRORS: &str = "detailed-errors";
+
+/// Features of the WebSocket protocol between viewers and the server.
+pub const VIEWER_FEATURES: &[&str] = &[COMPRESSION, FLOW_CONTROL, PRESENCE, DETAILED_ERRORS];
+
+/// Return the features supported by both sides, in the order of `ours`.
+pub fn negotiate(ours: &[&str], theirs: &[impl AsRef<str>]) -> Vec<String> {
+    (ours.iter())
+        .filter(|feature| theirs.iter().any(|other| other.as_ref() == **feature))
+        .map(|feature| feature.to_string())
+        .collect()
+}
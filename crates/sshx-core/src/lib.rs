@@ -1,6 +1,6 @@
 //! The core crate for shared code used in the sshx application.
 
-#![forbid(unsafe_code)]
+#![deny(unsafe_code)]
 #![warn(missing_docs)]
 
 use std::fmt::Display;
@@ -9,6 +9,7 @@ use std::sync::atomic::{AtomicU32, Ordering};
 use serde::{Deserialize, Serialize};
 
 /// Protocol buffer and gRPC definitions, automatically generated by Tonic.
+#[cfg(feature = "grpc")]
 #[allow(missing_docs, non_snake_case)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 pub mod proto {
@@ -16,8 +17,29 @@ pub mod proto {
 
     /// File descriptor set used for gRPC reflection.
     pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("sshx");
+
+    /// Standard gRPC health checking protocol, `grpc.health.v1`.
+    pub mod health {
+        tonic::include_proto!("grpc.health.v1");
+    }
 }
 
+pub mod error;
+pub mod feature;
+#[cfg(feature = "grpc")]
+pub mod telemetry;
+pub mod totp;
+pub mod ws;
+
+pub use error::ErrorCode;
+
+/// Major version of the gRPC protocol, exchanged when opening a session.
+///
+/// This is bumped on breaking changes to the protocol. Clients and servers with
+/// different major versions refuse to talk to each other, while differences in
+/// the package version only produce a warning.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 /// Generate a cryptographically-secure, random alphanumeric value.
 pub fn rand_alphanumeric(len: usize) -> String {
     use rand::{distributions::Alphanumeric, thread_rng, Rng};
@@ -28,6 +50,37 @@ pub fn rand_alphanumeric(len: usize) -> String {
         .collect()
 }
 
+/// Generate a random session name that is easy to read aloud, like
+/// `calm-otter-42`.
+///
+/// These have only about 18 bits of entropy, so they must not be used as
+/// secrets, and callers should check for collisions.
+pub fn rand_memorable() -> String {
+    use rand::{seq::SliceRandom, thread_rng, Rng};
+    const ADJECTIVES: [&str; 64] = [
+        "amber", "bold", "brave", "brisk", "calm", "clever", "cosmic", "crisp", "curly", "dapper",
+        "eager", "fancy", "fluffy", "gentle", "giddy", "glad", "golden", "grand", "happy", "hazy",
+        "honest", "humble", "jolly", "kind", "lively", "lucky", "mellow", "merry", "mighty",
+        "misty", "noble", "odd", "plucky", "polite", "proud", "quick", "quiet", "rapid", "rosy",
+        "rusty", "shiny", "silent", "silly", "sleepy", "snowy", "sunny", "swift", "tidy", "tiny",
+        "upbeat", "vivid", "warm", "wavy", "wise", "witty", "young", "zany", "zesty", "breezy",
+        "cheery", "dusty", "frosty", "jazzy", "lunar",
+    ];
+    const ANIMALS: [&str; 64] = [
+        "badger", "bat", "bear", "beaver", "bison", "camel", "cat", "cobra", "crab", "crane",
+        "crow", "deer", "dingo", "dolphin", "duck", "eagle", "eel", "falcon", "ferret", "finch",
+        "fox", "frog", "gecko", "goat", "goose", "hare", "hawk", "heron", "ibis", "koala", "lemur",
+        "lion", "llama", "lynx", "mole", "moose", "moth", "newt", "otter", "owl", "panda",
+        "parrot", "pelican", "penguin", "puffin", "quail", "rabbit", "raven", "robin", "seal",
+        "shark", "sloth", "snail", "swan", "tapir", "tiger", "toad", "trout", "turtle", "walrus",
+        "whale", "wolf", "yak", "zebra",
+    ];
+    let mut rng = thread_rng();
+    let adjective = ADJECTIVES.choose(&mut rng).unwrap();
+    let animal = ANIMALS.choose(&mut rng).unwrap();
+    format!("{adjective}-{animal}-{}", rng.gen_range(10..100))
+}
+
 /// Unique identifier for a shell within the session.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(transparent)]
@@ -72,6 +125,12 @@ impl IdCounter {
         Sid(self.next_sid.fetch_add(1, Ordering::Relaxed))
     }
 
+    /// Make sure that new shell IDs come after one chosen by the client.
+    pub fn observe_sid(&self, id: Sid) {
+        self.next_sid
+            .fetch_max(id.0.saturating_add(1), Ordering::Relaxed);
+    }
+
     /// Returns the next unique user ID.
     pub fn next_uid(&self) -> Uid {
         Uid(self.next_uid.fetch_add(1, Ordering::Relaxed))
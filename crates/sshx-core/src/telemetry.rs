@@ -0,0 +1,97 @@
+//! Export of tracing spans with OpenTelemetry, shared by the client and server.
+//!
+//! Spans are sent to an OTLP collector over gRPC. The trace context of each
+//! request from the client travels in W3C `traceparent` metadata, so that
+//! server spans are linked to the client spans that caused them.
+
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TraceError;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tonic::metadata::{KeyRef, MetadataKey, MetadataMap, MetadataValue};
+use tonic::{Request, Status};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Build a layer that exports spans to an OTLP collector at an endpoint,
+/// like `http://localhost:4317`.
+///
+/// This must be called from within a Tokio runtime, which sends batches of
+/// spans in the background.
+pub fn layer<S>(
+    service_name: &'static str,
+    endpoint: &str,
+) -> Result<OpenTelemetryLayer<S, trace::Tracer>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint);
+    let resource = Resource::new([KeyValue::new("service.name", service_name)]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Send any spans that have not been exported yet, before the process exits.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Attach the trace context of the current span to an outgoing request.
+///
+/// This has the signature of a tonic interceptor, for wrapping clients.
+#[allow(clippy::result_large_err)]
+pub fn inject_context(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataInjector(request.metadata_mut()));
+    });
+    Ok(request)
+}
+
+/// Make a span a child of the trace context sent with an incoming request.
+pub fn set_parent<T>(span: &tracing::Span, request: &Request<T>) {
+    let context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(request.metadata()))
+    });
+    span.set_parent(context);
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        (self.0.keys())
+            .filter_map(|key| match key {
+                KeyRef::Ascii(key) => Some(key.as_str()),
+                KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
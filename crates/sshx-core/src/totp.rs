@@ -0,0 +1,104 @@
+//! Time-based one-time passwords (RFC 6238), used as a second factor for
+//! joining sessions.
+//!
+//! Codes are 6 digits, computed with HMAC-SHA1 over 30-second time steps, which
+//! is what common authenticator apps expect from an `otpauth://` URI.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// Length of a generated TOTP secret, in bytes.
+pub const SECRET_LEN: usize = 20;
+
+/// Duration of each time step, in seconds.
+pub const STEP_SECS: u64 = 30;
+
+/// Number of adjacent time steps accepted, to allow for clock drift.
+const SKEW_STEPS: u64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generate a new random secret for a session.
+pub fn generate_secret() -> Vec<u8> {
+    use rand::{thread_rng, RngCore};
+    let mut secret = vec![0; SECRET_LEN];
+    thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// Compute the code for a secret at a given Unix time, in seconds.
+pub fn code_at(secret: &[u8], time: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&(time / STEP_SECS).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap());
+    (value & 0x7fff_ffff) % 1_000_000
+}
+
+/// Check a code typed by a user against the secret at the current time.
+pub fn verify(secret: &[u8], code: &str) -> bool {
+    let code = code.trim();
+    if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let Ok(code) = code.parse::<u32>() else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // Check every step rather than returning early, so timing does not leak.
+    (0..=2 * SKEW_STEPS)
+        .map(|i| (now + i * STEP_SECS).saturating_sub(SKEW_STEPS * STEP_SECS))
+        .fold(false, |ok, time| ok | (code_at(secret, time) == code))
+}
+
+/// Encode a secret in unpadded base32, as shown to users.
+pub fn encode_base32(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0_u32, 0);
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/// Decode a base32 secret, ignoring case, spaces, and padding.
+pub fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0_u32, 0);
+    for c in text.chars().filter(|c| !matches!(c, ' ' | '=')) {
+        let c = c.to_ascii_uppercase() as u8;
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Returns an `otpauth://` URI for enrolling a secret in an authenticator app.
+pub fn provisioning_uri(secret: &[u8], account: &str) -> String {
+    let account: String = account
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .collect();
+    format!(
+        "otpauth://totp/sshx:{account}?secret={}&issuer=sshx",
+        encode_base32(secret),
+    )
+}
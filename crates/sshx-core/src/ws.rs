@@ -0,0 +1,299 @@
+//! Messages of the WebSocket protocol between viewers and the server.
+//!
+//! Messages are encoded in CBOR. These definitions are shared by the server
+//! and every client, including the browser frontend through the bindings in
+//! the `sshx-wasm` crate.
+
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{ErrorCode, Sid, Uid};
+
+/// Error from encoding a message in CBOR.
+pub type EncodeError = ciborium::ser::Error<std::io::Error>;
+
+/// Error from decoding a message in CBOR.
+pub type DecodeError = ciborium::de::Error<std::io::Error>;
+
+/// Encode a message in CBOR, as it is sent over the WebSocket.
+pub fn encode<T: Serialize>(msg: &T) -> Result<Vec<u8>, EncodeError> {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(msg, &mut buf)?;
+    Ok(buf)
+}
+
+/// Decode a message in CBOR, as it is received over the WebSocket.
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, DecodeError> {
+    ciborium::de::from_reader(data)
+}
+
+/// Real-time message conveying the position and size of a terminal.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsWinsize {
+    /// The top-left x-coordinate of the window, offset from origin.
+    pub x: i32,
+    /// The top-left y-coordinate of the window, offset from origin.
+    pub y: i32,
+    /// The number of rows in the window.
+    pub rows: u16,
+    /// The number of columns in the terminal.
+    pub cols: u16,
+}
+
+impl Default for WsWinsize {
+    fn default() -> Self {
+        WsWinsize {
+            x: 0,
+            y: 0,
+            rows: 24,
+            cols: 80,
+        }
+    }
+}
+
+/// Real-time message providing information about a user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsUser {
+    /// The user's display name.
+    pub name: String,
+    /// Live coordinates of the mouse cursor, if available.
+    pub cursor: Option<(i32, i32)>,
+    /// Currently focused terminal window ID.
+    pub focus: Option<Sid>,
+}
+
+/// Authoritative state of a session, sent when the client asks to resync.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsSyncState {
+    /// ID of the user receiving this state.
+    pub user_id: Uid,
+    /// Open shells with their sizes and titles, in order.
+    pub shells: Vec<(Sid, WsWinsize, String)>,
+    /// All current users in the session.
+    pub users: Vec<(Uid, WsUser)>,
+    /// Number of bytes of output so far, for each open shell.
+    pub seqnums: Vec<(Sid, u64)>,
+    /// Offset where stored output begins, for each open shell.
+    pub history: Vec<(Sid, u64)>,
+    /// Whether the user's input is currently dropped by the rate limit.
+    pub muted: bool,
+}
+
+/// Request from a viewer to create a shell, with optional settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsNewShell {
+    /// The x-coordinate of the shell's window.
+    pub x: i32,
+    /// The y-coordinate of the shell's window.
+    pub y: i32,
+    /// Initial number of rows and columns, instead of the default 24x80.
+    pub size: Option<(u16, u16)>,
+    /// Working directory of the shell on the host, if not the default.
+    /// Encrypted like input, with the offset for decrypting it.
+    pub cwd: Option<(Bytes, u64)>,
+    /// Command typed into the shell when it starts. Encrypted like input,
+    /// with the offset for decrypting it.
+    pub command: Option<(Bytes, u64)>,
+    /// Program to run instead of the session's shell, with its arguments
+    /// separated by NUL bytes. Encrypted like input, with the offset for
+    /// decrypting it. Hosts refuse this in read-only or filtered sessions.
+    pub argv: Option<(Bytes, u64)>,
+}
+
+/// A real-time message sent from the server over WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum WsServer {
+    /// Initial server message, with the user's ID and session metadata.
+    Hello(Uid),
+    /// Optional features that both the server and client support, sent after
+    /// the hello to clients that listed their own.
+    Features(Vec<String>),
+    /// The user's authentication was invalid.
+    InvalidAuth(),
+    /// The session requires a TOTP code before the user can join.
+    TotpRequired(),
+    /// A snapshot of all current users in the session.
+    Users(Vec<(Uid, WsUser)>),
+    /// Info about a single user in the session: joined, left, or changed.
+    UserDiff(Uid, Option<WsUser>),
+    /// Notification when the set of open shells has changed, with their
+    /// titles, which are empty unless a viewer renamed them.
+    Shells(Vec<(Sid, WsWinsize, String)>),
+    /// Subscription results, in the form of terminal data chunks.
+    Chunks(Sid, u64, Vec<Bytes>),
+    /// The process in a shell exited with this code, or 128 plus the signal
+    /// that killed it. The shell closes right after.
+    ShellExited(Sid, i32),
+    /// Get a chat message tuple `(uid, name, text, time_ms)` from the room.
+    Hear(Uid, String, String, u64),
+    /// Forward a latency measurement between the server and backend shell.
+    ShellLatency(u64),
+    /// Echo back a timestamp, for the the client's own latency measurement.
+    Pong(u64),
+    /// Round-trip times in milliseconds measured by the server, to the host
+    /// and to this viewer, if known. Sent after each pong.
+    Latency(Option<u64>, Option<u64>),
+    /// Alert the client of an application error, with a machine-readable code.
+    Error(ErrorCode, String),
+    /// Seconds left until the session reaches its time limit and closes.
+    TimeLeft(u64),
+    /// Message from the host, shown to the user when they join.
+    Banner(String),
+    /// Text identifying the viewer, to overlay faintly on the session.
+    Watermark(String),
+    /// Full state of the session, in response to a sync request.
+    Sync(WsSyncState),
+    /// Output of a shell before this offset was discarded and will not be sent.
+    HistoryStart(Sid, u64),
+    /// Stored output of a shell covering a range of lines, with the line
+    /// number and byte offset where it starts.
+    Lines(Sid, u64, u64, Vec<Bytes>),
+    /// Rendering of a shell's screen, followed by chunks from its sequence
+    /// number, with the offset for decrypting it.
+    Screen(Sid, u64, u64, Bytes),
+    /// Encrypted link to a fork of the session, with the offset for
+    /// decrypting it.
+    Forked(Bytes, u64),
+    /// The host has not connected yet, and is scheduled to at this time, in
+    /// milliseconds since the epoch.
+    Waiting(u64),
+    /// The host of a scheduled session has connected.
+    HostJoined(),
+    /// The user authenticated, and waits for the host to let them in. They
+    /// join with the usual list of users once admitted.
+    Pending(),
+    /// The user was kicked from the session, and banned if the flag is set.
+    /// The connection closes right after, and should not reconnect on its own.
+    Removed(bool),
+    /// The user cannot change shells, so their input is ignored. The flag is
+    /// set if entering the write password would allow it.
+    ReadOnly(bool),
+    /// The user entered the write password, and can now change shells.
+    Writable(),
+    /// The host accepted a file transfer, with the size of a file being
+    /// downloaded.
+    FileAccepted(u32, u64),
+    /// Chunk of a file downloaded from the host, with the offset for
+    /// decrypting it.
+    FileData(u32, Bytes, u64),
+    /// A file transfer finished successfully.
+    FileDone(u32),
+    /// The host refused a file transfer, or it failed partway.
+    FileFailed(u32, String),
+    /// Another message encoded in CBOR, compressed with the algorithm that
+    /// the client asked for.
+    Compressed(Bytes),
+}
+
+/// A real-time message sent from the client over WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum WsClient {
+    /// Authenticate the user's encryption key by zeros block.
+    Authenticate(Bytes),
+    /// Enter a TOTP code, after the server asks for one.
+    Totp(String),
+    /// Enter the session's write password, to change shells.
+    AuthenticateWrite(String),
+    /// Set the name of the current user.
+    SetName(String),
+    /// Send real-time information about the user's cursor.
+    SetCursor(Option<(i32, i32)>),
+    /// Set the currently focused shell.
+    SetFocus(Option<Sid>),
+    /// Create a new shell.
+    Create(i32, i32),
+    /// Create a new shell with an initial size, directory, or command.
+    CreateShell(WsNewShell),
+    /// Close a specific shell.
+    Close(Sid),
+    /// Set the title of a shell, or clear it with an empty string.
+    Rename(Sid, String),
+    /// Move a shell window to a new position and focus it.
+    Move(Sid, Option<WsWinsize>),
+    /// Add user data to a given shell.
+    Data(Sid, Bytes, u64),
+    /// Paste text into a shell, encrypted like other input. The host wraps
+    /// it in bracketed paste markers if the shell's program asked for them.
+    Paste(Sid, Bytes, u64),
+    /// Type the same input into every shell in the session at once.
+    Broadcast(Bytes, u64),
+    /// Subscribe to a shell, starting at a given chunk index.
+    Subscribe(Sid, u64),
+    /// Subscribe to a shell, starting at a given byte offset.
+    SubscribeFrom(Sid, u64),
+    /// Subscribe to a shell, starting with its current screen if available.
+    SubscribeScreen(Sid),
+    /// Fetch stored output of a shell from a start line up to an end line,
+    /// if the host counts lines, without subscribing.
+    FetchLines(Sid, u64, u64),
+    /// Acknowledge a shell's output up to a byte offset, if the connection
+    /// asked for flow control.
+    Ack(Sid, u64),
+    /// Send a a chat message to the room.
+    Chat(String),
+    /// Send a ping to the server, for latency measurement.
+    Ping(u64),
+    /// Ask the server to resend the full state of the session.
+    Sync(),
+    /// Fork the session into a new one, optionally copying recent output.
+    Fork(bool),
+    /// Start uploading a file to the host, with its encrypted name, the
+    /// offset for decrypting the name, and its size. Encrypted like input.
+    FileStart(u32, Bytes, u64, u64),
+    /// Send a chunk of a file being uploaded, after the host accepts it.
+    /// Encrypted like input, with the offset for decrypting it.
+    FileChunk(u32, Bytes, u64),
+    /// Finish uploading a file.
+    FileEnd(u32),
+    /// Ask to download a file from the host, with its encrypted path and the
+    /// offset for decrypting it. Encrypted like input.
+    FileRequest(u32, Bytes, u64),
+    /// Disconnect another viewer, if this viewer can type.
+    Kick(Uid),
+    /// Disconnect another viewer and turn away their address for the rest of
+    /// the session, if this viewer can type.
+    Ban(Uid),
+}
+
+impl WsClient {
+    /// Name of the message type, as it is serialized.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            WsClient::Authenticate(..) => "authenticate",
+            WsClient::Totp(..) => "totp",
+            WsClient::AuthenticateWrite(..) => "authenticateWrite",
+            WsClient::SetName(..) => "setName",
+            WsClient::SetCursor(..) => "setCursor",
+            WsClient::SetFocus(..) => "setFocus",
+            WsClient::Create(..) => "create",
+            WsClient::CreateShell(..) => "createShell",
+            WsClient::Close(..) => "close",
+            WsClient::Rename(..) => "rename",
+            WsClient::Move(..) => "move",
+            WsClient::Data(..) => "data",
+            WsClient::Paste(..) => "paste",
+            WsClient::Broadcast(..) => "broadcast",
+            WsClient::Subscribe(..) => "subscribe",
+            WsClient::SubscribeFrom(..) => "subscribeFrom",
+            WsClient::SubscribeScreen(..) => "subscribeScreen",
+            WsClient::FetchLines(..) => "fetchLines",
+            WsClient::Ack(..) => "ack",
+            WsClient::Chat(..) => "chat",
+            WsClient::Ping(..) => "ping",
+            WsClient::Sync(..) => "sync",
+            WsClient::Fork(..) => "fork",
+            WsClient::FileStart(..) => "fileStart",
+            WsClient::FileChunk(..) => "fileChunk",
+            WsClient::FileEnd(..) => "fileEnd",
+            WsClient::FileRequest(..) => "fileRequest",
+            WsClient::Kick(..) => "kick",
+            WsClient::Ban(..) => "ban",
+        }
+    }
+}
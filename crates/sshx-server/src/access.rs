@@ -0,0 +1,126 @@
+//! Network access control, based on the IP address of each request.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error};
+use hyper::HeaderMap;
+
+/// Header with the token that a server in the mesh sends when it proxies a
+/// request, vouching for the client address it adds to `X-Forwarded-For`.
+pub const PROXY_TOKEN_HEADER: &str = "x-sshx-proxy-token";
+
+/// A network block in CIDR notation, like `10.0.0.0/8` or `fd00::/8`.
+///
+/// Single addresses without a prefix length are also accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Returns whether an address is inside this network block.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().context("invalid IP address")?;
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().context("invalid prefix length")?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("prefix length /{prefix} is too long for {addr}");
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// IP address of the client making a request, stored as a request extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Rules for which client addresses may connect to the server.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    /// If not empty, only clients in these networks are allowed.
+    pub allow: Vec<IpNet>,
+    /// Clients in these networks are denied, even if otherwise allowed.
+    pub deny: Vec<IpNet>,
+    /// Reverse proxies whose `X-Forwarded-For` headers are trusted.
+    pub trusted_proxies: Vec<IpNet>,
+    /// If not empty, only viewers in these networks may join sessions, while
+    /// command-line clients may still connect from anywhere allowed.
+    pub viewer_allow: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Returns whether a client address is allowed to connect.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+    }
+
+    /// Returns whether a client address is allowed to join sessions as a
+    /// viewer, on top of [`AccessList::allows`].
+    pub fn allows_viewer(&self, ip: IpAddr) -> bool {
+        self.viewer_allow.is_empty() || self.viewer_allow.iter().any(|net| net.contains(ip))
+    }
+
+    /// Find the address of the client that sent a request.
+    ///
+    /// If the connection comes from a trusted proxy, this walks backward
+    /// through the `X-Forwarded-For` chain, returning the last address that
+    /// was not added by another trusted proxy. Other servers in the mesh are
+    /// trusted like proxies when `from_peer` is set.
+    pub fn client_ip(&self, remote: IpAddr, headers: &HeaderMap, from_peer: bool) -> IpAddr {
+        let mut ip = remote.to_canonical();
+        let mut trusted = from_peer;
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for hop in forwarded.into_iter().rev() {
+            if !trusted && !self.trusted_proxies.iter().any(|net| net.contains(ip)) {
+                break;
+            }
+            trusted = false;
+            match hop.trim().parse::<IpAddr>() {
+                Ok(hop) => ip = hop.to_canonical(),
+                Err(_) => break,
+            }
+        }
+        ip
+    }
+}
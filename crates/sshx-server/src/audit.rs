@@ -0,0 +1,386 @@
+//! Audit logging of security-relevant events, shipped to configurable sinks.
+//!
+//! Each event is serialized as a single line of JSON, with a timestamp and the
+//! client's IP address if known, then written to every sink in the background
+//! so that slow sinks do not block request handling.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, ensure, Context, Error, Result};
+use futures_util::future::BoxFuture;
+use hyper::{client::HttpConnector, Body, Client, Method, Request, Uri};
+use parking_lot::Mutex;
+use serde::Serialize;
+use sshx_core::{Sid, Uid};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixDatagram;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::warn;
+
+/// Rotate audit log files when they grow past this size.
+const FILE_ROTATE_BYTES: u64 = 64 << 20; // 64 MiB
+
+/// Number of rotated audit log files to keep, besides the current one.
+const FILE_ROTATE_KEEP: usize = 5;
+
+/// Timeout for delivering an event to an HTTP sink.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A security-relevant event recorded in the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A new session was created by a command-line client.
+    SessionCreated {
+        /// Name of the session.
+        session: String,
+    },
+    /// A session was closed by its command-line client.
+    SessionClosed {
+        /// Name of the session.
+        session: String,
+    },
+    /// A session was closed after reaching its time limit.
+    SessionExpired {
+        /// Name of the session.
+        session: String,
+    },
+    /// A session was terminated by an administrator.
+    SessionTerminated {
+        /// Name of the session.
+        session: String,
+    },
+    /// A read-only replay was made of a session's history.
+    SessionReplayed {
+        /// Name of the session.
+        session: String,
+        /// Name of the replay, which viewers connect to.
+        replay: String,
+    },
+    /// A session was closed after no terminal activity for too long.
+    SessionIdle {
+        /// Name of the session.
+        session: String,
+    },
+    /// Another command-line client took over hosting a session.
+    SessionAdopted {
+        /// Name of the session.
+        session: String,
+    },
+    /// Another command-line client started hosting some shells of a session.
+    HostJoined {
+        /// Name of the session.
+        session: String,
+    },
+    /// A command-line client stopped hosting some shells of a session.
+    HostLeft {
+        /// Name of the session.
+        session: String,
+    },
+    /// A command-line client presented a valid token for a session.
+    ClientAuthenticated {
+        /// Name of the session.
+        session: String,
+    },
+    /// A command-line client presented an invalid token for a session.
+    ClientRejected {
+        /// Name of the session.
+        session: String,
+    },
+    /// A viewer proved they have the encryption key for a session.
+    ViewerAuthenticated {
+        /// Name of the session.
+        session: String,
+        /// ID assigned to the viewer.
+        user: Uid,
+    },
+    /// A viewer failed to prove they have the encryption key for a session.
+    ViewerRejected {
+        /// Name of the session.
+        session: String,
+    },
+    /// An authenticated viewer disconnected from a session.
+    ViewerLeft {
+        /// Name of the session.
+        session: String,
+        /// ID assigned to the viewer.
+        user: Uid,
+        /// Number of input bytes the viewer sent to shells.
+        input_bytes: u64,
+    },
+    /// The command-line client opened a new shell.
+    ShellCreated {
+        /// Name of the session.
+        session: String,
+        /// ID of the shell.
+        shell: Sid,
+    },
+    /// The command-line client closed a shell.
+    ShellClosed {
+        /// Name of the session.
+        session: String,
+        /// ID of the shell.
+        shell: Sid,
+    },
+    /// A request was denied by the IP access list.
+    AddressDenied,
+    /// An address was banned for probing too many session names.
+    AddressBanned,
+}
+
+/// A timestamped event, as serialized to audit sinks.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<IpAddr>,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Destination that audit events are written to, one JSON line at a time.
+pub trait AuditSink: Send {
+    /// Write a single serialized event, without a trailing newline.
+    fn write<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Configuration for one of the built-in audit sinks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditTarget {
+    /// Append to a file, rotating it when it grows too large.
+    File(PathBuf),
+    /// Send to the local syslog daemon over a Unix datagram socket.
+    Syslog(PathBuf),
+    /// POST each event to a plain HTTP endpoint.
+    Http(Uri),
+}
+
+impl FromStr for AuditTarget {
+    type Err = Error;
+
+    /// Parse `file:<path>`, `syslog`, `syslog:<socket>`, or an `http://` URL.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file:") {
+            ensure!(!path.is_empty(), "missing path for audit file");
+            Ok(Self::File(path.into()))
+        } else if s == "syslog" {
+            Ok(Self::Syslog("/dev/log".into()))
+        } else if let Some(path) = s.strip_prefix("syslog:") {
+            Ok(Self::Syslog(path.into()))
+        } else if s.starts_with("http://") {
+            Ok(Self::Http(s.parse().context("invalid audit URL")?))
+        } else if s.starts_with("https://") {
+            bail!("HTTPS audit sinks are not supported, use a local forwarder")
+        } else {
+            bail!("unknown audit sink {s:?}, expected file:, syslog, or http://")
+        }
+    }
+}
+
+impl fmt::Display for AuditTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::Syslog(path) => write!(f, "syslog:{}", path.display()),
+            Self::Http(uri) => write!(f, "{uri}"),
+        }
+    }
+}
+
+impl AuditTarget {
+    /// Create a sink that writes to this target.
+    pub fn open(&self) -> Box<dyn AuditSink> {
+        match self {
+            Self::File(path) => Box::new(FileSink {
+                path: path.clone(),
+                file: None,
+                written: 0,
+            }),
+            Self::Syslog(path) => Box::new(SyslogSink {
+                path: path.clone(),
+                socket: None,
+            }),
+            Self::Http(uri) => Box::new(HttpSink {
+                uri: uri.clone(),
+                client: Client::new(),
+            }),
+        }
+    }
+}
+
+/// Records audit events and ships them to sinks in the background.
+pub struct AuditLog {
+    tx: mpsc::UnboundedSender<String>,
+    rx: Mutex<Option<mpsc::UnboundedReceiver<String>>>,
+    sinks: Mutex<Vec<Box<dyn AuditSink>>>,
+    enabled: bool,
+}
+
+impl AuditLog {
+    /// Create an audit log that writes to a set of sinks.
+    pub fn new(sinks: Vec<Box<dyn AuditSink>>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            tx,
+            rx: Mutex::new(Some(rx)),
+            enabled: !sinks.is_empty(),
+            sinks: Mutex::new(sinks),
+        }
+    }
+
+    /// Record an event, from a client at an IP address if known.
+    pub fn record(&self, ip: Option<IpAddr>, event: AuditEvent) {
+        if !self.enabled {
+            return;
+        }
+        let record = AuditRecord {
+            time_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            ip,
+            event: &event,
+        };
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                self.tx.send(line).ok();
+            }
+            Err(err) => warn!(?err, "failed to serialize audit event"),
+        }
+    }
+
+    /// Write recorded events to the sinks, until the log is dropped.
+    ///
+    /// This should only be called once, as a background task.
+    pub async fn run(&self) {
+        let Some(mut rx) = self.rx.lock().take() else {
+            return;
+        };
+        let mut sinks = std::mem::take(&mut *self.sinks.lock());
+        while let Some(line) = rx.recv().await {
+            for sink in &mut sinks {
+                if let Err(err) = sink.write(&line).await {
+                    warn!(?err, "failed to write audit event");
+                }
+            }
+        }
+    }
+}
+
+/// Appends events to a file, with size-based rotation.
+struct FileSink {
+    path: PathBuf,
+    file: Option<File>,
+    written: u64,
+}
+
+impl FileSink {
+    async fn rotate(&mut self) -> Result<()> {
+        self.file = None;
+        let rotated = |i: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{i}"));
+            PathBuf::from(name)
+        };
+        for i in (1..FILE_ROTATE_KEEP).rev() {
+            rename_if_exists(&rotated(i), &rotated(i + 1)).await?;
+        }
+        rename_if_exists(&self.path, &rotated(1)).await
+    }
+}
+
+async fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+impl AuditSink for FileSink {
+    fn write<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if self.written >= FILE_ROTATE_BYTES {
+                self.rotate().await?;
+            }
+            let file = match &mut self.file {
+                Some(file) => file,
+                None => {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.path)
+                        .await
+                        .with_context(|| format!("failed to open {}", self.path.display()))?;
+                    self.written = file.metadata().await?.len();
+                    self.file.insert(file)
+                }
+            };
+            file.write_all(format!("{line}\n").as_bytes()).await?;
+            file.flush().await?;
+            self.written += line.len() as u64 + 1;
+            Ok(())
+        })
+    }
+}
+
+/// Sends events to syslog with the `authpriv` facility.
+struct SyslogSink {
+    path: PathBuf,
+    socket: Option<UnixDatagram>,
+}
+
+impl AuditSink for SyslogSink {
+    fn write<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let socket = match &mut self.socket {
+                Some(socket) => socket,
+                None => {
+                    let socket = UnixDatagram::unbound()?;
+                    socket
+                        .connect(&self.path)
+                        .with_context(|| format!("failed to connect to {}", self.path.display()))?;
+                    self.socket.insert(socket)
+                }
+            };
+            // Priority 86 is the `authpriv` facility (10) at `info` severity (6).
+            let msg = format!("<86>sshx-server[{}]: {line}", std::process::id());
+            if let Err(err) = socket.send(msg.as_bytes()).await {
+                self.socket = None; // Reconnect on the next event.
+                return Err(err.into());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Sends each event as the JSON body of an HTTP POST request.
+struct HttpSink {
+    uri: Uri,
+    client: Client<HttpConnector>,
+}
+
+impl AuditSink for HttpSink {
+    fn write<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(&self.uri)
+                .header("content-type", "application/json")
+                .body(Body::from(line.to_owned()))?;
+            let resp = time::timeout(HTTP_TIMEOUT, self.client.request(req))
+                .await
+                .context("timed out sending audit event")??;
+            ensure!(
+                resp.status().is_success(),
+                "audit endpoint returned {}",
+                resp.status(),
+            );
+            Ok(())
+        })
+    }
+}
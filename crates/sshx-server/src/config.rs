@@ -0,0 +1,171 @@
+//! Configuration files for the server, in a flat subset of TOML.
+//!
+//! Each key is the name of a command-line flag, like `shell-history-bytes` or
+//! `shell_history_bytes`, and each value is a string, number, boolean, or an
+//! array of them for flags that can be repeated:
+//!
+//! ```toml
+//! bind = ["web=0.0.0.0:8051", "grpc=0.0.0.0:8052"]
+//! secret = "correct-horse-battery-staple"
+//! idle_timeout = 3600
+//! require_api_key = true
+//! ```
+
+use std::fs;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::{Chars, FromStr};
+
+use anyhow::{bail, Context, Error, Result};
+
+/// Settings loaded from a configuration file, in the order they were written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerConfig {
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl ServerConfig {
+    /// Read and parse a configuration file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        text.parse()
+            .with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Returns the values of a key, written with dashes like a flag.
+    pub fn get(&self, key: &str) -> Option<&[String]> {
+        let (_, values) = self.entries.iter().find(|(k, _)| k == key)?;
+        Some(values)
+    }
+
+    /// Iterate over each key and its values.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[String])> {
+        (self.entries.iter()).map(|(key, values)| (key.as_str(), values.as_slice()))
+    }
+}
+
+impl FromStr for ServerConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries: Vec<(String, Vec<String>)> = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let parse_line = || -> Result<Option<(String, Vec<String>)>> {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    return Ok(None);
+                }
+                if line.starts_with('[') {
+                    bail!("tables are not supported, keys must be at the top level");
+                }
+                let Some((key, value)) = line.split_once('=') else {
+                    bail!("expected `key = value`");
+                };
+                let key = key.trim();
+                if key.is_empty()
+                    || !key
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    bail!("invalid key {key:?}");
+                }
+                Ok(Some((key.replace('_', "-"), parse_value(value)?)))
+            };
+            let Some((key, values)) = parse_line().with_context(|| format!("line {}", i + 1))?
+            else {
+                continue;
+            };
+            if entries.iter().any(|(k, _)| *k == key) {
+                bail!("line {}: duplicate key {key:?}", i + 1);
+            }
+            entries.push((key, values));
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Parse a value and any trailing comment, returning it as a list of strings.
+fn parse_value(s: &str) -> Result<Vec<String>> {
+    let mut chars = s.trim().chars().peekable();
+    let values = match chars.peek() {
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            loop {
+                skip_whitespace(&mut chars);
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    break;
+                }
+                values.push(parse_scalar(&mut chars)?);
+                skip_whitespace(&mut chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => break,
+                    _ => bail!("expected `,` or `]` in array"),
+                }
+            }
+            values
+        }
+        _ => vec![parse_scalar(&mut chars)?],
+    };
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        None | Some('#') => Ok(values),
+        Some(c) => bail!("unexpected {c:?} after value"),
+    }
+}
+
+/// Parse a string, number, or boolean.
+fn parse_scalar(chars: &mut Peekable<Chars>) -> Result<String> {
+    let mut value = String::new();
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => return Ok(value),
+                    Some('\\') => value.push(match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some(c @ ('"' | '\\')) => c,
+                        _ => bail!("invalid escape in string"),
+                    }),
+                    Some(c) => value.push(c),
+                    None => bail!("unterminated string"),
+                }
+            }
+        }
+        Some('\'') => {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('\'') => return Ok(value),
+                    Some(c) => value.push(c),
+                    None => bail!("unterminated string"),
+                }
+            }
+        }
+        _ => {
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-' | '_')) {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+            let is_number = value.replace('_', "").parse::<f64>().is_ok();
+            if !is_number && value != "true" && value != "false" {
+                bail!("expected a string, number, or boolean");
+            }
+            Ok(value.replace('_', ""))
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
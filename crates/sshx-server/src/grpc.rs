@@ -1,22 +1,32 @@
 //! Defines gRPC routes and application request logic.
 
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use base64::prelude::{Engine as _, BASE64_STANDARD};
-use hmac::Mac;
+use bytes::Bytes;
+use sshx_core::feature::{self, HOST_FEATURES, LINE_INDEX};
 use sshx_core::proto::{
     client_update::ClientMessage, server_update::ServerMessage, sshx_service_server::SshxService,
-    ClientUpdate, CloseRequest, CloseResponse, OpenRequest, OpenResponse, ServerUpdate,
+    AccessEvent, AdoptRequest, AdoptResponse, ClientUpdate, CloseRequest, CloseResponse, FileState,
+    KickRequest, KickResponse, OpenRequest, OpenResponse, ServerUpdate, ViewersRequest,
+    ViewersResponse,
 };
-use sshx_core::{rand_alphanumeric, Sid};
-use tokio::sync::mpsc;
+use sshx_core::ws::WsServer;
+use sshx_core::{
+    rand_alphanumeric, rand_memorable, telemetry, ErrorCode, Sid, Uid, PROTOCOL_VERSION,
+};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task;
 use tokio::time::{self, MissedTickBehavior};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
-use tracing::{error, info, warn};
+use tracing::{debug, error, field, info, instrument, warn, Instrument, Span};
 
-use crate::session::{Metadata, Session};
+use crate::access::ClientIp;
+use crate::audit::AuditEvent;
+use crate::session::{Metadata, ScreenSnapshot, Session};
+use crate::web::auth::hash_write_password;
 use crate::ServerState;
 
 /// Interval for synchronizing sequence numbers with the client.
@@ -25,6 +35,33 @@ pub const SYNC_INTERVAL: Duration = Duration::from_secs(5);
 /// Interval for measuring client latency.
 pub const PING_INTERVAL: Duration = Duration::from_secs(2);
 
+/// Interval for synchronizing sequence numbers in low-bandwidth mode.
+pub const LOW_BANDWIDTH_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval for measuring client latency in low-bandwidth mode.
+pub const LOW_BANDWIDTH_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval for acknowledging output to clients that use flow control.
+pub const ACK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Maximum length of the banner shown to viewers, in bytes.
+pub const MAX_BANNER_BYTES: usize = 4096;
+
+/// Maximum length of the password that viewers enter to write, in bytes.
+pub const MAX_WRITE_PASSWORD_LEN: usize = 1024;
+
+/// Maximum length of the encrypted program that shells run, in bytes.
+pub const MAX_ARGV_LEN: usize = 4096;
+
+/// Maximum length of a custom session name requested by the client.
+pub const MAX_SESSION_NAME_LEN: usize = 48;
+
+/// Attempts at generating a memorable session name that is not taken.
+const MEMORABLE_NAME_ATTEMPTS: usize = 16;
+
+/// Furthest ahead that a session can be scheduled to start.
+pub const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(30 * 86400);
+
 /// Server that handles gRPC requests from the sshx command-line client.
 #[derive(Clone)]
 pub struct GrpcServer(Arc<ServerState>);
@@ -34,6 +71,143 @@ impl GrpcServer {
     pub fn new(state: Arc<ServerState>) -> Self {
         Self(state)
     }
+
+    /// Validate a client token for a session, recording the decision, and
+    /// connect to the session if it exists.
+    ///
+    /// Tokens are only valid for the session they were issued for, not a
+    /// later session that reuses its name.
+    async fn authenticate(
+        &self,
+        ip: Option<IpAddr>,
+        name: &str,
+        token: &str,
+    ) -> Result<Option<Arc<Session>>, Status> {
+        // Check the signature first, so that bad tokens cannot move sessions.
+        let result = match self.0.verify_session_token(name, token) {
+            Some(nonce) => match self.0.backend_connect(name).await {
+                Ok(Some(session)) if session.metadata().nonce != nonce => Err(invalid_token()),
+                Ok(session) => Ok(session),
+                Err(err) => {
+                    error!(?err, "failed to connect to backend session");
+                    return Err(ErrorCode::Internal.status(err.to_string()));
+                }
+            },
+            None => Err(invalid_token()),
+        };
+        let session = name.to_string();
+        let event = match result {
+            Ok(_) => AuditEvent::ClientAuthenticated { session },
+            Err(_) => AuditEvent::ClientRejected { session },
+        };
+        self.0.audit().record(ip, event);
+        result
+    }
+
+    /// Find a session for a client that moderates its viewers, checking the
+    /// session's token.
+    async fn moderate(
+        &self,
+        ip: Option<IpAddr>,
+        name: &str,
+        token: &str,
+    ) -> Result<Arc<Session>, Status> {
+        (self.authenticate(ip, name, token).await?)
+            .ok_or_else(|| ErrorCode::NotFound.status("session not found"))
+    }
+
+    /// Add a new host to a session, either taking it over or alongside the
+    /// clients hosting it now.
+    async fn add_host(&self, request: Request<AdoptRequest>, join: bool) -> RR<AdoptResponse> {
+        let ip = client_ip(&request);
+        let request = request.into_inner();
+        if self.0.draining().is_some() {
+            return Err(ErrorCode::ShuttingDown.status("server is shutting down"));
+        }
+        let session = self.moderate(ip, &request.name, &request.token).await?;
+        if request.protocol != 0 && request.protocol != PROTOCOL_VERSION {
+            let msg = format!(
+                "client protocol v{} is incompatible with server protocol v{PROTOCOL_VERSION}",
+                request.protocol,
+            );
+            return Err(ErrorCode::Incompatible.status(msg));
+        }
+        let origin = self.0.override_origin().unwrap_or(request.origin);
+        if origin.is_empty() {
+            return Err(ErrorCode::InvalidRequest.status("origin is empty"));
+        }
+        // The new host must be able to encrypt output that viewers can read.
+        if request.encrypted_zeros != session.metadata().encrypted_zeros {
+            return Err(ErrorCode::InvalidAuth.status("encryption key does not match"));
+        }
+        let name = request.name;
+        let host = if join {
+            let host = (session.join_host())
+                .map_err(|err| ErrorCode::QuotaExceeded.status(err.to_string()))?;
+            info!(%name, "additional host joined session");
+            let session = name.clone();
+            self.0
+                .audit()
+                .record(ip, AuditEvent::HostJoined { session });
+            host
+        } else {
+            let host = session.adopt();
+            info!(%name, "session adopted by a new host");
+            let session = name.clone();
+            self.0
+                .audit()
+                .record(ip, AuditEvent::SessionAdopted { session });
+            host
+        };
+
+        let mut url = format!("{origin}/s/{name}");
+        if self.0.web_auth().signed_urls {
+            let nonce = &session.metadata().nonce;
+            url = format!("{url}?token={}", self.0.view_token(&name, nonce));
+        }
+        let write_token = match session.metadata().write_protected {
+            true => self.0.write_token(&name, &session.metadata().nonce),
+            false => String::new(),
+        };
+        Ok(Response::new(AdoptResponse {
+            host,
+            url,
+            write_token,
+            expires_ms: session.metadata().deadline.map_or(0, |deadline| {
+                let since_epoch = deadline.duration_since(SystemTime::UNIX_EPOCH);
+                since_epoch.unwrap_or_default().as_millis() as u64
+            }),
+            flow_control: session.metadata().flow_control,
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol: PROTOCOL_VERSION,
+        }))
+    }
+
+    /// Remove an additional host from a session, closing only its shells.
+    async fn leave(&self, ip: Option<IpAddr>, request: CloseRequest) -> RR<CloseResponse> {
+        let session = match self.0.backend_connect(&request.name).await {
+            Ok(Some(session)) => session,
+            Ok(None) => return Err(ErrorCode::NotFound.status("session not found")),
+            Err(err) => {
+                error!(?err, "failed to connect to backend session");
+                return Err(ErrorCode::Internal.status(err.to_string()));
+            }
+        };
+        match session.leave_host(&request.host) {
+            Ok(true) => {}
+            Ok(false) => return Err(ErrorCode::NotFound.status("host not found")),
+            Err(err) => return Err(ErrorCode::Internal.status(err.to_string())),
+        }
+        info!("additional host left session {}", request.name);
+        let session = request.name;
+        self.0.audit().record(ip, AuditEvent::HostLeft { session });
+        Ok(Response::new(CloseResponse {}))
+    }
+}
+
+/// Returns the IP address of the client that sent a request.
+fn client_ip<T>(request: &Request<T>) -> Option<IpAddr> {
+    request.extensions().get::<ClientIp>().map(|ip| ip.0)
 }
 
 type RR<T> = Result<Response<T>, Status>;
@@ -42,90 +216,331 @@ type RR<T> = Result<Response<T>, Status>;
 impl SshxService for GrpcServer {
     type ChannelStream = ReceiverStream<Result<ServerUpdate, Status>>;
 
+    #[instrument(name = "grpc.open", skip_all)]
     async fn open(&self, request: Request<OpenRequest>) -> RR<OpenResponse> {
+        telemetry::set_parent(&Span::current(), &request);
+        let ip = client_ip(&request);
         let request = request.into_inner();
+        if self.0.draining().is_some() {
+            return Err(ErrorCode::ShuttingDown.status("server is shutting down"));
+        }
+        if !self.0.allow_session(ip) {
+            return Err(ErrorCode::RateLimited.status("too many sessions opened recently"));
+        }
+        (self.0.check_session_capacity(ip))
+            .map_err(|err| ErrorCode::QuotaExceeded.status(err.to_string()))?;
+        let size_policy = request.size_policy();
+        let features = feature::negotiate(HOST_FEATURES, &request.features);
         let origin = self.0.override_origin().unwrap_or(request.origin);
         if origin.is_empty() {
-            return Err(Status::invalid_argument("origin is empty"));
-        }
-        let name = rand_alphanumeric(10);
-        info!(%name, "creating new session");
-        match self.0.lookup(&name) {
-            Some(_) => return Err(Status::already_exists("generated duplicate ID")),
-            None => {
-                let metadata = Metadata {
-                    encrypted_zeros: request.encrypted_zeros,
-                };
-                self.0.insert(&name, Arc::new(Session::new(metadata)));
+            return Err(ErrorCode::InvalidRequest.status("origin is empty"));
+        }
+        // Clients before version negotiation was added do not send a protocol.
+        if request.protocol != 0 && request.protocol != PROTOCOL_VERSION {
+            let msg = format!(
+                "client protocol v{} is incompatible with server protocol v{PROTOCOL_VERSION}",
+                request.protocol,
+            );
+            return Err(ErrorCode::Incompatible.status(msg));
+        }
+        if !request.totp_secret.is_empty() && request.totp_secret.len() < 16 {
+            return Err(ErrorCode::InvalidRequest.status("TOTP secret is too short"));
+        }
+        if request.banner.len() > MAX_BANNER_BYTES {
+            return Err(ErrorCode::InvalidRequest.status("banner is too long"));
+        }
+        let requested_bandwidth = (request.shell_bandwidth != 0).then_some(request.shell_bandwidth);
+        let shell_bandwidth = self.0.shell_bandwidth(requested_bandwidth);
+        let now = SystemTime::now();
+        let scheduled = (request.scheduled_ms != 0)
+            .then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(request.scheduled_ms));
+        if scheduled.is_some_and(|start| start > now + MAX_SCHEDULE_AHEAD) {
+            return Err(ErrorCode::InvalidRequest.status("session is scheduled too far ahead"));
+        }
+        // Time limits of scheduled sessions count from when they start.
+        let start = scheduled.map_or(now, |start| start.max(now));
+        let deadline = (request.time_limit != 0)
+            .then(|| start + Duration::from_secs(request.time_limit.into()));
+        let tenancy = self.0.tenancy();
+        let namespace = match tenancy.namespace(&request.api_key) {
+            Some(namespace) => Some(namespace.name.clone()),
+            None if !request.api_key.is_empty() => {
+                return Err(ErrorCode::InvalidAuth.status("invalid API key"));
+            }
+            None if tenancy.require_api_key => {
+                return Err(ErrorCode::InvalidAuth.status("an API key is required"));
+            }
+            None => None,
+        };
+        let quota = namespace
+            .as_deref()
+            .map(|ns| tenancy.quota(ns))
+            .unwrap_or_default();
+        if let (Some(namespace), Some(max)) = (&namespace, quota.max_sessions) {
+            if self.0.sessions_in(namespace).len() >= max {
+                let msg = format!("namespace {namespace} has reached its limit of {max} sessions");
+                return Err(ErrorCode::QuotaExceeded.status(msg));
+            }
+        }
+        let max_duration = [quota.max_duration, self.0.max_session_lifetime()]
+            .into_iter()
+            .flatten()
+            .min();
+        let deadline = match max_duration {
+            Some(max) => Some(deadline.map_or(start + max, |deadline| deadline.min(start + max))),
+            None => deadline,
+        };
+        let fork_from = match request.fork_from.split_once(',') {
+            Some((name, token)) => {
+                // Sessions are isolated between namespaces, even when forking.
+                match self.authenticate(ip, name, token).await? {
+                    Some(session) if session.metadata().namespace == namespace => Some(session),
+                    _ => return Err(ErrorCode::NotFound.status("session to fork not found")),
+                }
+            }
+            None if request.fork_from.is_empty() => None,
+            None => return Err(ErrorCode::InvalidRequest.status("missing name and token to fork")),
+        };
+        if !request.name.is_empty() && !is_valid_session_name(&request.name) {
+            let msg = format!(
+                "session names must be 3 to {MAX_SESSION_NAME_LEN} lowercase letters, digits, \
+                 or dashes, starting and ending with a letter or digit",
+            );
+            return Err(ErrorCode::InvalidRequest.status(msg));
+        }
+        if request.argv.len() > MAX_ARGV_LEN {
+            return Err(ErrorCode::InvalidRequest.status("shell command is too long"));
+        }
+        if request.write_password.len() > MAX_WRITE_PASSWORD_LEN {
+            return Err(ErrorCode::InvalidRequest.status("write password is too long"));
+        }
+        let write_password_hash = match request.write_password.is_empty() {
+            true => Bytes::new(),
+            false => {
+                // Hashing is slow on purpose, so keep it off the async runtime.
+                let password = request.write_password.clone();
+                task::spawn_blocking(move || hash_write_password(&password))
+                    .await
+                    .map_err(|_| ErrorCode::Internal.status("failed to hash write password"))?
+            }
+        };
+
+        let metadata = Metadata {
+            encrypted_zeros: request.encrypted_zeros,
+            low_bandwidth: request.low_bandwidth,
+            totp_secret: request.totp_secret.clone(),
+            deadline,
+            banner: request.banner,
+            watermark: request.watermark,
+            shell_bandwidth,
+            flow_control: request.flow_control,
+            output_limit: self.0.output_limit(),
+            scheduled,
+            namespace,
+            max_retained_bytes: self.0.shell_history_bytes(quota.max_retained_bytes),
+            write_protected: request.write_protected,
+            write_password_hash,
+            argv: (!request.argv.is_empty()).then_some((request.argv, request.argv_offset)),
+            size_policy,
+            features: features.clone(),
+            max_session_bytes: self.0.max_session_bytes(),
+            opened_from: ip,
+            knock: request.knock,
+            nonce: Bytes::copy_from_slice(&rand::random::<[u8; 16]>()),
+        };
+        let session = Session::new(metadata);
+        if let Some(parent) = fork_from {
+            if let Err(err) = session.copy_layout(parent.list_shells()) {
+                return Err(ErrorCode::InvalidRequest.status(err.to_string()));
             }
+        }
+        let session = Arc::new(session);
+        let (mut name, attempts) = if !request.name.is_empty() {
+            (request.name.clone(), 1)
+        } else if request.memorable_name {
+            (rand_memorable(), MEMORABLE_NAME_ATTEMPTS)
+        } else {
+            (rand_alphanumeric(10), 1)
+        };
+        for attempt in 1.. {
+            let inserted = self.0.try_insert(&name, session.clone()).await;
+            match inserted {
+                Ok(true) => break,
+                Ok(false) if !request.name.is_empty() => {
+                    let msg = format!("session name {name} is already taken");
+                    return Err(ErrorCode::NameTaken.status(msg));
+                }
+                Ok(false) if attempt < attempts => name = rand_memorable(),
+                Ok(false) => return Err(ErrorCode::Internal.status("generated duplicate ID")),
+                Err(err) => {
+                    error!(?err, "failed to check for duplicate session name");
+                    return Err(ErrorCode::Internal.status("failed to create session"));
+                }
+            }
+        }
+        info!(%name, "created new session");
+        let nonce = session.metadata().nonce.clone();
+        let session = name.clone();
+        self.0
+            .audit()
+            .record(ip, AuditEvent::SessionCreated { session });
+        let token = self.0.session_token(&name, &nonce);
+        let mut url = format!("{origin}/s/{name}");
+        if self.0.web_auth().signed_urls {
+            url = format!("{url}?token={}", self.0.view_token(&name, &nonce));
+        }
+        let write_token = match request.write_protected {
+            true => self.0.write_token(&name, &nonce),
+            false => String::new(),
         };
-        let token = self.0.mac().chain_update(&name).finalize();
-        let url = format!("{origin}/s/{name}");
         Ok(Response::new(OpenResponse {
             name,
-            token: BASE64_STANDARD.encode(token.into_bytes()),
+            token,
             url,
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol: PROTOCOL_VERSION,
+            low_bandwidth: request.low_bandwidth,
+            totp: !request.totp_secret.is_empty(),
+            expires_ms: deadline.map_or(0, |deadline| {
+                let since_epoch = deadline.duration_since(SystemTime::UNIX_EPOCH);
+                since_epoch.unwrap_or_default().as_millis() as u64
+            }),
+            watermark: request.watermark,
+            shell_bandwidth: shell_bandwidth.unwrap_or(0),
+            flow_control: request.flow_control,
+            scheduled: scheduled.is_some(),
+            write_token,
+            write_password: !request.write_password.is_empty(),
+            size_policy: size_policy.into(),
+            features,
+            knock: request.knock,
         }))
     }
 
+    #[instrument(name = "grpc.channel", skip_all, fields(session = field::Empty))]
     async fn channel(&self, request: Request<Streaming<ClientUpdate>>) -> RR<Self::ChannelStream> {
+        telemetry::set_parent(&Span::current(), &request);
+        let ip = client_ip(&request);
         let mut stream = request.into_inner();
         let first_update = match stream.next().await {
             Some(result) => result?,
-            None => return Err(Status::invalid_argument("missing first message")),
+            None => return Err(ErrorCode::InvalidRequest.status("missing first message")),
         };
-        let session_name = match first_update.client_message {
+        let (session_name, token, host) = match first_update.client_message {
             Some(ClientMessage::Hello(hello)) => {
                 let (name, token) = hello
                     .split_once(',')
-                    .ok_or_else(|| Status::invalid_argument("missing name and token"))?;
-                validate_token(self.0.mac(), name, token)?;
-                name.to_string()
+                    .ok_or_else(|| ErrorCode::InvalidRequest.status("missing name and token"))?;
+                // Clients that adopted a session also send their host identity.
+                let (token, host) = token.split_once(',').unwrap_or((token, ""));
+                (name.to_string(), token.to_string(), host.to_string())
             }
-            _ => return Err(Status::invalid_argument("invalid first message")),
+            _ => return Err(ErrorCode::InvalidRequest.status("invalid first message")),
         };
-        let session = match self.0.backend_connect(&session_name).await {
-            Ok(Some(session)) => session,
-            Ok(None) => return Err(Status::not_found("session not found")),
-            Err(err) => {
-                error!(?err, "failed to connect to backend session");
-                return Err(Status::internal(err.to_string()));
-            }
+        Span::current().record("session", &session_name);
+        if self.0.draining().is_some() {
+            return Err(ErrorCode::ShuttingDown.status("server is shutting down"));
+        }
+        let session = self.moderate(ip, &session_name, &token).await?;
+        // Additional hosts run only their own shells, and send only to them.
+        let owner = if session.is_host(&host) {
+            session.host_connected();
+            None
+        } else if session.is_co_host(&host) {
+            Some(host.clone())
+        } else {
+            return Err(ErrorCode::HostReplaced.status("session was adopted by another host"));
         };
 
         // We now spawn an asynchronous task that sends updates to the client. Note that
         // when this task finishes, the sender end is dropped, so the receiver is
         // automatically closed.
         let (tx, rx) = mpsc::channel(16);
-        tokio::spawn(async move {
-            if let Err(err) = handle_streaming(&tx, &session, stream).await {
+        let state = Arc::clone(&self.0);
+        let task = async move {
+            let _host_guard = owner.is_none().then(|| session.host_scope());
+            let audit = |event| state.audit().record(ip, event);
+            let (name, owner) = (&session_name, owner.as_deref());
+            let result = handle_streaming(&tx, &session, name, &host, owner, &audit, stream).await;
+            if let Err(err) = result {
                 warn!(?err, "connection exiting early due to an error");
             }
-        });
+        };
+        // The streaming span lasts as long as the connection.
+        tokio::spawn(task.in_current_span());
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
+    #[instrument(name = "grpc.close", skip_all)]
     async fn close(&self, request: Request<CloseRequest>) -> RR<CloseResponse> {
+        telemetry::set_parent(&Span::current(), &request);
+        let ip = client_ip(&request);
         let request = request.into_inner();
-        validate_token(self.0.mac(), &request.name, &request.token)?;
+        self.authenticate(ip, &request.name, &request.token).await?;
+        if !request.host.is_empty() {
+            return self.leave(ip, request).await;
+        }
         info!("closing session {}", request.name);
         if let Err(err) = self.0.close_session(&request.name).await {
             error!(?err, "failed to close session {}", request.name);
-            return Err(Status::internal(err.to_string()));
+            return Err(ErrorCode::Internal.status(err.to_string()));
         }
+        let session = request.name;
+        self.0
+            .audit()
+            .record(ip, AuditEvent::SessionClosed { session });
         Ok(Response::new(CloseResponse {}))
     }
-}
 
-/// Validate the client token for a session.
-fn validate_token(mac: impl Mac, name: &str, token: &str) -> Result<(), Status> {
-    if let Ok(token) = BASE64_STANDARD.decode(token) {
-        if mac.chain_update(name).verify_slice(&token).is_ok() {
-            return Ok(());
+    #[instrument(name = "grpc.adopt", skip_all)]
+    async fn adopt(&self, request: Request<AdoptRequest>) -> RR<AdoptResponse> {
+        telemetry::set_parent(&Span::current(), &request);
+        self.add_host(request, false).await
+    }
+
+    #[instrument(name = "grpc.join", skip_all)]
+    async fn join(&self, request: Request<AdoptRequest>) -> RR<AdoptResponse> {
+        telemetry::set_parent(&Span::current(), &request);
+        self.add_host(request, true).await
+    }
+
+    #[instrument(name = "grpc.viewers", skip_all)]
+    async fn viewers(&self, request: Request<ViewersRequest>) -> RR<ViewersResponse> {
+        telemetry::set_parent(&Span::current(), &request);
+        let ip = client_ip(&request);
+        let request = request.into_inner();
+        let session = self.moderate(ip, &request.name, &request.token).await?;
+        let viewers = session.list_viewers();
+        Ok(Response::new(ViewersResponse { viewers }))
+    }
+
+    #[instrument(name = "grpc.kick", skip_all)]
+    async fn kick(&self, request: Request<KickRequest>) -> RR<KickResponse> {
+        telemetry::set_parent(&Span::current(), &request);
+        let ip = client_ip(&request);
+        let request = request.into_inner();
+        let session = self.moderate(ip, &request.name, &request.token).await?;
+        if !session.kick(Uid(request.uid), request.ban, None) {
+            return Err(ErrorCode::NotFound.status("viewer not found"));
         }
+        info!(name = %request.name, uid = request.uid, ban = request.ban, "kicked viewer");
+        Ok(Response::new(KickResponse {}))
     }
-    Err(Status::unauthenticated("invalid token"))
+}
+
+/// Check that a custom session name is short and safe to use in URLs.
+fn is_valid_session_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    (3..=MAX_SESSION_NAME_LEN).contains(&bytes.len())
+        && (bytes.iter()).all(|&b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && bytes[0] != b'-'
+        && bytes[bytes.len() - 1] != b'-'
+}
+
+/// Error for a client token that is not valid for the session.
+fn invalid_token() -> Status {
+    ErrorCode::InvalidAuth.status("invalid token")
 }
 
 type ServerTx = mpsc::Sender<Result<ServerUpdate, Status>>;
@@ -134,37 +549,76 @@ type ServerTx = mpsc::Sender<Result<ServerUpdate, Status>>;
 async fn handle_streaming(
     tx: &ServerTx,
     session: &Session,
+    name: &str,
+    host: &str,
+    owner: Option<&str>,
+    audit: &(dyn Fn(AuditEvent) + Sync),
     mut stream: Streaming<ClientUpdate>,
 ) -> Result<(), &'static str> {
-    let mut sync_interval = time::interval(SYNC_INTERVAL);
+    let (sync_period, ping_period) = match session.metadata().low_bandwidth {
+        true => (LOW_BANDWIDTH_SYNC_INTERVAL, LOW_BANDWIDTH_PING_INTERVAL),
+        false => (SYNC_INTERVAL, PING_INTERVAL),
+    };
+
+    let mut sync_interval = time::interval(sync_period);
     sync_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-    let mut ping_interval = time::interval(PING_INTERVAL);
+    let mut ping_interval = time::interval(ping_period);
     ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
+    let mut ack_interval = time::interval(ACK_INTERVAL);
+    ack_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_ack = None;
+
+    // Only set if the client asks to watch the access log.
+    let mut access_rx = None;
+
+    let update_rx = session.update_rx_for(owner);
+
     loop {
         tokio::select! {
             // Send periodic sync messages to the client.
             _ = sync_interval.tick() => {
-                let msg = ServerMessage::Sync(session.sequence_numbers());
+                let msg = ServerMessage::Sync(session.host_sequence_numbers(owner));
                 if !send_msg(tx, msg).await {
                     return Err("failed to send sync message");
                 }
             }
+            // Acknowledge output as viewers consume it, if using flow control.
+            _ = ack_interval.tick(), if session.metadata().flow_control => {
+                let acks = session.acked_seqnums(owner);
+                if last_ack.as_ref() != Some(&acks) {
+                    if !send_msg(tx, ServerMessage::Ack(acks.clone())).await {
+                        return Err("failed to send ack message");
+                    }
+                    last_ack = Some(acks);
+                }
+            }
             // Send periodic pings to the client.
             _ = ping_interval.tick() => {
                 send_msg(tx, ServerMessage::Ping(get_time_ms())).await;
             }
             // Send buffered server updates to the client.
-            Ok(msg) = session.update_rx().recv() => {
+            result = update_rx.recv() => {
+                // Only the channels of additional hosts close, once they leave.
+                let Ok(msg) = result else {
+                    return Ok(());
+                };
                 if !send_msg(tx, msg).await {
                     return Err("failed to send update message");
                 }
             }
+            // Send new access log entries, if the client is watching them.
+            event = recv_access(&mut access_rx) => {
+                if !send_msg(tx, ServerMessage::Access(event)).await {
+                    return Err("failed to send access message");
+                }
+            }
             // Handle incoming client messages.
             maybe_update = stream.next() => {
                 if let Some(Ok(update)) = maybe_update {
-                    if !handle_update(tx, session, update).await {
+                    let update = handle_update(tx, session, name, owner, audit, &mut access_rx, update);
+                    if !update.await {
                         return Err("error responding to client update");
                     }
                 } else {
@@ -172,6 +626,12 @@ async fn handle_streaming(
                     return Ok(());
                 }
             }
+            // Exit when another client adopts the session, for good.
+            _ = session.host_replaced(host), if owner.is_none() => {
+                let status = ErrorCode::HostReplaced.status("session was adopted by another host");
+                tx.send(Err(status)).await.ok();
+                return Ok(());
+            }
             // Exit on a session shutdown signal.
             _ = session.terminated() => {
                 let msg = String::from("disconnecting because session is closed");
@@ -182,37 +642,146 @@ async fn handle_streaming(
     }
 }
 
+/// Receive the next access log entry, or wait forever if not watching.
+async fn recv_access(rx: &mut Option<broadcast::Receiver<AccessEvent>>) -> AccessEvent {
+    if let Some(rx) = rx {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!(n, "client fell behind on access log, skipping entries");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+    std::future::pending().await
+}
+
 /// Handles a singe update from the client. Returns `true` on success.
-async fn handle_update(tx: &ServerTx, session: &Session, update: ClientUpdate) -> bool {
+async fn handle_update(
+    tx: &ServerTx,
+    session: &Session,
+    name: &str,
+    owner: Option<&str>,
+    audit: &(dyn Fn(AuditEvent) + Sync),
+    access_rx: &mut Option<broadcast::Receiver<AccessEvent>>,
+    update: ClientUpdate,
+) -> bool {
     session.access();
+    // Each host may only change the shells that it runs.
+    let shell_id = match &update.client_message {
+        Some(ClientMessage::Data(data)) => Some(data.id),
+        Some(ClientMessage::Screen(screen)) => Some(screen.id),
+        Some(ClientMessage::ClosedShell(id)) => Some(*id),
+        Some(ClientMessage::Exited(exited)) => Some(exited.id),
+        _ => None,
+    };
+    if let Some(id) = shell_id {
+        if session.shell_owner(Sid(id)).as_deref() != owner {
+            return send_err(tx, format!("shell {id} is run by another host")).await;
+        }
+    }
     match update.client_message {
         Some(ClientMessage::Hello(_)) => {
             return send_err(tx, "unexpected hello".into()).await;
         }
         Some(ClientMessage::Data(data)) => {
+            if data.discontinuous {
+                if let Err(err) = session.skip_data(Sid(data.id), data.seq) {
+                    return send_err(tx, format!("skip data: {:?}", err)).await;
+                }
+            }
+            if data.line_known && session.metadata().has_feature(LINE_INDEX) {
+                if let Err(err) = session.mark_line(Sid(data.id), data.line, data.seq) {
+                    return send_err(tx, format!("mark line: {:?}", err)).await;
+                }
+            }
             if let Err(err) = session.add_data(Sid(data.id), data.data, data.seq) {
                 return send_err(tx, format!("add data: {:?}", err)).await;
             }
         }
+        Some(ClientMessage::Screen(screen)) => {
+            let snapshot = ScreenSnapshot {
+                seq: screen.seq,
+                offset: screen.offset,
+                data: screen.data,
+            };
+            if let Err(err) = session.set_screen(Sid(screen.id), snapshot) {
+                return send_err(tx, format!("set screen: {:?}", err)).await;
+            }
+        }
         Some(ClientMessage::CreatedShell(new_shell)) => {
             let id = Sid(new_shell.id);
             let center = (new_shell.x, new_shell.y);
-            if let Err(err) = session.add_shell(id, center) {
+            let clamp = |n: u32| u16::try_from(n).unwrap_or(u16::MAX);
+            let size = (new_shell.rows > 0 && new_shell.cols > 0)
+                .then(|| (clamp(new_shell.rows), clamp(new_shell.cols)));
+            if let Err(err) = session.add_host_shell(id, owner, center, size) {
                 return send_err(tx, format!("add shell: {:?}", err)).await;
             }
+            let session = name.to_owned();
+            audit(AuditEvent::ShellCreated { session, shell: id });
+        }
+        Some(ClientMessage::ClosedShell(id)) => match session.close_shell(Sid(id)) {
+            Ok(true) => {
+                let session = name.to_owned();
+                audit(AuditEvent::ShellClosed {
+                    session,
+                    shell: Sid(id),
+                });
+            }
+            Ok(false) => {}
+            Err(err) => return send_err(tx, format!("close shell: {:?}", err)).await,
+        },
+        Some(ClientMessage::Exited(exited)) => {
+            if let Err(err) = session.shell_exited(Sid(exited.id), exited.code) {
+                return send_err(tx, format!("shell exited: {:?}", err)).await;
+            }
+        }
+        Some(ClientMessage::Forked(forked)) => {
+            session.send_forked(forked.url, forked.offset);
+        }
+        Some(ClientMessage::FileStatus(status)) => {
+            let msg = match status.state() {
+                FileState::FileAccepted => WsServer::FileAccepted(status.id, status.size),
+                FileState::FileDone => WsServer::FileDone(status.id),
+                FileState::FileFailed => WsServer::FileFailed(status.id, status.error),
+            };
+            session.send_to(Uid(status.uid), msg).await;
+        }
+        Some(ClientMessage::FileData(file)) => {
+            let msg = WsServer::FileData(file.id, file.data, file.offset);
+            if !session.send_to(Uid(file.uid), msg).await {
+                debug!(
+                    id = file.id,
+                    uid = file.uid,
+                    "dropped chunk of downloaded file"
+                );
+            }
         }
-        Some(ClientMessage::ClosedShell(id)) => {
-            if let Err(err) = session.close_shell(Sid(id)) {
-                return send_err(tx, format!("close shell: {:?}", err)).await;
+        Some(ClientMessage::WatchAccess(since)) => {
+            let (events, rx) = session.watch_access(since);
+            *access_rx = Some(rx);
+            for event in events {
+                if !send_msg(tx, ServerMessage::Access(event)).await {
+                    return false;
+                }
             }
         }
+        Some(ClientMessage::Admit(decision))
+            if !session.admit(Uid(decision.uid), decision.admitted) =>
+        {
+            debug!(uid = decision.uid, "viewer left before being admitted");
+        }
+        Some(ClientMessage::Admit(_)) => (),
         Some(ClientMessage::Pong(ts)) => {
             let latency = get_time_ms().saturating_sub(ts);
             session.send_latency_measurement(latency);
         }
         Some(ClientMessage::Error(err)) => {
-            // TODO: Propagate these errors to listeners on the web interface?
             error!(?err, "error received from client");
+            session.send_client_error(err);
         }
         None => (), // Heartbeat message, ignored.
     }
@@ -0,0 +1,86 @@
+//! Standard gRPC health checks, for load balancers and orchestrators.
+
+use std::sync::Arc;
+
+use sshx_core::proto::health::{
+    health_check_response::ServingStatus, health_server::Health, HealthCheckRequest,
+    HealthCheckResponse,
+};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::{ReceiverStream, WatchStream};
+use tokio_stream::StreamExt;
+use tonic::{Request, Response, Status};
+
+use crate::ServerState;
+
+/// Services that health checks report on, besides the server as a whole.
+const SERVICES: &[&str] = &["sshx.SshxService"];
+
+/// Server that answers gRPC health checks.
+///
+/// Every service is serving until the server begins shutting down.
+#[derive(Clone)]
+pub struct GrpcHealth(Arc<ServerState>);
+
+impl GrpcHealth {
+    /// Construct a new [`GrpcHealth`] instance with associated state.
+    pub fn new(state: Arc<ServerState>) -> Self {
+        Self(state)
+    }
+
+    /// Watch the serving status of a service, if it is known.
+    fn serving(&self, service: &str) -> Option<watch::Receiver<bool>> {
+        (service.is_empty() || SERVICES.contains(&service)).then(|| self.0.watch_serving())
+    }
+}
+
+fn response(serving: bool) -> HealthCheckResponse {
+    let status = match serving {
+        true => ServingStatus::Serving,
+        false => ServingStatus::NotServing,
+    };
+    HealthCheckResponse {
+        status: status.into(),
+    }
+}
+
+#[tonic::async_trait]
+impl Health for GrpcHealth {
+    type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
+
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        match self.serving(&service) {
+            Some(serving) => Ok(Response::new(response(*serving.borrow()))),
+            None => Err(Status::not_found(format!("unknown service {service:?}"))),
+        }
+    }
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let (tx, rx) = mpsc::channel(1);
+        let serving = self.serving(&service);
+        tokio::spawn(async move {
+            let Some(serving) = serving else {
+                let unknown = HealthCheckResponse {
+                    status: ServingStatus::ServiceUnknown.into(),
+                };
+                tx.send(Ok(unknown)).await.ok();
+                return;
+            };
+            let mut updates = WatchStream::new(serving);
+            while let Some(serving) = updates.next().await {
+                if tx.send(Ok(response(serving))).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
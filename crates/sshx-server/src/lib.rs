@@ -12,18 +12,40 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
-use std::{net::SocketAddr, sync::Arc};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Result;
 use hyper::server::conn::AddrIncoming;
+use tracing::{error, info};
 use utils::Shutdown;
 
+use crate::access::AccessList;
+use crate::audit::AuditTarget;
+use crate::listen::{ListenAddr, ListenRole, Listener};
+use crate::recording::Recorder;
+use crate::spill::Spiller;
+use crate::state::store::SessionStore;
 use crate::state::ServerState;
+use crate::tenant::Tenancy;
+use crate::tls::TlsConfig;
+use crate::web::auth::WebAuth;
 
+pub mod access;
+pub mod audit;
+pub mod config;
 pub mod grpc;
-mod listen;
+pub mod health;
+pub mod listen;
+pub mod recording;
 pub mod session;
+pub mod spill;
 pub mod state;
+pub mod tenant;
+pub mod tls;
+pub mod usage;
 pub mod utils;
 pub mod web;
 
@@ -42,6 +64,114 @@ pub struct ServerOptions {
 
     /// Hostname of this server, if running multiple servers.
     pub host: Option<String>,
+
+    /// Origins allowed to open WebSocket connections, besides the server's own
+    /// host. Use `*` to allow any origin.
+    pub allowed_origins: Vec<String>,
+
+    /// Origins allowed to read responses from the web API in a browser, with
+    /// CORS headers. Use `*` to allow any origin.
+    pub cors_origins: Vec<String>,
+
+    /// Origins allowed to embed the web interface in a frame, besides the
+    /// server's own pages. Use `*` to allow any origin.
+    pub frame_ancestors: Vec<String>,
+
+    /// Sinks that audit events are written to.
+    pub audit: Vec<AuditTarget>,
+
+    /// Client IP addresses that are allowed or denied access to the server.
+    pub access: AccessList,
+
+    /// Maximum bytes of terminal input per second from each viewer.
+    pub input_rate_limit: Option<u32>,
+
+    /// How long viewers are muted after exceeding the input rate limit.
+    pub input_mute: Option<Duration>,
+
+    /// Maximum sessions opened per minute from each client address.
+    pub session_rate_limit: Option<u32>,
+
+    /// Maximum WebSocket connections per minute from each client address.
+    pub connect_rate_limit: Option<u32>,
+
+    /// Maximum sessions open at once on this server.
+    pub max_sessions: Option<usize>,
+
+    /// Maximum sessions open at once from each client address.
+    pub max_sessions_per_ip: Option<usize>,
+
+    /// Maximum shells that viewers can open in each session.
+    pub max_shells: Option<usize>,
+
+    /// Maximum viewers connected at once to each session.
+    pub max_viewers: Option<usize>,
+
+    /// Maximum bytes of output history kept for each session, shared evenly
+    /// between its shells.
+    pub max_session_bytes: Option<u64>,
+
+    /// Maximum bytes of output per second relayed from each shell to each
+    /// viewer, which also caps what hosts can request.
+    pub shell_bandwidth: Option<u32>,
+
+    /// Maximum bytes of output per second from each shell that is delivered
+    /// to viewers. Hosts with flow control are held back to this rate, and
+    /// output from others beyond the burst is truncated.
+    pub output_rate: Option<u32>,
+
+    /// Bytes of output that each shell can send at once under `output_rate`.
+    /// Defaults to 4 seconds of output.
+    pub output_burst: Option<u32>,
+
+    /// Maximum bytes of output history kept for each shell, which also caps
+    /// the limits of namespaces. Defaults to 2 MiB.
+    pub shell_history_bytes: Option<u64>,
+
+    /// Namespaces that sessions are opened in, and their limits.
+    pub tenancy: Tenancy,
+
+    /// Close sessions without terminal input or output for this long.
+    pub idle_timeout: Option<Duration>,
+
+    /// Maximum lifetime of every session, on top of any namespace quota.
+    pub max_session_lifetime: Option<Duration>,
+
+    /// Period of the usage rollups recorded for each namespace.
+    pub usage_rollup_interval: Option<Duration>,
+
+    /// Backend that saves sessions, so they survive restarts of the server.
+    pub session_store: Option<Arc<dyn SessionStore>>,
+
+    /// Records the output and input of every shell to disk, for download later.
+    pub recorder: Option<Recorder>,
+
+    /// Spills old output of every shell to disk, instead of discarding it.
+    pub spiller: Option<Spiller>,
+
+    /// Credentials required to connect to sessions over the web, if any.
+    pub web_auth: WebAuth,
+
+    /// Bearer token for the admin API, which can inspect every session.
+    pub admin_token: Option<String>,
+
+    /// Disconnect viewers whose WebSocket has been silent for this long,
+    /// despite pings. Defaults to 30 seconds.
+    pub keepalive_timeout: Option<Duration>,
+
+    /// Disconnect viewers that send no messages for this long, even if their
+    /// WebSocket answers pings.
+    pub viewer_idle_timeout: Option<Duration>,
+
+    /// How long read-only replays of session history are kept. Defaults to
+    /// 7 days.
+    pub replay_ttl: Option<Duration>,
+
+    /// Directory of the static web frontend. Defaults to `build`.
+    pub static_dir: Option<PathBuf>,
+
+    /// Serve HTTPS and gRPC over TLS with this certificate, if set.
+    pub tls: Option<TlsConfig>,
 }
 
 /// Stateful object that manages the sshx server, with graceful termination.
@@ -65,13 +195,29 @@ impl Server {
     }
 
     /// Run the application server, listening on a stream of connections.
-    pub async fn listen(&self, incoming: AddrIncoming) -> Result<()> {
+    pub async fn listen(&self, incoming: impl Into<Listener>) -> Result<()> {
+        self.listen_all(vec![(ListenRole::All, incoming.into())])
+            .await
+    }
+
+    /// Run the application server on several streams of connections at once,
+    /// each serving the requests allowed by its role.
+    pub async fn listen_all(&self, listeners: Vec<(ListenRole, Listener)>) -> Result<()> {
+        match self.state.restore_sessions().await {
+            Ok(0) => {}
+            Ok(restored) => info!(restored, "restored saved sessions"),
+            Err(err) => error!(?err, "failed to restore saved sessions"),
+        }
+
         let state = self.state.clone();
         let terminated = self.shutdown.wait();
         tokio::spawn(async move {
-            let background_tasks = futures_util::future::join(
+            let background_tasks = futures_util::future::join5(
                 state.listen_for_transfers(),
                 state.close_old_sessions(),
+                state.enforce_time_limits(),
+                state.roll_up_usage(),
+                state.audit().run(),
             );
             tokio::select! {
                 _ = terminated => {}
@@ -79,7 +225,11 @@ impl Server {
             }
         });
 
-        listen::start_server(self.state(), incoming, self.shutdown.wait()).await
+        let servers = listeners.into_iter().map(|(role, incoming)| {
+            listen::start_server(self.state(), role, incoming, self.shutdown.wait())
+        });
+        futures_util::future::try_join_all(servers).await?;
+        Ok(())
     }
 
     /// Convenience function to call [`Server::listen`] bound to a TCP address.
@@ -87,6 +237,34 @@ impl Server {
         self.listen(AddrIncoming::bind(addr)?).await
     }
 
+    /// Convenience function to call [`Server::listen_all`] bound to several
+    /// addresses.
+    pub async fn bind_all(&self, addrs: &[ListenAddr]) -> Result<()> {
+        let listeners = addrs
+            .iter()
+            .map(|addr| Ok((addr.role, addr.bind()?)))
+            .collect::<Result<_>>()?;
+        self.listen_all(listeners).await
+    }
+
+    /// Save every session to the session store, if one is configured, so that
+    /// they can be restored after a restart.
+    pub async fn persist(&self) {
+        self.state.persist_sessions().await;
+    }
+
+    /// Drain the server, then shut it down.
+    ///
+    /// New sessions are refused, and hosts are told to reconnect elsewhere.
+    /// Once they have disconnected, or the timeout passes, sessions are saved
+    /// and viewers are disconnected with a code that asks them to reconnect.
+    pub async fn drain(&self, timeout: Duration) {
+        self.state.begin_drain(SystemTime::now() + timeout);
+        self.state.wait_for_hosts(timeout).await;
+        self.persist().await;
+        self.shutdown();
+    }
+
     /// Send a graceful shutdown signal to the server.
     pub fn shutdown(&self) {
         // Stop receiving new network connections.
@@ -1,40 +1,350 @@
-use std::{error::Error as StdError, future::Future, sync::Arc};
+//! Listening for connections on one or more network addresses.
 
-use anyhow::Result;
+use std::{
+    convert::Infallible,
+    error::Error as StdError,
+    fmt, fs,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::fd::OwnedFd,
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{ready, Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{Context, Error, Result};
 use axum::body::HttpBody;
+use bytes::Bytes;
+use futures_util::future::{self, Either};
+use http_body::combinators::UnsyncBoxBody;
 use hyper::{
     header::CONTENT_TYPE,
-    server::{conn::AddrIncoming, Server as HyperServer},
-    service::make_service_fn,
-    Body, Request,
+    server::accept::Accept,
+    server::conn::{AddrIncoming, AddrStream},
+    server::Server as HyperServer,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, StatusCode,
 };
+use socket2::{Domain, Protocol, Socket, Type};
+use sshx_core::proto::health::health_server::HealthServer;
 use sshx_core::proto::{sshx_service_server::SshxServiceServer, FILE_DESCRIPTOR_SET};
+use sshx_core::ErrorCode;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::time::{self, Sleep};
+use tokio_rustls::server::TlsStream;
 use tonic::transport::Server as TonicServer;
 use tower::{steer::Steer, ServiceBuilder, ServiceExt};
 use tower_http::trace::TraceLayer;
+use tracing::warn;
+
+use crate::health::GrpcHealth;
+use crate::tls;
+use crate::{access::ClientIp, audit::AuditEvent, grpc::GrpcServer, web, ServerState};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Which kinds of requests are served on a listening address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListenRole {
+    /// Serve both the web interface and the gRPC API.
+    #[default]
+    All,
+    /// Serve only the web interface and WebSocket connections.
+    Web,
+    /// Serve only the gRPC API used by command-line clients.
+    Grpc,
+}
+
+impl ListenRole {
+    /// Returns whether a request is served by listeners with this role.
+    fn serves(self, req: &Request<Body>) -> bool {
+        match self {
+            Self::All => true,
+            Self::Web => !is_grpc(req),
+            Self::Grpc => is_grpc(req),
+        }
+    }
+}
+
+impl FromStr for ListenRole {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "web" => Ok(Self::Web),
+            "grpc" => Ok(Self::Grpc),
+            _ => anyhow::bail!("unknown listener role {s:?}, expected web or grpc"),
+        }
+    }
+}
+
+/// Where a listener accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenTarget {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket, like one proxied to by nginx.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An address to listen on, with the kinds of requests it serves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenAddr {
+    /// Kinds of requests served on this address.
+    pub role: ListenRole,
+    /// Socket address or path to bind to.
+    pub target: ListenTarget,
+}
+
+impl ListenAddr {
+    /// Bind a listener to this address.
+    ///
+    /// IPv6 sockets only accept IPv6 connections, so that an IPv4 address can
+    /// be bound separately on the same port for dual-stack hosts. A stale Unix
+    /// socket left behind at the path is replaced.
+    pub fn bind(&self) -> Result<Listener> {
+        let addr = match &self.target {
+            ListenTarget::Tcp(addr) => *addr,
+            ListenTarget::Unix(path) => {
+                let stale =
+                    fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+                if stale {
+                    fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("failed to bind to {}", path.display()))?;
+                return Ok(Listener::Unix(listener, None));
+            }
+        };
+        let bind = || -> std::io::Result<Socket> {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+            if addr.is_ipv6() {
+                socket.set_only_v6(true)?;
+            }
+            socket.set_reuse_address(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(1024)?;
+            Ok(socket)
+        };
+        let socket = bind().with_context(|| format!("failed to bind to {addr}"))?;
+        let listener = TcpListener::from_std(socket.into())?;
+        Ok(Listener::Tcp(AddrIncoming::from_listener(listener)?))
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            role: ListenRole::All,
+            target: ListenTarget::Tcp(addr),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = Error;
+
+    /// Parse a socket address or `unix:` path, optionally prefixed by `web=`
+    /// or `grpc=`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (role, addr) = if let Some(addr) = s.strip_prefix("web=") {
+            (ListenRole::Web, addr)
+        } else if let Some(addr) = s.strip_prefix("grpc=") {
+            (ListenRole::Grpc, addr)
+        } else {
+            (ListenRole::All, s)
+        };
+        let target = match addr.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => ListenTarget::Unix(path.into()),
+            _ => ListenTarget::Tcp(addr.parse().with_context(|| {
+                format!("invalid listen address {addr:?}, expected IP:PORT or unix:PATH")
+            })?),
+        };
+        Ok(Self { role, target })
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.role {
+            ListenRole::All => write!(f, "{}", self.target),
+            ListenRole::Web => write!(f, "web={}", self.target),
+            ListenRole::Grpc => write!(f, "grpc={}", self.target),
+        }
+    }
+}
+
+/// A bound socket that the server accepts connections from.
+#[derive(Debug)]
+pub enum Listener {
+    /// Accepts TCP connections.
+    Tcp(AddrIncoming),
+    /// Accepts Unix domain socket connections, pausing after errors.
+    Unix(UnixListener, Option<Pin<Box<Sleep>>>),
+}
 
-use crate::{grpc::GrpcServer, web, ServerState};
+impl Listener {
+    /// Listen on a socket inherited from another process, like the sockets
+    /// passed by systemd socket activation.
+    pub fn from_fd(fd: OwnedFd) -> Result<Self> {
+        let socket = Socket::from(fd);
+        socket.set_nonblocking(true)?;
+        let local_addr = socket.local_addr()?;
+        if local_addr.is_unix() {
+            let listener = UnixListener::from_std(socket.into())?;
+            Ok(Self::Unix(listener, None))
+        } else {
+            let listener = TcpListener::from_std(socket.into())?;
+            Ok(Self::Tcp(AddrIncoming::from_listener(listener)?))
+        }
+    }
+
+    /// Returns the local address of a TCP listener.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(incoming) => Some(incoming.local_addr()),
+            Self::Unix(..) => None,
+        }
+    }
+}
+
+impl From<AddrIncoming> for Listener {
+    fn from(incoming: AddrIncoming) -> Self {
+        Self::Tcp(incoming)
+    }
+}
+
+impl Accept for Listener {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<io::Result<Connection>>> {
+        match self.get_mut() {
+            Self::Tcp(incoming) => {
+                let conn = ready!(Pin::new(incoming).poll_accept(cx));
+                Poll::Ready(conn.map(|conn| conn.map(Connection::Tcp)))
+            }
+            Self::Unix(listener, timeout) => loop {
+                // Like `AddrIncoming`, sleep after errors such as running out
+                // of file descriptors, instead of stopping the server.
+                if let Some(sleep) = timeout {
+                    ready!(sleep.as_mut().poll(cx));
+                    *timeout = None;
+                }
+                match ready!(listener.poll_accept(cx)) {
+                    Ok((stream, _)) => return Poll::Ready(Some(Ok(Connection::Unix(stream)))),
+                    Err(err) => {
+                        warn!(?err, "failed to accept connection");
+                        *timeout = Some(Box::pin(time::sleep(Duration::from_secs(1))));
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// A connection accepted by a [`Listener`].
+#[derive(Debug)]
+pub enum Connection {
+    /// A TCP connection.
+    Tcp(AddrStream),
+    /// A Unix domain socket connection.
+    Unix(UnixStream),
+}
+
+impl Connection {
+    /// Returns the address of the peer, treating Unix socket peers as local.
+    ///
+    /// Reverse proxies on the same host connect over Unix sockets, so they
+    /// can be trusted to forward client addresses as `127.0.0.1`.
+    pub fn remote_ip(&self) -> IpAddr {
+        match self {
+            Self::Tcp(stream) => stream.remote_addr().ip(),
+            Self::Unix(_) => Ipv4Addr::LOCALHOST.into(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
 
 /// Bind and listen from the application, with a state and termination signal.
 ///
 /// This internal method is responsible for multiplexing the HTTP and gRPC
-/// servers onto a single, consolidated `hyper` service.
+/// servers onto a single, consolidated `hyper` service. Requests that the
+/// listener's role does not serve are rejected as not found.
 pub(crate) async fn start_server(
     state: Arc<ServerState>,
-    incoming: AddrIncoming,
+    role: ListenRole,
+    mut incoming: Listener,
     signal: impl Future<Output = ()>,
 ) -> Result<()> {
-    type BoxError = Box<dyn StdError + Send + Sync>;
-
-    let http_service = web::app()
-        .with_state(state.clone())
+    let http_service = web::app(state.clone())
         .layer(TraceLayer::new_for_http())
         .map_response(|r| r.map(|b| b.map_err(BoxError::from).boxed_unsync()))
         .map_err(BoxError::from)
         .boxed_clone();
 
     let grpc_service = TonicServer::builder()
-        .add_service(SshxServiceServer::new(GrpcServer::new(state)))
+        .add_service(SshxServiceServer::new(GrpcServer::new(state.clone())))
+        .add_service(HealthServer::new(GrpcHealth::new(state.clone())))
         .add_service(
             tonic_reflection::server::Builder::configure()
                 .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
@@ -48,26 +358,97 @@ pub(crate) async fn start_server(
         .map_response(|r| r.map(|b| b.map_err(BoxError::from).boxed_unsync()))
         .boxed_clone();
 
-    let svc = Steer::new(
-        [http_service, grpc_service],
-        |req: &Request<Body>, _services: &[_]| {
-            let headers = req.headers();
-            match headers.get(CONTENT_TYPE) {
-                Some(content) if content == "application/grpc" => 1,
-                _ => 0,
-            }
-        },
-    );
-    let make_svc = make_service_fn(move |_| {
+    let svc = Steer::new([http_service, grpc_service], pick_service);
+    let tls = state.tls().cloned();
+    let conn_service = move |remote: IpAddr| {
         let svc = svc.clone();
-        async { Ok::<_, std::convert::Infallible>(svc) }
-    });
+        let state = state.clone();
+        service_fn(move |mut req: Request<Body>| {
+            let access = state.access();
+            let from_peer = state.from_mesh_peer(req.headers());
+            let ip = access.client_ip(remote, req.headers(), from_peer);
+            let allowed = access.allows(ip);
+            req.extensions_mut().insert(ClientIp(ip));
+            if !role.serves(&req) {
+                Either::Right(future::ok(not_served(&req)))
+            } else if allowed {
+                Either::Left(svc.clone().oneshot(req))
+            } else {
+                warn!(%ip, "rejected request from disallowed address");
+                state.audit().record(Some(ip), AuditEvent::AddressDenied);
+                Either::Right(future::ok(forbidden(&req)))
+            }
+        })
+    };
 
-    HyperServer::builder(incoming)
-        .tcp_nodelay(true)
-        .serve(make_svc)
-        .with_graceful_shutdown(signal)
-        .await?;
+    if let Listener::Tcp(incoming) = &mut incoming {
+        incoming.set_nodelay(true);
+    }
+    match tls {
+        None => {
+            let make_svc = make_service_fn(move |conn: &Connection| {
+                let svc = conn_service(conn.remote_ip());
+                async { Ok::<_, Infallible>(svc) }
+            });
+            HyperServer::builder(incoming)
+                .serve(make_svc)
+                .with_graceful_shutdown(signal)
+                .await?;
+        }
+        Some(tls) => {
+            let make_svc = make_service_fn(move |conn: &TlsStream<Connection>| {
+                let svc = conn_service(conn.get_ref().0.remote_ip());
+                async { Ok::<_, Infallible>(svc) }
+            });
+            HyperServer::builder(tls::accept_tls(incoming, &tls))
+                .serve(make_svc)
+                .with_graceful_shutdown(signal)
+                .await?;
+        }
+    }
 
     Ok(())
 }
+
+/// Response for requests from a client address that is not allowed.
+fn forbidden(req: &Request<Body>) -> Response<UnsyncBoxBody<Bytes, BoxError>> {
+    const MESSAGE: &str = "your network address is not allowed";
+    if is_grpc(req) {
+        let resp = ErrorCode::PermissionDenied.status(MESSAGE).to_http();
+        resp.map(|b| b.map_err(BoxError::from).boxed_unsync())
+    } else {
+        let resp = Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from(MESSAGE))
+            .unwrap();
+        resp.map(|b| b.map_err(BoxError::from).boxed_unsync())
+    }
+}
+
+/// Response for requests that are not served on this listening address.
+fn not_served(req: &Request<Body>) -> Response<UnsyncBoxBody<Bytes, BoxError>> {
+    if is_grpc(req) {
+        let resp = ErrorCode::NotFound
+            .status("the gRPC API is not served on this address")
+            .to_http();
+        resp.map(|b| b.map_err(BoxError::from).boxed_unsync())
+    } else {
+        let resp = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(
+                "the web interface is not served on this address",
+            ))
+            .unwrap();
+        resp.map(|b| b.map_err(BoxError::from).boxed_unsync())
+    }
+}
+
+/// Picks the gRPC service for gRPC requests, and otherwise the web service.
+fn pick_service<S>(req: &Request<Body>, _services: &[S]) -> usize {
+    usize::from(is_grpc(req))
+}
+
+/// Returns whether a request should be routed to the gRPC service.
+fn is_grpc(req: &Request<Body>) -> bool {
+    matches!(req.headers().get(CONTENT_TYPE), Some(content) if content == "application/grpc")
+}
@@ -1,32 +1,70 @@
 use std::{
+    env,
+    ffi::OsString,
     net::{IpAddr, SocketAddr},
-    process::ExitCode,
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    path::PathBuf,
+    process::{self, ExitCode},
+    sync::Arc,
+    time::Duration,
 };
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use sshx_core::telemetry;
+use sshx_server::access::{AccessList, IpNet};
+use sshx_server::audit::AuditTarget;
+use sshx_server::config::ServerConfig;
+use sshx_server::listen::{ListenAddr, ListenRole, Listener};
+use sshx_server::recording::Recorder;
+use sshx_server::session::SHELL_STORED_BYTES;
+use sshx_server::spill::Spiller;
+use sshx_server::state::store::FileStore;
+use sshx_server::tenant::{Namespace, QuotaRule};
+use sshx_server::tls::TlsConfig;
+use sshx_server::web::auth::WebAuth;
 use sshx_server::{Server, ServerOptions};
 use tokio::signal::unix::{signal, SignalKind};
 use tracing::{error, info};
+use tracing_subscriber::{prelude::*, EnvFilter};
 
 /// The sshx server CLI interface.
+///
+/// Every option can also be set in a config file, and most in environment
+/// variables. Command-line flags take precedence over environment variables,
+/// which take precedence over the config file.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// Read options from a config file, with a `key = value` line for each
+    /// flag, in TOML syntax.
+    #[clap(long, value_name = "FILE", env = "SSHX_CONFIG")]
+    config: Option<PathBuf>,
+
     /// Specify port to listen on.
-    #[clap(long, default_value_t = 8051)]
+    #[clap(long, env = "SSHX_PORT", default_value_t = 8051)]
     port: u16,
 
     /// Which IP address or network interface to listen on.
-    #[clap(long, value_parser, default_value = "::1")]
+    #[clap(long, env = "SSHX_LISTEN", value_parser, default_value = "::1")]
     listen: IpAddr,
 
+    /// Listen on an explicit socket address or `unix:PATH` socket instead of
+    /// `--listen` and `--port`, can be repeated. Prefix with `web=` or `grpc=`
+    /// to serve only the web interface or the gRPC API on that address.
+    ///
+    /// Sockets passed by systemd socket activation are also listened on, with
+    /// roles from their `FileDescriptorName=`.
+    #[clap(long, value_name = "[ROLE=]ADDR")]
+    bind: Vec<ListenAddr>,
+
     /// Secret used for signing session tokens.
     #[clap(long, env = "SSHX_SECRET")]
     secret: Option<String>,
 
     /// Override the origin URL returned by the Open() RPC.
-    #[clap(long)]
+    #[clap(long, env = "SSHX_OVERRIDE_ORIGIN")]
     override_origin: Option<String>,
 
     /// URL of the Redis server that stores session data.
@@ -34,11 +72,262 @@ struct Args {
     redis_url: Option<String>,
 
     /// Hostname of this server, if running multiple servers.
-    #[clap(long)]
+    #[clap(long, env = "SSHX_HOST")]
     host: Option<String>,
+
+    /// Additional origin allowed to open WebSocket connections, can be
+    /// repeated. Use `*` to allow any origin.
+    #[clap(long = "allowed-origin", value_name = "ORIGIN")]
+    allowed_origins: Vec<String>,
+
+    /// Origin allowed to read web API responses in a browser, can be
+    /// repeated. Use `*` to allow any origin.
+    #[clap(long = "cors-origin", value_name = "ORIGIN")]
+    cors_origins: Vec<String>,
+
+    /// Origin allowed to embed the web interface in a frame, can be
+    /// repeated. Use `*` to allow any origin.
+    #[clap(long = "frame-ancestor", value_name = "ORIGIN")]
+    frame_ancestors: Vec<String>,
+
+    /// Write audit events to a sink, can be repeated. One of `file:<path>`,
+    /// `syslog`, `syslog:<socket>`, or an `http://` URL.
+    #[clap(long, value_name = "SINK")]
+    audit: Vec<AuditTarget>,
+
+    /// Only allow clients from this IP network, in CIDR notation, can be
+    /// repeated.
+    #[clap(long, value_name = "CIDR")]
+    allow_ip: Vec<IpNet>,
+
+    /// Deny clients from this IP network, in CIDR notation, can be repeated.
+    #[clap(long, value_name = "CIDR")]
+    deny_ip: Vec<IpNet>,
+
+    /// Trust X-Forwarded-For headers from this reverse proxy network, in CIDR
+    /// notation, can be repeated.
+    #[clap(long, value_name = "CIDR")]
+    trusted_proxy: Vec<IpNet>,
+
+    /// Only allow viewers from this IP network to join sessions, in CIDR
+    /// notation, can be repeated. Command-line clients are not affected.
+    #[clap(long, value_name = "CIDR")]
+    viewer_allow_ip: Vec<IpNet>,
+
+    /// Maximum bytes of terminal input per second from each viewer.
+    #[clap(long, value_name = "BYTES", env = "SSHX_INPUT_RATE_LIMIT")]
+    input_rate_limit: Option<u32>,
+
+    /// Seconds that viewers are muted after exceeding the input rate limit.
+    #[clap(
+        long,
+        value_name = "SECS",
+        env = "SSHX_INPUT_MUTE",
+        default_value_t = 10
+    )]
+    input_mute: u64,
+
+    /// Maximum sessions opened per minute from each client address.
+    #[clap(long, value_name = "COUNT", env = "SSHX_SESSION_RATE_LIMIT")]
+    session_rate_limit: Option<u32>,
+
+    /// Maximum WebSocket connections per minute from each client address.
+    #[clap(long, value_name = "COUNT", env = "SSHX_CONNECT_RATE_LIMIT")]
+    connect_rate_limit: Option<u32>,
+
+    /// Maximum sessions open at once on this server.
+    #[clap(long, value_name = "COUNT", env = "SSHX_MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+
+    /// Maximum sessions open at once from each client address.
+    #[clap(long, value_name = "COUNT", env = "SSHX_MAX_SESSIONS_PER_IP")]
+    max_sessions_per_ip: Option<usize>,
+
+    /// Maximum shells that viewers can open in each session.
+    #[clap(long, value_name = "COUNT", env = "SSHX_MAX_SHELLS")]
+    max_shells: Option<usize>,
+
+    /// Maximum viewers connected at once to each session.
+    #[clap(long, value_name = "COUNT", env = "SSHX_MAX_VIEWERS")]
+    max_viewers: Option<usize>,
+
+    /// Maximum bytes of output history kept for each session, shared evenly
+    /// between its shells.
+    #[clap(long, value_name = "BYTES", env = "SSHX_MAX_SESSION_BYTES")]
+    max_session_bytes: Option<u64>,
+
+    /// Maximum bytes of output per second relayed from each shell to each
+    /// viewer. Viewers that fall behind skip ahead to recent output.
+    #[clap(long, value_name = "BYTES", env = "SSHX_SHELL_BANDWIDTH")]
+    shell_bandwidth: Option<u32>,
+
+    /// Maximum bytes of output per second from each shell delivered to
+    /// viewers. Clients with flow control are slowed down to this rate, and
+    /// output from older clients past the burst is truncated.
+    #[clap(long, value_name = "BYTES", env = "SSHX_OUTPUT_RATE")]
+    output_rate: Option<u32>,
+
+    /// Bytes of output that each shell can send at once with `--output-rate`,
+    /// 4 seconds of output by default.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        env = "SSHX_OUTPUT_BURST",
+        requires = "output_rate"
+    )]
+    output_burst: Option<u32>,
+
+    /// Namespace whose clients open sessions with an API key, in the form
+    /// `NAME=KEY`, can be repeated or given as a comma-separated list.
+    #[clap(
+        long,
+        value_name = "NAME=KEY",
+        env = "SSHX_NAMESPACES",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    namespace: Vec<Namespace>,
+
+    /// Reject clients that do not send the API key of a namespace.
+    #[clap(long, env = "SSHX_REQUIRE_API_KEY")]
+    require_api_key: bool,
+
+    /// Limits for namespaces, in the form `[NAMESPACE:]LIMIT=VALUE,...` with
+    /// limits `sessions`, `viewers`, `bytes` (of history per shell), and
+    /// `duration` (like 8h). Without a namespace, sets the default for all.
+    #[clap(long, value_name = "RULE")]
+    quota: Vec<QuotaRule>,
+
+    /// Maximum bytes of output history kept for each shell, for viewers who
+    /// join later. Also caps the `bytes` limit of namespaces.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        env = "SSHX_SHELL_HISTORY_BYTES",
+        default_value_t = SHELL_STORED_BYTES
+    )]
+    shell_history_bytes: u64,
+
+    /// Seconds between the usage rollups recorded for each namespace.
+    #[clap(
+        long,
+        value_name = "SECS",
+        env = "SSHX_USAGE_ROLLUP",
+        default_value_t = 3600
+    )]
+    usage_rollup: u64,
+
+    /// Close sessions after this many seconds without terminal input or
+    /// output.
+    #[clap(long, value_name = "SECS", env = "SSHX_IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+
+    /// Close every session after this many seconds, even if active. Hosts
+    /// and viewers are warned before it ends.
+    #[clap(long, value_name = "SECS", env = "SSHX_MAX_SESSION_LIFETIME")]
+    max_session_lifetime: Option<u64>,
+
+    /// Disconnect viewers after this many seconds without answering pings,
+    /// such as over a half-open connection.
+    #[clap(
+        long,
+        value_name = "SECS",
+        env = "SSHX_KEEPALIVE_TIMEOUT",
+        default_value_t = 30
+    )]
+    keepalive_timeout: u64,
+
+    /// Disconnect viewers after this many seconds without sending any
+    /// messages, to bound the resources held by forgotten connections.
+    #[clap(long, value_name = "SECS", env = "SSHX_VIEWER_IDLE_TIMEOUT")]
+    viewer_idle_timeout: Option<u64>,
+
+    /// Keep read-only replays of session history for this many seconds after
+    /// they are made through the API.
+    #[clap(
+        long,
+        value_name = "SECS",
+        env = "SSHX_REPLAY_TTL",
+        default_value_t = 7 * 24 * 3600
+    )]
+    replay_ttl: u64,
+
+    /// Save sessions to this directory, so they survive server restarts.
+    #[clap(long, value_name = "DIR", env = "SSHX_PERSIST_DIR")]
+    persist_dir: Option<PathBuf>,
+
+    /// Record the output of every shell to this directory, in asciicast
+    /// format, along with input and the user who typed it. Recordings stay
+    /// end-to-end encrypted.
+    #[clap(long, value_name = "DIR", env = "SSHX_RECORD_DIR")]
+    record_dir: Option<PathBuf>,
+
+    /// Spill output beyond --shell-history-bytes to files in this directory,
+    /// so viewers can still scroll back through long sessions. Files stay
+    /// end-to-end encrypted, and are deleted when sessions end.
+    #[clap(long, value_name = "DIR", env = "SSHX_SPILL_DIR")]
+    spill_dir: Option<PathBuf>,
+
+    /// Require viewers to connect with this bearer token, or with a signed
+    /// link if --signed-urls is set. Can be repeated.
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "SSHX_WEB_TOKENS",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    web_token: Vec<String>,
+
+    /// Sign the links of new sessions, and require viewers to connect with a
+    /// signed link or a bearer token from --web-token.
+    #[clap(long, env = "SSHX_SIGNED_URLS")]
+    signed_urls: bool,
+
+    /// Bearer token for the admin API, which lists and inspects every session
+    /// under /api/sessions.
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "SSHX_ADMIN_TOKEN",
+        hide_env_values = true
+    )]
+    admin_token: Option<String>,
+
+    /// Serve HTTPS and gRPC over TLS with the certificate chain in this PEM
+    /// file. Requires --tls-key.
+    #[clap(long, value_name = "FILE", env = "SSHX_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key in PEM format for the certificate from --tls-cert.
+    #[clap(long, value_name = "FILE", env = "SSHX_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Directory of the static web frontend to serve.
+    #[clap(
+        long,
+        value_name = "DIR",
+        env = "SSHX_STATIC_DIR",
+        default_value = "build"
+    )]
+    static_dir: PathBuf,
+
+    /// Seconds to wait for hosts to move their sessions to another server
+    /// when shutting down.
+    #[clap(
+        long,
+        value_name = "SECS",
+        env = "SSHX_DRAIN_TIMEOUT",
+        default_value_t = 10
+    )]
+    drain_timeout: u64,
+
+    /// Export tracing spans to an OpenTelemetry collector at this OTLP/gRPC
+    /// endpoint, like `http://localhost:4317`.
+    #[clap(long, value_name = "URL", env = "SSHX_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
 }
 
-#[tokio::main]
 async fn start(args: Args) -> Result<()> {
     let addr = SocketAddr::new(args.listen, args.port);
 
@@ -50,13 +339,76 @@ async fn start(args: Args) -> Result<()> {
     options.override_origin = args.override_origin;
     options.redis_url = args.redis_url;
     options.host = args.host;
+    options.audit = args.audit;
+    options.access = AccessList {
+        allow: args.allow_ip,
+        deny: args.deny_ip,
+        trusted_proxies: args.trusted_proxy,
+        viewer_allow: args.viewer_allow_ip,
+    };
+    options.allowed_origins = args.allowed_origins;
+    options.cors_origins = args.cors_origins;
+    options.frame_ancestors = args.frame_ancestors;
+    options.input_rate_limit = args.input_rate_limit;
+    options.input_mute = Some(Duration::from_secs(args.input_mute));
+    options.session_rate_limit = args.session_rate_limit;
+    options.connect_rate_limit = args.connect_rate_limit;
+    options.max_sessions = args.max_sessions;
+    options.max_sessions_per_ip = args.max_sessions_per_ip;
+    options.max_shells = args.max_shells;
+    options.max_viewers = args.max_viewers;
+    options.max_session_bytes = args.max_session_bytes;
+    options.shell_bandwidth = args.shell_bandwidth;
+    options.output_rate = args.output_rate;
+    options.output_burst = args.output_burst;
+    options.shell_history_bytes = Some(args.shell_history_bytes);
+    options.tenancy.namespaces = args.namespace;
+    options.tenancy.require_api_key = args.require_api_key;
+    for rule in args.quota {
+        options.tenancy.add_rule(rule);
+    }
+    options.usage_rollup_interval = Some(Duration::from_secs(args.usage_rollup));
+    options.idle_timeout = args.idle_timeout.map(Duration::from_secs);
+    options.max_session_lifetime = args.max_session_lifetime.map(Duration::from_secs);
+    options.keepalive_timeout = Some(Duration::from_secs(args.keepalive_timeout));
+    options.viewer_idle_timeout = args.viewer_idle_timeout.map(Duration::from_secs);
+    options.replay_ttl = Some(Duration::from_secs(args.replay_ttl));
+    if let Some(dir) = &args.persist_dir {
+        options.session_store = Some(Arc::new(FileStore::new(dir)?));
+    }
+    if let Some(dir) = &args.record_dir {
+        options.recorder = Some(Recorder::new(dir)?);
+    }
+    if let Some(dir) = &args.spill_dir {
+        options.spiller = Some(Spiller::new(dir)?);
+    }
+    options.web_auth = WebAuth {
+        bearer_tokens: args.web_token,
+        signed_urls: args.signed_urls,
+    };
+    options.admin_token = args.admin_token;
+    options.static_dir = Some(args.static_dir);
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        options.tls = Some(TlsConfig::load(cert, key)?);
+    }
 
     let server = Server::new(options)?;
 
-    let serve_task = async {
+    let mut listeners = Vec::new();
+    for (role, fd) in systemd_sockets()? {
+        info!(?role, "server listening on socket from systemd");
+        listeners.push((role, Listener::from_fd(fd)?));
+    }
+    for addr in &args.bind {
         info!("server listening at {addr}");
-        server.bind(&addr).await
-    };
+        listeners.push((addr.role, addr.bind()?));
+    }
+    if listeners.is_empty() {
+        info!("server listening at {addr}");
+        listeners.push((ListenRole::All, ListenAddr::from(addr).bind()?));
+    }
+
+    let serve_task = server.listen_all(listeners);
 
     let signals_task = async {
         tokio::select! {
@@ -65,7 +417,7 @@ async fn start(args: Args) -> Result<()> {
             else => return Ok(()),
         }
         info!("gracefully shutting down...");
-        server.shutdown();
+        server.drain(Duration::from_secs(args.drain_timeout)).await;
         Ok(())
     };
 
@@ -73,15 +425,116 @@ async fn start(args: Args) -> Result<()> {
     Ok(())
 }
 
+/// Take ownership of the listening sockets passed by systemd, if any.
+///
+/// See `sd_listen_fds(3)`. Each socket's role is read from its name, which
+/// is set by `FileDescriptorName=web` or `grpc` in the socket unit.
+fn systemd_sockets() -> Result<Vec<(ListenRole, OwnedFd)>> {
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    if pid != Some(process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = env::var("LISTEN_FDS")?
+        .parse()
+        .context("invalid LISTEN_FDS")?;
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    // Child processes should not see the sockets as theirs.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let mut sockets = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        let role = names.next().and_then(|name| name.parse().ok());
+        // SAFETY: systemd passes these descriptors to this process to own,
+        // and nothing else in the process uses them.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        sockets.push((role.unwrap_or_default(), fd));
+    }
+    Ok(sockets)
+}
+
+/// Parse the command line, filling in options from the config file if any.
+fn load_args() -> Result<Args> {
+    let argv: Vec<OsString> = env::args_os().collect();
+    let matches = Args::command().get_matches_from(&argv);
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(Args::from_arg_matches(&matches)?);
+    };
+
+    let config = ServerConfig::load(path)?;
+    let command = Args::command();
+    let mut full_argv = vec![argv[0].clone()];
+    for (key, values) in config.iter() {
+        let arg = (command.get_arguments())
+            .find(|arg| arg.get_long() == Some(key) && key != "config")
+            .with_context(|| format!("unknown option {key:?} in config file"))?;
+        let source = matches.value_source(arg.get_id().as_str());
+        if matches!(
+            source,
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        for value in values {
+            if arg.get_action().takes_values() {
+                full_argv.push(format!("--{key}={value}").into());
+            } else if value
+                .parse()
+                .with_context(|| format!("{key:?} must be a boolean"))?
+            {
+                full_argv.push(format!("--{key}").into());
+            }
+        }
+    }
+    full_argv.extend(argv.into_iter().skip(1));
+    Ok(Args::from_arg_matches(
+        &Args::command().get_matches_from(full_argv),
+    )?)
+}
+
 fn main() -> ExitCode {
-    let args = Args::parse();
+    let args = match load_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("error: {err:?}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-    tracing_subscriber::fmt()
-        .with_env_filter(std::env::var("RUST_LOG").unwrap_or("info".into()))
-        .with_writer(std::io::stderr)
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            eprintln!("error: failed to start runtime: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // The OTLP exporter sends batches of spans from a task on the runtime.
+    let _guard = runtime.enter();
+
+    let filter = EnvFilter::new(std::env::var("RUST_LOG").unwrap_or("info".into()));
+    let otlp = match &args.otlp_endpoint {
+        Some(endpoint) => match telemetry::layer("sshx-server", endpoint) {
+            Ok(layer) => Some(layer),
+            Err(err) => {
+                eprintln!("error: failed to export traces to {endpoint}: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(otlp)
         .init();
 
-    match start(args) {
+    let result = runtime.block_on(start(args));
+    telemetry::shutdown();
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
             error!("{err:?}");
@@ -0,0 +1,279 @@
+//! Recording of shell output to disk, in the asciicast v2 format.
+//!
+//! Terminal output is end-to-end encrypted, so the server only records
+//! ciphertext. Each recording starts with an asciicast header that has an extra
+//! `sshx` field, giving the shell ID and the byte offset of the first output.
+//! Output events hold base64-encoded encrypted chunks, which follow each other
+//! in the shell's stream, so clients with the session's key can decrypt a
+//! recording into one that any asciicast player can replay.
+//!
+//! Input from viewers is recorded to a parallel file for each shell, with
+//! `input` set in the header. Each input event has a fourth element giving the
+//! ID and name of the user who typed it, and the offset for decrypting it,
+//! since viewers encrypt their input separately from each other.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use sshx_core::{Sid, Uid};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::error;
+
+/// File extension of asciicast recordings.
+const CAST_EXTENSION: &str = "cast";
+
+/// Suffix of the file name for recordings of input, before the extension.
+const INPUT_SUFFIX: &str = "input";
+
+/// Saves recordings of every session's shells to a local directory.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    dir: PathBuf,
+}
+
+impl Recorder {
+    /// Create a recorder in a directory, which is created if missing.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Start recording a session, writing events in a background task.
+    ///
+    /// Recordings of a session that already exist are appended to, such as
+    /// after the server restarts.
+    pub fn start(&self, name: &str) -> Recording {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let dir = self.dir.join(name);
+        tokio::spawn(async move {
+            let mut writer = RecordingWriter {
+                dir,
+                files: HashMap::new(),
+            };
+            writer.run(rx).await;
+        });
+        Recording { tx }
+    }
+
+    /// Read the recording of a shell's output, if it exists.
+    pub async fn read(&self, name: &str, id: Sid) -> Result<Option<Vec<u8>>> {
+        self.read_track(name, id, Track::Output).await
+    }
+
+    /// Read the recording of input typed into a shell, if it exists.
+    pub async fn read_input(&self, name: &str, id: Sid) -> Result<Option<Vec<u8>>> {
+        self.read_track(name, id, Track::Input).await
+    }
+
+    async fn read_track(&self, name: &str, id: Sid, track: Track) -> Result<Option<Vec<u8>>> {
+        // Session names are generated by the server, but check them anyway
+        // since they come from request paths.
+        if !valid_name(name) {
+            return Ok(None);
+        }
+        let path = track_path(&self.dir.join(name), id, track);
+        match fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+}
+
+/// Handle to the recording of a single session.
+#[derive(Debug)]
+pub struct Recording {
+    tx: mpsc::UnboundedSender<RecordEvent>,
+}
+
+impl Recording {
+    /// Record encrypted output of a shell, starting at a byte offset.
+    pub fn output(&self, id: Sid, offset: u64, data: Bytes) {
+        let time = SystemTime::now();
+        self.tx
+            .send(RecordEvent::Output(id, time, offset, data))
+            .ok();
+    }
+
+    /// Record the size of a shell, when it is created or resized.
+    pub fn resize(&self, id: Sid, offset: u64, rows: u16, cols: u16) {
+        let time = SystemTime::now();
+        (self
+            .tx
+            .send(RecordEvent::Resize(id, time, offset, rows, cols)))
+        .ok();
+    }
+
+    /// Record encrypted input that a user typed into a shell, with the
+    /// offset for decrypting it.
+    pub fn input(&self, id: Sid, user: Uid, name: String, offset: u64, data: Bytes) {
+        let time = SystemTime::now();
+        (self.tx)
+            .send(RecordEvent::Input(id, time, user, name, offset, data))
+            .ok();
+    }
+
+    /// Finish the recording of a shell that was closed.
+    pub fn close(&self, id: Sid) {
+        self.tx.send(RecordEvent::Close(id)).ok();
+    }
+}
+
+/// An event sent to the background task writing recordings.
+#[derive(Debug)]
+enum RecordEvent {
+    Output(Sid, SystemTime, u64, Bytes),
+    Resize(Sid, SystemTime, u64, u16, u16),
+    Input(Sid, SystemTime, Uid, String, u64, Bytes),
+    Close(Sid),
+}
+
+/// Stream of events in the recording of a shell, each kept in its own file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Track {
+    Output,
+    Input,
+}
+
+/// Open recording file of a shell.
+struct ShellFile {
+    file: fs::File,
+    /// Time in the header, which events are relative to.
+    started: SystemTime,
+}
+
+struct RecordingWriter {
+    dir: PathBuf,
+    files: HashMap<(Sid, Track), ShellFile>,
+}
+
+impl RecordingWriter {
+    async fn run(&mut self, mut rx: mpsc::UnboundedReceiver<RecordEvent>) {
+        while let Some(event) = rx.recv().await {
+            if let Err(err) = self.write(event).await {
+                error!(?err, dir = %self.dir.display(), "failed to write recording");
+            }
+        }
+    }
+
+    async fn write(&mut self, event: RecordEvent) -> Result<()> {
+        let (id, track, time, offset, size, event) = match event {
+            RecordEvent::Output(id, time, offset, data) => (
+                id,
+                Track::Output,
+                time,
+                offset,
+                None,
+                vec![json!("o"), json!(BASE64_STANDARD.encode(&data))],
+            ),
+            RecordEvent::Resize(id, time, offset, rows, cols) => (
+                id,
+                Track::Output,
+                time,
+                offset,
+                Some((rows, cols)),
+                vec![json!("r"), json!(format!("{cols}x{rows}"))],
+            ),
+            RecordEvent::Input(id, time, user, name, offset, data) => (
+                id,
+                Track::Input,
+                time,
+                offset,
+                None,
+                vec![
+                    json!("i"),
+                    json!(BASE64_STANDARD.encode(&data)),
+                    json!({ "user": user.0, "name": name, "offset": offset }),
+                ],
+            ),
+            RecordEvent::Close(id) => {
+                self.files.remove(&(id, Track::Output));
+                self.files.remove(&(id, Track::Input));
+                return Ok(());
+            }
+        };
+
+        let shell = match self.files.get_mut(&(id, track)) {
+            Some(shell) => shell,
+            None => {
+                let size = size.unwrap_or((24, 80));
+                let shell = self.open(id, track, offset, size).await?;
+                self.files.entry((id, track)).or_insert(shell)
+            }
+        };
+        let elapsed = time.duration_since(shell.started).unwrap_or_default();
+        let mut line = vec![json!(elapsed.as_secs_f64())];
+        line.extend(event);
+        let line = Value::from(line).to_string() + "\n";
+        shell.file.write_all(line.as_bytes()).await?;
+        shell.file.flush().await?;
+        Ok(())
+    }
+
+    /// Open the recording of a shell, writing the header if it is new.
+    async fn open(
+        &self,
+        id: Sid,
+        track: Track,
+        offset: u64,
+        size: (u16, u16),
+    ) -> Result<ShellFile> {
+        fs::create_dir_all(&self.dir).await?;
+        let path = track_path(&self.dir, id, track);
+        let existing = match fs::read_to_string(&path).await {
+            Ok(text) => Some(header_time(&text).context("recording has an invalid header")?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let mut file = (fs::OpenOptions::new().create(true).append(true))
+            .open(&path)
+            .await?;
+        // Headers only store whole seconds, so events are relative to that.
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let started = existing.unwrap_or(UNIX_EPOCH + Duration::from_secs(timestamp));
+        if existing.is_none() {
+            let info = match track {
+                Track::Output => json!({ "shell": id.0, "offset": offset, "encrypted": true }),
+                Track::Input => json!({ "shell": id.0, "input": true, "encrypted": true }),
+            };
+            let header = json!({
+                "version": 2,
+                "width": size.1,
+                "height": size.0,
+                "timestamp": timestamp,
+                "sshx": info,
+            });
+            file.write_all((header.to_string() + "\n").as_bytes())
+                .await?;
+        }
+        Ok(ShellFile { file, started })
+    }
+}
+
+/// Returns the path of a shell's recording in a session directory.
+fn track_path(dir: &Path, id: Sid, track: Track) -> PathBuf {
+    match track {
+        Track::Output => dir.join(format!("{}.{CAST_EXTENSION}", id.0)),
+        Track::Input => dir.join(format!("{}.{INPUT_SUFFIX}.{CAST_EXTENSION}", id.0)),
+    }
+}
+
+/// Parse the time that a recording started from its header.
+fn header_time(text: &str) -> Option<SystemTime> {
+    let header: serde_json::Value = serde_json::from_str(text.lines().next()?).ok()?;
+    let timestamp = header.get("timestamp")?.as_u64()?;
+    Some(UNIX_EPOCH + Duration::from_secs(timestamp))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && (name.chars()).all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -1,35 +1,169 @@
 //! Core logic for sshx sessions, independent of message transport.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use std::ops::DerefMut;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{bail, Context, Result};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
 use sshx_core::{
-    proto::{server_update::ServerMessage, SequenceNumbers},
-    IdCounter, Sid, Uid,
+    feature::RESYNC,
+    proto::{
+        server_update::ServerMessage, AccessEvent, AccessKind, NewShell, ResyncShell,
+        SequenceNumbers, SizePolicy, TerminalSize, Viewer, ViewerKnock,
+    },
+    rand_alphanumeric,
+    ws::{WsServer, WsUser, WsWinsize},
+    ErrorCode, IdCounter, Sid, Uid,
 };
-use tokio::sync::{broadcast, watch, Notify};
-use tokio::time::Instant;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify};
+use tokio::task;
+use tokio::time::{self, Instant};
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, WatchStream};
 use tokio_stream::Stream;
 use tracing::{debug, warn};
 
+use crate::recording::Recording;
+use crate::spill::Spill;
+use crate::usage::UsageCounters;
 use crate::utils::Shutdown;
-use crate::web::protocol::{WsServer, WsUser, WsWinsize};
 
 mod snapshot;
 
 /// Store a rolling buffer with at most this quantity of output, per shell.
-const SHELL_STORED_BYTES: u64 = 1 << 21; // 2 MiB
+pub const SHELL_STORED_BYTES: u64 = 1 << 21; // 2 MiB
+
+/// Compact a shell's output after this many chunks are received.
+const COMPACT_BATCH_CHUNKS: usize = 64;
+
+/// Small chunks of output are merged into chunks of up to this size.
+const COMPACT_CHUNK_BYTES: usize = 16 << 10; // 16 KiB
+
+/// Largest batch of stored output sent to a subscriber at once, so replaying
+/// history to a slow viewer does not hold it all in one message.
+const MAX_SUBSCRIBE_BATCH_BYTES: u64 = 256 << 10; // 256 KiB
+
+/// Viewers that fall this many seconds behind a shell's bandwidth cap skip
+/// ahead, resuming with the last second of output.
+const BANDWIDTH_MAX_LAG_SECS: u64 = 4;
+
+/// Longest title that viewers can give a shell, in bytes.
+const MAX_SHELL_TITLE_LEN: usize = 256;
+
+/// Largest rendering of a shell's screen that is stored for late joiners.
+const MAX_SCREEN_BYTES: usize = 1 << 20; // 1 MiB
+
+/// Keep at most this many of the latest entries in the access log.
+const ACCESS_LOG_ENTRIES: usize = 1000;
+
+/// Keep at most this many recent chat messages, sent to viewers who join late.
+const CHAT_HISTORY_MESSAGES: usize = 100;
+
+/// Messages for a single viewer, like file chunks, buffered before the
+/// sender waits for them to be delivered.
+const MAILBOX_MESSAGES: usize = 16;
+
+/// Longest time to wait for a viewer to take a message from their mailbox,
+/// before dropping it.
+const MAILBOX_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most clients that can host shells in a session, besides its main host.
+pub const MAX_CO_HOSTS: usize = 8;
+
+/// Remaining times at which clients are notified about the time limit.
+const TIME_LIMIT_NOTICES: &[Duration] = &[
+    Duration::from_secs(3600),
+    Duration::from_secs(1800),
+    Duration::from_secs(600),
+    Duration::from_secs(300),
+    Duration::from_secs(60),
+    Duration::from_secs(30),
+    Duration::from_secs(10),
+    Duration::ZERO,
+];
 
 /// Static metadata for this session.
 #[derive(Debug, Clone)]
 pub struct Metadata {
     /// Used to validate that clients have the correct encryption key.
     pub encrypted_zeros: Bytes,
+
+    /// Whether the client asked for reduced update rates over a poor link.
+    pub low_bandwidth: bool,
+
+    /// Secret for TOTP codes that viewers must enter to join, if not empty.
+    pub totp_secret: Bytes,
+
+    /// Time when the session is closed, if it has a time limit.
+    pub deadline: Option<SystemTime>,
+
+    /// Message from the host shown to each viewer when they join.
+    pub banner: String,
+
+    /// Whether to send each viewer a watermark identifying them.
+    pub watermark: bool,
+
+    /// Maximum bytes of output per second relayed from each shell.
+    pub shell_bandwidth: Option<u32>,
+
+    /// Whether the client waits for output to be acknowledged.
+    pub flow_control: bool,
+
+    /// Rate of output from each shell that is delivered to viewers, if any.
+    pub output_limit: Option<OutputLimit>,
+
+    /// Time when the host is expected to connect, if the session was
+    /// scheduled ahead of time.
+    pub scheduled: Option<SystemTime>,
+
+    /// Namespace that owns the session, if it was opened with an API key.
+    pub namespace: Option<String>,
+
+    /// Maximum bytes of output history retained for each shell, if not the
+    /// default of [`SHELL_STORED_BYTES`].
+    pub max_retained_bytes: Option<u64>,
+
+    /// Whether viewers need the token from the writable link to change the
+    /// session, instead of only watching.
+    pub write_protected: bool,
+
+    /// Salted Argon2 hash of the password that viewers enter to change the
+    /// session, if not empty.
+    pub write_password_hash: Bytes,
+
+    /// Program that the host runs in each new shell, encrypted with the
+    /// offset for decrypting it, if not the host's default shell.
+    pub argv: Option<(Bytes, u64)>,
+
+    /// How the size of a shell is chosen when viewers resize it differently.
+    pub size_policy: SizePolicy,
+
+    /// Optional protocol features that both the host and server support.
+    pub features: Vec<String>,
+
+    /// Maximum bytes of output history retained across all shells, if set.
+    pub max_session_bytes: Option<u64>,
+
+    /// Address of the client that opened the session, if known.
+    pub opened_from: Option<IpAddr>,
+
+    /// Whether each new viewer waits for the host to admit them.
+    pub knock: bool,
+
+    /// Random value that the session's tokens are signed with, so that they
+    /// are not valid for a later session that reuses the name.
+    pub nonce: Bytes,
+}
+
+impl Metadata {
+    /// Whether the host negotiated a feature from [`sshx_core::feature`].
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
 }
 
 /// In-memory state for a single sshx session.
@@ -50,8 +184,12 @@ pub struct Session {
     /// Timestamp of the last backend client message from an active connection.
     last_accessed: Mutex<Instant>,
 
-    /// Watch channel source for the ordered list of open shells and sizes.
-    source: watch::Sender<Vec<(Sid, WsWinsize)>>,
+    /// Timestamp of the last terminal input or output in any shell.
+    last_activity: Mutex<Instant>,
+
+    /// Watch channel source for the ordered list of open shells, with their
+    /// sizes and titles.
+    source: watch::Sender<Vec<(Sid, WsWinsize, String)>>,
 
     /// Broadcasts updates to all WebSocket clients.
     ///
@@ -60,6 +198,9 @@ pub struct Session {
     /// state. Duplicated events should remain consistent.
     broadcast: broadcast::Sender<WsServer>,
 
+    /// Channels for messages meant for a single viewer, like file transfers.
+    mailboxes: RwLock<HashMap<Uid, mpsc::Sender<WsServer>>>,
+
     /// Sender end of a channel that buffers messages for the client.
     update_tx: async_channel::Sender<ServerMessage>,
 
@@ -69,32 +210,276 @@ pub struct Session {
     /// Triggered from metadata events when an immediate snapshot is needed.
     sync_notify: Notify,
 
+    /// Recent viewers joining and leaving, retained for the host.
+    access_log: Mutex<VecDeque<AccessEvent>>,
+
+    /// Recent chat messages as `(uid, name, text, time_ms)` tuples.
+    chat_history: Mutex<VecDeque<(Uid, String, String, u64)>>,
+
+    /// Streams new access log entries to backend clients watching them.
+    access_tx: broadcast::Sender<AccessEvent>,
+
+    /// Viewers waiting for the host to admit them, with a channel for the
+    /// host's decision.
+    knocks: Mutex<HashMap<Uid, oneshot::Sender<bool>>>,
+
+    /// Connected viewers that can be kicked from the session.
+    kicks: Mutex<HashMap<Uid, Kickable>>,
+
+    /// Addresses of kicked viewers that may not rejoin the session.
+    banned: RwLock<HashSet<IpAddr>>,
+
+    /// Last time limit notice sent to clients, to avoid repeating it.
+    time_limit_notice: Mutex<Option<Duration>>,
+
+    /// Positions and sizes of shells copied from another session, applied
+    /// when the client creates them.
+    layout: Mutex<HashMap<Sid, WsWinsize>>,
+
+    /// Set once the host has connected to stream the session.
+    host_joined: AtomicBool,
+
+    /// Number of streaming connections open from the host.
+    host_channels: AtomicUsize,
+
+    /// Identity of the client hosting the session, which is empty until
+    /// another client adopts it.
+    host: watch::Sender<String>,
+
+    /// Set when the session was adopted, until the new host connects and
+    /// reopens its shells.
+    adopting: AtomicBool,
+
+    /// Channels that buffer messages for each additional host, which runs
+    /// some of the shells, by its identity.
+    co_hosts: RwLock<HashMap<String, HostChannel>>,
+
+    /// Most recent round-trip time to the host, in milliseconds.
+    host_latency: Mutex<Option<u64>>,
+
+    /// Resources used by the session, for accounting.
+    usage: UsageCounters,
+
+    /// Recording of the session's output to disk, if enabled.
+    recording: OnceLock<Recording>,
+
+    /// Old output of the session's shells spilled to disk, if enabled.
+    spill: OnceLock<Spill>,
+
     /// Set when this session has been closed and removed.
     shutdown: Shutdown,
 }
 
+/// Both ends of a channel that buffers messages for one host.
+type HostChannel = (
+    async_channel::Sender<ServerMessage>,
+    async_channel::Receiver<ServerMessage>,
+);
+
+/// Rendering of a shell's screen from the client, sent to viewers that join
+/// late instead of the full output history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScreenSnapshot {
+    /// Sequence number of the output that follows the screen.
+    pub seq: u64,
+    /// Offset of the first byte for encryption.
+    pub offset: u64,
+    /// Encrypted terminal output that redraws the screen.
+    pub data: Bytes,
+}
+
+/// Limit on the rate of output from each shell, applied as it is received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLimit {
+    /// Bytes per second that a shell can sustain.
+    pub rate: u32,
+
+    /// Bytes that a shell can send at once after being quiet.
+    pub burst: u32,
+}
+
 /// Internal state for each shell.
 #[derive(Default, Debug)]
 struct State {
     /// Sequence number, indicating how many bytes have been received.
     seqnum: u64,
 
-    /// Terminal data chunks.
+    /// Terminal data, with small chunks merged together.
     data: Vec<Bytes>,
 
-    /// Number of pruned data chunks before `data[0]`.
+    /// Number of chunks in `data` that have already been compacted.
+    compacted: usize,
+
+    /// Byte offsets where each stored chunk started when it was received,
+    /// before compaction.
+    chunk_starts: VecDeque<u64>,
+
+    /// Number of pruned chunks before `chunk_starts[0]`.
     chunk_offset: u64,
 
     /// Number of bytes in pruned data chunks.
     byte_offset: u64,
 
+    /// Line numbers reported by the client, with the byte offsets where
+    /// they start, in increasing order.
+    line_marks: VecDeque<(u64, u64)>,
+
+    /// Furthest byte offset delivered to any subscriber.
+    delivered: u64,
+
+    /// Number of active subscriptions to this shell.
+    subscribers: usize,
+
+    /// Latest rendering of the screen, if the client has sent one.
+    screen: Option<ScreenSnapshot>,
+
     /// Set when this shell is terminated.
     closed: bool,
 
+    /// Exit code of the shell's process, if the client reported it.
+    exit_code: Option<i32>,
+
+    /// Size that each viewer last asked for, under the smallest-size policy.
+    sizes: HashMap<Uid, (u16, u16)>,
+
+    /// Additional host that runs this shell, or `None` for the main host.
+    owner: Option<String>,
+
+    /// Bytes left under the output limit when it was last checked, which is
+    /// negative if the host still owes bytes under flow control.
+    budget: f64,
+
+    /// Time when `budget` was last checked, or `None` if never.
+    budget_at: Option<Instant>,
+
+    /// Byte ranges of output over the limit, or lost by the host, which are
+    /// not sent to viewers.
+    truncated: VecDeque<(u64, u64)>,
+
+    /// Offset that the host was last asked to resend output from, if any.
+    resync: Option<u64>,
+
     /// Updated when any of the above fields change.
     notify: Arc<Notify>,
 }
 
+impl State {
+    /// Merge runs of small chunks received since the last compaction, so that
+    /// output from many small writes is not kept as many tiny allocations.
+    fn compact(&mut self) {
+        let mut merged = Vec::new();
+        let mut run = BytesMut::new();
+        for chunk in self.data.drain(self.compacted..) {
+            if !run.is_empty() && run.len() + chunk.len() > COMPACT_CHUNK_BYTES {
+                merged.push(run.split().freeze());
+            }
+            if chunk.len() >= COMPACT_CHUNK_BYTES {
+                merged.push(chunk);
+            } else {
+                run.extend_from_slice(&chunk);
+            }
+        }
+        if !run.is_empty() {
+            merged.push(run.freeze());
+        }
+        self.data.extend(merged);
+        self.compacted = self.data.len();
+    }
+
+    /// Return the output budget under a limit at some time, after refilling.
+    fn budget(&self, limit: OutputLimit, now: Instant) -> f64 {
+        match self.budget_at {
+            Some(at) => {
+                let refill = (now - at).as_secs_f64() * f64::from(limit.rate);
+                (self.budget + refill).min(f64::from(limit.burst))
+            }
+            None => f64::from(limit.burst),
+        }
+    }
+
+    /// Spend the output budget on bytes received at an offset.
+    ///
+    /// Bytes beyond the budget are hidden from viewers, unless the host is
+    /// held back by flow control instead, in which case the budget goes into
+    /// debt until it is refilled.
+    fn throttle(&mut self, limit: OutputLimit, start: u64, len: u64, flow_control: bool) {
+        let now = Instant::now();
+        let budget = self.budget(limit, now);
+        self.budget = budget - len as f64;
+        self.budget_at = Some(now);
+        if flow_control || self.budget >= 0.0 {
+            return;
+        }
+        self.budget = 0.0;
+        self.truncate(start + budget.max(0.0) as u64, start + len);
+    }
+
+    /// Hide a range of output from viewers, merging it with the last range.
+    fn truncate(&mut self, from: u64, to: u64) {
+        match self.truncated.back_mut() {
+            Some((_, end)) if *end == from => *end = to,
+            _ => self.truncated.push_back((from, to)),
+        }
+    }
+
+    /// Prune old chunks if the stored output exceeds a number of bytes,
+    /// returning the output that was pruned.
+    fn prune(&mut self, max_stored: u64) -> Pruned {
+        let mut pruned = Pruned {
+            offset: self.byte_offset,
+            ..Default::default()
+        };
+        let mut stored_bytes = self.seqnum - self.byte_offset;
+        if stored_bytes <= max_stored {
+            return pruned;
+        }
+        let mut offset = 0;
+        while offset < self.data.len() && stored_bytes > max_stored {
+            let bytes = self.data[offset].len() as u64;
+            stored_bytes -= bytes;
+            self.byte_offset += bytes;
+            offset += 1;
+        }
+        pruned.chunks = self.data.drain(..offset).collect();
+        pruned.truncated = (self.truncated.iter())
+            .map(|&(start, end)| (start.max(pruned.offset), end.min(self.byte_offset)))
+            .filter(|&(start, end)| start < end)
+            .collect();
+        self.compacted = self.compacted.saturating_sub(offset);
+        while (self.chunk_starts.front()).is_some_and(|&start| start < self.byte_offset) {
+            self.chunk_starts.pop_front();
+            self.chunk_offset += 1;
+        }
+        while (self.line_marks.front()).is_some_and(|&(_, byte)| byte < self.byte_offset) {
+            self.line_marks.pop_front();
+        }
+        while (self.truncated.front()).is_some_and(|&(_, end)| end <= self.byte_offset) {
+            self.truncated.pop_front();
+        }
+        pruned
+    }
+}
+
+/// A connected viewer, with a channel that disconnects them when kicked.
+#[derive(Debug)]
+struct Kickable {
+    /// Address of the viewer, banned if they are kicked with a ban.
+    address: Option<IpAddr>,
+    /// Sends whether the viewer was banned when kicking them.
+    tx: oneshot::Sender<bool>,
+}
+
+/// Output of a shell that was pruned from memory.
+#[derive(Default)]
+struct Pruned {
+    /// Byte offset where the pruned output starts.
+    offset: u64,
+    /// Chunks of output that were pruned, in order.
+    chunks: Vec<Bytes>,
+    /// Byte ranges of the pruned output that are hidden from viewers.
+    truncated: Vec<(u64, u64)>,
+}
+
 impl Session {
     /// Construct a new session.
     pub fn new(metadata: Metadata) -> Self {
@@ -106,20 +491,55 @@ impl Session {
             users: RwLock::new(HashMap::new()),
             counter: IdCounter::default(),
             last_accessed: Mutex::new(now),
+            last_activity: Mutex::new(now),
             source: watch::channel(Vec::new()).0,
             broadcast: broadcast::channel(64).0,
+            mailboxes: RwLock::new(HashMap::new()),
             update_tx,
             update_rx,
             sync_notify: Notify::new(),
+            access_log: Mutex::new(VecDeque::new()),
+            chat_history: Mutex::new(VecDeque::new()),
+            access_tx: broadcast::channel(64).0,
+            knocks: Mutex::new(HashMap::new()),
+            kicks: Mutex::new(HashMap::new()),
+            banned: RwLock::new(HashSet::new()),
+            time_limit_notice: Mutex::new(None),
+            layout: Mutex::new(HashMap::new()),
+            host_joined: AtomicBool::new(false),
+            host_channels: AtomicUsize::new(0),
+            host: watch::channel(String::new()).0,
+            adopting: AtomicBool::new(false),
+            co_hosts: RwLock::new(HashMap::new()),
+            host_latency: Mutex::new(None),
+            usage: UsageCounters::new(SystemTime::now()),
+            recording: OnceLock::new(),
+            spill: OnceLock::new(),
             shutdown: Shutdown::new(),
         }
     }
 
+    /// Record the session's output and input from now on, if not already
+    /// recording.
+    pub fn record_to(&self, recording: Recording) {
+        self.recording.set(recording).ok();
+    }
+
+    /// Spill pruned output to disk from now on, if not already spilling.
+    pub fn spill_to(&self, spill: Spill) {
+        self.spill.set(spill).ok();
+    }
+
     /// Returns the metadata for this session.
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
     }
 
+    /// Returns the counters of resources used by this session.
+    pub fn usage(&self) -> &UsageCounters {
+        &self.usage
+    }
+
     /// Gives access to the ID counter for obtaining new IDs.
     pub fn counter(&self) -> &IdCounter {
         &self.counter
@@ -137,29 +557,200 @@ impl Session {
         SequenceNumbers { map }
     }
 
+    /// Return the sequence numbers of current shells run by one host, given
+    /// as `None` for the main host.
+    pub fn host_sequence_numbers(&self, owner: Option<&str>) -> SequenceNumbers {
+        let shells = self.shells.read();
+        let mut map = HashMap::new();
+        for (key, value) in &*shells {
+            if !value.closed && value.owner.as_deref() == owner {
+                map.insert(key.0, value.seqnum);
+            }
+        }
+        SequenceNumbers { map }
+    }
+
+    /// Return the output consumed so far for current shells run by one host,
+    /// for flow control.
+    ///
+    /// Output counts as consumed once it has been delivered to any viewer, or
+    /// as soon as it is stored if no viewers are subscribed to the shell.
+    /// Under an output limit, output sent faster than the limit is only
+    /// consumed as the shell's budget refills.
+    pub fn acked_seqnums(&self, owner: Option<&str>) -> SequenceNumbers {
+        let now = Instant::now();
+        let shells = self.shells.read();
+        let mut map = HashMap::with_capacity(shells.len());
+        for (key, value) in &*shells {
+            if !value.closed && value.owner.as_deref() == owner {
+                let mut acked = match value.subscribers {
+                    0 => value.seqnum,
+                    _ => value.delivered.min(value.seqnum),
+                };
+                // Viewers skip over truncated output without it being delivered.
+                if let Some(&(_, end)) = value.truncated.iter().find(|&&(s, _)| s == acked) {
+                    acked = end;
+                }
+                if let Some(limit) = self.metadata.output_limit {
+                    let debt = -value.budget(limit, now).min(0.0);
+                    acked = acked.min(value.seqnum.saturating_sub(debt.ceil() as u64));
+                }
+                map.insert(key.0, acked);
+            }
+        }
+        SequenceNumbers { map }
+    }
+
     /// Receive a notification on broadcasted message events.
+    ///
+    /// Also returns recent chat messages, each sent before subscribing.
     pub fn subscribe_broadcast(
         &self,
-    ) -> impl Stream<Item = Result<WsServer, BroadcastStreamRecvError>> + Unpin {
-        BroadcastStream::new(self.broadcast.subscribe())
+    ) -> (
+        Vec<WsServer>,
+        impl Stream<Item = Result<WsServer, BroadcastStreamRecvError>> + Unpin,
+    ) {
+        // Hold the lock so that no message is both in history and broadcast.
+        let history = self.chat_history.lock();
+        let stream = BroadcastStream::new(self.broadcast.subscribe());
+        let messages = (history.iter())
+            .map(|(id, name, msg, time_ms)| {
+                WsServer::Hear(*id, name.clone(), msg.clone(), *time_ms)
+            })
+            .collect();
+        (messages, stream)
+    }
+
+    /// Return the current list of open shells and their sizes.
+    pub fn list_shells(&self) -> Vec<(Sid, WsWinsize)> {
+        (self.source.borrow().iter())
+            .map(|(id, winsize, _)| (*id, *winsize))
+            .collect()
+    }
+
+    /// Returns whether a shell is open in the session.
+    pub fn has_shell(&self, id: Sid) -> bool {
+        self.source.borrow().iter().any(|(sid, ..)| *sid == id)
+    }
+
+    /// Return the current list of open shells with their sizes and titles.
+    pub fn list_titled_shells(&self) -> Vec<(Sid, WsWinsize, String)> {
+        self.source.borrow().clone()
     }
 
     /// Receive a notification every time the set of shells is changed.
-    pub fn subscribe_shells(&self) -> impl Stream<Item = Vec<(Sid, WsWinsize)>> + Unpin {
+    pub fn subscribe_shells(&self) -> impl Stream<Item = Vec<(Sid, WsWinsize, String)>> + Unpin {
         WatchStream::new(self.source.subscribe())
     }
 
-    /// Subscribe for chunks from a shell, until it is closed.
+    /// Convert a chunk index in a shell to the byte offset where it starts.
+    ///
+    /// This is for older clients that subscribe by chunk index. Chunks keep
+    /// the numbers they were received with, even after being compacted.
+    /// Chunks that were pruned start with the oldest history, which may have
+    /// been spilled to disk.
+    pub fn chunk_byte_offset(&self, id: Sid, chunknum: u64) -> u64 {
+        match self.shells.read().get(&id) {
+            Some(shell) if chunknum < shell.chunk_offset => self.spilled_start(id, shell),
+            Some(shell) => {
+                let start = (chunknum - shell.chunk_offset) as usize;
+                shell
+                    .chunk_starts
+                    .get(start)
+                    .copied()
+                    .unwrap_or(shell.seqnum)
+            }
+            None => 0,
+        }
+    }
+
+    /// Returns the byte offset where a shell's stored output begins.
+    ///
+    /// Output before this offset has been discarded by the retention limit
+    /// and can no longer be sent to viewers. Output spilled to disk counts
+    /// as stored.
+    pub fn history_start(&self, id: Sid) -> u64 {
+        self.shells
+            .read()
+            .get(&id)
+            .map_or(0, |shell| self.spilled_start(id, shell))
+    }
+
+    /// Subscribe for chunks from a shell, starting at an absolute byte offset,
+    /// until it is closed.
+    ///
+    /// The first chunk is sliced so that output starts exactly at the offset,
+    /// or at the oldest stored byte if the offset was already pruned. Stored
+    /// output is yielded in bounded batches, and the stream only reads more
+    /// when polled, so a slow consumer holds back its own cursor. Output over
+    /// the session's output limit is skipped.
     pub fn subscribe_chunks(
         &self,
         id: Sid,
-        mut chunknum: u64,
+        mut offset: u64,
     ) -> impl Stream<Item = (u64, Vec<Bytes>)> + '_ {
+        let rate = self
+            .metadata
+            .shell_bandwidth
+            .map(|rate| u64::from(rate.max(1)));
+        /// Counts a subscription to a shell while it is active.
+        struct SubscriberGuard<'a>(&'a Session, Sid);
+        impl Drop for SubscriberGuard<'_> {
+            fn drop(&mut self) {
+                if let Some(shell) = self.0.shells.write().get_mut(&self.1) {
+                    shell.subscribers -= 1;
+                }
+            }
+        }
+
         async_stream::stream! {
+            let _guard = match self.shells.write().get_mut(&id) {
+                Some(shell) => {
+                    shell.subscribers += 1;
+                    SubscriberGuard(self, id)
+                }
+                None => return,
+            };
+
+            // Stored output is replayed in full when subscribing, and only
+            // live output after that is paced by the bandwidth cap.
+            let mut live = false;
             while !self.shutdown.is_terminated() {
+                // Output spilled to disk is read without holding `shells`.
+                let spilled = {
+                    let shells = self.shells.read();
+                    let shell = match shells.get(&id) {
+                        Some(shell) if !shell.closed => shell,
+                        _ => return,
+                    };
+                    offset = offset.max(self.spilled_start(id, shell));
+                    let spilled = (offset < shell.byte_offset)
+                        .then(|| self.spill.get()?.get(id))
+                        .flatten();
+                    spilled.map(|file| (file, shell.byte_offset))
+                };
+                if let Some((file, byte_offset)) = spilled {
+                    let (start, max) = (offset, MAX_SUBSCRIBE_BATCH_BYTES);
+                    let read = task::spawn_blocking(move || file.read(start, max));
+                    match read.await {
+                        Ok(Ok((seqnum, data))) => {
+                            offset = seqnum + data.len() as u64;
+                            if !data.is_empty() {
+                                yield (seqnum, vec![data]);
+                            }
+                        }
+                        Ok(Err(err)) => {
+                            warn!(?err, %id, "failed to read spilled output");
+                            offset = byte_offset;
+                        }
+                        Err(_) => return,
+                    }
+                    continue;
+                }
+
                 // We absolutely cannot hold `shells` across an await point,
                 // since that would cause deadlocks.
-                let (seqnum, chunks, notified) = {
+                let (seqnum, chunks, more, notified) = {
                     let shells = self.shells.read();
                     let shell = match shells.get(&id) {
                         Some(shell) if !shell.closed => shell,
@@ -167,21 +758,68 @@ impl Session {
                     };
                     let notify = Arc::clone(&shell.notify);
                     let notified = async move { notify.notified().await };
+                    if let Some(rate) = rate.filter(|_| live) {
+                        // Skip ahead to recent output if this viewer is too far behind.
+                        let lag = shell.seqnum.saturating_sub(offset);
+                        if lag > rate * BANDWIDTH_MAX_LAG_SECS {
+                            debug!(%id, skipped = lag - rate, "skipping output for viewer");
+                            offset = shell.seqnum - rate;
+                        }
+                    }
+                    offset = offset.max(shell.byte_offset);
+                    let skipped = (shell.truncated.iter())
+                        .find(|&&(start, end)| start <= offset && offset < end);
+                    if let Some(&(_, end)) = skipped {
+                        offset = end;
+                    }
+                    let stop = (shell.truncated.iter())
+                        .map(|&(start, _)| start)
+                        .find(|&start| start > offset)
+                        .unwrap_or(shell.seqnum);
                     let mut seqnum = shell.byte_offset;
                     let mut chunks = Vec::new();
-                    let current_chunks = shell.chunk_offset + shell.data.len() as u64;
-                    if chunknum < current_chunks {
-                        let start = chunknum.saturating_sub(shell.chunk_offset) as usize;
-                        seqnum += shell.data[..start].iter().map(|x| x.len() as u64).sum::<u64>();
-                        chunks = shell.data[start..].to_vec();
-                        chunknum = current_chunks;
+                    if offset < shell.seqnum {
+                        let mut end = seqnum;
+                        for chunk in &shell.data {
+                            if end - seqnum >= MAX_SUBSCRIBE_BATCH_BYTES || end >= stop {
+                                break;
+                            }
+                            let start = end;
+                            end += chunk.len() as u64;
+                            if end <= offset {
+                                seqnum = end;
+                                continue;
+                            }
+                            if chunks.is_empty() {
+                                seqnum = start.max(offset);
+                            }
+                            let lo = offset.saturating_sub(start) as usize;
+                            let hi = (end.min(stop) - start) as usize;
+                            chunks.push(chunk.slice(lo..hi));
+                        }
+                        offset = end.min(stop).max(offset);
                     }
-                    (seqnum, chunks, notified)
+                    (seqnum, chunks, offset < shell.seqnum, notified)
                 };
 
                 if !chunks.is_empty() {
+                    let bytes: u64 = chunks.iter().map(|c| c.len() as u64).sum();
                     yield (seqnum, chunks);
+                    if let Some(shell) = self.shells.write().get_mut(&id) {
+                        shell.delivered = shell.delivered.max(seqnum + bytes);
+                    }
+                    if let Some(rate) = rate.filter(|_| live) {
+                        let pause = Duration::from_secs_f64(bytes as f64 / rate as f64);
+                        tokio::select! {
+                            _ = tokio::time::sleep(pause) => continue,
+                            _ = self.terminated() => return,
+                        }
+                    }
+                }
+                if more {
+                    continue;
                 }
+                live = true;
                 tokio::select! {
                     _ = notified => (),
                     _ = self.terminated() => return,
@@ -190,42 +828,119 @@ impl Session {
         }
     }
 
-    /// Add a new shell to the session.
-    pub fn add_shell(&self, id: Sid, center: (i32, i32)) -> Result<()> {
+    /// Ask the client to recreate the shells of another session, with the
+    /// same IDs, positions, and sizes.
+    ///
+    /// This should be called on a new session, before the client connects.
+    pub fn copy_layout(&self, shells: Vec<(Sid, WsWinsize)>) -> Result<()> {
+        let mut layout = self.layout.lock();
+        for (id, winsize) in shells {
+            self.counter.observe_sid(id);
+            let (argv, argv_offset) = self.metadata.argv.clone().unwrap_or_default();
+            let new_shell = NewShell {
+                id: id.0,
+                x: winsize.x,
+                y: winsize.y,
+                argv,
+                argv_offset,
+                ..Default::default()
+            };
+            self.update_tx
+                .try_send(ServerMessage::CreateShell(new_shell))
+                .context("too many shells to copy")?;
+            layout.insert(id, winsize);
+        }
+        Ok(())
+    }
+
+    /// Add a new shell to the session, with a size chosen by the client or
+    /// else the default.
+    pub fn add_shell(&self, id: Sid, center: (i32, i32), size: Option<(u16, u16)>) -> Result<()> {
+        self.add_host_shell(id, None, center, size)
+    }
+
+    /// Add a new shell run by one host, given as `None` for the main host.
+    pub fn add_host_shell(
+        &self,
+        id: Sid,
+        owner: Option<&str>,
+        center: (i32, i32),
+        size: Option<(u16, u16)>,
+    ) -> Result<()> {
         use std::collections::hash_map::Entry::*;
         let _guard = match self.shells.write().entry(id) {
             Occupied(_) => bail!("shell already exists with id={id}"),
-            Vacant(v) => v.insert(State::default()),
-        };
-        self.source.send_modify(|source| {
-            let winsize = WsWinsize {
-                x: center.0,
-                y: center.1,
+            Vacant(v) => v.insert(State {
+                owner: owner.map(String::from),
                 ..Default::default()
-            };
-            source.push((id, winsize));
-        });
+            }),
+        };
+        self.counter.observe_sid(id);
+        let winsize = match self.layout.lock().remove(&id) {
+            Some(winsize) => {
+                let resize = TerminalSize {
+                    id: id.0,
+                    rows: winsize.rows.into(),
+                    cols: winsize.cols.into(),
+                };
+                let update_tx = self.update_tx_for(owner);
+                update_tx.try_send(ServerMessage::Resize(resize)).ok();
+                winsize
+            }
+            None => {
+                let (rows, cols) = size.unwrap_or((24, 80));
+                WsWinsize {
+                    x: center.0,
+                    y: center.1,
+                    rows,
+                    cols,
+                }
+            }
+        };
+        if let Some(recording) = self.recording.get() {
+            recording.resize(id, 0, winsize.rows, winsize.cols);
+        }
+        self.source
+            .send_modify(|source| source.push((id, winsize, String::new())));
         self.sync_now();
         Ok(())
     }
 
-    /// Terminates an existing shell.
-    pub fn close_shell(&self, id: Sid) -> Result<()> {
+    /// Terminates an existing shell, returning `false` if it was already closed.
+    pub fn close_shell(&self, id: Sid) -> Result<bool> {
         match self.shells.write().get_mut(&id) {
             Some(shell) if !shell.closed => {
                 shell.closed = true;
                 shell.notify.notify_waiters();
             }
-            Some(_) => return Ok(()),
+            Some(_) => return Ok(false),
             None => bail!("cannot close shell with id={id}, does not exist"),
         }
+        if let Some(recording) = self.recording.get() {
+            recording.close(id);
+        }
+        if let Some(spill) = self.spill.get() {
+            spill.remove(id);
+        }
         self.source.send_modify(|source| {
-            source.retain(|&(x, _)| x != id);
+            source.retain(|&(x, ..)| x != id);
         });
         self.sync_now();
+        Ok(true)
+    }
+
+    /// Record the exit code of a shell's process, and tell viewers about it.
+    pub fn shell_exited(&self, id: Sid, code: i32) -> Result<()> {
+        self.get_shell_mut(id)?.exit_code = Some(code);
+        self.broadcast.send(WsServer::ShellExited(id, code)).ok();
         Ok(())
     }
 
+    /// Returns the exit code of a shell's process, if it has exited.
+    pub fn exit_code(&self, id: Sid) -> Option<i32> {
+        self.shells.read().get(&id)?.exit_code
+    }
+
     fn get_shell_mut(&self, id: Sid) -> Result<impl DerefMut<Target = State> + '_> {
         let shells = self.shells.write();
         match shells.get(&id) {
@@ -237,13 +952,97 @@ impl Session {
         }
     }
 
-    /// Change the size of a terminal, notifying clients if necessary.
-    pub fn move_shell(&self, id: Sid, winsize: Option<WsWinsize>) -> Result<()> {
-        let _guard = self.get_shell_mut(id)?; // Ensures mutual exclusion.
+    /// Move a terminal's window for a user, and resize it if they asked.
+    ///
+    /// When viewers ask for different sizes, the session's [`SizePolicy`]
+    /// picks the one that applies. Returns the new size if it changed, which
+    /// the client should resize the terminal to.
+    pub fn move_shell(
+        &self,
+        id: Sid,
+        user: Uid,
+        winsize: Option<WsWinsize>,
+    ) -> Result<Option<(u16, u16)>> {
+        let mut shell = self.get_shell_mut(id)?; // Ensures mutual exclusion.
+        let policy = self.metadata.size_policy;
+        if let (Some(winsize), SizePolicy::SizeSmallest) = (winsize, policy) {
+            shell.sizes.insert(user, (winsize.rows, winsize.cols));
+        }
+        let mut resized = None;
+        self.source.send_modify(|source| {
+            if let Some(idx) = source.iter().position(|&(sid, ..)| sid == id) {
+                let (_, oldsize, title) = source.remove(idx);
+                let mut newsize = winsize.unwrap_or(oldsize);
+                match policy {
+                    SizePolicy::SizeLatest => {}
+                    SizePolicy::SizeSmallest => {
+                        if let Some((rows, cols)) = smallest_size(&shell.sizes) {
+                            (newsize.rows, newsize.cols) = (rows, cols);
+                        }
+                    }
+                    SizePolicy::SizeHost => {
+                        (newsize.rows, newsize.cols) = (oldsize.rows, oldsize.cols);
+                    }
+                }
+                if (newsize.rows, newsize.cols) != (oldsize.rows, oldsize.cols) {
+                    if let Some(recording) = self.recording.get() {
+                        recording.resize(id, shell.seqnum, newsize.rows, newsize.cols);
+                    }
+                    resized = Some((newsize.rows, newsize.cols));
+                }
+                source.push((id, newsize, title));
+            }
+        });
+        Ok(resized)
+    }
+
+    /// Forget the sizes that a user asked for, growing shells that they were
+    /// holding back under the smallest-size policy.
+    fn release_sizes(&self, user: Uid) {
+        if self.metadata.size_policy != SizePolicy::SizeSmallest {
+            return;
+        }
+        let mut shells = self.shells.write();
+        for (&id, shell) in shells.iter_mut() {
+            if shell.sizes.remove(&user).is_none() || shell.closed {
+                continue;
+            }
+            let Some((rows, cols)) = smallest_size(&shell.sizes) else {
+                continue;
+            };
+            self.source.send_if_modified(|source| {
+                let Some((_, winsize, _)) = source.iter_mut().find(|(sid, ..)| *sid == id) else {
+                    return false;
+                };
+                if (winsize.rows, winsize.cols) == (rows, cols) {
+                    return false;
+                }
+                (winsize.rows, winsize.cols) = (rows, cols);
+                if let Some(recording) = self.recording.get() {
+                    recording.resize(id, shell.seqnum, rows, cols);
+                }
+                let resize = TerminalSize {
+                    id: id.0,
+                    rows: rows.into(),
+                    cols: cols.into(),
+                };
+                let update_tx = self.update_tx_for(shell.owner.as_deref());
+                update_tx.try_send(ServerMessage::Resize(resize)).ok();
+                true
+            });
+        }
+    }
+
+    /// Set the title of a shell, shown to viewers on its window.
+    pub fn rename_shell(&self, id: Sid, title: &str) -> Result<()> {
+        let title: String = title.chars().filter(|c| !c.is_control()).collect();
+        if title.len() > MAX_SHELL_TITLE_LEN {
+            bail!("shell title is too long");
+        }
+        let _shell = self.get_shell_mut(id)?; // Ensures mutual exclusion.
         self.source.send_modify(|source| {
-            if let Some(idx) = source.iter().position(|&(sid, _)| sid == id) {
-                let (_, oldsize) = source.remove(idx);
-                source.push((id, winsize.unwrap_or(oldsize)));
+            if let Some((.., old)) = source.iter_mut().find(|(sid, ..)| *sid == id) {
+                *old = title;
             }
         });
         Ok(())
@@ -251,35 +1050,193 @@ impl Session {
 
     /// Receive new data into the session.
     pub fn add_data(&self, id: Sid, data: Bytes, seq: u64) -> Result<()> {
+        let max_stored = self.max_stored_bytes();
         let mut shell = self.get_shell_mut(id)?;
 
         if seq <= shell.seqnum && seq + data.len() as u64 > shell.seqnum {
             let start = shell.seqnum - seq;
             let segment = data.slice(start as usize..);
             debug!(%id, bytes = segment.len(), "adding data to shell");
+            if let Some(recording) = self.recording.get() {
+                recording.output(id, shell.seqnum, segment.clone());
+            }
+            let seqnum = shell.seqnum;
+            if let Some(limit) = self.metadata.output_limit {
+                let flow_control = self.metadata.flow_control;
+                shell.throttle(limit, seqnum, segment.len() as u64, flow_control);
+            }
+            shell.chunk_starts.push_back(seqnum);
             shell.seqnum += segment.len() as u64;
             shell.data.push(segment);
+            shell.resync = None;
+            self.record_activity();
+            if shell.data.len() - shell.compacted >= COMPACT_BATCH_CHUNKS {
+                shell.compact();
+            }
 
             // Prune old chunks if we've exceeded the maximum stored bytes.
-            let mut stored_bytes = shell.seqnum - shell.byte_offset;
-            if stored_bytes > SHELL_STORED_BYTES {
-                let mut offset = 0;
-                while offset < shell.data.len() && stored_bytes > SHELL_STORED_BYTES {
-                    let bytes = shell.data[offset].len() as u64;
-                    stored_bytes -= bytes;
-                    shell.chunk_offset += 1;
-                    shell.byte_offset += bytes;
-                    offset += 1;
-                }
-                shell.data.drain(..offset);
+            let pruned = shell.prune(max_stored);
+            self.spill_pruned(id, pruned);
+            shell.notify.notify_waiters();
+        } else if seq > shell.seqnum && shell.resync != Some(shell.seqnum) {
+            // Output in between was lost, so ask the host to send it again.
+            warn!(%id, seq, expected = shell.seqnum, "gap in output from shell");
+            shell.resync = Some(shell.seqnum);
+            if self.metadata.has_feature(RESYNC) {
+                let resync = ResyncShell {
+                    id: id.0,
+                    seq: shell.seqnum,
+                };
+                let update_tx = self.update_tx_for(shell.owner.as_deref());
+                update_tx.try_send(ServerMessage::ResyncShell(resync)).ok();
             }
+        }
 
-            shell.notify.notify_waiters();
+        Ok(())
+    }
+
+    /// Skip a shell's output ahead to a byte offset, after the host reported
+    /// that the output before it was lost.
+    ///
+    /// The missing bytes are filled in, and hidden from viewers like output
+    /// over the output limit.
+    pub fn skip_data(&self, id: Sid, seq: u64) -> Result<()> {
+        static FILLER: [u8; COMPACT_CHUNK_BYTES] = [0; COMPACT_CHUNK_BYTES];
+        let max_stored = self.max_stored_bytes();
+        let mut shell = self.get_shell_mut(id)?;
+        if seq <= shell.seqnum {
+            return Ok(());
+        }
+        warn!(%id, from = shell.seqnum, to = seq, "skipping output lost by host");
+        let seqnum = shell.seqnum;
+        shell.truncate(seqnum, seq);
+        while shell.seqnum < seq {
+            let len = (seq - shell.seqnum).min(FILLER.len() as u64);
+            let seqnum = shell.seqnum;
+            shell.chunk_starts.push_back(seqnum);
+            shell.data.push(Bytes::from_static(&FILLER[..len as usize]));
+            shell.seqnum += len;
+        }
+        shell.resync = None;
+        let pruned = shell.prune(max_stored);
+        self.spill_pruned(id, pruned);
+        shell.notify.notify_waiters();
+        Ok(())
+    }
+
+    /// Spill output pruned from a shell to disk, if enabled.
+    fn spill_pruned(&self, id: Sid, pruned: Pruned) {
+        if let Some(spill) = self.spill.get() {
+            spill.append(id, pruned.offset, &pruned.chunks, &pruned.truncated);
+        }
+    }
+
+    /// Returns the byte offset where a shell's history starts, including any
+    /// output spilled to disk that leads up to what is stored in memory.
+    fn spilled_start(&self, id: Sid, shell: &State) -> u64 {
+        let spilled = (self.spill.get())
+            .and_then(|spill| spill.get(id))
+            .map(|file| file.range());
+        match spilled {
+            Some((start, end)) if end == shell.byte_offset => start,
+            _ => shell.byte_offset,
         }
+    }
 
+    /// Returns the bytes of output history to keep for each shell, sharing
+    /// the session's limit evenly between its open shells.
+    fn max_stored_bytes(&self) -> u64 {
+        let max_stored = (self.metadata.max_retained_bytes).unwrap_or(SHELL_STORED_BYTES);
+        match self.metadata.max_session_bytes {
+            Some(max) => {
+                let shells = self.source.borrow().len().max(1) as u64;
+                max_stored.min(max / shells)
+            }
+            None => max_stored,
+        }
+    }
+
+    /// Record that a line of a shell's output starts at a byte offset.
+    ///
+    /// Marks that are out of order or outside the stored output are ignored,
+    /// and only the first offset seen for each line is kept.
+    pub fn mark_line(&self, id: Sid, line: u64, seq: u64) -> Result<()> {
+        let mut shell = self.get_shell_mut(id)?;
+        let after_last = (shell.line_marks.back()).is_none_or(|&(l, b)| l < line && b < seq);
+        if after_last && seq >= shell.byte_offset && seq <= shell.seqnum {
+            shell.line_marks.push_back((line, seq));
+        }
         Ok(())
     }
 
+    /// Fetch stored output covering a range of lines in a shell.
+    ///
+    /// Returns the line number and byte offset where the output starts, which
+    /// may be before `start`, along with the encrypted chunks. The output is
+    /// cut short if it would exceed the subscription batch size.
+    pub fn fetch_lines(&self, id: Sid, start: u64, end: u64) -> Result<(u64, u64, Vec<Bytes>)> {
+        let shells = self.shells.read();
+        let Some(shell) = shells.get(&id) else {
+            bail!("cannot fetch lines from shell with id={id}, does not exist");
+        };
+        let marks = &shell.line_marks;
+        let Some(&first) = marks.front() else {
+            bail!("output of shell with id={id} is not indexed by line");
+        };
+        let (line, from) = marks
+            .iter()
+            .take_while(|&&(l, _)| l <= start)
+            .last()
+            .copied()
+            .unwrap_or(first);
+        let to = marks
+            .iter()
+            .find(|&&(l, _)| l >= end)
+            .map_or(shell.seqnum, |&(_, b)| b)
+            .min(from + MAX_SUBSCRIBE_BATCH_BYTES);
+
+        let mut chunks = Vec::new();
+        let mut offset = shell.byte_offset;
+        for chunk in &shell.data {
+            let chunk_end = offset + chunk.len() as u64;
+            if chunk_end > from && offset < to {
+                let lo = from.saturating_sub(offset) as usize;
+                let hi = (to.min(chunk_end) - offset) as usize;
+                chunks.push(chunk.slice(lo..hi));
+            }
+            offset = chunk_end;
+        }
+        Ok((line, from, chunks))
+    }
+
+    /// Store a rendering of a shell's screen, replacing any older one.
+    ///
+    /// Screens ahead of the received output are ignored, since the output in
+    /// between may never arrive.
+    pub fn set_screen(&self, id: Sid, screen: ScreenSnapshot) -> Result<()> {
+        if screen.data.len() > MAX_SCREEN_BYTES {
+            bail!("screen for shell with id={id} is too large");
+        }
+        let mut shell = self.get_shell_mut(id)?;
+        let newer = shell.screen.as_ref().is_none_or(|s| s.seq < screen.seq);
+        if screen.seq <= shell.seqnum && newer {
+            debug!(%id, seq = screen.seq, bytes = screen.data.len(), "storing screen");
+            shell.screen = Some(screen);
+        }
+        Ok(())
+    }
+
+    /// Returns the latest rendering of a shell's screen, if the output after
+    /// it is still stored.
+    pub fn screen(&self, id: Sid) -> Option<ScreenSnapshot> {
+        let shells = self.shells.read();
+        let shell = shells.get(&id)?;
+        shell
+            .screen
+            .clone()
+            .filter(|screen| screen.seq >= shell.byte_offset)
+    }
+
     /// List all the users in the session.
     pub fn list_users(&self) -> Vec<(Uid, WsUser)> {
         self.users
@@ -304,18 +1261,23 @@ impl Session {
     }
 
     /// Add a new user, and return a guard that removes the user when dropped.
-    pub fn user_scope(&self, id: Uid) -> Result<impl Drop + '_> {
+    ///
+    /// Joining and leaving are recorded in the access log, along with the
+    /// user's address if known.
+    pub fn user_scope(&self, id: Uid, address: Option<IpAddr>) -> Result<impl Drop + '_> {
         use std::collections::hash_map::Entry::*;
 
         #[must_use]
-        struct UserGuard<'a>(&'a Session, Uid);
+        struct UserGuard<'a>(&'a Session, Uid, Option<IpAddr>);
         impl Drop for UserGuard<'_> {
             fn drop(&mut self) {
+                self.0.record_access(AccessKind::AccessLeft, self.1, self.2);
                 self.0.remove_user(self.1);
             }
         }
 
-        match self.users.write().entry(id) {
+        let mut users = self.users.write();
+        match users.entry(id) {
             Occupied(_) => bail!("user already exists with id={id}"),
             Vacant(v) => {
                 let user = WsUser {
@@ -325,9 +1287,159 @@ impl Session {
                 };
                 v.insert(user.clone());
                 self.broadcast.send(WsServer::UserDiff(id, Some(user))).ok();
-                Ok(UserGuard(self, id))
             }
         }
+        self.usage.record_viewers(users.len());
+        drop(users);
+        self.record_access(AccessKind::AccessJoined, id, address);
+        Ok(UserGuard(self, id, address))
+    }
+
+    /// Add an entry to the access log, and stream it to watching clients.
+    ///
+    /// The user's current name is looked up, so this should be called before
+    /// they are removed. Rejected viewers have no user ID.
+    pub fn record_access(&self, kind: AccessKind, id: Uid, address: Option<IpAddr>) {
+        self.log_access(kind, id, address, None);
+    }
+
+    /// Add an entry to the access log for a viewer who was kicked, along with
+    /// the viewer who kicked them, or `None` for the host.
+    fn log_access(&self, kind: AccessKind, id: Uid, address: Option<IpAddr>, actor: Option<Uid>) {
+        let name = match self.users.read().get(&id) {
+            Some(user) => user.name.clone(),
+            None => String::new(),
+        };
+        let mut log = self.access_log.lock();
+        let event = AccessEvent {
+            seq: log.back().map_or(1, |event| event.seq + 1),
+            time_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            kind: kind.into(),
+            uid: id.0,
+            name,
+            address: address.map(|ip| ip.to_string()).unwrap_or_default(),
+            writable: !matches!(
+                kind,
+                AccessKind::AccessRejected
+                    | AccessKind::AccessDenied
+                    | AccessKind::AccessKicked
+                    | AccessKind::AccessBanned
+                    | AccessKind::AccessBlocked
+            ),
+            actor: actor.map_or(0, |actor| actor.0),
+        };
+        if log.len() >= ACCESS_LOG_ENTRIES {
+            log.pop_front();
+        }
+        log.push_back(event.clone());
+        self.access_tx.send(event).ok();
+    }
+
+    /// Returns access log entries starting at a sequence number, along with a
+    /// receiver for all entries recorded afterward.
+    pub fn watch_access(&self, since: u64) -> (Vec<AccessEvent>, broadcast::Receiver<AccessEvent>) {
+        let log = self.access_log.lock();
+        let events = log.iter().filter(|event| event.seq >= since).cloned();
+        (events.collect(), self.access_tx.subscribe())
+    }
+
+    /// Ask the host to admit a viewer, returning a receiver for the decision
+    /// and a guard that stops waiting for it when dropped.
+    ///
+    /// The request is queued for the main host, so it is delivered after a
+    /// reconnection if the host is away.
+    pub fn knock(
+        &self,
+        id: Uid,
+        address: Option<IpAddr>,
+    ) -> (oneshot::Receiver<bool>, impl Drop + '_) {
+        #[must_use]
+        struct KnockGuard<'a>(&'a Session, Uid);
+        impl Drop for KnockGuard<'_> {
+            fn drop(&mut self) {
+                self.0.knocks.lock().remove(&self.1);
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.knocks.lock().insert(id, tx);
+        let knock = ViewerKnock {
+            uid: id.0,
+            address: address.map(|ip| ip.to_string()).unwrap_or_default(),
+        };
+        self.update_tx.try_send(ServerMessage::Knock(knock)).ok();
+        (rx, KnockGuard(self, id))
+    }
+
+    /// Deliver the host's decision on a waiting viewer, returning whether
+    /// they were still waiting.
+    pub fn admit(&self, id: Uid, admitted: bool) -> bool {
+        match self.knocks.lock().remove(&id) {
+            Some(tx) => tx.send(admitted).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Returns a receiver that fires when a viewer is kicked, with whether
+    /// they were banned, and a guard that stops listening for it when dropped.
+    pub fn kick_scope(
+        &self,
+        id: Uid,
+        address: Option<IpAddr>,
+    ) -> (oneshot::Receiver<bool>, impl Drop + '_) {
+        #[must_use]
+        struct KickGuard<'a>(&'a Session, Uid);
+        impl Drop for KickGuard<'_> {
+            fn drop(&mut self) {
+                self.0.kicks.lock().remove(&self.1);
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.kicks.lock().insert(id, Kickable { address, tx });
+        (rx, KickGuard(self, id))
+    }
+
+    /// Disconnect a viewer, and if banning them, turn away their address for
+    /// the rest of the session. The actor is the viewer who asked for it, or
+    /// `None` for the host. Returns whether the viewer was connected.
+    pub fn kick(&self, id: Uid, ban: bool, actor: Option<Uid>) -> bool {
+        let Some(Kickable { address, tx }) = self.kicks.lock().remove(&id) else {
+            return false;
+        };
+        let kind = match ban {
+            true => AccessKind::AccessBanned,
+            false => AccessKind::AccessKicked,
+        };
+        if let Some(ip) = address.filter(|_| ban) {
+            self.banned.write().insert(ip);
+        }
+        self.log_access(kind, id, address, actor);
+        tx.send(ban).ok();
+        true
+    }
+
+    /// Check if a viewer's address was banned from the session.
+    pub fn is_banned(&self, address: Option<IpAddr>) -> bool {
+        address.is_some_and(|ip| self.banned.read().contains(&ip))
+    }
+
+    /// List the connected viewers, with their addresses if known.
+    pub fn list_viewers(&self) -> Vec<Viewer> {
+        let kicks = self.kicks.lock();
+        let mut viewers: Vec<_> = (self.users.read().iter())
+            .map(|(id, user)| Viewer {
+                uid: id.0,
+                name: user.name.clone(),
+                address: (kicks.get(id).and_then(|kickable| kickable.address))
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        viewers.sort_by_key(|viewer| viewer.uid);
+        viewers
     }
 
     /// Remove an existing user.
@@ -335,6 +1447,8 @@ impl Session {
         if self.users.write().remove(&id).is_none() {
             warn!(%id, "invariant violation: removed user that does not exist");
         }
+        self.mailboxes.write().remove(&id);
+        self.release_sizes(id);
         self.broadcast.send(WsServer::UserDiff(id, None)).ok();
     }
 
@@ -345,22 +1459,290 @@ impl Session {
             let users = self.users.read();
             users.get(&id).context("user not found")?.name.clone()
         };
+        let time_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut history = self.chat_history.lock();
+        if history.len() >= CHAT_HISTORY_MESSAGES {
+            history.pop_front();
+        }
+        history.push_back((id, name.clone(), msg.into(), time_ms));
         self.broadcast
-            .send(WsServer::Hear(id, name, msg.into()))
+            .send(WsServer::Hear(id, name, msg.into(), time_ms))
             .ok();
         Ok(())
     }
 
+    /// Receive messages meant only for one user, until they leave.
+    pub fn subscribe_mailbox(&self, id: Uid) -> mpsc::Receiver<WsServer> {
+        let (tx, rx) = mpsc::channel(MAILBOX_MESSAGES);
+        self.mailboxes.write().insert(id, tx);
+        rx
+    }
+
+    /// Send a message to a single user, waiting if their mailbox is full.
+    ///
+    /// Returns false if the user is gone, or too slow to take the message.
+    pub async fn send_to(&self, id: Uid, msg: WsServer) -> bool {
+        let Some(tx) = self.mailboxes.read().get(&id).cloned() else {
+            return false;
+        };
+        matches!(
+            time::timeout(MAILBOX_SEND_TIMEOUT, tx.send(msg)).await,
+            Ok(Ok(()))
+        )
+    }
+
+    /// Send viewers the encrypted link to a fork of this session.
+    pub fn send_forked(&self, url: Bytes, offset: u64) {
+        self.broadcast.send(WsServer::Forked(url, offset)).ok();
+    }
+
     /// Send a measurement of the shell latency.
     pub fn send_latency_measurement(&self, latency: u64) {
+        *self.host_latency.lock() = Some(latency);
         self.broadcast.send(WsServer::ShellLatency(latency)).ok();
     }
 
+    /// Returns the most recent round-trip time to the host, in milliseconds.
+    pub fn host_latency(&self) -> Option<u64> {
+        *self.host_latency.lock()
+    }
+
+    /// Send an error reported by the backend client to all viewers.
+    pub fn send_client_error(&self, err: String) {
+        let msg = WsServer::Error(ErrorCode::ClientReported, err);
+        self.broadcast.send(msg).ok();
+    }
+
+    /// Returns the time left before the session's deadline, if it has one.
+    pub fn time_left(&self) -> Option<Duration> {
+        let deadline = self.metadata.deadline?;
+        Some(
+            deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Notify clients if the time left has crossed one of the notice points.
+    pub fn notify_time_left(&self) {
+        let Some(left) = self.time_left() else {
+            return;
+        };
+        let notice = TIME_LIMIT_NOTICES
+            .iter()
+            .rev()
+            .find(|&&n| left <= n)
+            .copied();
+        let mut last_notice = self.time_limit_notice.lock();
+        if notice.is_some() && notice != *last_notice {
+            *last_notice = notice;
+            let secs = left.as_secs_f64().round() as u64;
+            self.broadcast.send(WsServer::TimeLeft(secs)).ok();
+            self.update_tx.try_send(ServerMessage::TimeLeft(secs)).ok();
+        }
+    }
+
+    /// Returns when the host is scheduled to connect, if the session was
+    /// scheduled and they have not connected yet.
+    pub fn waiting_for_host(&self) -> Option<SystemTime> {
+        match self.host_joined.load(Ordering::Relaxed) {
+            true => None,
+            false => self.metadata.scheduled,
+        }
+    }
+
+    /// Record that the host has connected, notifying viewers that were
+    /// waiting for them.
+    ///
+    /// If the host just adopted the session, it is also asked to reopen the
+    /// previous host's shells in the same windows.
+    pub fn host_connected(&self) {
+        let joined = self.host_joined.swap(true, Ordering::Relaxed);
+        if !joined && self.metadata.scheduled.is_some() {
+            self.broadcast.send(WsServer::HostJoined()).ok();
+        }
+        if self.adopting.swap(false, Ordering::Relaxed) {
+            if let Err(err) = self.reopen_shells() {
+                warn!(?err, "failed to reopen shells for the adopting host");
+            }
+        }
+    }
+
+    /// Returns the identity of the client hosting the session.
+    pub fn host(&self) -> String {
+        self.host.borrow().clone()
+    }
+
+    /// Returns whether a client with this identity hosts the session.
+    pub fn is_host(&self, host: &str) -> bool {
+        *self.host.borrow() == host
+    }
+
+    /// Hand the session to a new host, returning its identity.
+    ///
+    /// Streaming connections from the previous host end, and it cannot
+    /// reconnect afterward.
+    pub fn adopt(&self) -> String {
+        let host = rand_alphanumeric(16);
+        self.adopting.store(true, Ordering::Relaxed);
+        self.host.send_replace(host.clone());
+        self.sync_now();
+        host
+    }
+
+    /// Resolves once a client other than this one hosts the session.
+    pub async fn host_replaced(&self, host: &str) {
+        let mut rx = self.host.subscribe();
+        // The sender lives as long as the session, so this cannot fail.
+        rx.wait_for(|current| current != host).await.ok();
+    }
+
+    /// Close the open shells, whose processes ran on the previous host, and
+    /// ask the current host to open new shells in their windows.
+    ///
+    /// Shells of additional hosts are left running.
+    fn reopen_shells(&self) -> Result<()> {
+        let mut shells = self.list_shells();
+        shells.retain(|&(id, _)| self.shell_owner(id).is_none());
+        for &(id, _) in &shells {
+            self.close_shell(id)?;
+        }
+        let mut layout = self.layout.lock();
+        for (_, winsize) in shells {
+            let id = self.counter.next_sid();
+            let (argv, argv_offset) = self.metadata.argv.clone().unwrap_or_default();
+            let new_shell = NewShell {
+                id: id.0,
+                x: winsize.x,
+                y: winsize.y,
+                argv,
+                argv_offset,
+                ..Default::default()
+            };
+            self.update_tx
+                .try_send(ServerMessage::CreateShell(new_shell))
+                .context("too many shells to reopen")?;
+            layout.insert(id, winsize);
+        }
+        Ok(())
+    }
+
+    /// Add a host that runs some of the shells, alongside the main host,
+    /// returning its identity.
+    ///
+    /// The new host is asked to open one shell when it connects. New shells
+    /// from viewers still open on the main host.
+    pub fn join_host(&self) -> Result<String> {
+        let mut co_hosts = self.co_hosts.write();
+        if co_hosts.len() >= MAX_CO_HOSTS {
+            bail!("session already has {MAX_CO_HOSTS} additional hosts");
+        }
+        let host = rand_alphanumeric(16);
+        let (update_tx, update_rx) = async_channel::bounded(256);
+        let (argv, argv_offset) = self.metadata.argv.clone().unwrap_or_default();
+        let new_shell = NewShell {
+            id: self.counter.next_sid().0,
+            argv,
+            argv_offset,
+            ..Default::default()
+        };
+        update_tx.try_send(ServerMessage::CreateShell(new_shell))?;
+        co_hosts.insert(host.clone(), (update_tx, update_rx));
+        drop(co_hosts);
+        self.sync_now();
+        Ok(host)
+    }
+
+    /// Returns whether a client with this identity runs some of the shells,
+    /// alongside the main host.
+    pub fn is_co_host(&self, host: &str) -> bool {
+        self.co_hosts.read().contains_key(host)
+    }
+
+    /// Returns the identities of hosts besides the main host.
+    pub fn co_hosts(&self) -> Vec<String> {
+        self.co_hosts.read().keys().cloned().collect()
+    }
+
+    /// Remove an additional host, closing the shells that it runs.
+    ///
+    /// Returns `false` if there is no such host.
+    pub fn leave_host(&self, host: &str) -> Result<bool> {
+        let Some((update_tx, _)) = self.co_hosts.write().remove(host) else {
+            return Ok(false);
+        };
+        // End the host's streaming connection, if it is still open.
+        update_tx.close();
+        let owned: Vec<_> = (self.shells.read().iter())
+            .filter(|(_, shell)| !shell.closed && shell.owner.as_deref() == Some(host))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in owned {
+            self.close_shell(id)?;
+        }
+        self.sync_now();
+        Ok(true)
+    }
+
+    /// Returns the additional host that runs a shell, or `None` if it is the
+    /// main host's.
+    pub fn shell_owner(&self, id: Sid) -> Option<String> {
+        self.shells.read().get(&id)?.owner.clone()
+    }
+
+    /// Returns whether a host runs a shell, given as `None` for the main host.
+    pub fn owns_shell(&self, owner: Option<&str>, id: Sid) -> bool {
+        (self.shells.read().get(&id)).is_some_and(|shell| shell.owner.as_deref() == owner)
+    }
+
+    /// Count a streaming connection from the host while it is open.
+    pub fn host_scope(&self) -> impl Drop + '_ {
+        #[must_use]
+        struct HostGuard<'a>(&'a Session);
+        impl Drop for HostGuard<'_> {
+            fn drop(&mut self) {
+                self.0.host_channels.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        self.host_channels.fetch_add(1, Ordering::Relaxed);
+        HostGuard(self)
+    }
+
+    /// Returns whether the host currently has a streaming connection open.
+    pub fn host_streaming(&self) -> bool {
+        self.host_channels.load(Ordering::Relaxed) > 0
+    }
+
     /// Register a backend client heartbeat, refreshing the timestamp.
     pub fn access(&self) {
         *self.last_accessed.lock() = Instant::now();
     }
 
+    /// Add encrypted input that a user typed into a shell to its recording,
+    /// along with the user's current name.
+    pub fn record_input(&self, id: Sid, user: Uid, offset: u64, data: &Bytes) {
+        if let Some(recording) = self.recording.get() {
+            let name = match self.users.read().get(&user) {
+                Some(user) => user.name.clone(),
+                None => String::new(),
+            };
+            recording.input(id, user, name, offset, data.clone());
+        }
+    }
+
+    /// Record terminal input or output, so the session is not idle.
+    pub fn record_activity(&self) {
+        *self.last_activity.lock() = Instant::now();
+    }
+
+    /// Returns the timestamp of the last terminal input or output.
+    pub fn last_activity(&self) -> Instant {
+        *self.last_activity.lock()
+    }
+
     /// Returns the timestamp of the last backend client activity.
     pub fn last_accessed(&self) -> Instant {
         *self.last_accessed.lock()
@@ -376,6 +1758,34 @@ impl Session {
         &self.update_rx
     }
 
+    /// Returns the sender of the message channel for one host, given as
+    /// `None` for the main host.
+    ///
+    /// Messages for a host that has left go to the main host instead.
+    pub fn update_tx_for(&self, owner: Option<&str>) -> async_channel::Sender<ServerMessage> {
+        let co_hosts = self.co_hosts.read();
+        match owner.and_then(|host| co_hosts.get(host)) {
+            Some((tx, _)) => tx.clone(),
+            None => self.update_tx.clone(),
+        }
+    }
+
+    /// Returns the receiver of the message channel for one host, given as
+    /// `None` for the main host.
+    pub fn update_rx_for(&self, owner: Option<&str>) -> async_channel::Receiver<ServerMessage> {
+        let co_hosts = self.co_hosts.read();
+        match owner.and_then(|host| co_hosts.get(host)) {
+            Some((_, rx)) => rx.clone(),
+            None => self.update_rx.clone(),
+        }
+    }
+
+    /// Returns the sender of the message channel for the host that runs a
+    /// shell, for input and other messages about that shell.
+    pub fn shell_update_tx(&self, id: Sid) -> async_channel::Sender<ServerMessage> {
+        self.update_tx_for(self.shell_owner(id).as_deref())
+    }
+
     /// Mark the session as requiring an immediate storage sync.
     ///
     /// This is needed for consistency when creating new shells, removing old
@@ -405,3 +1815,10 @@ impl Session {
         self.shutdown.wait().await
     }
 }
+
+/// Returns the smallest number of rows and columns among requested sizes.
+fn smallest_size(sizes: &HashMap<Uid, (u16, u16)>) -> Option<(u16, u16)> {
+    let rows = sizes.values().map(|&(rows, _)| rows).min()?;
+    let cols = sizes.values().map(|&(_, cols)| cols).min()?;
+    Some((rows, cols))
+}
@@ -1,16 +1,21 @@
 //! Snapshot and restore sessions from serialized state.
 
 use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{ensure, Context, Result};
+use bytes::Bytes;
 use prost::Message;
 use sshx_core::{
     proto::{SerializedSession, SerializedShell},
+    ws::WsWinsize,
     Sid, Uid,
 };
 
-use super::{Metadata, Session, State};
-use crate::web::protocol::WsWinsize;
+use super::{Metadata, OutputLimit, ScreenSnapshot, Session, State};
+use crate::usage::UsageCounters;
 
 /// Persist at most this many bytes of output in storage, per shell.
 const SHELL_SNAPSHOT_BYTES: u64 = 1 << 15; // 32 KiB
@@ -20,62 +25,194 @@ const MAX_SNAPSHOT_SIZE: usize = 1 << 22; // 4 MiB
 impl Session {
     /// Snapshot the session, returning a compressed representation.
     pub fn snapshot(&self) -> Result<Vec<u8>> {
+        let data = self.serialize(SHELL_SNAPSHOT_BYTES).encode_to_vec();
+        ensure!(data.len() < MAX_SNAPSHOT_SIZE, "snapshot too large");
+        Ok(zstd::bulk::compress(&data, 3)?)
+    }
+
+    /// Serialize the session, keeping at most `max_bytes` of output per shell.
+    fn serialize(&self, max_bytes: u64) -> SerializedSession {
         let ids = self.counter.get_current_values();
-        let winsizes: BTreeMap<Sid, WsWinsize> = self.source.borrow().iter().cloned().collect();
-        let message = SerializedSession {
+        let winsizes: BTreeMap<Sid, (WsWinsize, String)> = (self.source.borrow().iter())
+            .map(|(id, winsize, title)| (*id, (*winsize, title.clone())))
+            .collect();
+        let (argv, argv_offset) = self.metadata().argv.clone().unwrap_or_default();
+        SerializedSession {
             encrypted_zeros: self.metadata().encrypted_zeros.clone(),
+            low_bandwidth: self.metadata().low_bandwidth,
+            totp_secret: self.metadata().totp_secret.clone(),
+            access_log: self.access_log.lock().iter().cloned().collect(),
+            banner: self.metadata().banner.clone(),
+            watermark: self.metadata().watermark,
+            shell_bandwidth: self.metadata().shell_bandwidth.unwrap_or(0),
+            flow_control: self.metadata().flow_control,
+            output_rate: self.metadata().output_limit.map_or(0, |limit| limit.rate),
+            output_burst: self.metadata().output_limit.map_or(0, |limit| limit.burst),
+            scheduled_ms: self.metadata().scheduled.map_or(0, |start| {
+                let since_epoch = start.duration_since(SystemTime::UNIX_EPOCH);
+                since_epoch.unwrap_or_default().as_millis() as u64
+            }),
+            host_joined: self.host_joined.load(Ordering::Relaxed),
+            namespace: self.metadata().namespace.clone().unwrap_or_default(),
+            max_retained_bytes: self.metadata().max_retained_bytes.unwrap_or(0),
+            write_protected: self.metadata().write_protected,
+            write_password_hash: self.metadata().write_password_hash.clone(),
+            argv,
+            argv_offset,
+            size_policy: self.metadata().size_policy.into(),
+            features: self.metadata().features.clone(),
+            max_session_bytes: self.metadata().max_session_bytes.unwrap_or(0),
+            opened_from: (self.metadata().opened_from)
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+            knock: self.metadata().knock,
+            nonce: self.metadata().nonce.clone(),
+            banned: (self.banned.read().iter())
+                .map(|ip| ip.to_string())
+                .collect(),
+            host: self.host(),
+            co_hosts: self.co_hosts(),
+            started_ms: {
+                let since_epoch = self
+                    .usage()
+                    .started()
+                    .duration_since(SystemTime::UNIX_EPOCH);
+                since_epoch.unwrap_or_default().as_millis() as u64
+            },
+            expires_ms: self.metadata().deadline.map_or(0, |deadline| {
+                let since_epoch = deadline.duration_since(SystemTime::UNIX_EPOCH);
+                since_epoch.unwrap_or_default().as_millis() as u64
+            }),
             shells: self
                 .shells
                 .read()
                 .iter()
                 .map(|(sid, shell)| {
-                    // Prune off data until its total length is at most `SHELL_SNAPSHOT_BYTES`.
+                    // Prune off data until its total length is at most `max_bytes`.
                     let mut prefix = 0;
-                    let mut chunk_offset = shell.chunk_offset;
                     let mut byte_offset = shell.byte_offset;
 
                     for i in 0..shell.data.len() {
-                        if shell.seqnum - byte_offset > SHELL_SNAPSHOT_BYTES {
+                        if shell.seqnum - byte_offset > max_bytes {
                             prefix += 1;
-                            chunk_offset += 1;
                             byte_offset += shell.data[i].len() as u64;
                         } else {
                             break;
                         }
                     }
+                    let pruned = shell.chunk_starts.iter().take_while(|&&s| s < byte_offset);
+                    let chunk_offset = shell.chunk_offset + pruned.count() as u64;
 
-                    let winsize = winsizes.get(sid).cloned().unwrap_or_default();
+                    let (winsize, title) = winsizes.get(sid).cloned().unwrap_or_default();
+                    let screen = (shell.screen.as_ref()).filter(|s| s.seq >= byte_offset);
+                    let line_marks = (shell.line_marks.iter())
+                        .filter(|&&(_, byte)| byte >= byte_offset)
+                        .flat_map(|&(line, byte)| [line, byte])
+                        .collect();
+                    let truncated = (shell.truncated.iter())
+                        .filter(|&&(_, end)| end > byte_offset)
+                        .flat_map(|&(start, end)| [start, end])
+                        .collect();
                     let shell = SerializedShell {
                         seqnum: shell.seqnum,
                         data: shell.data[prefix..].to_vec(),
                         chunk_offset,
                         byte_offset,
                         closed: shell.closed,
+                        exited: shell.exit_code.is_some(),
+                        exit_code: shell.exit_code.unwrap_or_default(),
                         winsize_x: winsize.x,
                         winsize_y: winsize.y,
                         winsize_rows: winsize.rows.into(),
                         winsize_cols: winsize.cols.into(),
+                        screen: screen.map(|s| s.data.clone()).unwrap_or_default(),
+                        screen_seq: screen.map_or(0, |s| s.seq),
+                        screen_offset: screen.map_or(0, |s| s.offset),
+                        title,
+                        line_marks,
+                        owner: shell.owner.clone().unwrap_or_default(),
+                        truncated,
                     };
                     (sid.0, shell)
                 })
                 .collect(),
             next_sid: ids.0 .0,
             next_uid: ids.1 .0,
-        };
-        let data = message.encode_to_vec();
-        ensure!(data.len() < MAX_SNAPSHOT_SIZE, "snapshot too large");
-        Ok(zstd::bulk::compress(&data, 3)?)
+        }
+    }
+
+    /// Copy the session's stored output into a new session, which viewers
+    /// can watch but not change.
+    ///
+    /// The copy keeps all output stored in memory, unlike snapshots, and can
+    /// be decrypted with the same key. It leaves out the time limit, write
+    /// password, approval of viewers, and access log of the original.
+    pub fn freeze(&self) -> Result<Self> {
+        let mut session = Self::deserialize(self.serialize(u64::MAX))?;
+        session.metadata.deadline = None;
+        session.metadata.scheduled = None;
+        session.metadata.write_protected = true;
+        session.metadata.write_password_hash = Bytes::new();
+        session.metadata.knock = false;
+        session.access_log.get_mut().clear();
+        Ok(session)
     }
 
     /// Restore the session from a previous compressed snapshot.
     pub fn restore(data: &[u8]) -> Result<Self> {
         let data = zstd::bulk::decompress(data, MAX_SNAPSHOT_SIZE)?;
-        let message = SerializedSession::decode(&*data)?;
+        Self::deserialize(SerializedSession::decode(&*data)?)
+    }
+
+    fn deserialize(message: SerializedSession) -> Result<Self> {
+        let size_policy = message.size_policy();
         let metadata = Metadata {
             encrypted_zeros: message.encrypted_zeros,
+            low_bandwidth: message.low_bandwidth,
+            totp_secret: message.totp_secret,
+            deadline: (message.expires_ms != 0)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(message.expires_ms)),
+            banner: message.banner,
+            watermark: message.watermark,
+            shell_bandwidth: (message.shell_bandwidth != 0).then_some(message.shell_bandwidth),
+            flow_control: message.flow_control,
+            output_limit: (message.output_rate != 0).then_some(OutputLimit {
+                rate: message.output_rate,
+                burst: message.output_burst,
+            }),
+            scheduled: (message.scheduled_ms != 0)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(message.scheduled_ms)),
+            namespace: (!message.namespace.is_empty()).then_some(message.namespace),
+            max_retained_bytes: (message.max_retained_bytes != 0)
+                .then_some(message.max_retained_bytes),
+            write_protected: message.write_protected,
+            write_password_hash: message.write_password_hash,
+            argv: (!message.argv.is_empty()).then_some((message.argv, message.argv_offset)),
+            size_policy,
+            features: message.features,
+            max_session_bytes: (message.max_session_bytes != 0)
+                .then_some(message.max_session_bytes),
+            opened_from: message.opened_from.parse().ok(),
+            knock: message.knock,
+            nonce: message.nonce,
         };
 
-        let session = Self::new(metadata);
+        let mut session = Self::new(metadata);
+        if message.started_ms != 0 {
+            let started = SystemTime::UNIX_EPOCH + Duration::from_millis(message.started_ms);
+            session.usage = UsageCounters::new(started);
+        }
+        (session.host_joined).store(message.host_joined, Ordering::Relaxed);
+        session.host.send_replace(message.host);
+        for host in message.co_hosts {
+            (session.co_hosts.write()).insert(host, async_channel::bounded(256));
+        }
+        session.access_log.lock().extend(message.access_log);
+        let banned = message
+            .banned
+            .iter()
+            .filter_map(|ip| ip.parse::<IpAddr>().ok());
+        session.banned.get_mut().extend(banned);
         let mut shells = session.shells.write();
         let mut winsizes = Vec::new();
         for (sid, shell) in message.shells {
@@ -87,13 +224,43 @@ impl Session {
                     rows: shell.winsize_rows.try_into().context("rows overflow")?,
                     cols: shell.winsize_cols.try_into().context("cols overflow")?,
                 },
+                shell.title,
             ));
+            // Chunks are saved compacted, so their original numbers are lost.
+            let chunk_starts = (shell.data.iter())
+                .scan(shell.byte_offset, |start, chunk| {
+                    let chunk_start = *start;
+                    *start += chunk.len() as u64;
+                    Some(chunk_start)
+                })
+                .collect();
             let shell = State {
                 seqnum: shell.seqnum,
+                compacted: shell.data.len(),
                 data: shell.data,
+                chunk_starts,
                 chunk_offset: shell.chunk_offset,
                 byte_offset: shell.byte_offset,
+                line_marks: (shell.line_marks.chunks_exact(2))
+                    .map(|pair| (pair[0], pair[1]))
+                    .collect(),
+                delivered: shell.seqnum,
+                subscribers: 0,
+                screen: (!shell.screen.is_empty()).then(|| ScreenSnapshot {
+                    seq: shell.screen_seq,
+                    offset: shell.screen_offset,
+                    data: shell.screen,
+                }),
                 closed: shell.closed,
+                exit_code: shell.exited.then_some(shell.exit_code),
+                sizes: Default::default(),
+                owner: (!shell.owner.is_empty()).then_some(shell.owner),
+                budget: 0.0,
+                budget_at: None,
+                truncated: (shell.truncated.chunks_exact(2))
+                    .map(|pair| (pair[0], pair[1]))
+                    .collect(),
+                resync: None,
                 notify: Default::default(),
             };
             shells.insert(Sid(sid), shell);
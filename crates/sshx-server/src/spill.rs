@@ -0,0 +1,200 @@
+//! Spilling of old shell output to disk, for long sessions.
+//!
+//! Each shell only keeps a bounded tail of its output in memory. When a
+//! session spills, output pruned from that tail is appended to a file for the
+//! shell instead of being discarded, so viewers that subscribe from the start
+//! still get the whole history. Output is end-to-end encrypted, so the files
+//! only hold ciphertext.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use sshx_core::Sid;
+use tracing::error;
+
+/// Spill at most this many bytes of a shell's output, after which the file
+/// starts over from the output being pruned.
+pub const MAX_SPILLED_BYTES: u64 = 1 << 30; // 1 GiB
+
+/// File extension of spilled output.
+const SPILL_EXTENSION: &str = "chunks";
+
+/// Spills old output of every session's shells to a local directory.
+#[derive(Debug, Clone)]
+pub struct Spiller {
+    dir: PathBuf,
+}
+
+impl Spiller {
+    /// Create a spiller in a directory, which is created if missing.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Start spilling the output of a session.
+    ///
+    /// Files are only indexed in memory, so output spilled before a restart
+    /// of the server is overwritten.
+    pub fn start(&self, name: &str) -> Spill {
+        Spill {
+            dir: self.dir.join(name),
+            shells: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Spilled output of a single session, removed from disk when dropped.
+#[derive(Debug)]
+pub struct Spill {
+    dir: PathBuf,
+    shells: Mutex<HashMap<Sid, Arc<SpillFile>>>,
+}
+
+impl Spill {
+    /// Append output pruned from memory, which starts at a byte offset.
+    ///
+    /// Byte ranges in `truncated` are hidden from viewers, and stay hidden
+    /// when reading the output back. If the output does not follow what was
+    /// spilled before, the shell's file starts over.
+    ///
+    /// Writes go to the page cache, so they are cheap enough to make while
+    /// the shell is locked, keeping the file in step with what is in memory.
+    pub fn append(&self, id: Sid, offset: u64, chunks: &[Bytes], truncated: &[(u64, u64)]) {
+        if chunks.is_empty() {
+            return;
+        }
+        let file = Arc::clone(
+            (self.shells.lock().entry(id))
+                .or_insert_with(|| Arc::new(SpillFile::new(shell_path(&self.dir, id)))),
+        );
+        if let Err(err) = file.append(offset, chunks, truncated) {
+            error!(?err, %id, dir = %self.dir.display(), "failed to spill output");
+        }
+    }
+
+    /// Returns the spilled output of a shell, if any.
+    pub fn get(&self, id: Sid) -> Option<Arc<SpillFile>> {
+        self.shells.lock().get(&id).cloned()
+    }
+
+    /// Delete the spilled output of a shell that was closed.
+    pub fn remove(&self, id: Sid) {
+        if let Some(file) = self.shells.lock().remove(&id) {
+            fs::remove_file(&file.path).ok();
+        }
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Append-only file holding spilled output of one shell.
+#[derive(Debug)]
+pub struct SpillFile {
+    path: PathBuf,
+    inner: Mutex<SpillInner>,
+}
+
+#[derive(Debug, Default)]
+struct SpillInner {
+    /// Open handle to the file, or `None` if nothing is spilled yet.
+    file: Option<File>,
+    /// Byte offset in the shell's output where the file starts.
+    start: u64,
+    /// Byte offset in the shell's output where the file ends.
+    end: u64,
+    /// Byte ranges of spilled output that are hidden from viewers.
+    truncated: VecDeque<(u64, u64)>,
+}
+
+impl SpillFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            inner: Mutex::new(SpillInner::default()),
+        }
+    }
+
+    /// Returns the range of output in the file, as byte offsets.
+    pub fn range(&self) -> (u64, u64) {
+        let inner = self.inner.lock();
+        (inner.start, inner.end)
+    }
+
+    fn append(&self, offset: u64, chunks: &[Bytes], truncated: &[(u64, u64)]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let len: u64 = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+        let restart = inner.end != offset || inner.end - inner.start + len > MAX_SPILLED_BYTES;
+        if inner.file.is_none() || restart {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            inner.file = None;
+            let file = (fs::OpenOptions::new().create(true).truncate(true))
+                .read(true)
+                .write(true)
+                .open(&self.path)
+                .with_context(|| format!("failed to open {}", self.path.display()))?;
+            *inner = SpillInner {
+                file: Some(file),
+                start: offset,
+                end: offset,
+                truncated: VecDeque::new(),
+            };
+        }
+        let file = inner.file.as_mut().unwrap();
+        file.seek(SeekFrom::End(0))?;
+        for chunk in chunks {
+            file.write_all(chunk)?;
+        }
+        inner.end += len;
+        inner.truncated.extend(truncated);
+        Ok(())
+    }
+
+    /// Read spilled output starting at a byte offset, up to a number of bytes.
+    ///
+    /// Returns the offset where the output starts, after skipping over any
+    /// hidden range, and the output until the next hidden range. The output
+    /// is empty if the offset is not in the file.
+    pub fn read(&self, mut offset: u64, max_bytes: u64) -> Result<(u64, Bytes)> {
+        let mut inner = self.inner.lock();
+        let skipped =
+            (inner.truncated.iter()).find(|&&(start, end)| start <= offset && offset < end);
+        if let Some(&(_, end)) = skipped {
+            offset = end;
+        }
+        let stop = (inner.truncated.iter())
+            .map(|&(start, _)| start)
+            .find(|&start| start > offset)
+            .unwrap_or(inner.end)
+            .min(offset.saturating_add(max_bytes));
+        if offset < inner.start || offset >= stop {
+            return Ok((offset, Bytes::new()));
+        }
+        let start = inner.start;
+        let Some(file) = inner.file.as_mut() else {
+            return Ok((offset, Bytes::new()));
+        };
+        let mut buf = vec![0; (stop - offset) as usize];
+        file.seek(SeekFrom::Start(offset - start))?;
+        file.read_exact(&mut buf)?;
+        Ok((offset, buf.into()))
+    }
+}
+
+/// Returns the path of a shell's spilled output in a session directory.
+fn shell_path(dir: &Path, id: Sid) -> PathBuf {
+    dir.join(format!("{}.{SPILL_EXTENSION}", id.0))
+}
@@ -1,23 +1,43 @@
 //! Stateful components of the server, managing multiple sessions.
 
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::pin::pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
-use dashmap::DashMap;
+use anyhow::{bail, Result};
+use base64::prelude::{Engine as _, BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use dashmap::{mapref::entry::Entry, DashMap};
 use hmac::{Hmac, Mac as _};
+use hyper::header::{HeaderMap, HeaderValue};
+use parking_lot::Mutex;
 use sha2::Sha256;
+use sshx_core::feature::SHUTDOWN_NOTICE;
+use sshx_core::proto::server_update::ServerMessage;
 use sshx_core::rand_alphanumeric;
+use tokio::sync::watch;
 use tokio::time;
 use tokio_stream::StreamExt;
-use tracing::error;
+use tracing::{error, info, warn};
 
 use self::mesh::StorageMesh;
-use crate::session::Session;
+use self::store::SessionStore;
+use crate::access::{AccessList, PROXY_TOKEN_HEADER};
+use crate::audit::{AuditEvent, AuditLog, AuditTarget};
+use crate::recording::Recorder;
+use crate::session::{OutputLimit, Session};
+use crate::spill::Spiller;
+use crate::tenant::{constant_time_eq, Tenancy};
+use crate::tls::TlsConfig;
+use crate::usage::{self, SessionUsage, UsageLedger};
+use crate::web::auth::WebAuth;
+use crate::web::limit::{AddressLimiter, InputLimiter, ProbeGuard};
 use crate::ServerOptions;
 
 pub mod mesh;
+pub mod store;
 
 /// Timeout for a disconnected session to be evicted and closed.
 ///
@@ -26,6 +46,32 @@ pub mod mesh;
 /// from the state to reduce memory usage.
 const DISCONNECTED_SESSION_EXPIRY: Duration = Duration::from_secs(300);
 
+/// Time after a session's deadline before the server closes it, giving the
+/// client a chance to close it first.
+const TIME_LIMIT_GRACE: Duration = Duration::from_secs(2);
+
+/// Default time that viewers are muted after exceeding the input rate limit.
+const DEFAULT_INPUT_MUTE: Duration = Duration::from_secs(10);
+
+/// Default period of usage rollups for each namespace.
+const DEFAULT_USAGE_ROLLUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Default time that viewers can go without answering pings.
+const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time that read-only replays of session history are kept.
+const DEFAULT_REPLAY_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Most read-only replays of session history kept by each server at once,
+/// since each holds a copy of its session's stored output in memory.
+const MAX_REPLAYS: usize = 256;
+
+/// Default burst of output from a shell under the output limit, in seconds.
+const DEFAULT_OUTPUT_BURST_SECS: u32 = 4;
+
+/// Length of the tag at the end of each token, after the session's nonce.
+const TAG_LEN: usize = 32;
+
 /// Shared state object for global server logic.
 pub struct ServerState {
     /// Message authentication code for signing tokens.
@@ -34,11 +80,119 @@ pub struct ServerState {
     /// Override the origin returned for the Open() RPC.
     override_origin: Option<String>,
 
+    /// Records security-relevant events to the configured sinks.
+    audit: AuditLog,
+
+    /// Client IP addresses that are allowed or denied access.
+    access: AccessList,
+
+    /// Origins allowed to open WebSocket connections.
+    allowed_origins: Vec<String>,
+
+    /// Origins allowed to read web API responses across sites.
+    cors_origins: Vec<String>,
+
+    /// Origins allowed to embed the web interface in a frame.
+    frame_ancestors: Vec<String>,
+
+    /// Maximum bytes of terminal input per second from each viewer.
+    input_rate_limit: Option<u32>,
+
+    /// How long viewers are muted after exceeding the input rate limit.
+    input_mute: Duration,
+
+    /// Limits how often each address can open sessions, if set.
+    session_limiter: Option<AddressLimiter>,
+
+    /// Limits how often each address can open WebSockets, if set.
+    connect_limiter: Option<AddressLimiter>,
+
+    /// Maximum sessions open at once on this server, if set.
+    max_sessions: Option<usize>,
+
+    /// Maximum sessions open at once from each address, if set.
+    max_sessions_per_ip: Option<usize>,
+
+    /// Maximum shells that viewers can open in each session, if set.
+    max_shells: Option<usize>,
+
+    /// Maximum viewers connected to each session, if set.
+    max_viewers: Option<usize>,
+
+    /// Maximum bytes of output history kept for each session, if set.
+    max_session_bytes: Option<u64>,
+
+    /// Maximum bytes of output per second from each shell to each viewer.
+    shell_bandwidth: Option<u32>,
+
+    /// Limit on the output from each shell delivered to viewers, if set.
+    output_limit: Option<OutputLimit>,
+
+    /// Tracks addresses that look up nonexistent sessions.
+    probes: ProbeGuard,
+
+    /// Maximum bytes of output history kept for each shell, if set.
+    shell_history_bytes: Option<u64>,
+
+    /// Namespaces that sessions are opened in, and their limits.
+    tenancy: Tenancy,
+
+    /// Records the usage of sessions, for accounting.
+    usage: UsageLedger,
+
+    /// Period of usage rollups for each namespace.
+    usage_rollup_interval: Duration,
+
+    /// Close sessions without terminal activity for this long, if set.
+    idle_timeout: Option<Duration>,
+
+    /// Maximum lifetime of every session, if set.
+    max_session_lifetime: Option<Duration>,
+
+    /// Disconnect viewers that are silent for this long.
+    keepalive_timeout: Duration,
+
+    /// Disconnect viewers that send no messages for this long, if set.
+    viewer_idle_timeout: Option<Duration>,
+
+    /// How long read-only replays of session history are kept.
+    replay_ttl: Duration,
+
+    /// Whether the server is serving requests, for health checks.
+    serving: watch::Sender<bool>,
+
+    /// Time when the server stops, if it is draining before a shutdown.
+    draining: Mutex<Option<SystemTime>>,
+
     /// A concurrent map of session IDs to session objects.
     store: DashMap<String, Arc<Session>>,
 
+    /// Read-only replays of session history, with the time each expires.
+    replays: DashMap<String, (Arc<Session>, SystemTime)>,
+
     /// Storage and distributed communication provider, if enabled.
     mesh: Option<StorageMesh>,
+
+    /// Saves session snapshots to restore after a restart, if enabled.
+    session_store: Option<Arc<dyn SessionStore>>,
+
+    /// Records the output of shells to disk, if enabled.
+    recorder: Option<Recorder>,
+
+    /// Spills old output of shells to disk, if enabled.
+    spiller: Option<Spiller>,
+
+    /// Credentials accepted for connecting to sessions over the web.
+    web_auth: WebAuth,
+
+    /// Bearer token for the admin API, if enabled.
+    admin_token: Option<String>,
+
+    /// Directory of the static web frontend.
+    static_dir: PathBuf,
+
+    /// Certificate that listeners serve TLS with, if enabled.
+    tls: Option<TlsConfig>,
 }
 
 impl ServerState {
@@ -52,8 +206,49 @@ impl ServerState {
         Ok(Self {
             mac: Hmac::new_from_slice(secret.as_bytes()).unwrap(),
             override_origin: options.override_origin,
+            audit: AuditLog::new(options.audit.iter().map(AuditTarget::open).collect()),
+            access: options.access,
+            allowed_origins: options.allowed_origins,
+            cors_origins: options.cors_origins,
+            frame_ancestors: options.frame_ancestors,
+            input_rate_limit: options.input_rate_limit,
+            input_mute: options.input_mute.unwrap_or(DEFAULT_INPUT_MUTE),
+            session_limiter: options.session_rate_limit.map(AddressLimiter::new),
+            connect_limiter: options.connect_rate_limit.map(AddressLimiter::new),
+            max_sessions: options.max_sessions,
+            max_sessions_per_ip: options.max_sessions_per_ip,
+            max_shells: options.max_shells,
+            max_viewers: options.max_viewers,
+            max_session_bytes: options.max_session_bytes,
+            shell_bandwidth: options.shell_bandwidth,
+            output_limit: options.output_rate.map(|rate| OutputLimit {
+                rate: rate.max(1),
+                burst: (options.output_burst)
+                    .unwrap_or(rate.saturating_mul(DEFAULT_OUTPUT_BURST_SECS)),
+            }),
+            probes: ProbeGuard::default(),
+            shell_history_bytes: options.shell_history_bytes,
+            tenancy: options.tenancy,
+            usage: UsageLedger::default(),
+            usage_rollup_interval: (options.usage_rollup_interval)
+                .unwrap_or(DEFAULT_USAGE_ROLLUP_INTERVAL),
+            idle_timeout: options.idle_timeout,
+            max_session_lifetime: options.max_session_lifetime,
+            keepalive_timeout: (options.keepalive_timeout).unwrap_or(DEFAULT_KEEPALIVE_TIMEOUT),
+            viewer_idle_timeout: options.viewer_idle_timeout,
+            replay_ttl: options.replay_ttl.unwrap_or(DEFAULT_REPLAY_TTL),
+            serving: watch::channel(true).0,
+            draining: Mutex::new(None),
             store: DashMap::new(),
+            replays: DashMap::new(),
             mesh,
+            session_store: options.session_store,
+            recorder: options.recorder,
+            spiller: options.spiller,
+            web_auth: options.web_auth,
+            admin_token: options.admin_token,
+            static_dir: options.static_dir.unwrap_or_else(|| "build".into()),
+            tls: options.tls,
         })
     }
 
@@ -62,11 +257,304 @@ impl ServerState {
         self.mac.clone()
     }
 
+    /// Returns the token that clients hosting a session authenticate with.
+    pub fn session_token(&self, name: &str, nonce: &[u8]) -> String {
+        let tag = self.session_mac(name, nonce).finalize().into_bytes();
+        BASE64_STANDARD.encode([nonce, &tag].concat())
+    }
+
+    /// Returns the nonce of the session that a client token was issued for,
+    /// if the token is valid.
+    pub fn verify_session_token(&self, name: &str, token: &str) -> Option<Bytes> {
+        let token = BASE64_STANDARD.decode(token).ok()?;
+        verify_signed(&token, |nonce| self.session_mac(name, nonce))
+    }
+
+    fn session_mac(&self, name: &str, nonce: &[u8]) -> Hmac<Sha256> {
+        self.mac().chain_update(name).chain_update(nonce)
+    }
+
+    /// Returns the token that writable links to a session carry.
+    pub fn write_token(&self, name: &str, nonce: &[u8]) -> String {
+        self.link_token("write:", name, nonce)
+    }
+
+    /// Returns whether a token from a link allows writing to a session.
+    pub fn verify_write_token(&self, name: &str, nonce: &[u8], token: &str) -> bool {
+        self.verify_link_token("write:", name, token)
+            .is_some_and(|signed| signed == nonce)
+    }
+
+    /// Returns the token that signed links to a session carry.
+    pub fn view_token(&self, name: &str, nonce: &[u8]) -> String {
+        self.link_token("view:", name, nonce)
+    }
+
+    /// Returns whether a token from a signed link allows viewing a session.
+    ///
+    /// If the session is not on this server, its nonce is unknown, and a
+    /// token for any session with the name is accepted.
+    pub fn verify_view_token(&self, name: &str, nonce: Option<&[u8]>, token: &str) -> bool {
+        self.verify_link_token("view:", name, token)
+            .is_some_and(|signed| nonce.is_none_or(|nonce| signed == nonce))
+    }
+
+    fn link_token(&self, scope: &str, name: &str, nonce: &[u8]) -> String {
+        let tag = self.link_mac(scope, name, nonce).finalize().into_bytes();
+        BASE64_URL_SAFE_NO_PAD.encode([nonce, &tag].concat())
+    }
+
+    fn verify_link_token(&self, scope: &str, name: &str, token: &str) -> Option<Bytes> {
+        let token = BASE64_URL_SAFE_NO_PAD.decode(token).ok()?;
+        verify_signed(&token, |nonce| self.link_mac(scope, name, nonce))
+    }
+
+    fn link_mac(&self, scope: &str, name: &str, nonce: &[u8]) -> Hmac<Sha256> {
+        // Session tokens sign the bare name, so prefix a scope for links.
+        (self.mac().chain_update(scope).chain_update(name)).chain_update(nonce)
+    }
+
+    /// Returns the headers that this server adds to a request it proxies to
+    /// another server in the mesh, forwarding the address of the client.
+    pub fn proxy_headers(&self, ip: IpAddr) -> HeaderMap {
+        let ip = ip.to_string();
+        let token = self.link_token("proxy:", &ip, &[]);
+        let mut headers = HeaderMap::new();
+        for (name, value) in [("x-forwarded-for", ip), (PROXY_TOKEN_HEADER, token)] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+
+    /// Returns whether a request was proxied by another server in the mesh,
+    /// which shares this server's secret, so its forwarded address is trusted.
+    pub fn from_mesh_peer(&self, headers: &HeaderMap) -> bool {
+        let forwarded = (headers.get_all("x-forwarded-for").iter().next_back())
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next());
+        let token = (headers.get(PROXY_TOKEN_HEADER)).and_then(|value| value.to_str().ok());
+        match (forwarded, token) {
+            (Some(ip), Some(token)) => self
+                .verify_link_token("proxy:", ip.trim(), token)
+                .is_some_and(|nonce| nonce.is_empty()),
+            _ => false,
+        }
+    }
+
+    /// Returns the credentials accepted for connecting to sessions.
+    pub fn web_auth(&self) -> &WebAuth {
+        &self.web_auth
+    }
+
+    /// Returns whether a token grants access to the admin API.
+    pub fn is_admin_token(&self, token: &str) -> bool {
+        (self.admin_token.as_ref())
+            .is_some_and(|admin| constant_time_eq(admin.as_bytes(), token.as_bytes()))
+    }
+
+    /// Returns the directory of the static web frontend.
+    pub fn static_dir(&self) -> &Path {
+        &self.static_dir
+    }
+
+    /// Returns the certificate that listeners serve TLS with, if enabled.
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    /// Returns the maximum lifetime of every session, if set.
+    pub fn max_session_lifetime(&self) -> Option<Duration> {
+        self.max_session_lifetime
+    }
+
+    /// Returns how long a viewer's connection can be silent before it is
+    /// considered dead.
+    pub fn keepalive_timeout(&self) -> Duration {
+        self.keepalive_timeout
+    }
+
+    /// Returns how long a viewer can go without sending any messages before
+    /// it is disconnected, if there is a limit.
+    pub fn viewer_idle_timeout(&self) -> Option<Duration> {
+        self.viewer_idle_timeout
+    }
+
+    /// Returns the recorder of shell output, if enabled.
+    pub fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
     /// Returns the override origin for the Open() RPC.
     pub fn override_origin(&self) -> Option<String> {
         self.override_origin.clone()
     }
 
+    /// Returns the audit log for security-relevant events.
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Returns the rules for which client IP addresses may connect.
+    pub fn access(&self) -> &AccessList {
+        &self.access
+    }
+
+    /// Returns whether a browser at an origin may connect over WebSocket.
+    ///
+    /// Requests are allowed from the same host that they were sent to, or
+    /// from any of the configured origins.
+    pub fn origin_allowed(&self, origin: &str, host: Option<&str>) -> bool {
+        let origin = origin.trim_end_matches('/');
+        let authority = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+        host.is_some_and(|host| authority.eq_ignore_ascii_case(host))
+            || (self.allowed_origins.iter())
+                .any(|allowed| allowed == "*" || allowed.trim_end_matches('/') == origin)
+    }
+
+    /// Returns whether a browser at another origin may read web API responses.
+    pub fn cors_allowed(&self, origin: &str) -> bool {
+        let origin = origin.trim_end_matches('/');
+        (self.cors_origins.iter())
+            .any(|allowed| allowed == "*" || allowed.trim_end_matches('/') == origin)
+    }
+
+    /// Returns the origins that may embed the web interface in a frame.
+    pub fn frame_ancestors(&self) -> &[String] {
+        &self.frame_ancestors
+    }
+
+    /// Returns a new input rate limiter for a viewer, if limits are enabled.
+    pub fn input_limiter(&self) -> Option<InputLimiter> {
+        let rate = self.input_rate_limit?;
+        Some(InputLimiter::new(rate, self.input_mute))
+    }
+
+    /// Record an attempt to open a session, returning whether it is allowed.
+    pub fn allow_session(&self, ip: Option<IpAddr>) -> bool {
+        match (&self.session_limiter, ip) {
+            (Some(limiter), Some(ip)) => limiter.check(ip),
+            _ => true,
+        }
+    }
+
+    /// Record an attempt to open a WebSocket, returning whether it is allowed.
+    pub fn allow_connect(&self, ip: Option<IpAddr>) -> bool {
+        match (&self.connect_limiter, ip) {
+            (Some(limiter), Some(ip)) => limiter.check(ip),
+            _ => true,
+        }
+    }
+
+    /// Check that another session can be opened from an address, without
+    /// going over the server's limits on open sessions.
+    pub fn check_session_capacity(&self, ip: Option<IpAddr>) -> Result<()> {
+        if let Some(max) = self.max_sessions {
+            if self.store.len() >= max {
+                bail!("server has reached its limit of {max} sessions");
+            }
+        }
+        if let (Some(max), Some(ip)) = (self.max_sessions_per_ip, ip) {
+            let opened = (self.store.iter())
+                .filter(|entry| entry.value().metadata().opened_from == Some(ip))
+                .count();
+            if opened >= max {
+                bail!("address has reached its limit of {max} sessions");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the maximum shells that viewers can open in each session.
+    pub fn max_shells(&self) -> Option<usize> {
+        self.max_shells
+    }
+
+    /// Returns the maximum viewers connected to each session.
+    pub fn max_viewers(&self) -> Option<usize> {
+        self.max_viewers
+    }
+
+    /// Returns the maximum bytes of output history kept for each session.
+    pub fn max_session_bytes(&self) -> Option<u64> {
+        self.max_session_bytes
+    }
+
+    /// Returns the output bandwidth cap for a session's shells, combining the
+    /// server's limit with the one requested by the host, if any.
+    pub fn shell_bandwidth(&self, requested: Option<u32>) -> Option<u32> {
+        match (self.shell_bandwidth, requested) {
+            (Some(limit), Some(requested)) => Some(limit.min(requested)),
+            (limit, requested) => limit.or(requested),
+        }
+    }
+
+    /// Returns the limit on output delivered from each shell of new sessions.
+    pub fn output_limit(&self) -> Option<OutputLimit> {
+        self.output_limit
+    }
+
+    /// Returns the bytes of output history to keep for each shell, combining
+    /// the server's limit with a namespace's, if any.
+    pub fn shell_history_bytes(&self, quota: Option<u64>) -> Option<u64> {
+        match (self.shell_history_bytes, quota) {
+            (Some(limit), Some(quota)) => Some(limit.min(quota)),
+            (limit, quota) => limit.or(quota),
+        }
+    }
+
+    /// Returns the guard against guessing session names.
+    pub fn probes(&self) -> &ProbeGuard {
+        &self.probes
+    }
+
+    /// Returns the namespaces that sessions are opened in.
+    pub fn tenancy(&self) -> &Tenancy {
+        &self.tenancy
+    }
+
+    /// List all local sessions, sorted by name.
+    pub fn sessions(&self) -> Vec<(String, Arc<Session>)> {
+        let mut sessions: Vec<_> = (self.store.iter())
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        sessions.sort_by(|a, b| a.0.cmp(&b.0));
+        sessions
+    }
+
+    /// List the local sessions in a namespace, sorted by name.
+    pub fn sessions_in(&self, namespace: &str) -> Vec<(String, Arc<Session>)> {
+        let mut sessions: Vec<_> = (self.store.iter())
+            .filter(|entry| entry.value().metadata().namespace.as_deref() == Some(namespace))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        sessions.sort_by(|a, b| a.0.cmp(&b.0));
+        sessions
+    }
+
+    /// Returns the ledger of session usage.
+    pub fn usage(&self) -> &UsageLedger {
+        &self.usage
+    }
+
+    /// Returns the usage of open and recently closed sessions in a namespace.
+    pub fn usage_in(&self, namespace: &str) -> Vec<SessionUsage> {
+        let now = SystemTime::now();
+        let mut usage = self.usage.closed_sessions(namespace);
+        for (name, session) in self.sessions_in(namespace) {
+            usage.push(SessionUsage::measure(&name, &session, now, false));
+        }
+        usage
+    }
+
+    /// Count the viewers connected to local sessions in a namespace.
+    pub fn viewers_in(&self, namespace: &str) -> usize {
+        (self.sessions_in(namespace).iter())
+            .map(|(_, session)| session.list_users().len())
+            .sum()
+    }
+
     /// Lookup a local session by name.
     pub fn lookup(&self, name: &str) -> Option<Arc<Session>> {
         self.store.get(name).map(|s| s.clone())
@@ -74,6 +562,38 @@ impl ServerState {
 
     /// Insert a session into the local store.
     pub fn insert(&self, name: &str, session: Arc<Session>) {
+        self.start_background(name, &session);
+        if let Some(prev_session) = self.store.insert(name.to_string(), session) {
+            prev_session.shutdown();
+        }
+    }
+
+    /// Insert a new session into the local store, unless its name is taken
+    /// here or on any other server in the mesh.
+    ///
+    /// Returns whether the session was inserted.
+    pub async fn try_insert(&self, name: &str, session: Arc<Session>) -> Result<bool> {
+        if self.store.contains_key(name) {
+            return Ok(false);
+        }
+        if let Some(mesh) = &self.mesh {
+            if mesh.is_taken(name).await? {
+                return Ok(false);
+            }
+        }
+        match self.store.entry(name.to_string()) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                self.start_background(name, &session);
+                entry.insert(session);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Start syncing, recording, and spilling a session that is added to the
+    /// store.
+    fn start_background(&self, name: &str, session: &Arc<Session>) {
         if let Some(mesh) = &self.mesh {
             let name = name.to_string();
             let session = session.clone();
@@ -82,8 +602,19 @@ impl ServerState {
                 mesh.background_sync(&name, session).await;
             });
         }
-        if let Some(prev_session) = self.store.insert(name.to_string(), session) {
-            prev_session.shutdown();
+        if let Some(store) = &self.session_store {
+            let name = name.to_string();
+            let session = session.clone();
+            let store = store.clone();
+            tokio::spawn(async move {
+                store::background_save(store, &name, session).await;
+            });
+        }
+        if let Some(recorder) = &self.recorder {
+            session.record_to(recorder.start(name));
+        }
+        if let Some(spiller) = &self.spiller {
+            session.spill_to(spiller.start(name));
         }
     }
 
@@ -99,13 +630,55 @@ impl ServerState {
 
     /// Close a session permanently on this and other servers.
     pub async fn close_session(&self, name: &str) -> Result<()> {
+        if let Some(session) = self.lookup(name) {
+            self.usage.record_closed(name, &session);
+        }
         self.remove(name);
         if let Some(mesh) = &self.mesh {
             mesh.mark_closed(name).await?;
         }
+        if let Some(store) = &self.session_store {
+            store.remove(name).await?;
+        }
         Ok(())
     }
 
+    /// Restore the sessions saved before the server last stopped.
+    ///
+    /// Returns the number of sessions restored. Their hosts can reconnect
+    /// within the usual grace period for disconnected clients.
+    pub async fn restore_sessions(&self) -> Result<usize> {
+        let Some(store) = &self.session_store else {
+            return Ok(0);
+        };
+        let mut restored = 0;
+        for (name, snapshot) in store.load_all().await? {
+            match Session::restore(&snapshot) {
+                Ok(session) => {
+                    self.insert(&name, Arc::new(session));
+                    restored += 1;
+                }
+                Err(err) => error!(?err, "failed to restore session {name}"),
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Save the latest snapshot of every session, before stopping.
+    pub async fn persist_sessions(&self) {
+        let Some(store) = &self.session_store else {
+            return;
+        };
+        let sessions: Vec<_> = (self.store.iter())
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        for (name, session) in sessions {
+            if let Err(err) = store::save_session(&**store, &name, &session).await {
+                error!(?err, "failed to save session {name}");
+            }
+        }
+    }
+
     /// Connect to a session by name from the `sshx` client, which provides the
     /// actual terminal backend.
     pub async fn backend_connect(&self, name: &str) -> Result<Option<Arc<Session>>> {
@@ -128,6 +701,23 @@ impl ServerState {
         Ok(None)
     }
 
+    /// Copy the history of a session into a read-only replay, which viewers
+    /// connect to by name like a live session.
+    ///
+    /// Replays are kept on this server until they expire, and are lost if it
+    /// restarts. Returns the name of the replay and when it expires, or
+    /// `None` if this server already holds too many replays.
+    pub fn create_replay(&self, session: &Session) -> Result<Option<(String, SystemTime)>> {
+        if self.replays.len() >= MAX_REPLAYS {
+            return Ok(None);
+        }
+        let replay = Arc::new(session.freeze()?);
+        let expires = SystemTime::now() + self.replay_ttl;
+        let name = rand_alphanumeric(10);
+        self.replays.insert(name.clone(), (replay, expires));
+        Ok(Some((name, expires)))
+    }
+
     /// Connect to a session from a web browser frontend, possibly redirecting.
     pub async fn frontend_connect(
         &self,
@@ -136,6 +726,9 @@ impl ServerState {
         if let Some(session) = self.lookup(name) {
             return Ok(Ok(session));
         }
+        if let Some(replay) = self.replays.get(name) {
+            return Ok(Ok(replay.0.clone()));
+        }
 
         if let Some(mesh) = &self.mesh {
             let mut owner = mesh.get_owner(name).await?;
@@ -159,15 +752,41 @@ impl ServerState {
         }
     }
 
-    /// Close all sessions that have been disconnected for too long.
+    /// Close all sessions that have been disconnected or idle for too long.
     pub async fn close_old_sessions(&self) {
+        let period = match self.idle_timeout {
+            Some(idle) => (idle / 5).min(DISCONNECTED_SESSION_EXPIRY / 5),
+            None => DISCONNECTED_SESSION_EXPIRY / 5,
+        };
         loop {
-            time::sleep(DISCONNECTED_SESSION_EXPIRY / 5).await;
+            time::sleep(period).await;
+            self.probes.prune();
+            self.session_limiter.iter().for_each(AddressLimiter::prune);
+            self.connect_limiter.iter().for_each(AddressLimiter::prune);
+            self.replays.retain(|_, (replay, expires)| {
+                let expired = SystemTime::now() >= *expires;
+                if expired {
+                    replay.shutdown();
+                }
+                !expired
+            });
             let mut to_close = Vec::new();
+            let mut idle = Vec::new();
             for entry in &self.store {
                 let session = entry.value();
+                // Scheduled sessions are kept until a while after their start time.
+                let reserved = session
+                    .waiting_for_host()
+                    .is_some_and(|start| SystemTime::now() < start + DISCONNECTED_SESSION_EXPIRY);
+                if reserved {
+                    continue;
+                }
                 if session.last_accessed().elapsed() > DISCONNECTED_SESSION_EXPIRY {
                     to_close.push(entry.key().clone());
+                } else if (self.idle_timeout)
+                    .is_some_and(|timeout| session.last_activity().elapsed() > timeout)
+                {
+                    idle.push(entry.key().clone());
                 }
             }
             for name in to_close {
@@ -175,13 +794,125 @@ impl ServerState {
                     error!(?err, "failed to close old session {name}");
                 }
             }
+            for name in idle {
+                info!(%name, "closing idle session");
+                if let Err(err) = self.close_session(&name).await {
+                    error!(?err, "failed to close idle session {name}");
+                }
+                let event = AuditEvent::SessionIdle { session: name };
+                self.audit.record(None, event);
+            }
         }
     }
 
+    /// Notify sessions approaching their time limit, and close expired ones.
+    pub async fn enforce_time_limits(&self) {
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let mut to_close = Vec::new();
+            for entry in &self.store {
+                let session = entry.value();
+                let Some(deadline) = session.metadata().deadline else {
+                    continue;
+                };
+                session.notify_time_left();
+                if deadline + TIME_LIMIT_GRACE <= SystemTime::now() {
+                    to_close.push(entry.key().clone());
+                }
+            }
+            for name in to_close {
+                info!(%name, "closing session at its time limit");
+                if let Err(err) = self.close_session(&name).await {
+                    error!(?err, "failed to close expired session {name}");
+                }
+                let event = AuditEvent::SessionExpired { session: name };
+                self.audit.record(None, event);
+            }
+        }
+    }
+
+    /// Periodically roll up the usage of each namespace.
+    pub async fn roll_up_usage(&self) {
+        let mut interval = time::interval(self.usage_rollup_interval);
+        interval.tick().await; // the first tick completes immediately
+        loop {
+            interval.tick().await;
+            let sessions: Vec<_> = (self.store.iter())
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect();
+            for rollup in self.usage.roll_up(&sessions) {
+                info!(
+                    namespace = rollup.namespace.as_deref().unwrap_or_default(),
+                    sessions = rollup.sessions,
+                    session_seconds = rollup.session_seconds,
+                    bytes_relayed = rollup.bytes_relayed,
+                    peak_viewers = rollup.peak_viewers,
+                    "usage rollup",
+                );
+            }
+        }
+    }
+
+    /// Start draining the server before it shuts down at a deadline.
+    ///
+    /// New sessions and host connections are refused, and hosts that are
+    /// connected are told to reconnect elsewhere.
+    pub fn begin_drain(&self, deadline: SystemTime) {
+        *self.draining.lock() = Some(deadline);
+        self.serving.send_replace(false);
+        let deadline_ms = usage::millis(deadline);
+        for (name, session) in self.sessions() {
+            // Save the latest state right away, for servers that take over.
+            session.sync_now();
+            if session.host_streaming() && session.metadata().has_feature(SHUTDOWN_NOTICE) {
+                let msg = ServerMessage::ServerShuttingDown(deadline_ms);
+                if session.update_tx().try_send(msg.clone()).is_err() {
+                    warn!("failed to notify host of session {name} about shutdown");
+                }
+                // Additional hosts reconnect on their own, so tell them too.
+                for host in session.co_hosts() {
+                    let update_tx = session.update_tx_for(Some(&host));
+                    update_tx.try_send(msg.clone()).ok();
+                }
+            }
+        }
+    }
+
+    /// Returns when the server shuts down, if it is draining.
+    pub fn draining(&self) -> Option<SystemTime> {
+        *self.draining.lock()
+    }
+
+    /// Wait until no host is streaming to a session, or the timeout passes.
+    pub async fn wait_for_hosts(&self, timeout: Duration) {
+        let deadline = time::Instant::now() + timeout;
+        while time::Instant::now() < deadline {
+            if !(self.store.iter()).any(|entry| entry.value().host_streaming()) {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Watch whether the server is serving requests, which stops when it
+    /// begins shutting down.
+    pub fn watch_serving(&self) -> watch::Receiver<bool> {
+        self.serving.subscribe()
+    }
+
     /// Send a graceful shutdown signal to every session.
     pub fn shutdown(&self) {
+        self.serving.send_replace(false);
         for entry in &self.store {
             entry.value().shutdown();
         }
     }
 }
+
+/// Check the tag at the end of a token, returning the nonce before it.
+fn verify_signed(token: &[u8], mac: impl FnOnce(&[u8]) -> Hmac<Sha256>) -> Option<Bytes> {
+    let (nonce, tag) = token.split_at(token.len().checked_sub(TAG_LEN)?);
+    mac(nonce).verify_slice(tag).ok()?;
+    Some(Bytes::copy_from_slice(nonce))
+}
@@ -7,7 +7,7 @@ use deadpool::managed::Manager;
 use redis::AsyncCommands;
 use tokio::time;
 use tokio_stream::{Stream, StreamExt};
-use tracing::error;
+use tracing::{error, info_span, instrument, Instrument};
 
 use crate::session::Session;
 
@@ -58,6 +58,7 @@ impl StorageMesh {
     }
 
     /// Retrieve the hostname of the owner of a session.
+    #[instrument(name = "store.get_owner", skip(self))]
     pub async fn get_owner(&self, name: &str) -> Result<Option<String>> {
         let mut conn = self.redis.get().await?;
         let (owner, closed) = redis::pipe()
@@ -72,7 +73,22 @@ impl StorageMesh {
         }
     }
 
+    /// Check whether a session name is in use, or was recently closed.
+    #[instrument(name = "store.is_taken", skip(self))]
+    pub async fn is_taken(&self, name: &str) -> Result<bool> {
+        let mut conn = self.redis.get().await?;
+        let count: usize = conn
+            .exists(&[
+                format!("session:{{{name}}}:owner"),
+                format!("session:{{{name}}}:snapshot"),
+                format!("session:{{{name}}}:closed"),
+            ])
+            .await?;
+        Ok(count > 0)
+    }
+
     /// Retrieve the owner and snapshot of a session.
+    #[instrument(name = "store.get_owner_snapshot", skip(self))]
     pub async fn get_owner_snapshot(
         &self,
         name: &str,
@@ -120,7 +136,8 @@ impl StorageMesh {
                 pipe.set_options(format!("session:{{{name}}}:owner"), host, set_opts());
             }
             pipe.set_options(format!("session:{{{name}}}:snapshot"), snapshot, set_opts());
-            match pipe.query_async(&mut conn).await {
+            let span = info_span!("store.sync", session = name);
+            match pipe.query_async(&mut conn).instrument(span).await {
                 Ok(()) => {}
                 Err(err) => error!(?err, "failed to sync session {name}"),
             }
@@ -128,6 +145,7 @@ impl StorageMesh {
     }
 
     /// Mark a session as closed, so it will expire and never be accessed again.
+    #[instrument(name = "store.mark_closed", skip(self))]
     pub async fn mark_closed(&self, name: &str) -> Result<()> {
         let mut conn = self.redis.get().await?;
         let (owner,): (Option<String>,) = redis::pipe()
@@ -145,9 +163,11 @@ impl StorageMesh {
     }
 
     /// Notify a host that a session has been transferred.
+    #[instrument(name = "store.notify_transfer", skip(self))]
     pub async fn notify_transfer(&self, name: &str, host: &str) -> Result<()> {
         let mut conn = self.redis.get().await?;
-        conn.publish(format!("transfers:{host}"), name).await?;
+        conn.publish::<_, _, ()>(format!("transfers:{host}"), name)
+            .await?;
         Ok(())
     }
 
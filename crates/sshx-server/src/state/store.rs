@@ -0,0 +1,113 @@
+//! Persistence of session snapshots, so that sessions survive restarts.
+
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::{fs, time};
+use tracing::{error, instrument};
+
+use crate::session::Session;
+
+/// Interval for saving the latest session state to the store.
+const STORE_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// File extension of snapshots saved by [`FileStore`].
+const SNAPSHOT_EXTENSION: &str = "snapshot";
+
+/// A backend that saves session snapshots, to restore them after a restart.
+///
+/// Snapshots are compressed [`Session::snapshot`] bytes, including shell
+/// metadata, sequence numbers, and recent output.
+#[tonic::async_trait]
+pub trait SessionStore: Debug + Send + Sync {
+    /// Save the latest snapshot of a session, replacing any older one.
+    async fn save(&self, name: &str, snapshot: Vec<u8>) -> Result<()>;
+
+    /// Remove a session that was closed, so it is never restored.
+    async fn remove(&self, name: &str) -> Result<()>;
+
+    /// Load the snapshots of every saved session.
+    async fn load_all(&self) -> Result<Vec<(String, Vec<u8>)>>;
+}
+
+/// Stores each session's snapshot as a file in a local directory.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Create a store in a directory, which is created if missing.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.{SNAPSHOT_EXTENSION}"))
+    }
+}
+
+#[tonic::async_trait]
+impl SessionStore for FileStore {
+    #[instrument(name = "store.save", skip(self, snapshot))]
+    async fn save(&self, name: &str, snapshot: Vec<u8>) -> Result<()> {
+        // Write to a temporary file first, so that a crash never leaves a
+        // partially-written snapshot behind.
+        let tmp = self.dir.join(format!(".{name}.tmp"));
+        fs::write(&tmp, snapshot).await?;
+        fs::rename(&tmp, self.path(name)).await?;
+        Ok(())
+    }
+
+    #[instrument(name = "store.remove", skip(self))]
+    async fn remove(&self, name: &str) -> Result<()> {
+        match fs::remove_file(self.path(name)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    #[instrument(name = "store.load_all", skip(self))]
+    async fn load_all(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut snapshots = Vec::new();
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SNAPSHOT_EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            snapshots.push((name.to_string(), fs::read(&path).await?));
+        }
+        Ok(snapshots)
+    }
+}
+
+/// Periodically save the snapshot of a session, until it is terminated.
+pub async fn background_save(store: Arc<dyn SessionStore>, name: &str, session: Arc<Session>) {
+    let mut interval = time::interval(STORE_SYNC_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = session.sync_now_wait() => {}
+            _ = session.terminated() => break,
+        }
+        if let Err(err) = save_session(&*store, name, &session).await {
+            error!(?err, "failed to save session {name}");
+        }
+    }
+}
+
+/// Save the current snapshot of a session to a store.
+pub async fn save_session(store: &dyn SessionStore, name: &str, session: &Session) -> Result<()> {
+    store.save(name, session.snapshot()?).await
+}
@@ -0,0 +1,190 @@
+//! Namespaces that group sessions by team, identified by API keys.
+//!
+//! When namespaces are configured, clients pass an API key when opening a
+//! session, and the session belongs to that key's namespace. Each namespace
+//! can only list and manage its own sessions.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Error};
+use hyper::header::AUTHORIZATION;
+use hyper::HeaderMap;
+
+/// A team that owns sessions, authenticated by an API key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespace {
+    /// Name of the namespace, shown to operators.
+    pub name: String,
+    /// Secret key that clients use to open sessions in the namespace.
+    pub api_key: String,
+}
+
+impl FromStr for Namespace {
+    type Err = Error;
+
+    /// Parse a namespace in the form `NAME=KEY`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, api_key)) = s.split_once('=') else {
+            bail!("expected a namespace in the form NAME=KEY");
+        };
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("namespace names may only contain letters, digits, '-', and '_'");
+        }
+        if api_key.len() < 16 {
+            bail!("API key for namespace {name} must be at least 16 characters");
+        }
+        Ok(Self {
+            name: name.into(),
+            api_key: api_key.into(),
+        })
+    }
+}
+
+/// Namespaces configured on the server, and the limits applied to each.
+#[derive(Debug, Clone, Default)]
+pub struct Tenancy {
+    /// Namespaces that clients can open sessions in.
+    pub namespaces: Vec<Namespace>,
+
+    /// Reject clients that do not send an API key, instead of opening their
+    /// sessions outside of any namespace.
+    pub require_api_key: bool,
+
+    /// Limits applied to every namespace, unless overridden.
+    pub quota: Quota,
+
+    /// Limits for specific namespaces, replacing those set in `quota`.
+    pub overrides: HashMap<String, Quota>,
+}
+
+impl Tenancy {
+    /// Find the namespace that an API key belongs to.
+    pub fn namespace(&self, api_key: &str) -> Option<&Namespace> {
+        // Compare every key in full, so that timing does not reveal prefixes.
+        let mut found = None;
+        for namespace in &self.namespaces {
+            if constant_time_eq(namespace.api_key.as_bytes(), api_key.as_bytes()) {
+                found = Some(namespace);
+            }
+        }
+        found
+    }
+
+    /// Returns the limits that apply to a namespace.
+    pub fn quota(&self, namespace: &str) -> Quota {
+        match self.overrides.get(namespace) {
+            Some(quota) => quota.or(&self.quota),
+            None => self.quota,
+        }
+    }
+
+    /// Add a quota rule, either for a namespace or as the default.
+    pub fn add_rule(&mut self, rule: QuotaRule) {
+        let quota = match rule.namespace {
+            Some(namespace) => self.overrides.entry(namespace).or_default(),
+            None => &mut self.quota,
+        };
+        *quota = rule.quota.or(quota);
+    }
+
+    /// Find the namespace of a bearer token in an `Authorization` header.
+    pub fn authorize(&self, headers: &HeaderMap) -> Option<&Namespace> {
+        let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+        let token = value.strip_prefix("Bearer ")?;
+        self.namespace(token.trim())
+    }
+}
+
+/// Limits on the resources used by a namespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Maximum number of sessions open at once.
+    pub max_sessions: Option<usize>,
+
+    /// Maximum number of viewers connected at once, across all sessions.
+    pub max_viewers: Option<usize>,
+
+    /// Maximum bytes of output history retained for each shell.
+    pub max_retained_bytes: Option<u64>,
+
+    /// Maximum lifetime of each session.
+    pub max_duration: Option<Duration>,
+}
+
+impl Quota {
+    /// Combine two quotas, preferring limits set in `self`.
+    pub fn or(&self, other: &Quota) -> Quota {
+        Quota {
+            max_sessions: self.max_sessions.or(other.max_sessions),
+            max_viewers: self.max_viewers.or(other.max_viewers),
+            max_retained_bytes: self.max_retained_bytes.or(other.max_retained_bytes),
+            max_duration: self.max_duration.or(other.max_duration),
+        }
+    }
+}
+
+/// A quota for a namespace, or the default quota, parsed from the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaRule {
+    /// Namespace the quota applies to, or `None` for every namespace.
+    pub namespace: Option<String>,
+    /// Limits set by the rule.
+    pub quota: Quota,
+}
+
+impl FromStr for QuotaRule {
+    type Err = Error;
+
+    /// Parse a rule in the form `[NAMESPACE:]LIMIT=VALUE,...`, where each
+    /// limit is one of `sessions`, `viewers`, `bytes`, or `duration`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (namespace, limits) = match s.split_once(':') {
+            Some((namespace, limits)) => (Some(namespace.to_string()), limits),
+            None => (None, s),
+        };
+        let mut quota = Quota::default();
+        for limit in limits.split(',') {
+            let Some((key, value)) = limit.split_once('=') else {
+                bail!("expected a limit in the form LIMIT=VALUE, got {limit:?}");
+            };
+            let invalid = || format!("invalid value for {key}: {value:?}");
+            match key {
+                "sessions" => quota.max_sessions = Some(value.parse().with_context(invalid)?),
+                "viewers" => quota.max_viewers = Some(value.parse().with_context(invalid)?),
+                "bytes" => quota.max_retained_bytes = Some(value.parse().with_context(invalid)?),
+                "duration" => quota.max_duration = Some(parse_secs(value).with_context(invalid)?),
+                _ => bail!("unknown limit {key:?}"),
+            }
+        }
+        Ok(Self { namespace, quota })
+    }
+}
+
+/// Parse a duration in seconds, or with an `s`, `m`, `h`, or `d` suffix.
+fn parse_secs(s: &str) -> anyhow::Result<Duration> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => bail!("unknown unit {unit:?}"),
+    };
+    let value: u64 = digits.parse()?;
+    Ok(Duration::from_secs(
+        value.checked_mul(scale).context("too long")?,
+    ))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,108 @@
+//! TLS termination for listeners, so that small deployments can serve HTTPS
+//! and gRPC over TLS without a reverse proxy.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use futures_util::future;
+use hyper::server::accept::{self, Accept};
+use rustls_pemfile::Item;
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+use crate::listen::{Connection, Listener};
+
+/// Time allowed for a client to finish its TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate and private key that listeners serve TLS with.
+#[derive(Clone, Debug)]
+pub struct TlsConfig(Arc<rustls::ServerConfig>);
+
+impl TlsConfig {
+    /// Load a certificate chain and private key from PEM files.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let open = |path: &Path| {
+            let file =
+                File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+            anyhow::Ok(BufReader::new(file))
+        };
+
+        let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+            .with_context(|| format!("invalid certificate file {}", cert_path.display()))?;
+        if certs.is_empty() {
+            bail!("no certificates found in {}", cert_path.display());
+        }
+        let certs = certs.into_iter().map(rustls::Certificate).collect();
+
+        let items = rustls_pemfile::read_all(&mut open(key_path)?)
+            .with_context(|| format!("invalid private key file {}", key_path.display()))?;
+        let key = items.into_iter().find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        });
+        let Some(key) = key else {
+            bail!("no private key found in {}", key_path.display());
+        };
+
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, rustls::PrivateKey(key))
+            .context("invalid certificate or private key")?;
+        // gRPC requires HTTP/2, while browsers open WebSockets over HTTP/1.1.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Self(Arc::new(config)))
+    }
+}
+
+/// Accept TLS connections on a listener.
+///
+/// Handshakes run in their own tasks, so slow or failed handshakes do not hold
+/// up other connections or stop the server.
+pub(crate) fn accept_tls(
+    mut incoming: Listener,
+    config: &TlsConfig,
+) -> impl Accept<Conn = TlsStream<Connection>, Error = std::io::Error> {
+    let acceptor = TlsAcceptor::from(Arc::clone(&config.0));
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let accept = future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx));
+            let conn = tokio::select! {
+                conn = accept => conn,
+                _ = tx.closed() => break,
+            };
+            let conn = match conn {
+                Some(Ok(conn)) => conn,
+                Some(Err(err)) => {
+                    warn!(?err, "failed to accept connection");
+                    continue;
+                }
+                None => break,
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let remote = conn.remote_ip();
+                match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                    Ok(Ok(stream)) => {
+                        tx.send(Ok(stream)).await.ok();
+                    }
+                    Ok(Err(err)) => debug!(%remote, ?err, "TLS handshake failed"),
+                    Err(_) => debug!(%remote, "TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    accept::from_stream(ReceiverStream::new(rx))
+}
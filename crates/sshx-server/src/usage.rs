@@ -0,0 +1,310 @@
+//! Accounting of the resources used by sessions, for chargeback by namespace.
+//!
+//! Each session counts the output bytes relayed to viewers and its peak number
+//! of viewers. The server keeps a record of recently closed sessions, and
+//! periodically rolls up the usage of each namespace over the past period.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::session::Session;
+
+/// Number of closed sessions whose usage is retained for export.
+const CLOSED_SESSIONS_RETAINED: usize = 10_000;
+
+/// Number of rollups retained for export, across all namespaces.
+const ROLLUPS_RETAINED: usize = 10_000;
+
+/// Counters of a session's usage, updated as it runs.
+#[derive(Debug)]
+pub struct UsageCounters {
+    started: SystemTime,
+    bytes_relayed: AtomicU64,
+    peak_viewers: AtomicUsize,
+    period_peak_viewers: AtomicUsize,
+}
+
+impl UsageCounters {
+    /// Create counters for a session that started at the given time.
+    pub fn new(started: SystemTime) -> Self {
+        Self {
+            started,
+            bytes_relayed: AtomicU64::new(0),
+            peak_viewers: AtomicUsize::new(0),
+            period_peak_viewers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns when the session started.
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Count bytes of output relayed to a viewer.
+    pub fn record_relayed(&self, bytes: u64) {
+        self.bytes_relayed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Update the peak number of viewers with the current number.
+    pub fn record_viewers(&self, viewers: usize) {
+        self.peak_viewers.fetch_max(viewers, Ordering::Relaxed);
+        self.period_peak_viewers
+            .fetch_max(viewers, Ordering::Relaxed);
+    }
+
+    /// Returns the total bytes of output relayed to viewers.
+    pub fn bytes_relayed(&self) -> u64 {
+        self.bytes_relayed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the most viewers that were connected at once.
+    pub fn peak_viewers(&self) -> usize {
+        self.peak_viewers.load(Ordering::Relaxed)
+    }
+
+    /// Returns the peak viewers since the last call, and starts a new period
+    /// from the number of viewers connected now.
+    fn take_period_peak(&self, viewers: usize) -> usize {
+        self.period_peak_viewers.swap(viewers, Ordering::Relaxed)
+    }
+}
+
+/// Usage of a single session, as exported.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionUsage {
+    /// Name of the session.
+    pub name: String,
+    /// Namespace that owns the session.
+    pub namespace: Option<String>,
+    /// When the session started, in milliseconds since the epoch.
+    pub started_ms: u64,
+    /// When the session closed, if it has.
+    pub ended_ms: Option<u64>,
+    /// Time that the session has been open, in seconds.
+    pub session_seconds: u64,
+    /// Bytes of output relayed to viewers.
+    pub bytes_relayed: u64,
+    /// Most viewers connected at once.
+    pub peak_viewers: usize,
+}
+
+impl SessionUsage {
+    /// Measure the usage of a session up to a point in time.
+    pub fn measure(name: &str, session: &Session, now: SystemTime, ended: bool) -> Self {
+        let usage = session.usage();
+        Self {
+            name: name.into(),
+            namespace: session.metadata().namespace.clone(),
+            started_ms: millis(usage.started()),
+            ended_ms: ended.then(|| millis(now)),
+            session_seconds: elapsed(usage.started(), now).as_secs(),
+            bytes_relayed: usage.bytes_relayed(),
+            peak_viewers: usage.peak_viewers(),
+        }
+    }
+}
+
+/// Usage of a namespace over one rollup period, as exported.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageRollup {
+    /// Namespace that the usage is for.
+    pub namespace: Option<String>,
+    /// Start of the period, in milliseconds since the epoch.
+    pub period_start_ms: u64,
+    /// End of the period, in milliseconds since the epoch.
+    pub period_end_ms: u64,
+    /// Number of sessions open at any time during the period.
+    pub sessions: usize,
+    /// Total time that sessions were open during the period, in seconds.
+    pub session_seconds: u64,
+    /// Bytes of output relayed to viewers during the period.
+    pub bytes_relayed: u64,
+    /// Sum of the peak viewers of each session during the period.
+    pub peak_viewers: usize,
+}
+
+impl UsageRollup {
+    fn add(&mut self, other: &UsageRollup) {
+        self.sessions += other.sessions;
+        self.session_seconds += other.session_seconds;
+        self.bytes_relayed += other.bytes_relayed;
+        self.peak_viewers += other.peak_viewers;
+    }
+}
+
+/// Records the usage of sessions over time.
+#[derive(Debug)]
+pub struct UsageLedger {
+    inner: Mutex<LedgerInner>,
+}
+
+#[derive(Debug)]
+struct LedgerInner {
+    /// Start of the current rollup period.
+    period_start: SystemTime,
+    /// Bytes relayed by each session as of the last rollup.
+    reported: HashMap<String, u64>,
+    /// Usage of sessions that closed during the current period.
+    pending: BTreeMap<Option<String>, UsageRollup>,
+    /// Usage of recently closed sessions.
+    closed: VecDeque<SessionUsage>,
+    /// Completed rollups, oldest first.
+    rollups: VecDeque<UsageRollup>,
+}
+
+impl Default for UsageLedger {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(LedgerInner {
+                period_start: SystemTime::now(),
+                reported: HashMap::new(),
+                pending: BTreeMap::new(),
+                closed: VecDeque::new(),
+                rollups: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl LedgerInner {
+    /// Add the usage of a session in the current period to a rollup.
+    fn add_period(&mut self, name: &str, session: &Session, now: SystemTime) -> UsageRollup {
+        let usage = session.usage();
+        let bytes = usage.bytes_relayed();
+        let reported = self.reported.insert(name.into(), bytes).unwrap_or(0);
+        let viewers = session.list_users().len();
+        UsageRollup {
+            sessions: 1,
+            session_seconds: elapsed(usage.started().max(self.period_start), now).as_secs(),
+            bytes_relayed: bytes.saturating_sub(reported),
+            peak_viewers: usage.take_period_peak(viewers),
+            ..Default::default()
+        }
+    }
+}
+
+impl UsageLedger {
+    /// Record the final usage of a session that is being closed.
+    pub fn record_closed(&self, name: &str, session: &Session) {
+        let now = SystemTime::now();
+        let mut inner = self.inner.lock();
+        let period = inner.add_period(name, session, now);
+        inner.reported.remove(name);
+        let namespace = session.metadata().namespace.clone();
+        let pending = inner.pending.entry(namespace).or_default();
+        pending.add(&period);
+
+        if inner.closed.len() >= CLOSED_SESSIONS_RETAINED {
+            inner.closed.pop_front();
+        }
+        inner
+            .closed
+            .push_back(SessionUsage::measure(name, session, now, true));
+    }
+
+    /// Close the current period, rolling up the usage of each namespace.
+    pub fn roll_up(&self, sessions: &[(String, Arc<Session>)]) -> Vec<UsageRollup> {
+        let now = SystemTime::now();
+        let mut inner = self.inner.lock();
+        let mut totals = std::mem::take(&mut inner.pending);
+        for (name, session) in sessions {
+            let period = inner.add_period(name, session, now);
+            let namespace = session.metadata().namespace.clone();
+            totals.entry(namespace).or_default().add(&period);
+        }
+        // Forget sessions that were transferred to other servers.
+        inner
+            .reported
+            .retain(|name, _| sessions.iter().any(|(n, _)| n == name));
+
+        let period_start = std::mem::replace(&mut inner.period_start, now);
+        let mut rollups = Vec::new();
+        for (namespace, mut rollup) in totals {
+            rollup.namespace = namespace;
+            rollup.period_start_ms = millis(period_start);
+            rollup.period_end_ms = millis(now);
+            if inner.rollups.len() >= ROLLUPS_RETAINED {
+                inner.rollups.pop_front();
+            }
+            inner.rollups.push_back(rollup.clone());
+            rollups.push(rollup);
+        }
+        rollups
+    }
+
+    /// Returns the usage of recently closed sessions in a namespace.
+    pub fn closed_sessions(&self, namespace: &str) -> Vec<SessionUsage> {
+        let inner = self.inner.lock();
+        (inner.closed.iter())
+            .filter(|usage| usage.namespace.as_deref() == Some(namespace))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the completed rollups for a namespace, oldest first.
+    pub fn rollups(&self, namespace: &str) -> Vec<UsageRollup> {
+        let inner = self.inner.lock();
+        (inner.rollups.iter())
+            .filter(|rollup| rollup.namespace.as_deref() == Some(namespace))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Format session usage as CSV, with a header row.
+pub fn sessions_csv(sessions: &[SessionUsage]) -> String {
+    let mut csv = String::from(
+        "name,namespace,started_ms,ended_ms,session_seconds,bytes_relayed,peak_viewers\n",
+    );
+    for s in sessions {
+        // Session and namespace names are alphanumeric, so need no quoting.
+        let namespace = s.namespace.as_deref().unwrap_or_default();
+        let ended_ms = s.ended_ms.map(|ms| ms.to_string()).unwrap_or_default();
+        writeln!(
+            csv,
+            "{},{namespace},{},{ended_ms},{},{},{}",
+            s.name, s.started_ms, s.session_seconds, s.bytes_relayed, s.peak_viewers,
+        )
+        .unwrap();
+    }
+    csv
+}
+
+/// Format usage rollups as CSV, with a header row.
+pub fn rollups_csv(rollups: &[UsageRollup]) -> String {
+    let mut csv = String::from(
+        "namespace,period_start_ms,period_end_ms,sessions,session_seconds,bytes_relayed,peak_viewers\n",
+    );
+    for r in rollups {
+        let namespace = r.namespace.as_deref().unwrap_or_default();
+        writeln!(
+            csv,
+            "{namespace},{},{},{},{},{},{}",
+            r.period_start_ms,
+            r.period_end_ms,
+            r.sessions,
+            r.session_seconds,
+            r.bytes_relayed,
+            r.peak_viewers,
+        )
+        .unwrap();
+    }
+    csv
+}
+
+pub(crate) fn millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_millis() as u64
+}
+
+fn elapsed(since: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(since).unwrap_or_default()
+}
@@ -2,33 +2,84 @@
 
 use std::sync::Arc;
 
-use axum::routing::{get, get_service};
+use axum::extract::State;
+use axum::middleware;
+use axum::routing::{get, get_service, post};
 use axum::Router;
 use tower_http::services::{ServeDir, ServeFile};
 
 use crate::ServerState;
 
+mod api;
+pub mod auth;
+pub mod batch;
+pub mod limit;
+mod policy;
 pub mod protocol;
 mod socket;
 
 /// Returns the web application server, routed with Axum.
-pub fn app() -> Router<Arc<ServerState>> {
-    let root_spa = ServeFile::new("build/spa.html")
+pub fn app(state: Arc<ServerState>) -> Router {
+    let root_spa = ServeFile::new(state.static_dir().join("spa.html"))
         .precompressed_gzip()
         .precompressed_br();
 
     // Serves static SvelteKit build files.
-    let static_files = ServeDir::new("build")
+    let static_files = ServeDir::new(state.static_dir())
         .precompressed_gzip()
         .precompressed_br()
         .fallback(root_spa);
 
     Router::new()
-        .nest("/api", backend())
+        .nest("/api", backend(state.clone()))
         .fallback_service(get_service(static_files))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            policy::frame_ancestors,
+        ))
+        .with_state(state)
 }
 
 /// Routes for the backend web API server.
-fn backend() -> Router<Arc<ServerState>> {
-    Router::new().route("/s/:name", get(socket::get_session_ws))
+fn backend(state: Arc<ServerState>) -> Router<Arc<ServerState>> {
+    // Routes that connect to a session, which may require authentication.
+    let sessions = Router::new()
+        .route("/s/:name", get(socket::get_session_ws))
+        .route("/s/:name/recording/:shell", get(api::download_recording))
+        .route(
+            "/s/:name/recording/:shell/input",
+            get(api::download_input_recording),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_auth,
+        ));
+
+    Router::new()
+        .merge(sessions)
+        .route("/metrics", get(get_metrics))
+        .route("/sessions", get(api::list_sessions))
+        .route(
+            "/sessions/:name",
+            get(api::get_session).delete(api::delete_session),
+        )
+        .route("/sessions/:name/snapshot", post(api::snapshot_session))
+        .route("/usage", get(api::export_usage))
+        .route("/usage/rollups", get(api::export_rollups))
+        .layer(middleware::from_fn_with_state(state, policy::cors))
+}
+
+/// Report server metrics in the Prometheus text format.
+async fn get_metrics(State(state): State<Arc<ServerState>>) -> String {
+    let probes = state.probes();
+    format!(
+        "# HELP sshx_session_lookup_misses_total Lookups of sessions that do not exist.\n\
+         # TYPE sshx_session_lookup_misses_total counter\n\
+         sshx_session_lookup_misses_total {}\n\
+         # HELP sshx_session_lookup_banned_total Lookups rejected from banned addresses.\n\
+         # TYPE sshx_session_lookup_banned_total counter\n\
+         sshx_session_lookup_banned_total {}\n",
+        probes.total_misses(),
+        probes.total_rejected(),
+    )
 }
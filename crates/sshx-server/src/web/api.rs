@@ -0,0 +1,321 @@
+//! JSON endpoints for managing the sessions on the server.
+
+use std::sync::Arc;
+
+use axum::extract::{Extension, Path, Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, HOST};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use sshx_core::ws::{WsUser, WsWinsize};
+use sshx_core::{Sid, Uid};
+use tracing::{error, info};
+
+use crate::access::ClientIp;
+use crate::audit::AuditEvent;
+use crate::session::Session;
+use crate::usage::{self, millis};
+use crate::ServerState;
+
+/// Summary of an open session, as listed by the API.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    /// Name of the session.
+    pub name: String,
+    /// Namespace that owns the session.
+    pub namespace: Option<String>,
+    /// When the session was created, in milliseconds since the epoch.
+    pub created_ms: u64,
+    /// Number of open shells.
+    pub shells: usize,
+    /// Number of connected viewers.
+    pub viewers: usize,
+}
+
+impl SessionInfo {
+    fn new(name: String, session: &Session) -> Self {
+        Self {
+            name,
+            namespace: session.metadata().namespace.clone(),
+            created_ms: millis(session.usage().started()),
+            shells: session.list_shells().len(),
+            viewers: session.list_users().len(),
+        }
+    }
+}
+
+/// Details of an open session, as inspected through the API.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionDetail {
+    /// Name of the session.
+    pub name: String,
+    /// Namespace that owns the session.
+    pub namespace: Option<String>,
+    /// When the session was created, in milliseconds since the epoch.
+    pub created_ms: u64,
+    /// When the session will be closed, if it has a time limit.
+    pub deadline_ms: Option<u64>,
+    /// Open shells and their sizes, in order.
+    pub shells: Vec<(Sid, WsWinsize)>,
+    /// Connected viewers.
+    pub users: Vec<(Uid, WsUser)>,
+    /// Total bytes of terminal output relayed to viewers.
+    pub bytes_relayed: u64,
+}
+
+/// Read-only replay of a session's history, made through the API.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayInfo {
+    /// Name of the replay, which viewers connect to like a session.
+    pub name: String,
+    /// Link to the replay. Viewers need the encryption key of the session, so
+    /// append the `#` fragment from the session's own link.
+    pub url: String,
+    /// When the replay is deleted, in milliseconds since the epoch.
+    pub expires_ms: u64,
+}
+
+/// Which sessions the caller of an endpoint may see.
+enum Caller {
+    /// The admin, who may see every session.
+    Admin,
+    /// A client with the API key of a namespace.
+    Namespace(String),
+}
+
+impl Caller {
+    /// Authorize a request by the admin token, or a namespace's API key.
+    fn authorize(state: &ServerState, headers: &HeaderMap) -> Option<Self> {
+        let is_admin = (headers.get(AUTHORIZATION))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| state.is_admin_token(token.trim()));
+        if is_admin {
+            return Some(Caller::Admin);
+        }
+        let namespace = state.tenancy().authorize(headers)?;
+        Some(Caller::Namespace(namespace.name.clone()))
+    }
+
+    fn can_see(&self, session: &Session) -> bool {
+        match self {
+            Caller::Admin => true,
+            Caller::Namespace(name) => session.metadata().namespace.as_ref() == Some(name),
+        }
+    }
+}
+
+/// Query parameters for exporting usage.
+#[derive(Deserialize, Debug, Default)]
+pub struct ExportQuery {
+    /// Either `json` (the default) or `csv`.
+    format: Option<String>,
+}
+
+impl ExportQuery {
+    /// Returns whether to export CSV, or `None` for an unknown format.
+    fn csv(&self) -> Option<bool> {
+        match self.format.as_deref() {
+            None | Some("json") => Some(false),
+            Some("csv") => Some(true),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Export the usage of open and recently closed sessions in a namespace.
+pub async fn export_usage(
+    State(state): State<Arc<ServerState>>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(namespace) = state.tenancy().authorize(&headers) else {
+        return (StatusCode::UNAUTHORIZED, "invalid API key").into_response();
+    };
+    let sessions = state.usage_in(&namespace.name);
+    match query.csv() {
+        Some(true) => {
+            ([(CONTENT_TYPE, "text/csv")], usage::sessions_csv(&sessions)).into_response()
+        }
+        Some(false) => Json(sessions).into_response(),
+        None => (StatusCode::BAD_REQUEST, "unknown export format").into_response(),
+    }
+}
+
+/// Export the periodic usage rollups of a namespace.
+pub async fn export_rollups(
+    State(state): State<Arc<ServerState>>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(namespace) = state.tenancy().authorize(&headers) else {
+        return (StatusCode::UNAUTHORIZED, "invalid API key").into_response();
+    };
+    let rollups = state.usage().rollups(&namespace.name);
+    match query.csv() {
+        Some(true) => ([(CONTENT_TYPE, "text/csv")], usage::rollups_csv(&rollups)).into_response(),
+        Some(false) => Json(rollups).into_response(),
+        None => (StatusCode::BAD_REQUEST, "unknown export format").into_response(),
+    }
+}
+
+/// List every session for the admin, or those in the namespace of the
+/// caller's API key.
+pub async fn list_sessions(State(state): State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    let sessions = match Caller::authorize(&state, &headers) {
+        Some(Caller::Admin) => state.sessions(),
+        Some(Caller::Namespace(namespace)) => state.sessions_in(&namespace),
+        None => return (StatusCode::UNAUTHORIZED, "invalid API key").into_response(),
+    };
+    let sessions: Vec<_> = (sessions.into_iter())
+        .map(|(name, session)| SessionInfo::new(name, &session))
+        .collect();
+    Json(sessions).into_response()
+}
+
+/// Inspect a single session, if the caller may see it.
+pub async fn get_session(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(caller) = Caller::authorize(&state, &headers) else {
+        return (StatusCode::UNAUTHORIZED, "invalid API key").into_response();
+    };
+    let Some(session) = state
+        .lookup(&name)
+        .filter(|session| caller.can_see(session))
+    else {
+        return (StatusCode::NOT_FOUND, "session not found").into_response();
+    };
+    let metadata = session.metadata();
+    Json(SessionDetail {
+        name,
+        namespace: metadata.namespace.clone(),
+        created_ms: millis(session.usage().started()),
+        deadline_ms: metadata.deadline.map(millis),
+        shells: session.list_shells(),
+        users: session.list_users(),
+        bytes_relayed: session.usage().bytes_relayed(),
+    })
+    .into_response()
+}
+
+/// Copy the history of a session into a read-only replay, if the caller may
+/// see the session.
+///
+/// The replay stays the same while the live session continues, and viewers
+/// of it cannot type into or change any shells.
+pub async fn snapshot_session(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    client_ip: Option<Extension<ClientIp>>,
+) -> Response {
+    let Some(caller) = Caller::authorize(&state, &headers) else {
+        return (StatusCode::UNAUTHORIZED, "invalid API key").into_response();
+    };
+    let Some(session) = state
+        .lookup(&name)
+        .filter(|session| caller.can_see(session))
+    else {
+        return (StatusCode::NOT_FOUND, "session not found").into_response();
+    };
+    let (replay, expires) = match state.create_replay(&session) {
+        Ok(Some(replay)) => replay,
+        Ok(None) => {
+            return (StatusCode::SERVICE_UNAVAILABLE, "too many replays").into_response();
+        }
+        Err(err) => {
+            error!(?err, "failed to snapshot session {name}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let origin = state.override_origin().unwrap_or_else(|| {
+        let scheme = match state.tls() {
+            Some(_) => "https",
+            None => "http",
+        };
+        let host = headers.get(HOST).and_then(|v| v.to_str().ok());
+        format!("{scheme}://{}", host.unwrap_or("localhost"))
+    });
+    let ip = client_ip.map(|Extension(ClientIp(ip))| ip);
+    let event = AuditEvent::SessionReplayed {
+        session: name,
+        replay: replay.clone(),
+    };
+    state.audit().record(ip, event);
+    Json(ReplayInfo {
+        url: format!("{origin}/s/{replay}"),
+        name: replay,
+        expires_ms: millis(expires),
+    })
+    .into_response()
+}
+
+/// Forcibly close a session, which only the admin may do.
+pub async fn delete_session(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    client_ip: Option<Extension<ClientIp>>,
+) -> Response {
+    let Some(Caller::Admin) = Caller::authorize(&state, &headers) else {
+        return (StatusCode::UNAUTHORIZED, "invalid admin token").into_response();
+    };
+    if state.lookup(&name).is_none() {
+        return (StatusCode::NOT_FOUND, "session not found").into_response();
+    }
+    info!(%name, "terminating session from the admin API");
+    if let Err(err) = state.close_session(&name).await {
+        error!(?err, "failed to terminate session {name}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let ip = client_ip.map(|Extension(ClientIp(ip))| ip);
+    state
+        .audit()
+        .record(ip, AuditEvent::SessionTerminated { session: name });
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Download the encrypted asciicast recording of a shell.
+pub async fn download_recording(
+    State(state): State<Arc<ServerState>>,
+    Path((name, shell)): Path<(String, u32)>,
+) -> Response {
+    let Some(recorder) = state.recorder() else {
+        return (StatusCode::NOT_FOUND, "recording is not enabled").into_response();
+    };
+    match recorder.read(&name, Sid(shell)).await {
+        Ok(Some(cast)) => ([(CONTENT_TYPE, "application/x-asciicast")], cast).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "recording not found").into_response(),
+        Err(err) => {
+            error!(?err, "failed to read recording");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Download the encrypted asciicast recording of input typed into a shell,
+/// with the user who typed each event.
+pub async fn download_input_recording(
+    State(state): State<Arc<ServerState>>,
+    Path((name, shell)): Path<(String, u32)>,
+) -> Response {
+    let Some(recorder) = state.recorder() else {
+        return (StatusCode::NOT_FOUND, "recording is not enabled").into_response();
+    };
+    match recorder.read_input(&name, Sid(shell)).await {
+        Ok(Some(cast)) => ([(CONTENT_TYPE, "application/x-asciicast")], cast).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "recording not found").into_response(),
+        Err(err) => {
+            error!(?err, "failed to read input recording");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
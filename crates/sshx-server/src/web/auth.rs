@@ -0,0 +1,126 @@
+//! Optional authentication for connecting to sessions over the web.
+//!
+//! Without it, anyone who learns a session's name can connect to it, though
+//! they still need the encryption key to read or write anything. When enabled,
+//! connections must also present a bearer token configured on the server, or
+//! a token from a session link signed by the server.
+//!
+//! Separately, hosts can set a write password for their session. Viewers who
+//! do not enter it can only watch.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use argon2::Argon2;
+use axum::extract::{Path, Query, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use hyper::header::AUTHORIZATION;
+use hyper::{HeaderMap, StatusCode};
+use serde::Deserialize;
+
+use crate::tenant::constant_time_eq;
+use crate::ServerState;
+
+/// Credentials accepted for connecting to sessions.
+#[derive(Debug, Clone, Default)]
+pub struct WebAuth {
+    /// Static tokens accepted in an `Authorization: Bearer` header.
+    pub bearer_tokens: Vec<String>,
+
+    /// Accept links signed by the server, which carry a `token` parameter.
+    /// The server adds it to the links of new sessions.
+    pub signed_urls: bool,
+}
+
+impl WebAuth {
+    /// Returns whether connections need to be authenticated.
+    pub fn is_enabled(&self) -> bool {
+        !self.bearer_tokens.is_empty() || self.signed_urls
+    }
+
+    /// Returns whether a header carries one of the accepted bearer tokens.
+    fn has_bearer_token(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = (headers.get(AUTHORIZATION))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        // Check every token, so that timing does not reveal which matched.
+        (self.bearer_tokens.iter()).fold(false, |found, accepted| {
+            found | constant_time_eq(accepted.as_bytes(), token.trim().as_bytes())
+        })
+    }
+}
+
+/// Length of the random salt stored with a write password's hash.
+const SALT_LEN: usize = 16;
+
+/// Hash the write password of a session with Argon2 and a random salt.
+///
+/// This is slow on purpose, so call it from a blocking task.
+pub fn hash_write_password(password: &str) -> Bytes {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let mut hash = salt.to_vec();
+    hash.extend(argon2_hash(password, &salt));
+    hash.into()
+}
+
+/// Check a password against a hash from [`hash_write_password`].
+///
+/// This is slow on purpose, so call it from a blocking task.
+pub fn verify_write_password(hash: &[u8], password: &str) -> bool {
+    if hash.len() <= SALT_LEN {
+        return false;
+    }
+    let (salt, expected) = hash.split_at(SALT_LEN);
+    constant_time_eq(&argon2_hash(password, salt), expected)
+}
+
+fn argon2_hash(password: &str, salt: &[u8]) -> [u8; 32] {
+    let mut output = [0; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut output)
+        .expect("argon2 parameters are valid");
+    output
+}
+
+/// Query parameters of a signed session link.
+#[derive(Deserialize, Debug, Default)]
+pub struct AuthQuery {
+    token: Option<String>,
+}
+
+/// Reject requests for a session without a valid token, if required.
+pub async fn require_auth<B>(
+    State(state): State<Arc<ServerState>>,
+    Path(params): Path<HashMap<String, String>>,
+    Query(query): Query<AuthQuery>,
+    headers: HeaderMap,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let auth = state.web_auth();
+    if !auth.is_enabled() || auth.has_bearer_token(&headers) {
+        return next.run(request).await;
+    }
+    let signed = match (params.get("name"), &query.token) {
+        (Some(name), Some(token)) if auth.signed_urls => {
+            // Sessions on other servers check the token again when proxied.
+            let session = state.lookup(name);
+            let nonce = session
+                .as_ref()
+                .map(|session| &session.metadata().nonce[..]);
+            state.verify_view_token(name, nonce, token)
+        }
+        _ => false,
+    };
+    if signed {
+        next.run(request).await
+    } else {
+        (StatusCode::UNAUTHORIZED, "missing or invalid token").into_response()
+    }
+}
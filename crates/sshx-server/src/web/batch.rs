@@ -0,0 +1,131 @@
+//! Adaptive batching of terminal output sent to each viewer.
+//!
+//! Viewers with a short round-trip time get output as soon as it arrives.
+//! For viewers on slow links, output is held back for a fraction of their
+//! round-trip time and coalesced into fewer, larger messages, which saves
+//! framing overhead while adding little to the latency they already see.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use sshx_core::Sid;
+use tokio::time::Instant;
+
+/// Interval between WebSocket pings used to measure the round-trip time.
+pub const RTT_PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Round-trip time below which output is never held back.
+const FAST_RTT: Duration = Duration::from_millis(20);
+
+/// Maximum time that output is held back for batching.
+const MAX_DELAY: Duration = Duration::from_millis(100);
+
+/// Pending output is sent right away once it reaches this many bytes.
+const MAX_BATCH_BYTES: usize = 64 << 10; // 64 KiB
+
+/// Output for one shell that has not been sent yet, as in `WsServer::Chunks`.
+type PendingChunks = (Sid, u64, Vec<Bytes>);
+
+/// Collects output for a single WebSocket connection, deciding when to send
+/// it based on the measured round-trip time.
+#[derive(Debug)]
+pub struct OutputBatcher {
+    rtt: Option<Duration>,
+    pending: Vec<PendingChunks>,
+    pending_bytes: usize,
+    pending_since: Option<Instant>,
+    ping: Option<(u64, Instant)>,
+    ping_count: u64,
+}
+
+impl Default for OutputBatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutputBatcher {
+    /// Create a new batcher, which sends output immediately until the
+    /// round-trip time is known.
+    pub fn new() -> Self {
+        Self {
+            rtt: None,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            pending_since: None,
+            ping: None,
+            ping_count: 0,
+        }
+    }
+
+    /// Returns the smoothed round-trip time, if it has been measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Record a round-trip time measurement.
+    pub fn record_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    /// Returns how long output is currently held back before sending.
+    pub fn delay(&self) -> Duration {
+        match self.rtt {
+            Some(rtt) if rtt >= FAST_RTT => (rtt / 4).min(MAX_DELAY),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Add output from a shell to the pending batch.
+    pub fn push(&mut self, id: Sid, seqnum: u64, chunks: Vec<Bytes>) {
+        let bytes: usize = chunks.iter().map(|c| c.len()).sum();
+        self.pending_bytes += bytes;
+        self.pending_since.get_or_insert_with(Instant::now);
+
+        // Merge with earlier output from the same shell if it is contiguous.
+        if let Some((_, start, existing)) = self.pending.iter_mut().rev().find(|p| p.0 == id) {
+            let end = *start + existing.iter().map(|c| c.len() as u64).sum::<u64>();
+            if end == seqnum {
+                existing.extend(chunks);
+                return;
+            }
+        }
+        self.pending.push((id, seqnum, chunks));
+    }
+
+    /// Returns when the pending output should be sent, if there is any.
+    pub fn deadline(&self) -> Option<Instant> {
+        let since = self.pending_since?;
+        if self.pending_bytes >= MAX_BATCH_BYTES {
+            return Some(since);
+        }
+        Some(since + self.delay())
+    }
+
+    /// Take all pending output, to be sent now.
+    pub fn take(&mut self) -> Vec<PendingChunks> {
+        self.pending_bytes = 0;
+        self.pending_since = None;
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Start a round-trip time measurement, returning the ping payload.
+    pub fn start_ping(&mut self) -> Vec<u8> {
+        self.ping_count += 1;
+        self.ping = Some((self.ping_count, Instant::now()));
+        self.ping_count.to_be_bytes().to_vec()
+    }
+
+    /// Finish a round-trip time measurement when a pong is received.
+    pub fn record_pong(&mut self, payload: &[u8]) {
+        if let Some((count, sent)) = self.ping {
+            if payload == count.to_be_bytes() {
+                self.ping = None;
+                self.record_rtt(sent.elapsed());
+            }
+        }
+    }
+}
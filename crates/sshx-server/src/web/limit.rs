@@ -0,0 +1,242 @@
+//! Rate limiting of viewer input, session lookups, and requests per address.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Result of checking a viewer's input against the rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitResult {
+    /// The input is within the limit and can be forwarded.
+    Allowed,
+    /// The viewer just exceeded the limit, and is now muted for a duration.
+    Muted(Duration),
+    /// The viewer is currently muted, so the input should be dropped.
+    Dropped,
+}
+
+/// Token bucket limiting the bytes of input sent by one WebSocket connection,
+/// with a one-second burst allowance.
+#[derive(Debug)]
+pub struct InputLimiter {
+    rate: u32,
+    mute: Duration,
+    tokens: f64,
+    updated: Instant,
+    muted_until: Option<Instant>,
+}
+
+impl InputLimiter {
+    /// Create a new limiter, allowing a number of bytes per second.
+    pub fn new(rate: u32, mute: Duration) -> Self {
+        Self {
+            rate,
+            mute,
+            tokens: rate as f64,
+            updated: Instant::now(),
+            muted_until: None,
+        }
+    }
+
+    /// Record input of a given size, returning whether it is allowed.
+    pub fn check(&mut self, bytes: usize) -> LimitResult {
+        let now = Instant::now();
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return LimitResult::Dropped;
+            }
+            self.muted_until = None;
+            self.tokens = self.rate as f64;
+            self.updated = now;
+        }
+
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = now;
+        if bytes as f64 > self.tokens {
+            self.muted_until = Some(now + self.mute);
+            return LimitResult::Muted(self.mute);
+        }
+        self.tokens -= bytes as f64;
+        LimitResult::Allowed
+    }
+
+    /// Returns whether input is currently being dropped.
+    pub fn is_muted(&self) -> bool {
+        self.muted_until.is_some_and(|until| Instant::now() < until)
+    }
+}
+
+/// Token buckets limiting how often each address can make some request, like
+/// opening a session, with a burst allowance of one minute's worth.
+#[derive(Debug)]
+pub struct AddressLimiter {
+    per_minute: u32,
+    buckets: DashMap<IpAddr, (f64, Instant)>,
+}
+
+impl AddressLimiter {
+    /// Create a new limiter, allowing a number of requests per minute.
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Record a request from an address, returning whether it is allowed.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let capacity = self.per_minute as f64;
+        let mut bucket = self.buckets.entry(ip).or_insert((capacity, now));
+        let (tokens, updated) = &mut *bucket;
+        let elapsed = now.duration_since(*updated).as_secs_f64();
+        *tokens = (*tokens + elapsed * capacity / 60.0).min(capacity);
+        *updated = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+
+    /// Forget about addresses whose buckets have refilled.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let capacity = self.per_minute as f64;
+        self.buckets.retain(|_, (tokens, updated)| {
+            *tokens + now.duration_since(*updated).as_secs_f64() * capacity / 60.0 < capacity
+        });
+    }
+}
+
+/// Missed session lookups allowed from one address before slowing it down.
+const PROBE_FREE_MISSES: u32 = 10;
+
+/// Missed session lookups from one address before it is temporarily banned.
+const PROBE_BAN_MISSES: u32 = 50;
+
+/// How long an address is banned after probing too many session names.
+const PROBE_BAN_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Forget about an address after it has not missed a lookup for this long.
+const PROBE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum delay added to lookups from an address that is slowed down.
+const PROBE_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Result of checking an address before looking up a session by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeCheck {
+    /// Continue with the lookup after waiting for some time, possibly zero.
+    Delay(Duration),
+    /// The address is temporarily banned from looking up sessions.
+    Banned,
+}
+
+#[derive(Debug)]
+struct ProbeEntry {
+    misses: u32,
+    last_miss: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Protection against guessing session names, by tracking addresses that
+/// look up many sessions that do not exist.
+///
+/// After a number of misses, each lookup from the address is delayed
+/// progressively longer, and after more misses the address is banned.
+#[derive(Debug)]
+pub struct ProbeGuard {
+    free_misses: u32,
+    ban_misses: u32,
+    entries: DashMap<IpAddr, ProbeEntry>,
+    total_misses: AtomicU64,
+    total_rejected: AtomicU64,
+}
+
+impl Default for ProbeGuard {
+    fn default() -> Self {
+        Self::with_limits(PROBE_FREE_MISSES, PROBE_BAN_MISSES)
+    }
+}
+
+impl ProbeGuard {
+    /// Create a guard with custom thresholds for slowing down and banning.
+    pub fn with_limits(free_misses: u32, ban_misses: u32) -> Self {
+        Self {
+            free_misses,
+            ban_misses,
+            entries: DashMap::new(),
+            total_misses: AtomicU64::new(0),
+            total_rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Check an address before it looks up a session.
+    pub fn check(&self, ip: IpAddr) -> ProbeCheck {
+        let Some(entry) = self.entries.get(&ip) else {
+            return ProbeCheck::Delay(Duration::ZERO);
+        };
+        let now = Instant::now();
+        if entry.banned_until.is_some_and(|until| now < until) {
+            self.total_rejected.fetch_add(1, Ordering::Relaxed);
+            return ProbeCheck::Banned;
+        }
+        let excess = entry.misses.saturating_sub(self.free_misses);
+        if excess == 0 {
+            return ProbeCheck::Delay(Duration::ZERO);
+        }
+        let delay = Duration::from_millis(100).saturating_mul(1 << (excess - 1).min(16));
+        ProbeCheck::Delay(delay.min(PROBE_MAX_DELAY))
+    }
+
+    /// Record that an address looked up a session that does not exist.
+    ///
+    /// Returns `true` if the address was banned as a result.
+    pub fn record_miss(&self, ip: IpAddr) -> bool {
+        self.total_misses.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut entry = self.entries.entry(ip).or_insert(ProbeEntry {
+            misses: 0,
+            last_miss: now,
+            banned_until: None,
+        });
+        let ban_expired = entry.banned_until.is_some_and(|until| now >= until);
+        if ban_expired || now.duration_since(entry.last_miss) > PROBE_WINDOW {
+            entry.misses = 0;
+            entry.banned_until = None;
+        }
+        entry.misses += 1;
+        entry.last_miss = now;
+        if entry.misses >= self.ban_misses && entry.banned_until.is_none() {
+            warn!(%ip, misses = entry.misses, "banning address for probing sessions");
+            entry.banned_until = Some(now + PROBE_BAN_DURATION);
+            return true;
+        }
+        false
+    }
+
+    /// Forget about addresses that have not missed a lookup recently.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.entries.retain(|_, entry| match entry.banned_until {
+            Some(until) => now < until,
+            None => now.duration_since(entry.last_miss) <= PROBE_WINDOW,
+        });
+    }
+
+    /// Returns the total number of missed lookups.
+    pub fn total_misses(&self) -> u64 {
+        self.total_misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of lookups rejected from banned addresses.
+    pub fn total_rejected(&self) -> u64 {
+        self.total_rejected.load(Ordering::Relaxed)
+    }
+}
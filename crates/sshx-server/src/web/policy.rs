@@ -0,0 +1,77 @@
+//! Cross-origin policies, for embedding the web interface in other sites.
+//!
+//! By default, only the server's own pages may frame the interface, and
+//! browsers on other sites cannot read responses from the web API. Both can be
+//! opened up to an explicit list of origins, like an internal dashboard.
+
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::{HeaderValue, Method, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hyper::header::{
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_SECURITY_POLICY, ORIGIN, VARY,
+};
+use hyper::StatusCode;
+
+use crate::ServerState;
+
+/// Add CORS headers to web API responses for allowed origins, and answer
+/// their preflight requests.
+pub async fn cors<B>(
+    State(state): State<Arc<ServerState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let origin = (request.headers().get(ORIGIN))
+        .filter(|origin| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| state.cors_allowed(origin))
+        })
+        .cloned();
+    let Some(origin) = origin else {
+        return next.run(request).await;
+    };
+
+    let preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    let mut response = if preflight {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        next.run(request).await
+    };
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.append(VARY, HeaderValue::from_static("origin"));
+    if preflight {
+        let methods = HeaderValue::from_static("GET, DELETE");
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        let allowed = HeaderValue::from_static("authorization");
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+    }
+    response
+}
+
+/// Tell browsers which origins may embed the web interface in a frame.
+pub async fn frame_ancestors<B>(
+    State(state): State<Arc<ServerState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut response = next.run(request).await;
+    let mut policy = String::from("frame-ancestors 'self'");
+    for origin in state.frame_ancestors() {
+        policy.push(' ');
+        policy.push_str(origin);
+    }
+    if let Ok(value) = HeaderValue::from_str(&policy) {
+        (response.headers_mut()).insert(CONTENT_SECURITY_POLICY, value);
+    }
+    response
+}
@@ -1,96 +1,71 @@
 //! Serializable types sent and received by the web server.
+//!
+//! The messages are defined in [`sshx_core::ws`], so that clients can share
+//! them, and are re-exported here. Compression is only done by the server.
 
-use bytes::Bytes;
-use serde::{Deserialize, Serialize};
-use sshx_core::{Sid, Uid};
+use anyhow::{bail, Result};
+pub use sshx_core::ws::{WsClient, WsNewShell, WsServer, WsSyncState, WsUser, WsWinsize};
 
-/// Real-time message conveying the position and size of a terminal.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
-#[serde(rename_all = "camelCase")]
-pub struct WsWinsize {
-    /// The top-left x-coordinate of the window, offset from origin.
-    pub x: i32,
-    /// The top-left y-coordinate of the window, offset from origin.
-    pub y: i32,
-    /// The number of rows in the window.
-    pub rows: u16,
-    /// The number of columns in the terminal.
-    pub cols: u16,
+/// Chunks of output smaller than this are not worth compressing.
+const COMPRESS_MIN_BYTES: usize = 1024;
+
+/// Compression of large messages, which clients can ask for when connecting
+/// with a `compression` query parameter.
+///
+/// Browsers do not support it, but other clients can save bandwidth when
+/// catching up on a lot of output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsCompression {
+    /// Compress with Zstandard, from the `zstd` parameter.
+    Zstd,
 }
 
-impl Default for WsWinsize {
-    fn default() -> Self {
-        WsWinsize {
-            x: 0,
-            y: 0,
-            rows: 24,
-            cols: 80,
+impl WsCompression {
+    /// Parse a query parameter, ignoring unsupported algorithms.
+    pub fn from_query(value: &str) -> Option<Self> {
+        match value {
+            "zstd" => Some(Self::Zstd),
+            _ => None,
         }
     }
 }
 
-/// Real-time message providing information about a user.
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-#[serde(rename_all = "camelCase")]
-pub struct WsUser {
-    /// The user's display name.
-    pub name: String,
-    /// Live coordinates of the mouse cursor, if available.
-    pub cursor: Option<(i32, i32)>,
-    /// Currently focused terminal window ID.
-    pub focus: Option<Sid>,
-}
+/// Compression of messages from the server, for clients that ask for it.
+pub trait WsServerExt: Sized {
+    /// Compress a message if compression is enabled and it has enough output
+    /// to be worth it.
+    fn compress(self, compression: Option<WsCompression>) -> Result<Self>;
 
-/// A real-time message sent from the server over WebSocket.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-pub enum WsServer {
-    /// Initial server message, with the user's ID and session metadata.
-    Hello(Uid),
-    /// The user's authentication was invalid.
-    InvalidAuth(),
-    /// A snapshot of all current users in the session.
-    Users(Vec<(Uid, WsUser)>),
-    /// Info about a single user in the session: joined, left, or changed.
-    UserDiff(Uid, Option<WsUser>),
-    /// Notification when the set of open shells has changed.
-    Shells(Vec<(Sid, WsWinsize)>),
-    /// Subscription results, in the form of terminal data chunks.
-    Chunks(Sid, u64, Vec<Bytes>),
-    /// Get a chat message tuple `(uid, name, text)` from the room.
-    Hear(Uid, String, String),
-    /// Forward a latency measurement between the server and backend shell.
-    ShellLatency(u64),
-    /// Echo back a timestamp, for the the client's own latency measurement.
-    Pong(u64),
-    /// Alert the client of an application error.
-    Error(String),
+    /// Decompress a message from [`WsServerExt::compress`], for clients that
+    /// asked for compression. Other messages are returned unchanged.
+    fn decompress(self) -> Result<Self>;
 }
 
-/// A real-time message sent from the client over WebSocket.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-pub enum WsClient {
-    /// Authenticate the user's encryption key by zeros block.
-    Authenticate(Bytes),
-    /// Set the name of the current user.
-    SetName(String),
-    /// Send real-time information about the user's cursor.
-    SetCursor(Option<(i32, i32)>),
-    /// Set the currently focused shell.
-    SetFocus(Option<Sid>),
-    /// Create a new shell.
-    Create(i32, i32),
-    /// Close a specific shell.
-    Close(Sid),
-    /// Move a shell window to a new position and focus it.
-    Move(Sid, Option<WsWinsize>),
-    /// Add user data to a given shell.
-    Data(Sid, Bytes, u64),
-    /// Subscribe to a shell, starting at a given chunk index.
-    Subscribe(Sid, u64),
-    /// Send a a chat message to the room.
-    Chat(String),
-    /// Send a ping to the server, for latency measurement.
-    Ping(u64),
+impl WsServerExt for WsServer {
+    fn compress(self, compression: Option<WsCompression>) -> Result<Self> {
+        let large = match &self {
+            WsServer::Chunks(_, _, chunks) | WsServer::Lines(_, _, _, chunks) => {
+                chunks.iter().map(|chunk| chunk.len()).sum::<usize>() >= COMPRESS_MIN_BYTES
+            }
+            _ => false,
+        };
+        match compression {
+            Some(WsCompression::Zstd) if large => {
+                let buf = sshx_core::ws::encode(&self)?;
+                Ok(WsServer::Compressed(zstd::bulk::compress(&buf, 3)?.into()))
+            }
+            _ => Ok(self),
+        }
+    }
+
+    fn decompress(self) -> Result<Self> {
+        let WsServer::Compressed(data) = self else {
+            return Ok(self);
+        };
+        let buf = zstd::stream::decode_all(&*data)?;
+        match sshx_core::ws::decode(&buf)? {
+            WsServer::Compressed(_) => bail!("nested compressed message"),
+            msg => Ok(msg),
+        }
+    }
 }
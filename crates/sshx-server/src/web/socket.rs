@@ -1,146 +1,724 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 use axum::extract::{
     ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
-    Path, State,
+    Path, Query, RawQuery, State,
 };
-use axum::response::IntoResponse;
+use axum::http::header::{AUTHORIZATION, HOST, ORIGIN};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
 use bytes::Bytes;
 use futures_util::SinkExt;
-use sshx_core::proto::{server_update::ServerMessage, NewShell, TerminalInput, TerminalSize};
-use sshx_core::Sid;
-use tokio::sync::mpsc;
+use hyper::StatusCode;
+use serde::Deserialize;
+use sshx_core::feature::{
+    self, COMPRESSION, DETAILED_ERRORS, FLOW_CONTROL, PRESENCE, VIEWER_FEATURES,
+};
+use sshx_core::proto::{
+    server_update::ServerMessage, AccessKind, FileDownload, FileUpload, ForkRequest, NewShell,
+    TerminalInput, TerminalSize,
+};
+use sshx_core::ws::{self, WsClient, WsNewShell, WsServer, WsSyncState};
+use sshx_core::{totp, ErrorCode, Sid, Uid};
+use tokio::sync::{mpsc, watch};
+use tokio::task;
+use tokio::time::{self, Instant};
 use tokio_stream::StreamExt;
 use tracing::{error, info_span, warn, Instrument};
 
+use crate::access::ClientIp;
+use crate::audit::AuditEvent;
 use crate::session::Session;
-use crate::web::protocol::{WsClient, WsServer};
+use crate::web::auth::verify_write_password;
+use crate::web::batch::{OutputBatcher, RTT_PING_INTERVAL};
+use crate::web::limit::{InputLimiter, LimitResult, ProbeCheck};
+use crate::web::protocol::{WsCompression, WsServerExt};
 use crate::ServerState;
 
+/// Number of wrong TOTP codes a viewer may enter before being disconnected.
+const MAX_TOTP_ATTEMPTS: u32 = 3;
+
+/// Number of wrong write passwords a viewer may enter on one connection.
+const MAX_WRITE_PASSWORD_ATTEMPTS: u32 = 5;
+
+/// Bytes of a shell's output that can be sent to a viewer who acknowledges
+/// output, before waiting for an acknowledgement.
+const VIEWER_ACK_WINDOW: u64 = 1 << 20; // 1 MiB
+
+/// Longest working directory or command that a viewer can start a shell with.
+const MAX_SHELL_OPTION_LEN: usize = 4096;
+
+/// Longest name or path of a file that a viewer can transfer.
+const MAX_FILE_PATH_LEN: usize = 4096;
+
+/// Largest chunk of a file that a viewer can upload in one message.
+const MAX_FILE_CHUNK_BYTES: usize = 1 << 20; // 1 MiB
+
+/// How long a viewer waits for the host to admit them, before being turned
+/// away.
+const KNOCK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Query parameters of a session's WebSocket URL.
+#[derive(Deserialize, Debug, Default)]
+pub struct WsQuery {
+    /// Token from a writable share link, which permits changing shells.
+    write: Option<String>,
+
+    /// Compression that the client supports for large messages.
+    compression: Option<String>,
+
+    /// Whether the client acknowledges output, so the server sends no more
+    /// than a bounded window of output ahead of it.
+    #[serde(default)]
+    ack: bool,
+
+    /// Optional protocol features that the client supports, separated by
+    /// commas. Clients that leave this out get every feature that predates
+    /// negotiation, while others get only those listed.
+    features: Option<String>,
+}
+
 pub async fn get_session_ws(
     Path(name): Path<String>,
+    Query(query): Query<WsQuery>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+    client_ip: Option<Extension<ClientIp>>,
     ws: WebSocketUpgrade,
     State(state): State<Arc<ServerState>>,
-) -> impl IntoResponse {
+) -> Response {
+    let ip = client_ip.map(|Extension(ClientIp(ip))| ip);
+    if let Some(ip) = ip.filter(|&ip| !state.access().allows_viewer(ip)) {
+        warn!(%ip, "rejected viewer from disallowed address");
+        state.audit().record(Some(ip), AuditEvent::AddressDenied);
+        return (
+            StatusCode::FORBIDDEN,
+            "address is not allowed to view sessions",
+        )
+            .into_response();
+    }
+    if !state.allow_connect(ip) {
+        return (StatusCode::TOO_MANY_REQUESTS, "too many connections").into_response();
+    }
+    if let Some(ip) = ip {
+        match state.probes().check(ip) {
+            ProbeCheck::Banned => {
+                return (StatusCode::TOO_MANY_REQUESTS, "too many failed lookups").into_response();
+            }
+            ProbeCheck::Delay(delay) => time::sleep(delay).await,
+        }
+    }
+
+    // Browsers always send an origin, so reject cross-site requests from them.
+    let origin = headers.get(ORIGIN).and_then(|v| v.to_str().ok());
+    let host = headers.get(HOST).and_then(|v| v.to_str().ok());
+    let origin_allowed = origin.is_none_or(|origin| state.origin_allowed(origin, host));
+    if !origin_allowed {
+        warn!(?origin, "rejecting websocket from disallowed origin");
+    }
+
     ws.on_upgrade(move |mut socket| {
         let span = info_span!("ws", %name);
         async move {
+            if !origin_allowed {
+                let reason = "origin is not allowed to connect";
+                socket
+                    .send(close_with(ErrorCode::PermissionDenied, reason))
+                    .await
+                    .ok();
+                return;
+            }
             match state.frontend_connect(&name).await {
                 Ok(Ok(session)) => {
-                    if let Err(err) = handle_socket(&mut socket, session).await {
+                    // Without write protection, anyone with the link can write.
+                    let metadata = session.metadata();
+                    let protected =
+                        metadata.write_protected || !metadata.write_password_hash.is_empty();
+                    let writable = !protected
+                        || (query.write.as_deref()).is_some_and(|token| {
+                            state.verify_write_token(&name, &metadata.nonce, token)
+                        });
+                    let result =
+                        handle_socket(&mut socket, &state, session, &name, ip, writable, &query)
+                            .await;
+                    if let Err(err) = result {
                         warn!(?err, "websocket exiting early");
                     } else {
                         socket.close().await.ok();
                     }
                 }
                 Ok(Err(Some(host))) => {
-                    if let Err(err) = proxy_redirect(&mut socket, &host, &name).await {
+                    // Forward credentials, since the other server may check them,
+                    // and the client's address, since bans are keyed by it.
+                    let mut path = format!("/api/s/{name}");
+                    if let Some(raw_query) = &raw_query {
+                        path = format!("{path}?{raw_query}");
+                    }
+                    let mut forwarded = ip.map(|ip| state.proxy_headers(ip)).unwrap_or_default();
+                    if let Some(auth) = headers.get(AUTHORIZATION) {
+                        forwarded.insert(AUTHORIZATION, auth.clone());
+                    }
+                    if let Err(err) = proxy_redirect(&mut socket, &host, &path, forwarded).await {
                         error!(?err, "failed to proxy websocket");
-                        let frame = CloseFrame {
-                            code: 4500,
-                            reason: format!("proxy redirect: {err}").into(),
-                        };
-                        socket.send(Message::Close(Some(frame))).await.ok();
+                        let reason = format!("proxy redirect: {err}");
+                        socket
+                            .send(close_with(ErrorCode::Internal, reason))
+                            .await
+                            .ok();
                     } else {
                         socket.close().await.ok();
                     }
                 }
                 Ok(Err(None)) => {
-                    let frame = CloseFrame {
-                        code: 4404,
-                        reason: "could not find the requested session".into(),
-                    };
-                    socket.send(Message::Close(Some(frame))).await.ok();
+                    record_miss(&state, ip);
+                    let reason = "could not find the requested session";
+                    socket
+                        .send(close_with(ErrorCode::NotFound, reason))
+                        .await
+                        .ok();
                 }
                 Err(err) => {
                     error!(?err, "failed to connect to frontend session");
-                    let frame = CloseFrame {
-                        code: 4500,
-                        reason: format!("session connect: {err}").into(),
-                    };
-                    socket.send(Message::Close(Some(frame))).await.ok();
+                    let reason = format!("session connect: {err}");
+                    socket
+                        .send(close_with(ErrorCode::Internal, reason))
+                        .await
+                        .ok();
                 }
             }
         }
         .instrument(span)
     })
+    .into_response()
 }
 
-/// Handle an incoming live WebSocket connection to a given session.
-async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<()> {
+/// Minimum interval between watermarks sent alongside a viewer's output.
+const WATERMARK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Text identifying a viewer, overlaid faintly on their view of the session.
+///
+/// The time is included so that a leaked screenshot can also be matched
+/// against the access log.
+fn watermark_text(session: &str, user_id: Uid, name: &str) -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (hours, minutes) = (secs / 3600 % 24, secs / 60 % 60);
+    format!("{name} #{user_id} {session} {hours:02}:{minutes:02} UTC")
+}
+
+/// Sleep until a deadline, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Forward chunks from a shell to the socket, starting at a byte offset.
+///
+/// If the viewer acknowledges output, no more chunks are read from the shell
+/// while a full window of sent output is unacknowledged.
+fn spawn_subscription(
+    session: &Arc<Session>,
+    id: Sid,
+    offset: u64,
+    chunks_tx: mpsc::Sender<(Sid, u64, Vec<Bytes>)>,
+    mut acked: Option<watch::Receiver<u64>>,
+) {
+    let session = Arc::clone(session);
+    tokio::spawn(async move {
+        let stream = session.subscribe_chunks(id, offset);
+        tokio::pin!(stream);
+        let mut sent = 0;
+        loop {
+            if let Some(acked) = &mut acked {
+                let caught_up =
+                    acked.wait_for(|&acked| sent < acked.saturating_add(VIEWER_ACK_WINDOW));
+                tokio::select! {
+                    result = caught_up => if result.is_err() { break },
+                    _ = session.terminated() => break,
+                }
+            }
+            let Some((seqnum, chunks)) = stream.next().await else {
+                break;
+            };
+            sent = seqnum + chunks.iter().map(|chunk| chunk.len() as u64).sum::<u64>();
+            if chunks_tx.send((id, seqnum, chunks)).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Returns a receiver for a viewer's acknowledgements of a shell's output,
+/// if the viewer sends them.
+fn ack_receiver(
+    acks: &mut HashMap<Sid, watch::Sender<u64>>,
+    id: Sid,
+    enabled: bool,
+) -> Option<watch::Receiver<u64>> {
+    enabled.then(|| {
+        acks.entry(id)
+            .or_insert_with(|| watch::channel(0).0)
+            .subscribe()
+    })
+}
+
+/// Build a close message for the WebSocket, carrying an error code.
+fn close_with(code: ErrorCode, reason: impl Into<String>) -> Message {
+    let frame = CloseFrame {
+        code: code.close_code(),
+        reason: reason.into().into(),
+    };
+    Message::Close(Some(frame))
+}
+
+/// Records in the audit log when a viewer leaves, however the connection ends.
+struct LeaveAudit<'a> {
+    state: &'a ServerState,
+    session: &'a str,
+    user: Uid,
+    ip: Option<IpAddr>,
+    input_bytes: u64,
+}
+
+impl Drop for LeaveAudit<'_> {
+    fn drop(&mut self) {
+        let event = AuditEvent::ViewerLeft {
+            session: self.session.to_owned(),
+            user: self.user,
+            input_bytes: self.input_bytes,
+        };
+        self.state.audit().record(self.ip, event);
+    }
+}
+
+/// Count a failed guess from an address, banning it after too many.
+fn record_miss(state: &ServerState, ip: Option<IpAddr>) {
+    if let Some(ip) = ip {
+        if state.probes().record_miss(ip) {
+            state.audit().record(Some(ip), AuditEvent::AddressBanned);
+        }
+    }
+}
+
+/// Protocol features negotiated with a viewer, and the behavior they select.
+struct ViewerFeatures {
+    /// Features the viewer asked for that the server supports, if it asked.
+    negotiated: Option<Vec<String>>,
+    compression: Option<WsCompression>,
+    flow_control: bool,
+    presence: bool,
+    detailed_errors: bool,
+}
+
+impl ViewerFeatures {
+    fn negotiate(query: &WsQuery) -> Self {
+        let negotiated = (query.features.as_deref()).map(|list| {
+            let requested: Vec<_> = list.split(',').map(str::trim).collect();
+            feature::negotiate(VIEWER_FEATURES, &requested)
+        });
+        let has = |name: &str| {
+            negotiated
+                .as_ref()
+                .is_some_and(|f| f.iter().any(|f| f == name))
+        };
+        let compression = (query.compression.as_deref())
+            .and_then(WsCompression::from_query)
+            .or_else(|| has(COMPRESSION).then_some(WsCompression::Zstd));
+        let flow_control = query.ack || has(FLOW_CONTROL);
+        let presence = negotiated.is_none() || has(PRESENCE);
+        let detailed_errors = has(DETAILED_ERRORS);
+        Self {
+            negotiated,
+            compression,
+            flow_control,
+            presence,
+            detailed_errors,
+        }
+    }
+
+    /// Older viewers cannot decode newer error codes, so they get generic ones.
+    fn error_code(&self, code: ErrorCode) -> ErrorCode {
+        if self.detailed_errors {
+            code
+        } else {
+            code.fallback()
+        }
+    }
+}
+
+/// A viewer's WebSocket, with the output batcher that also tracks its pings.
+struct ViewerSocket<'a> {
+    socket: &'a mut WebSocket,
+    batcher: OutputBatcher,
+    /// When any frame last arrived from the viewer.
+    last_received: Instant,
+}
+
+impl ViewerSocket<'_> {
     /// Send a message to the client over WebSocket.
-    async fn send(socket: &mut WebSocket, msg: WsServer) -> Result<()> {
-        let mut buf = Vec::new();
-        ciborium::ser::into_writer(&msg, &mut buf)?;
-        socket.send(Message::Binary(buf)).await?;
+    async fn send(&mut self, msg: WsServer) -> Result<()> {
+        self.socket.send(Message::Binary(ws::encode(&msg)?)).await?;
         Ok(())
     }
 
-    /// Receive a message from the client over WebSocket.
-    async fn recv(socket: &mut WebSocket) -> Result<Option<WsClient>> {
+    /// Close the connection, telling the client why.
+    async fn close(&mut self, code: ErrorCode, reason: impl Into<String>) -> Result<()> {
+        self.socket.send(close_with(code, reason)).await?;
+        Ok(())
+    }
+
+    /// Receive a message from the client over WebSocket, noting when any
+    /// frame last arrived.
+    async fn recv(&mut self) -> Result<Option<WsClient>> {
         Ok(loop {
-            match socket.recv().await.transpose()? {
+            let msg = self.socket.recv().await.transpose()?;
+            self.last_received = Instant::now();
+            match msg {
                 Some(Message::Text(_)) => warn!("ignoring text message over WebSocket"),
-                Some(Message::Binary(msg)) => break Some(ciborium::de::from_reader(&*msg)?),
-                Some(_) => (), // ignore other message types, keep looping
-                None => break None,
+                Some(Message::Binary(msg)) => break Some(ws::decode(&msg)?),
+                Some(Message::Pong(payload)) => self.batcher.record_pong(&payload),
+                Some(Message::Ping(_)) => (), // answered automatically by the WebSocket
+                Some(Message::Close(_)) | None => break None,
             }
         })
     }
+}
 
-    let user_id = session.counter().next_uid();
-    session.sync_now();
-    send(socket, WsServer::Hello(user_id)).await?;
+/// Decide whether a viewer may join, turning them away if they are banned,
+/// fail to authenticate, find the session full, or are not let in by the host.
+async fn handshake(
+    ws: &mut ViewerSocket<'_>,
+    state: &ServerState,
+    session: &Session,
+    name: &str,
+    ip: Option<IpAddr>,
+    user_id: Uid,
+    features: &ViewerFeatures,
+) -> Result<bool> {
+    if session.is_banned(ip) {
+        session.record_access(AccessKind::AccessBlocked, user_id, ip);
+        ws.send(WsServer::Removed(true)).await?;
+        let reason = "you were banned from this session";
+        ws.close(ErrorCode::PermissionDenied, reason).await?;
+        return Ok(false);
+    }
+    Ok(authenticate(ws, state, session, name, ip, user_id).await?
+        && check_capacity(ws, state, session, features).await?
+        && knock(ws, session, user_id, ip).await?)
+}
 
-    match recv(socket).await? {
-        Some(WsClient::Authenticate(bytes)) if bytes == session.metadata().encrypted_zeros => {}
-        _ => {
-            send(socket, WsServer::InvalidAuth()).await?;
-            return Ok(());
+/// Check the viewer's encryption key, and their authentication code if the
+/// session has one, recording the outcome in the audit log.
+async fn authenticate(
+    ws: &mut ViewerSocket<'_>,
+    state: &ServerState,
+    session: &Session,
+    name: &str,
+    ip: Option<IpAddr>,
+    user_id: Uid,
+) -> Result<bool> {
+    // Clients authenticate right away, so do not wait long on silent ones.
+    let first = time::timeout(state.keepalive_timeout(), ws.recv());
+    let mut authenticated = matches!(
+        first.await.context("viewer did not authenticate in time")??,
+        Some(WsClient::Authenticate(bytes)) if bytes == session.metadata().encrypted_zeros
+    );
+    let totp_secret = &session.metadata().totp_secret;
+    if authenticated && !totp_secret.is_empty() {
+        authenticated = false;
+        for attempt in 1..=MAX_TOTP_ATTEMPTS {
+            ws.send(WsServer::TotpRequired()).await?;
+            // Other messages sent before the code, like the user's name, are
+            // ignored, and the client sends them again after joining.
+            let code = loop {
+                match ws.recv().await? {
+                    Some(WsClient::Totp(code)) => break Some(code),
+                    Some(_) => continue,
+                    None => break None,
+                }
+            };
+            let Some(code) = code else {
+                return Ok(false);
+            };
+            if totp::verify(totp_secret, &code) {
+                authenticated = true;
+                break;
+            }
+            // Count wrong codes like missed lookups, to slow down guessing.
+            record_miss(state, ip);
+            if attempt < MAX_TOTP_ATTEMPTS {
+                let msg = "Invalid authentication code".into();
+                ws.send(WsServer::Error(ErrorCode::InvalidAuth, msg))
+                    .await?;
+            }
         }
     }
+    let session_name = name.to_string();
+    if authenticated {
+        let event = AuditEvent::ViewerAuthenticated {
+            session: session_name,
+            user: user_id,
+        };
+        state.audit().record(ip, event);
+    } else {
+        let event = AuditEvent::ViewerRejected {
+            session: session_name,
+        };
+        state.audit().record(ip, event);
+        session.record_access(AccessKind::AccessRejected, Uid(0), ip);
+        ws.send(WsServer::InvalidAuth()).await?;
+    }
+    Ok(authenticated)
+}
 
-    let _user_guard = session.user_scope(user_id)?;
+/// Turn the viewer away if their namespace or the session has no room left.
+async fn check_capacity(
+    ws: &mut ViewerSocket<'_>,
+    state: &ServerState,
+    session: &Session,
+    features: &ViewerFeatures,
+) -> Result<bool> {
+    if let Some(namespace) = &session.metadata().namespace {
+        let max_viewers = state.tenancy().quota(namespace).max_viewers;
+        if let Some(max) = max_viewers.filter(|&max| state.viewers_in(namespace) >= max) {
+            let reason = format!("namespace has reached its limit of {max} viewers");
+            ws.close(ErrorCode::QuotaExceeded, reason).await?;
+            return Ok(false);
+        }
+    }
+    if let Some(max) = (state.max_viewers()).filter(|&max| session.list_users().len() >= max) {
+        let reason = format!("session has reached its limit of {max} viewers");
+        let code = features.error_code(ErrorCode::SessionFull);
+        ws.close(code, reason).await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
 
-    let update_tx = session.update_tx(); // start listening for updates before any state reads
-    let mut broadcast_stream = session.subscribe_broadcast();
-    send(socket, WsServer::Users(session.list_users())).await?;
+/// Hold the viewer until the host lets them in, if the session asks for it.
+async fn knock(
+    ws: &mut ViewerSocket<'_>,
+    session: &Session,
+    user_id: Uid,
+    ip: Option<IpAddr>,
+) -> Result<bool> {
+    if !session.metadata().knock {
+        return Ok(true);
+    }
+    ws.send(WsServer::Pending()).await?;
+    let (decision, _knock_guard) = session.knock(user_id, ip);
+    let waiting = async {
+        // Other messages sent while waiting, like the user's name, are
+        // ignored, and the client sends them again after joining.
+        loop {
+            if ws.recv().await?.is_none() {
+                return anyhow::Ok(false);
+            }
+        }
+    };
+    let admitted = tokio::select! {
+        result = decision => result.unwrap_or(false),
+        _ = time::sleep(KNOCK_TIMEOUT) => false,
+        _ = session.terminated() => return Ok(false),
+        result = waiting => return result,
+    };
+    if !admitted {
+        session.record_access(AccessKind::AccessDenied, user_id, ip);
+        let reason = "the host did not let you in";
+        ws.close(ErrorCode::PermissionDenied, reason).await?;
+    }
+    Ok(admitted)
+}
 
-    let mut subscribed = HashSet::new(); // prevent duplicate subscriptions
-    let (chunks_tx, mut chunks_rx) = mpsc::channel::<(Sid, u64, Vec<Bytes>)>(1);
+/// A viewer who has joined a session, with the state of their connection.
+struct Connection<'a> {
+    state: &'a ServerState,
+    session: &'a Arc<Session>,
+    name: &'a str,
+    ip: Option<IpAddr>,
+    user_id: Uid,
+    features: ViewerFeatures,
+    writable: bool,
+    /// Whether the viewer can type because they entered the write password.
+    entered_password: bool,
+    write_attempts: u32,
+    viewer_name: String,
+    watermarked: Option<Instant>,
+    limiter: Option<InputLimiter>,
+    subscribed: HashSet<Sid>, // prevent duplicate subscriptions
+    acks: HashMap<Sid, watch::Sender<u64>>,
+    chunks_tx: mpsc::Sender<(Sid, u64, Vec<Bytes>)>,
+    leave_audit: LeaveAudit<'a>,
+}
 
-    let mut shells_stream = session.subscribe_shells();
-    loop {
-        let msg = tokio::select! {
-            _ = session.terminated() => break,
-            Some(result) = broadcast_stream.next() => {
-                let msg = result.context("client fell behind on broadcast stream")?;
-                send(socket, msg).await?;
-                continue;
+impl Connection<'_> {
+    /// Tell a viewer who just joined about the users and state of the session.
+    async fn greet(&self, ws: &mut ViewerSocket<'_>, chat_history: Vec<WsServer>) -> Result<()> {
+        let session = self.session;
+        // Viewers without presence still get an empty list, as the signal that
+        // they joined.
+        let users = if self.features.presence {
+            session.list_users()
+        } else {
+            vec![]
+        };
+        ws.send(WsServer::Users(users)).await?;
+        if self.features.presence {
+            for msg in chat_history {
+                ws.send(msg).await?;
             }
-            Some(shells) = shells_stream.next() => {
-                send(socket, WsServer::Shells(shells)).await?;
-                continue;
+        }
+        if !session.metadata().banner.is_empty() {
+            let banner = session.metadata().banner.clone();
+            ws.send(WsServer::Banner(banner)).await?;
+        }
+        if let Some(left) = session.time_left() {
+            ws.send(WsServer::TimeLeft(left.as_secs())).await?;
+        }
+        if let Some(start) = session.waiting_for_host() {
+            let start_ms = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+            ws.send(WsServer::Waiting(start_ms.as_millis() as u64))
+                .await?;
+        }
+        if !self.writable {
+            let has_password = !session.metadata().write_password_hash.is_empty();
+            ws.send(WsServer::ReadOnly(has_password)).await?;
+        }
+        Ok(())
+    }
+
+    /// Send batched output to the viewer, with a watermark if one is due.
+    async fn flush_output(&mut self, ws: &mut ViewerSocket<'_>) -> Result<()> {
+        let recent = (self.watermarked).is_some_and(|at| at.elapsed() < WATERMARK_INTERVAL);
+        if self.session.metadata().watermark && !recent {
+            let text = watermark_text(self.name, self.user_id, &self.viewer_name);
+            ws.send(WsServer::Watermark(text)).await?;
+            self.watermarked = Some(Instant::now());
+        }
+        for (id, seqnum, chunks) in ws.batcher.take() {
+            let bytes = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+            self.session.usage().record_relayed(bytes);
+            let msg = WsServer::Chunks(id, seqnum, chunks).compress(self.features.compression)?;
+            ws.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Check input against the viewer's rate limit, telling them if they were
+    /// just muted. Returns whether the input should be forwarded.
+    async fn check_input(&mut self, ws: &mut ViewerSocket<'_>, bytes: usize) -> Result<bool> {
+        match self.limiter.as_mut().map(|l| l.check(bytes)) {
+            Some(LimitResult::Dropped) => Ok(false),
+            Some(LimitResult::Muted(duration)) => {
+                let msg = format!(
+                    "Input rate limit exceeded, muted for {} seconds",
+                    duration.as_secs(),
+                );
+                ws.send(WsServer::Error(ErrorCode::RateLimited, msg))
+                    .await?;
+                Ok(false)
             }
-            Some((id, seqnum, chunks)) = chunks_rx.recv() => {
-                send(socket, WsServer::Chunks(id, seqnum, chunks)).await?;
-                continue;
+            Some(LimitResult::Allowed) | None => Ok(true),
+        }
+    }
+
+    /// Check whether the viewer may send a message, telling them why not
+    /// when they are waiting to hear back.
+    async fn permitted(&self, ws: &mut ViewerSocket<'_>, msg: &WsClient) -> Result<bool> {
+        // Viewers with a read-only link can watch and chat, but their changes
+        // to shells are silently dropped.
+        let changes_shells = matches!(
+            msg,
+            WsClient::Create(..)
+                | WsClient::CreateShell(_)
+                | WsClient::Close(_)
+                | WsClient::Rename(..)
+                | WsClient::Fork(_)
+                | WsClient::Move(..)
+                | WsClient::Data(..)
+                | WsClient::Paste(..)
+                | WsClient::Broadcast(..)
+        );
+        if changes_shells && !self.writable {
+            return Ok(false);
+        }
+        if let WsClient::FileStart(id, ..) | WsClient::FileRequest(id, ..) = *msg {
+            // Unlike other changes, the viewer waits to hear back about files.
+            if !self.writable {
+                let msg = "Only viewers who can type may transfer files".into();
+                ws.send(WsServer::FileFailed(id, msg)).await?;
+                return Ok(false);
             }
-            result = recv(socket) => {
-                match result? {
-                    Some(msg) => msg,
-                    None => break,
-                }
+        }
+        if let WsClient::Kick(_) = msg {
+            if !self.writable {
+                let msg = "Only viewers who can type may remove others".into();
+                ws.send(WsServer::Error(ErrorCode::PermissionDenied, msg))
+                    .await?;
+                return Ok(false);
             }
-        };
+        }
+        if let WsClient::Ban(_) = msg {
+            // Bans can lock out everyone behind the same address, so anyone
+            // with the link should not be able to make them.
+            if !self.entered_password {
+                let msg = "Only viewers who entered the write password may ban others".into();
+                ws.send(WsServer::Error(ErrorCode::PermissionDenied, msg))
+                    .await?;
+                return Ok(false);
+            }
+        }
+        if let WsClient::Create(..) | WsClient::CreateShell(_) = msg {
+            let shells = self.session.list_shells().len();
+            if let Some(max) = self.state.max_shells().filter(|&max| shells >= max) {
+                let msg = format!("Session has reached its limit of {max} shells");
+                let code = self.features.error_code(ErrorCode::SessionFull);
+                ws.send(WsServer::Error(code, msg)).await?;
+                return Ok(false);
+            }
+        }
+        if let WsClient::Close(id)
+        | WsClient::Rename(id, _)
+        | WsClient::Move(id, _)
+        | WsClient::FetchLines(id, ..) = *msg
+        {
+            if !self.session.has_shell(id) {
+                let msg = format!("Shell {id} does not exist");
+                let code = self.features.error_code(ErrorCode::ShellNotFound);
+                ws.send(WsServer::Error(code, msg)).await?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 
+    /// Handle a message from the viewer, after checking that it is permitted.
+    async fn handle_message(&mut self, ws: &mut ViewerSocket<'_>, msg: WsClient) -> Result<()> {
+        let session = self.session;
+        let user_id = self.user_id;
         match msg {
-            WsClient::Authenticate(_) => {}
+            WsClient::Authenticate(_) | WsClient::Totp(_) => {}
+            WsClient::AuthenticateWrite(password) => self.authenticate_write(ws, password).await?,
             WsClient::SetName(name) => {
                 if !name.is_empty() {
-                    session.update_user(user_id, |user| user.name = name)?;
+                    let mut renamed = false;
+                    self.viewer_name.clone_from(&name);
+                    session.update_user(user_id, |user| {
+                        renamed = user.name != name;
+                        user.name = name;
+                    })?;
+                    if renamed {
+                        session.record_access(AccessKind::AccessRenamed, user_id, self.ip);
+                        self.watermarked = None; // show the new name with the next output
+                    }
                 }
             }
             WsClient::SetCursor(cursor) => {
@@ -152,72 +730,516 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
             WsClient::Create(x, y) => {
                 let id = session.counter().next_sid();
                 session.sync_now();
-                let new_shell = NewShell { id: id.0, x, y };
-                update_tx
+                let (argv, argv_offset) = session.metadata().argv.clone().unwrap_or_default();
+                let new_shell = NewShell {
+                    id: id.0,
+                    x,
+                    y,
+                    argv,
+                    argv_offset,
+                    ..Default::default()
+                };
+                session
+                    .update_tx()
                     .send(ServerMessage::CreateShell(new_shell))
                     .await?;
             }
+            WsClient::CreateShell(shell) => self.create_shell(ws, shell).await?,
             WsClient::Close(id) => {
-                update_tx.send(ServerMessage::CloseShell(id.0)).await?;
+                let msg = ServerMessage::CloseShell(id.0);
+                session.shell_update_tx(id).send(msg).await?;
+            }
+            WsClient::Fork(scrollback) => {
+                let msg = ServerMessage::Fork(ForkRequest { scrollback });
+                session.update_tx().send(msg).await?;
             }
-            WsClient::Move(id, winsize) => {
-                if let Err(err) = session.move_shell(id, winsize) {
-                    send(socket, WsServer::Error(err.to_string())).await?;
-                    continue;
+            WsClient::Rename(id, title) => {
+                if let Err(err) = session.rename_shell(id, &title) {
+                    let msg = WsServer::Error(ErrorCode::InvalidRequest, err.to_string());
+                    ws.send(msg).await?;
                 }
-                if let Some(winsize) = winsize {
+            }
+            WsClient::Move(id, winsize) => match session.move_shell(id, user_id, winsize) {
+                Ok(Some((rows, cols))) => {
                     let msg = ServerMessage::Resize(TerminalSize {
                         id: id.0,
-                        rows: winsize.rows as u32,
-                        cols: winsize.cols as u32,
+                        rows: rows.into(),
+                        cols: cols.into(),
                     });
-                    session.update_tx().send(msg).await?;
+                    session.shell_update_tx(id).send(msg).await?;
                 }
-            }
+                Ok(None) => {}
+                Err(err) => {
+                    let msg = WsServer::Error(ErrorCode::InvalidRequest, err.to_string());
+                    ws.send(msg).await?;
+                }
+            },
             WsClient::Data(id, data, offset) => {
-                let input = TerminalInput {
-                    id: id.0,
+                self.send_input(ws, id, data, offset, false).await?;
+            }
+            WsClient::Paste(id, data, offset) => {
+                self.send_input(ws, id, data, offset, true).await?;
+            }
+            WsClient::Broadcast(data, offset) => self.broadcast(ws, data, offset).await?,
+            WsClient::Subscribe(id, chunknum) => {
+                if self.subscribed.insert(id) {
+                    let offset = session.chunk_byte_offset(id, chunknum);
+                    self.start_subscription(id, offset);
+                }
+            }
+            WsClient::SubscribeFrom(id, offset) => {
+                if self.subscribed.insert(id) {
+                    let start = session.history_start(id);
+                    if offset < start {
+                        ws.send(WsServer::HistoryStart(id, start)).await?;
+                    }
+                    self.start_subscription(id, offset);
+                }
+            }
+            WsClient::SubscribeScreen(id) => {
+                if self.subscribed.insert(id) {
+                    let mut offset = 0;
+                    if let Some(screen) = session.screen(id) {
+                        offset = screen.seq;
+                        let msg = WsServer::Screen(id, screen.seq, screen.offset, screen.data);
+                        ws.send(msg).await?;
+                    }
+                    self.start_subscription(id, offset);
+                }
+            }
+            WsClient::FetchLines(id, start, end) => match session.fetch_lines(id, start, end) {
+                Ok((line, offset, chunks)) => {
+                    let bytes = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+                    session.usage().record_relayed(bytes);
+                    let msg = WsServer::Lines(id, line, offset, chunks)
+                        .compress(self.features.compression)?;
+                    ws.send(msg).await?;
+                }
+                Err(err) => {
+                    let msg = format!("fetch lines: {err}");
+                    ws.send(WsServer::Error(ErrorCode::InvalidRequest, msg))
+                        .await?;
+                }
+            },
+            WsClient::Ack(id, offset) => {
+                if let Some(acked) = self.acks.get(&id) {
+                    acked.send_if_modified(|acked| {
+                        let advanced = offset > *acked;
+                        *acked = (*acked).max(offset);
+                        advanced
+                    });
+                }
+            }
+            WsClient::FileStart(id, name, offset, size) => {
+                if name.is_empty() || name.len() > MAX_FILE_PATH_LEN {
+                    let msg = "File name is empty or too long".into();
+                    ws.send(WsServer::FileFailed(id, msg)).await?;
+                    return Ok(());
+                }
+                let upload = FileUpload {
+                    id,
+                    uid: user_id.0,
+                    name,
+                    size,
+                    offset,
+                    ..Default::default()
+                };
+                session
+                    .update_tx()
+                    .send(ServerMessage::Upload(upload))
+                    .await?;
+            }
+            WsClient::FileChunk(id, data, offset) => {
+                // Chunks of uploads that were never accepted are dropped by the host.
+                if !self.writable || data.len() > MAX_FILE_CHUNK_BYTES {
+                    return Ok(());
+                }
+                let upload = FileUpload {
+                    id,
+                    uid: user_id.0,
                     data,
                     offset,
+                    ..Default::default()
                 };
-                update_tx.send(ServerMessage::Input(input)).await?;
+                session
+                    .update_tx()
+                    .send(ServerMessage::Upload(upload))
+                    .await?;
             }
-            WsClient::Subscribe(id, chunknum) => {
-                if subscribed.contains(&id) {
-                    continue;
+            WsClient::FileEnd(id) => {
+                if !self.writable {
+                    return Ok(());
                 }
-                subscribed.insert(id);
-                let session = Arc::clone(&session);
-                let chunks_tx = chunks_tx.clone();
-                tokio::spawn(async move {
-                    let stream = session.subscribe_chunks(id, chunknum);
-                    tokio::pin!(stream);
-                    while let Some((seqnum, chunks)) = stream.next().await {
-                        if chunks_tx.send((id, seqnum, chunks)).await.is_err() {
-                            break;
-                        }
-                    }
-                });
+                let upload = FileUpload {
+                    id,
+                    uid: user_id.0,
+                    done: true,
+                    ..Default::default()
+                };
+                session
+                    .update_tx()
+                    .send(ServerMessage::Upload(upload))
+                    .await?;
+            }
+            WsClient::FileRequest(id, path, offset) => {
+                if path.is_empty() || path.len() > MAX_FILE_PATH_LEN {
+                    let msg = "File path is empty or too long".into();
+                    ws.send(WsServer::FileFailed(id, msg)).await?;
+                    return Ok(());
+                }
+                let download = FileDownload {
+                    id,
+                    uid: user_id.0,
+                    path,
+                    offset,
+                };
+                session
+                    .update_tx()
+                    .send(ServerMessage::Download(download))
+                    .await?;
             }
             WsClient::Chat(msg) => {
                 session.send_chat(user_id, &msg)?;
             }
+            WsClient::Kick(id) => self.remove_user(ws, id, false).await?,
+            WsClient::Ban(id) => self.remove_user(ws, id, true).await?,
             WsClient::Ping(ts) => {
-                send(socket, WsServer::Pong(ts)).await?;
+                ws.send(WsServer::Pong(ts)).await?;
+                let viewer_latency = ws.batcher.rtt().map(|rtt| rtt.as_millis() as u64);
+                let msg = WsServer::Latency(session.host_latency(), viewer_latency);
+                ws.send(msg).await?;
+            }
+            WsClient::Sync() => ws.send(WsServer::Sync(self.sync_state())).await?,
+        }
+        Ok(())
+    }
+
+    /// Make the viewer writable if they send the session's write password.
+    async fn authenticate_write(
+        &mut self,
+        ws: &mut ViewerSocket<'_>,
+        password: String,
+    ) -> Result<()> {
+        let hash = self.session.metadata().write_password_hash.clone();
+        if self.writable || hash.is_empty() {
+            return Ok(());
+        }
+        if self.write_attempts >= MAX_WRITE_PASSWORD_ATTEMPTS {
+            let msg = "Too many wrong passwords, reconnect to try again".into();
+            ws.send(WsServer::Error(ErrorCode::InvalidAuth, msg))
+                .await?;
+            return Ok(());
+        }
+        self.write_attempts += 1;
+        // Hashing is slow on purpose, so keep it off the async runtime.
+        if task::spawn_blocking(move || verify_write_password(&hash, &password)).await? {
+            self.writable = true;
+            self.entered_password = true;
+            ws.send(WsServer::Writable()).await?;
+        } else {
+            record_miss(self.state, self.ip);
+            let msg = "Invalid write password".into();
+            ws.send(WsServer::Error(ErrorCode::InvalidAuth, msg))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Ask the host to open a shell with the viewer's chosen options.
+    async fn create_shell(&mut self, ws: &mut ViewerSocket<'_>, shell: WsNewShell) -> Result<()> {
+        let session = self.session;
+        let (cwd, cwd_offset) = shell.cwd.unwrap_or_default();
+        let (command, command_offset) = shell.command.unwrap_or_default();
+        let (argv, argv_offset) = (shell.argv)
+            .or_else(|| session.metadata().argv.clone())
+            .unwrap_or_default();
+        if [&cwd, &command, &argv]
+            .iter()
+            .any(|field| field.len() > MAX_SHELL_OPTION_LEN)
+        {
+            let msg = "Shell directory or command is too long".into();
+            ws.send(WsServer::Error(ErrorCode::InvalidRequest, msg))
+                .await?;
+            return Ok(());
+        }
+        let id = session.counter().next_sid();
+        session.sync_now();
+        let (rows, cols) = shell.size.unwrap_or_default();
+        let new_shell = NewShell {
+            id: id.0,
+            x: shell.x,
+            y: shell.y,
+            rows: rows.into(),
+            cols: cols.into(),
+            cwd,
+            cwd_offset,
+            command,
+            command_offset,
+            argv,
+            argv_offset,
+        };
+        session
+            .update_tx()
+            .send(ServerMessage::CreateShell(new_shell))
+            .await?;
+        Ok(())
+    }
+
+    /// Forward typed or pasted input to a shell, within the rate limit.
+    async fn send_input(
+        &mut self,
+        ws: &mut ViewerSocket<'_>,
+        id: Sid,
+        data: Bytes,
+        offset: u64,
+        paste: bool,
+    ) -> Result<()> {
+        if !self.check_input(ws, data.len()).await? {
+            return Ok(());
+        }
+        let input = TerminalInput {
+            id: id.0,
+            data,
+            offset,
+            paste,
+        };
+        self.leave_audit.input_bytes += input.data.len() as u64;
+        self.session
+            .record_input(id, self.user_id, offset, &input.data);
+        self.session
+            .shell_update_tx(id)
+            .send(ServerMessage::Input(input))
+            .await?;
+        self.session.record_activity();
+        Ok(())
+    }
+
+    /// Forward the same input to every shell, within the rate limit.
+    async fn broadcast(
+        &mut self,
+        ws: &mut ViewerSocket<'_>,
+        data: Bytes,
+        offset: u64,
+    ) -> Result<()> {
+        // Each copy counts against the limit, since the host gets them all.
+        let shells = self.session.list_shells();
+        if !self.check_input(ws, data.len() * shells.len()).await? {
+            return Ok(());
+        }
+        for (id, _) in shells {
+            let input = TerminalInput {
+                id: id.0,
+                data: data.clone(),
+                offset,
+                paste: false,
+            };
+            self.leave_audit.input_bytes += data.len() as u64;
+            self.session.record_input(id, self.user_id, offset, &data);
+            self.session
+                .shell_update_tx(id)
+                .send(ServerMessage::Input(input))
+                .await?;
+        }
+        self.session.record_activity();
+        Ok(())
+    }
+
+    /// Start forwarding a shell's output to the viewer from a byte offset.
+    fn start_subscription(&mut self, id: Sid, offset: u64) {
+        let acked = ack_receiver(&mut self.acks, id, self.features.flow_control);
+        spawn_subscription(self.session, id, offset, self.chunks_tx.clone(), acked);
+    }
+
+    /// Disconnect another viewer on this viewer's behalf, and ban them if asked.
+    async fn remove_user(&self, ws: &mut ViewerSocket<'_>, id: Uid, ban: bool) -> Result<()> {
+        if !self.session.kick(id, ban, Some(self.user_id)) {
+            let msg = format!("User {id} is not in the session");
+            ws.send(WsServer::Error(ErrorCode::NotFound, msg)).await?;
+        }
+        Ok(())
+    }
+
+    /// Snapshot of the session for a viewer checking that they are in sync.
+    fn sync_state(&self) -> WsSyncState {
+        let session = self.session;
+        let mut seqnums: Vec<_> = (session.sequence_numbers().map)
+            .into_iter()
+            .map(|(id, seqnum)| (Sid(id), seqnum))
+            .collect();
+        seqnums.sort_unstable();
+        let shells = session.list_titled_shells();
+        let history = shells
+            .iter()
+            .map(|&(id, ..)| (id, session.history_start(id)))
+            .collect();
+        WsSyncState {
+            user_id: self.user_id,
+            shells,
+            users: session.list_users(),
+            seqnums,
+            history,
+            muted: self.limiter.as_ref().is_some_and(|l| l.is_muted()),
+        }
+    }
+}
+
+/// Handle an incoming live WebSocket connection to a given session.
+async fn handle_socket(
+    socket: &mut WebSocket,
+    state: &ServerState,
+    session: Arc<Session>,
+    name: &str,
+    ip: Option<IpAddr>,
+    writable: bool,
+    query: &WsQuery,
+) -> Result<()> {
+    let features = ViewerFeatures::negotiate(query);
+    let mut ws = ViewerSocket {
+        socket,
+        batcher: OutputBatcher::new(),
+        last_received: Instant::now(),
+    };
+    let user_id = session.counter().next_uid();
+    session.sync_now();
+    ws.send(WsServer::Hello(user_id)).await?;
+    if let Some(negotiated) = features.negotiated.clone() {
+        ws.send(WsServer::Features(negotiated)).await?;
+    }
+    if !handshake(&mut ws, state, &session, name, ip, user_id, &features).await? {
+        return Ok(());
+    }
+
+    let _user_guard = session.user_scope(user_id, ip)?;
+    let (mut kicked, _kick_guard) = session.kick_scope(user_id, ip);
+    let mut mailbox = session.subscribe_mailbox(user_id);
+    let (chat_history, mut broadcast_stream) = session.subscribe_broadcast();
+    let (chunks_tx, mut chunks_rx) = mpsc::channel(1);
+    let mut conn = Connection {
+        state,
+        session: &session,
+        name,
+        ip,
+        user_id,
+        features,
+        writable,
+        entered_password: false,
+        write_attempts: 0,
+        viewer_name: String::new(),
+        watermarked: None,
+        limiter: state.input_limiter(),
+        subscribed: HashSet::new(),
+        acks: HashMap::new(),
+        chunks_tx,
+        leave_audit: LeaveAudit {
+            state,
+            session: name,
+            user: user_id,
+            ip,
+            input_bytes: 0,
+        },
+    };
+    conn.greet(&mut ws, chat_history).await?;
+
+    let mut shells_stream = session.subscribe_shells();
+    let mut rtt_interval = time::interval(RTT_PING_INTERVAL);
+    let keepalive_timeout = state.keepalive_timeout();
+    let idle_timeout = state.viewer_idle_timeout();
+    let mut last_message = Instant::now();
+    loop {
+        let flush_at = ws.batcher.deadline();
+        let idle_at = idle_timeout.map(|timeout| last_message + timeout);
+        let msg = tokio::select! {
+            _ = session.terminated() => {
+                let msg = if state.draining().is_some() {
+                    close_with(ErrorCode::ShuttingDown, "server is shutting down")
+                } else if session.time_left().is_some_and(|left| left.is_zero()) {
+                    close_with(ErrorCode::SessionExpired, "session reached its time limit")
+                } else {
+                    close_with(ErrorCode::SessionClosed, "session was closed")
+                };
+                ws.socket.send(msg).await.ok();
+                break;
+            }
+            Some(result) = broadcast_stream.next() => {
+                let msg = result.context("client fell behind on broadcast stream")?;
+                let presence = conn.features.presence;
+                if presence || !matches!(msg, WsServer::UserDiff(..) | WsServer::Hear(..)) {
+                    ws.send(msg).await?;
+                }
+                continue;
+            }
+            Some(shells) = shells_stream.next() => {
+                ws.send(WsServer::Shells(shells)).await?;
+                continue;
+            }
+            Ok(banned) = &mut kicked => {
+                ws.send(WsServer::Removed(banned)).await.ok();
+                let reason = "you were removed from the session";
+                ws.close(ErrorCode::PermissionDenied, reason).await.ok();
+                break;
+            }
+            Some(msg) = mailbox.recv() => {
+                ws.send(msg).await?;
+                continue;
             }
+            Some((id, seqnum, chunks)) = chunks_rx.recv() => {
+                ws.batcher.push(id, seqnum, chunks);
+                continue;
+            }
+            _ = sleep_until(flush_at) => {
+                conn.flush_output(&mut ws).await?;
+                continue;
+            }
+            _ = rtt_interval.tick() => {
+                if ws.last_received.elapsed() > keepalive_timeout {
+                    // The connection is likely half-open, so do not wait on it.
+                    let close = ws.socket.send(Message::Close(None));
+                    time::timeout(RTT_PING_INTERVAL, close).await.ok();
+                    break;
+                }
+                let ping = ws.batcher.start_ping();
+                ws.socket.send(Message::Ping(ping)).await?;
+                continue;
+            }
+            _ = sleep_until(idle_at) => {
+                let reason = "disconnected after sending nothing for too long";
+                ws.close(ErrorCode::IdleTimeout, reason).await.ok();
+                break;
+            }
+            result = ws.recv() => {
+                match result? {
+                    Some(msg) => msg,
+                    None => break,
+                }
+            }
+        };
+        last_message = Instant::now();
+
+        if conn.permitted(&mut ws, &msg).await? {
+            // Each message is handled in its own span, for tracing.
+            let span = info_span!("ws.message", kind = msg.kind());
+            conn.handle_message(&mut ws, msg).instrument(span).await?;
         }
     }
     Ok(())
 }
 
 /// Transparently reverse-proxy a WebSocket connection to a different host.
-async fn proxy_redirect(socket: &mut WebSocket, host: &str, name: &str) -> Result<()> {
+async fn proxy_redirect(
+    socket: &mut WebSocket,
+    host: &str,
+    path: &str,
+    headers: HeaderMap,
+) -> Result<()> {
     use tokio_tungstenite::{
         connect_async,
+        tungstenite::client::IntoClientRequest,
         tungstenite::protocol::{CloseFrame as TCloseFrame, Message as TMessage},
     };
 
-    let (mut upstream, _) = connect_async(format!("ws://{host}/api/s/{name}")).await?;
+    let mut request = format!("ws://{host}{path}").into_client_request()?;
+    request.headers_mut().extend(headers);
+    let (mut upstream, _) = connect_async(request).await?;
     loop {
         // Due to axum having its own WebSocket API types, we need to manually translate
         // between it and tungstenite's message type.
@@ -1,12 +1,16 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use sshx::{controller::Controller, runner::Runner};
 use sshx_core::{Sid, Uid};
 use sshx_server::{
     session::Session,
+    state::store::{FileStore, SessionStore},
     web::protocol::{WsClient, WsWinsize},
+    ServerOptions,
 };
+use tokio::time;
 
 use crate::common::*;
 
@@ -38,6 +42,7 @@ async fn test_basic_restore() -> Result<()> {
     s.send_input(Sid(1), b"hello there!").await;
     s.send_input(Sid(1), b" - another message").await;
     s.send(WsClient::Move(Sid(1), Some(new_size))).await;
+    s.send(WsClient::Rename(Sid(1), "greetings".into())).await;
     s.flush().await;
     assert!(s.shells.contains_key(&Sid(1)));
 
@@ -53,6 +58,61 @@ async fn test_basic_restore() -> Result<()> {
 
     assert_eq!(s.read(Sid(1)), "hello there! - another message");
     assert_eq!(s.shells.get(&Sid(1)).unwrap(), &new_size);
+    assert_eq!(s.titles.get(&Sid(1)).unwrap(), "greetings");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_after_restart() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let store = Arc::new(FileStore::new(dir.path())?);
+    let mut options = ServerOptions::default();
+    options.session_store = Some(store.clone());
+    let server = TestServer::with_options(options.clone()).await;
+
+    let mut controller = Controller::new(&server.endpoint(), Runner::Echo).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    let closed_name = {
+        let closed = Controller::new(&server.endpoint(), Runner::Echo).await?;
+        let name = closed.name().to_owned();
+        closed.close().await?;
+        name
+    };
+    let task = tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    s.send_input(Sid(1), b"before restart").await;
+    assert!(s.flush_until(|s| s.read(Sid(1)) == "before restart").await);
+
+    // Stop the server, and start a new one from the same store.
+    server.state().persist_sessions().await;
+    task.abort();
+    drop(server);
+
+    let server = TestServer::with_options(options).await;
+    let mut restored = None;
+    for _ in 0..50 {
+        restored = server.state().lookup(&name);
+        if restored.is_some() {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(restored.is_some(), "session should be restored");
+    assert!(server.state().lookup(&closed_name).is_none());
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key).await?;
+    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    assert!(s.flush_until(|s| s.read(Sid(1)) == "before restart").await);
+
+    // Closed sessions are removed from the store.
+    server.state().close_session(&name).await?;
+    assert!(store.load_all().await?.is_empty());
 
     Ok(())
 }
@@ -0,0 +1,39 @@
+//! Utilities for integration testing against an in-process sshx server.
+//!
+//! [`TestServer`] starts an isolated server on an unused local port, and
+//! [`TestSession`] hosts a session on it from an in-process client.
+//! [`ClientSocket`] drives the WebSocket protocol like a browser viewer would,
+//! decrypting output and recording every update in public fields that tests
+//! can assert on, along with each [`WsServer`](sshx_core::ws::WsServer)
+//! message received. A [`Script`] runs a sequence of viewer steps on it.
+//!
+//! ```no_run
+//! use sshx_core::ws::WsClient;
+//! use sshx_core::Sid;
+//! use sshx_testkit::{TestServer, TestSession};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let server = TestServer::new().await;
+//! let session = TestSession::new(&server).await?;
+//!
+//! let mut s = session.connect().await?;
+//! s.send(WsClient::Create(0, 0)).await;
+//! s.send(WsClient::Subscribe(Sid(1), 0)).await;
+//! s.send_input(Sid(1), b"hello").await;
+//! s.flush_until(|s| s.read(Sid(1)) == "hello").await;
+//! # Ok(())
+//! # }
+//! ```
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+pub use crate::script::Script;
+pub use crate::server::TestServer;
+pub use crate::session::TestSession;
+pub use crate::socket::ClientSocket;
+
+mod script;
+mod server;
+mod session;
+mod socket;
@@ -0,0 +1,202 @@
+//! Scripted interactions of a viewer, for protocol regression tests.
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+use sshx_core::ws::{WsClient, WsServer, WsWinsize};
+use sshx_core::Sid;
+
+use crate::ClientSocket;
+
+type Condition = Box<dyn Fn(&ClientSocket) -> bool + Send + Sync>;
+type Matcher = Box<dyn Fn(&WsServer) -> bool + Send + Sync>;
+
+/// A sequence of steps that a viewer takes, built up front and then run
+/// against a [`ClientSocket`].
+///
+/// Each step that waits gives up after a timeout, and running the script
+/// fails with the number and description of that step.
+///
+/// ```no_run
+/// use sshx_core::{ws::WsServer, Sid};
+/// use sshx_testkit::{Script, TestServer, TestSession};
+///
+/// # async fn example() -> anyhow::Result<()> {
+/// let server = TestServer::new().await;
+/// let session = TestSession::new(&server).await?;
+/// let mut s = session.connect().await?;
+/// Script::new()
+///     .create(0, 0)
+///     .subscribe(Sid(1))
+///     .type_input(Sid(1), "hello")
+///     .wait_for_output(Sid(1), "hello")
+///     .resize(Sid(1), 24, 80)
+///     .expect("shells", |msg| matches!(msg, WsServer::Shells(_)))
+///     .run(&mut s)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+enum Step {
+    Send(WsClient),
+    Create(i32, i32),
+    Input(Sid, Vec<u8>),
+    Resize(Sid, u16, u16),
+    Flush,
+    WaitFor(String, Condition),
+    Expect(String, Matcher),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Send(msg) => write!(f, "send {msg:?}"),
+            Step::Create(x, y) => write!(f, "create a shell at ({x}, {y})"),
+            Step::Input(id, data) => write!(f, "type {} bytes into shell {id}", data.len()),
+            Step::Resize(id, rows, cols) => write!(f, "resize shell {id} to {rows}x{cols}"),
+            Step::Flush => write!(f, "flush"),
+            Step::WaitFor(desc, _) => write!(f, "wait for {desc}"),
+            Step::Expect(desc, _) => write!(f, "expect {desc}"),
+        }
+    }
+}
+
+impl Script {
+    /// Start an empty script.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send any message to the server.
+    pub fn send(mut self, msg: WsClient) -> Self {
+        self.steps.push(Step::Send(msg));
+        self
+    }
+
+    /// Create a shell centered at a position, and wait for it to open.
+    pub fn create(mut self, x: i32, y: i32) -> Self {
+        self.steps.push(Step::Create(x, y));
+        self
+    }
+
+    /// Subscribe to the output of a shell from the start.
+    pub fn subscribe(self, id: Sid) -> Self {
+        self.send(WsClient::Subscribe(id, 0))
+    }
+
+    /// Encrypt and type input into a shell.
+    pub fn type_input(mut self, id: Sid, data: impl AsRef<[u8]>) -> Self {
+        self.steps.push(Step::Input(id, data.as_ref().to_vec()));
+        self
+    }
+
+    /// Resize a shell, keeping the position of its window.
+    pub fn resize(mut self, id: Sid, rows: u16, cols: u16) -> Self {
+        self.steps.push(Step::Resize(id, rows, cols));
+        self.wait_for(format!("shell {id} to be resized"), move |s| {
+            (s.shells.get(&id)).is_some_and(|w| w.rows == rows && w.cols == cols)
+        })
+    }
+
+    /// Close a shell, and wait for it to be gone.
+    pub fn close(self, id: Sid) -> Self {
+        self.send(WsClient::Close(id))
+            .wait_for(format!("shell {id} to close"), move |s| {
+                !s.shells.contains_key(&id)
+            })
+    }
+
+    /// Receive messages until none arrive for a short while.
+    pub fn flush(mut self) -> Self {
+        self.steps.push(Step::Flush);
+        self
+    }
+
+    /// Receive messages until a condition on the viewer holds.
+    pub fn wait_for(
+        mut self,
+        desc: impl Into<String>,
+        cond: impl Fn(&ClientSocket) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.steps.push(Step::WaitFor(desc.into(), Box::new(cond)));
+        self
+    }
+
+    /// Receive messages until the decrypted output of a shell ends with some
+    /// text.
+    pub fn wait_for_output(self, id: Sid, text: impl Into<String>) -> Self {
+        let text = text.into();
+        self.wait_for(format!("{text:?} from shell {id}"), move |s| {
+            s.read(id).ends_with(&text)
+        })
+    }
+
+    /// Receive messages until the server sends one that matches, since the
+    /// last message matched by an earlier step.
+    pub fn expect(
+        mut self,
+        desc: impl Into<String>,
+        matcher: impl Fn(&WsServer) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.steps
+            .push(Step::Expect(desc.into(), Box::new(matcher)));
+        self
+    }
+
+    /// Run each step in order on a connected viewer.
+    pub async fn run(&self, s: &mut ClientSocket) -> Result<()> {
+        let mut seen = s.received.len();
+        for (i, step) in self.steps.iter().enumerate() {
+            let done = match step {
+                Step::Send(msg) => {
+                    s.send(msg.clone()).await;
+                    true
+                }
+                Step::Create(x, y) => {
+                    s.flush().await;
+                    let before: Vec<Sid> = s.shells.keys().copied().collect();
+                    s.send(WsClient::Create(*x, *y)).await;
+                    (s.flush_until(|s| s.shells.keys().any(|id| !before.contains(id)))).await
+                }
+                Step::Input(id, data) => {
+                    s.send_input(*id, data).await;
+                    true
+                }
+                Step::Resize(id, rows, cols) => {
+                    let winsize = s.shells.get(id).copied().unwrap_or_default();
+                    let winsize = WsWinsize {
+                        rows: *rows,
+                        cols: *cols,
+                        ..winsize
+                    };
+                    s.send(WsClient::Move(*id, Some(winsize))).await;
+                    true
+                }
+                Step::Flush => {
+                    s.flush().await;
+                    true
+                }
+                Step::WaitFor(_, cond) => s.flush_until(|s| cond(s)).await,
+                Step::Expect(_, matcher) => {
+                    let found = s
+                        .flush_until(|s| s.received[seen..].iter().any(matcher))
+                        .await;
+                    if found {
+                        let pos = s.received[seen..].iter().position(matcher);
+                        seen += pos.unwrap() + 1;
+                    }
+                    found
+                }
+            };
+            if !done {
+                bail!("step {} timed out: {step}", i + 1);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+//! An ephemeral server for each test.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::server::conn::AddrIncoming;
+use sshx_core::proto::sshx_service_client::SshxServiceClient;
+use sshx_server::{state::ServerState, Server, ServerOptions};
+use tokio::net::TcpListener;
+use tonic::transport::Channel;
+
+/// An ephemeral, isolated server that is created for each test.
+pub struct TestServer {
+    local_addr: SocketAddr,
+    server: Arc<Server>,
+}
+
+impl TestServer {
+    /// Create a fresh server listening on an unused local port for testing.
+    ///
+    /// Returns an object with the local address, as well as a custom [`Drop`]
+    /// implementation that gracefully shuts down the server.
+    pub async fn new() -> Self {
+        Self::with_options(Default::default()).await
+    }
+
+    /// Create a fresh server for testing, with custom options.
+    pub async fn with_options(options: ServerOptions) -> Self {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+
+        let incoming = AddrIncoming::from_listener(listener).unwrap();
+        let server = Arc::new(Server::new(options).unwrap());
+        {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                server.listen(incoming).await.unwrap();
+            });
+        }
+
+        TestServer { local_addr, server }
+    }
+
+    /// Returns the local TCP address of this server.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the HTTP/2 base endpoint URI for this server.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.local_addr)
+    }
+
+    /// Returns the WebSocket endpoint for streaming connections to a session.
+    pub fn ws_endpoint(&self, name: &str) -> String {
+        format!("ws://{}/api/s/{}", self.local_addr, name)
+    }
+
+    /// Creates a gRPC client connected to this server.
+    pub async fn grpc_client(&self) -> SshxServiceClient<Channel> {
+        SshxServiceClient::connect(self.endpoint()).await.unwrap()
+    }
+
+    /// Returns the server, to drive its lifecycle directly.
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Return the current server state object.
+    pub fn state(&self) -> Arc<ServerState> {
+        self.server.state()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.shutdown();
+    }
+}
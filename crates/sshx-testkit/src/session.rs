@@ -0,0 +1,75 @@
+//! A session hosted by an in-process client, for viewers to connect to.
+
+use anyhow::Result;
+use sshx::controller::{Controller, ControllerOptions};
+use sshx::runner::Runner;
+use tokio::task::JoinHandle;
+
+use crate::{ClientSocket, TestServer};
+
+/// A session on a [`TestServer`], hosted by a controller that runs in the
+/// background until this is dropped.
+pub struct TestSession {
+    name: String,
+    key: String,
+    ws_endpoint: String,
+    task: JoinHandle<()>,
+}
+
+impl TestSession {
+    /// Open a session whose shells echo their input back as output.
+    pub async fn new(server: &TestServer) -> Result<Self> {
+        Self::with_options(server, Runner::Echo, ControllerOptions::default()).await
+    }
+
+    /// Open a session with a custom runner and options for the host.
+    pub async fn with_options(
+        server: &TestServer,
+        runner: Runner,
+        options: ControllerOptions,
+    ) -> Result<Self> {
+        let controller = Controller::with_options(&server.endpoint(), runner, options).await?;
+        Ok(Self::spawn(server, controller))
+    }
+
+    /// Run a controller that was already opened on the server, for tests that
+    /// need to set it up first, like creating shells.
+    pub fn spawn(server: &TestServer, mut controller: Controller) -> Self {
+        let name = controller.name().to_owned();
+        let key = controller.encryption_key().to_owned();
+        let ws_endpoint = server.ws_endpoint(&name);
+        let task = tokio::spawn(async move { controller.run().await });
+        Self {
+            name,
+            key,
+            ws_endpoint,
+            task,
+        }
+    }
+
+    /// Returns the name of the session.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the encryption key of the session.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the WebSocket endpoint of the session.
+    pub fn ws_endpoint(&self) -> &str {
+        &self.ws_endpoint
+    }
+
+    /// Connect a new viewer to the session.
+    pub async fn connect(&self) -> Result<ClientSocket> {
+        ClientSocket::connect(&self.ws_endpoint, &self.key).await
+    }
+}
+
+impl Drop for TestSession {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,403 @@
+//! A viewer that drives the WebSocket protocol.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use futures_util::{SinkExt, StreamExt};
+use hyper::{HeaderMap, StatusCode};
+use sshx::encrypt::Encrypt;
+use sshx_core::ws::{self, WsClient, WsServer, WsSyncState, WsUser, WsWinsize};
+use sshx_core::{ErrorCode, Sid, Uid};
+use sshx_server::web::protocol::WsServerExt;
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest, handshake::client::Request, Message,
+};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// How long [`ClientSocket::flush`] waits for more messages.
+const FLUSH_DURATION: Duration = Duration::from_millis(50);
+
+/// How long [`ClientSocket::flush_until`] waits for a condition to hold.
+const FLUSH_UNTIL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A WebSocket client that interacts with the server, used for testing.
+pub struct ClientSocket {
+    inner: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    encrypt: Encrypt,
+    input_offset: u64,
+
+    /// ID assigned to this viewer by the server.
+    pub user_id: Uid,
+    /// Features negotiated with the server, if the viewer listed any.
+    pub features: Option<Vec<String>>,
+    /// Users connected to the session.
+    pub users: BTreeMap<Uid, WsUser>,
+    /// Open shells and their window sizes.
+    pub shells: BTreeMap<Sid, WsWinsize>,
+    /// Titles of shells that were renamed.
+    pub titles: BTreeMap<Sid, String>,
+    /// Decrypted output of each subscribed shell.
+    pub data: HashMap<Sid, String>,
+    /// Decrypted rendering of each shell's screen, if the server sent one.
+    pub screens: HashMap<Sid, String>,
+    /// Byte offset where each shell's retained history starts.
+    pub history: HashMap<Sid, u64>,
+    /// Latest round-trip times to the host and this viewer, in milliseconds.
+    pub latency: Option<(Option<u64>, Option<u64>)>,
+    /// Exit codes of shells whose processes exited.
+    pub exited: HashMap<Sid, i32>,
+    /// Decrypted output of each shell fetched by line, with its first line.
+    pub lines: HashMap<Sid, (u64, String)>,
+    /// Decrypted URL of a session forked from this one.
+    pub forked: Option<String>,
+    /// Scheduled start time in milliseconds, if the host has not joined.
+    pub waiting: Option<u64>,
+    /// Whether the host of a scheduled session has joined.
+    pub host_joined: bool,
+    /// Whether this viewer is waiting for the host to let them in.
+    pub pending: bool,
+    /// Whether this viewer was kicked, with whether they were also banned.
+    pub removed: Option<bool>,
+    /// Whether the server marked this viewer as read-only, until they enter
+    /// the write password.
+    pub read_only: bool,
+    /// Chat messages received, as user ID, name, and text.
+    pub messages: Vec<(Uid, String, String)>,
+    /// Errors reported by the server.
+    pub errors: Vec<(ErrorCode, String)>,
+    /// Every message received from the server, in order, after decompression.
+    pub received: Vec<WsServer>,
+    /// Number of compressed messages received.
+    pub compressed: usize,
+    /// Whether the server asked for a TOTP code.
+    pub totp_required: bool,
+    /// Seconds left before the session's time limit, if announced.
+    pub time_left: Option<u64>,
+    /// Banner shown to viewers when they join.
+    pub banner: Option<String>,
+    /// Latest watermark text sent to this viewer.
+    pub watermark: Option<String>,
+    /// Latest synchronization state of the session.
+    pub sync: Option<WsSyncState>,
+    /// Code of the close frame received from the server, if any.
+    pub close_code: Option<u16>,
+    /// File transfers accepted by the host, with the size of each download.
+    pub files_accepted: HashMap<u32, u64>,
+    /// Decrypted contents of files downloaded from the host so far.
+    pub files_data: HashMap<u32, Vec<u8>>,
+    /// File transfers that finished successfully.
+    pub files_done: HashSet<u32>,
+    /// File transfers that the host refused or that failed, with the reason.
+    pub files_failed: HashMap<u32, String>,
+}
+
+impl ClientSocket {
+    /// Connect to a WebSocket endpoint, and authenticate with the session's
+    /// encryption key.
+    pub async fn connect(uri: &str, key: &str) -> Result<Self> {
+        Self::connect_with_origin(uri, key, None).await
+    }
+
+    /// Connect to a WebSocket endpoint, sending an `Origin` header.
+    pub async fn connect_with_origin(uri: &str, key: &str, origin: Option<&str>) -> Result<Self> {
+        let mut request = uri.into_client_request()?;
+        if let Some(origin) = origin {
+            request.headers_mut().insert("Origin", origin.parse()?);
+        }
+        Self::connect_with_request(request, key).await
+    }
+
+    /// Connect to a WebSocket endpoint with a bearer token, for servers that
+    /// require authentication.
+    pub async fn connect_with_bearer(uri: &str, key: &str, token: &str) -> Result<Self> {
+        let mut request = uri.into_client_request()?;
+        let value = format!("Bearer {token}").parse()?;
+        request.headers_mut().insert("Authorization", value);
+        Self::connect_with_request(request, key).await
+    }
+
+    /// Connect to a WebSocket endpoint with extra headers, like those that
+    /// another server in the mesh adds when proxying a viewer.
+    pub async fn connect_with_headers(uri: &str, key: &str, headers: HeaderMap) -> Result<Self> {
+        let mut request = uri.into_client_request()?;
+        request.headers_mut().extend(headers);
+        Self::connect_with_request(request, key).await
+    }
+
+    async fn connect_with_request(request: Request, key: &str) -> Result<Self> {
+        let (stream, resp) = tokio_tungstenite::connect_async(request).await?;
+        ensure!(resp.status() == StatusCode::SWITCHING_PROTOCOLS);
+
+        let mut this = Self {
+            inner: stream,
+            encrypt: Encrypt::new(key),
+            input_offset: 0,
+            user_id: Uid(0),
+            features: None,
+            users: BTreeMap::new(),
+            shells: BTreeMap::new(),
+            titles: BTreeMap::new(),
+            data: HashMap::new(),
+            screens: HashMap::new(),
+            history: HashMap::new(),
+            forked: None,
+            waiting: None,
+            host_joined: false,
+            pending: false,
+            removed: None,
+            read_only: false,
+            messages: Vec::new(),
+            errors: Vec::new(),
+            received: Vec::new(),
+            compressed: 0,
+            totp_required: false,
+            time_left: None,
+            banner: None,
+            watermark: None,
+            sync: None,
+            close_code: None,
+            files_accepted: HashMap::new(),
+            files_data: HashMap::new(),
+            files_done: HashSet::new(),
+            files_failed: HashMap::new(),
+            exited: HashMap::new(),
+            lines: HashMap::new(),
+            latency: None,
+        };
+        this.authenticate().await;
+        Ok(this)
+    }
+
+    async fn authenticate(&mut self) {
+        let encrypted_zeros = self.encrypt.zeros().into();
+        self.send(WsClient::Authenticate(encrypted_zeros)).await;
+    }
+
+    /// Send a message to the server.
+    pub async fn send(&mut self, msg: WsClient) {
+        let buf = ws::encode(&msg).unwrap();
+        self.inner.send(Message::Binary(buf)).await.unwrap();
+    }
+
+    /// Encrypt and send input to a shell.
+    pub async fn send_input(&mut self, id: Sid, data: &[u8]) {
+        let offset = self.input_offset;
+        self.input_offset += data.len() as u64;
+        let data = self.encrypt.segment(0x200000000, offset, data);
+        self.send(WsClient::Data(id, data.into(), offset)).await;
+    }
+
+    /// Encrypt and send input to every shell at once.
+    pub async fn send_broadcast(&mut self, data: &[u8]) {
+        let (data, offset) = self.encrypt_input(data);
+        self.send(WsClient::Broadcast(data.into(), offset)).await;
+    }
+
+    /// Encrypt and paste text into a shell.
+    pub async fn send_paste(&mut self, id: Sid, data: &[u8]) {
+        let (data, offset) = self.encrypt_input(data);
+        self.send(WsClient::Paste(id, data.into(), offset)).await;
+    }
+
+    /// Encrypt a name, path, or file chunk like input, returning its offset.
+    fn encrypt_input(&mut self, data: &[u8]) -> (Vec<u8>, u64) {
+        let offset = self.input_offset;
+        self.input_offset += data.len() as u64;
+        (self.encrypt.segment(0x200000000, offset, data), offset)
+    }
+
+    /// Start uploading a file to the host.
+    pub async fn send_file_start(&mut self, id: u32, name: &str, size: u64) {
+        let (name, offset) = self.encrypt_input(name.as_bytes());
+        self.send(WsClient::FileStart(id, name.into(), offset, size))
+            .await;
+    }
+
+    /// Send a chunk of a file being uploaded.
+    pub async fn send_file_chunk(&mut self, id: u32, data: &[u8]) {
+        let (data, offset) = self.encrypt_input(data);
+        self.send(WsClient::FileChunk(id, data.into(), offset))
+            .await;
+    }
+
+    /// Ask to download a file from the host.
+    pub async fn request_file(&mut self, id: u32, path: &str) {
+        let (path, offset) = self.encrypt_input(path.as_bytes());
+        self.send(WsClient::FileRequest(id, path.into(), offset))
+            .await;
+    }
+
+    async fn recv(&mut self) -> Option<WsServer> {
+        loop {
+            match self.inner.next().await.transpose().unwrap() {
+                Some(Message::Text(_)) => panic!("unexpected text message over WebSocket"),
+                Some(Message::Binary(msg)) => {
+                    let msg: WsServer = ws::decode(&msg).unwrap();
+                    if let WsServer::Compressed(_) = msg {
+                        self.compressed += 1;
+                    }
+                    break Some(msg.decompress().unwrap());
+                }
+                Some(Message::Close(Some(frame))) => {
+                    // Nothing follows a close frame, and the server may already be gone.
+                    self.close_code = Some(frame.code.into());
+                    break None;
+                }
+                Some(_) => (), // ignore other message types, keep looping
+                None => break None,
+            }
+        }
+    }
+
+    /// Wait for the next message, and assert that it closes the connection
+    /// with the given code.
+    pub async fn expect_close(&mut self, code: u16) {
+        let msg = self.inner.next().await.unwrap().unwrap();
+        match msg {
+            Message::Close(Some(frame)) => assert!(frame.code == code.into()),
+            _ => panic!("unexpected non-close message over WebSocket: {:?}", msg),
+        }
+    }
+
+    /// Receive messages and update the fields of this object, until none
+    /// arrive for a short while.
+    pub async fn flush(&mut self) {
+        let flush_task = async {
+            while let Some(msg) = self.recv().await {
+                self.received.push(msg.clone());
+                match msg {
+                    WsServer::Hello(user_id) => self.user_id = user_id,
+                    WsServer::Features(features) => self.features = Some(features),
+                    WsServer::InvalidAuth() => panic!("invalid authentication"),
+                    WsServer::TotpRequired() => self.totp_required = true,
+                    WsServer::Users(users) => {
+                        self.pending = false;
+                        self.users = BTreeMap::from_iter(users);
+                    }
+                    WsServer::UserDiff(id, maybe_user) => {
+                        self.users.remove(&id);
+                        if let Some(user) = maybe_user {
+                            self.users.insert(id, user);
+                        }
+                    }
+                    WsServer::Shells(shells) => {
+                        self.shells = (shells.iter())
+                            .map(|(id, winsize, _)| (*id, *winsize))
+                            .collect();
+                        self.titles = (shells.into_iter())
+                            .filter(|(_, _, title)| !title.is_empty())
+                            .map(|(id, _, title)| (id, title))
+                            .collect();
+                    }
+                    WsServer::Chunks(id, seqnum, chunks) => {
+                        let value = self.data.entry(id).or_default();
+                        assert!(seqnum >= value.len() as u64);
+                        // Pad output skipped by the server, so offsets still line up.
+                        let skipped = seqnum as usize - value.len();
+                        value.extend(std::iter::repeat_n('\0', skipped));
+                        for buf in chunks {
+                            let plaintext = self.encrypt.segment(
+                                0x100000000 | id.0 as u64,
+                                value.len() as u64,
+                                &buf,
+                            );
+                            value.push_str(std::str::from_utf8(&plaintext).unwrap());
+                        }
+                    }
+                    WsServer::Lines(id, line, mut offset, chunks) => {
+                        let mut text = String::new();
+                        for buf in chunks {
+                            let plaintext =
+                                self.encrypt
+                                    .segment(0x100000000 | id.0 as u64, offset, &buf);
+                            offset += buf.len() as u64;
+                            text.push_str(std::str::from_utf8(&plaintext).unwrap());
+                        }
+                        self.lines.insert(id, (line, text));
+                    }
+                    WsServer::Hear(id, name, msg, _) => {
+                        self.messages.push((id, name, msg));
+                    }
+                    WsServer::ShellLatency(_) => {}
+                    WsServer::Pong(_) => {}
+                    WsServer::Latency(host, viewer) => self.latency = Some((host, viewer)),
+                    WsServer::Error(code, err) => self.errors.push((code, err)),
+                    WsServer::TimeLeft(secs) => self.time_left = Some(secs),
+                    WsServer::Banner(text) => self.banner = Some(text),
+                    WsServer::Watermark(text) => self.watermark = Some(text),
+                    WsServer::Sync(state) => self.sync = Some(state),
+                    WsServer::HistoryStart(id, start) => {
+                        self.history.insert(id, start);
+                    }
+                    WsServer::Screen(id, _, offset, data) => {
+                        let stream_num = 0x300000000 | id.0 as u64;
+                        let plaintext = self.encrypt.segment(stream_num, offset, &data);
+                        let screen = String::from_utf8(plaintext).unwrap();
+                        self.screens.insert(id, screen);
+                    }
+                    WsServer::ShellExited(id, code) => {
+                        self.exited.insert(id, code);
+                    }
+                    WsServer::Forked(url, offset) => {
+                        let plaintext = self.encrypt.segment(0x400000000, offset, &url);
+                        self.forked = Some(String::from_utf8(plaintext).unwrap());
+                    }
+                    WsServer::Waiting(start_ms) => self.waiting = Some(start_ms),
+                    WsServer::HostJoined() => self.host_joined = true,
+                    WsServer::Pending() => self.pending = true,
+                    WsServer::Removed(banned) => self.removed = Some(banned),
+                    WsServer::ReadOnly(_) => self.read_only = true,
+                    WsServer::Writable() => self.read_only = false,
+                    WsServer::FileAccepted(id, size) => {
+                        self.files_accepted.insert(id, size);
+                    }
+                    WsServer::FileData(id, data, offset) => {
+                        let plaintext = self.encrypt.segment(0x500000000, offset, &data);
+                        self.files_data.entry(id).or_default().extend(plaintext);
+                    }
+                    WsServer::FileDone(id) => {
+                        self.files_done.insert(id);
+                    }
+                    WsServer::FileFailed(id, err) => {
+                        self.files_failed.insert(id, err);
+                    }
+                    WsServer::Compressed(_) => unreachable!("decompressed on receipt"),
+                }
+            }
+        };
+        time::timeout(FLUSH_DURATION, flush_task).await.ok();
+    }
+
+    /// Keep flushing messages until a condition on this object holds,
+    /// returning whether it did before timing out.
+    pub async fn flush_until(&mut self, mut cond: impl FnMut(&Self) -> bool) -> bool {
+        let deadline = time::Instant::now() + FLUSH_UNTIL_TIMEOUT;
+        while !cond(self) {
+            if time::Instant::now() >= deadline {
+                return false;
+            }
+            self.flush().await;
+        }
+        true
+    }
+
+    /// Keep flushing messages until the server sends one that matches,
+    /// returning the first match received so far.
+    ///
+    /// Panics if no such message arrives before timing out.
+    pub async fn expect_message(&mut self, matcher: impl Fn(&WsServer) -> bool) -> WsServer {
+        self.flush_until(|s| s.received.iter().any(&matcher)).await;
+        match self.received.iter().find(|msg| matcher(msg)) {
+            Some(msg) => msg.clone(),
+            None => panic!("timed out waiting for message, got {:?}", self.received),
+        }
+    }
+
+    /// Returns the decrypted output of a shell received so far.
+    pub fn read(&self, id: Sid) -> &str {
+        self.data.get(&id).map(|s| &**s).unwrap_or("")
+    }
+}
@@ -0,0 +1,26 @@
+//! JavaScript bindings for the WebSocket protocol, built with wasm-bindgen.
+//!
+//! Messages cross the boundary as plain JavaScript values shaped like the
+//! `WsServer` and `WsClient` types in the frontend, so that it no longer has
+//! to encode and decode CBOR itself.
+
+#![warn(missing_docs)]
+
+use serde::Serialize;
+use serde_wasm_bindgen::Serializer;
+use sshx_core::ws::{self, WsClient, WsServer};
+use wasm_bindgen::prelude::*;
+/// Decode a message from the server into a JavaScript value.
+#[wasm_bindgen(js_name = decodeServer)]
+pub fn decode_server(data: &[u8]) -> Result<JsValue, JsError> {
+    let msg: WsServer = ws::decode(data)?;
+    let serializer = Serializer::new().serialize_maps_as_objects(true);
+    Ok(msg.serialize(&serializer)?)
+}
+
+/// Encode a message to the server from a JavaScript value.
+#[wasm_bindgen(js_name = encodeClient)]
+pub fn encode_client(msg: JsValue) -> Result<Vec<u8>, JsError> {
+    let msg: WsClient = serde_wasm_bindgen::from_value(msg)?;
+    Ok(ws::encode(&msg)?)
+}
@@ -0,0 +1,370 @@
+//! Viewer that attaches to a session from another terminal, without a browser.
+//!
+//! This speaks the same WebSocket protocol as the web interface, with the
+//! message types from [`sshx_core::ws`], but only handles the subset of
+//! messages needed to follow a single shell.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write as _;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use nix::sys::termios::{self, SetArg, Termios};
+use sshx_core::ws::{self, WsClient, WsServer, WsWinsize};
+use sshx_core::Sid;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::{self, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, trace};
+
+use crate::controller::random_offset;
+use crate::encrypt::Encrypt;
+use crate::terminal::local_winsize;
+
+/// Key that detaches from the session, like the escape key of `telnet`.
+pub const DETACH_KEY: u8 = 0x1d; // Ctrl-]
+
+/// Something that happened in a session, from the viewer's point of view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ViewerEvent {
+    /// The session needs a TOTP code, sent with [`Viewer::send_totp`].
+    TotpRequired,
+    /// The viewer joined the session after authenticating.
+    Joined,
+    /// The set of open shells changed, listed in order of their IDs.
+    Shells(Vec<Sid>),
+    /// Decrypted output from a subscribed shell.
+    Output(Sid, Vec<u8>),
+    /// The process in a shell exited with a code.
+    ShellExited(Sid, i32),
+    /// The viewer can only watch, so their input is ignored.
+    ReadOnly,
+    /// An error reported by the server, like a rate limit.
+    Error(String),
+}
+
+/// Any stream that a WebSocket can run over, with or without TLS.
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// Connection to a session as a viewer, over WebSocket.
+pub struct Viewer {
+    socket: WebSocketStream<Box<dyn Transport>>,
+    encrypt: Encrypt,
+    input_offset: u64,
+    shells: BTreeMap<Sid, WsWinsize>,
+    /// Bytes of output already received from each subscribed shell.
+    seqnums: HashMap<Sid, u64>,
+}
+
+impl Viewer {
+    /// Connect to a session from its link, which has the encryption key after
+    /// the `#`, and authenticate with the key.
+    pub async fn connect(link: &str) -> Result<Self> {
+        let (url, key) = link.split_once('#').context("link is missing its key")?;
+        let (scheme, rest) = url
+            .split_once("://")
+            .context("link is missing its scheme")?;
+        let (authority, path) = rest.split_once('/').context("link is missing a path")?;
+        let path = path
+            .strip_prefix("s/")
+            .context("link is not for a session")?;
+        let secure = match scheme {
+            "https" => true,
+            "http" => false,
+            _ => bail!("unsupported scheme {scheme:?} in link"),
+        };
+
+        let kdf_key = key.to_owned();
+        let kdf_task = tokio::task::spawn_blocking(move || Encrypt::new(&kdf_key));
+        let stream = connect_transport(authority, secure).await?;
+        let ws_url = format!(
+            "{}://{authority}/api/s/{path}",
+            if secure { "wss" } else { "ws" }
+        );
+        debug!(%ws_url, "connecting to session");
+        let (socket, _) = tokio_tungstenite::client_async(ws_url, stream).await?;
+
+        let mut viewer = Self {
+            socket,
+            encrypt: kdf_task.await?,
+            input_offset: random_offset(),
+            shells: BTreeMap::new(),
+            seqnums: HashMap::new(),
+        };
+        let zeros = viewer.encrypt.zeros().into();
+        viewer.send(WsClient::Authenticate(zeros)).await?;
+        Ok(viewer)
+    }
+
+    /// Send a message to the server.
+    async fn send(&mut self, msg: WsClient) -> Result<()> {
+        self.socket.send(Message::Binary(ws::encode(&msg)?)).await?;
+        Ok(())
+    }
+
+    /// Send a TOTP code, after the server asks for one.
+    pub async fn send_totp(&mut self, code: &str) -> Result<()> {
+        self.send(WsClient::Totp(code.trim().into())).await
+    }
+
+    /// Set the name that other users see for this viewer.
+    pub async fn set_name(&mut self, name: &str) -> Result<()> {
+        self.send(WsClient::SetName(name.into())).await
+    }
+
+    /// Start receiving output from a shell, beginning with its current screen.
+    pub async fn subscribe(&mut self, id: Sid) -> Result<()> {
+        self.seqnums.entry(id).or_insert(0);
+        self.send(WsClient::SubscribeScreen(id)).await
+    }
+
+    /// Type input into a shell.
+    pub async fn send_input(&mut self, id: Sid, data: &[u8]) -> Result<()> {
+        let offset = self.input_offset;
+        self.input_offset += data.len() as u64;
+        let data = self.encrypt.segment(0x200000000, offset, data);
+        self.send(WsClient::Data(id, data.into(), offset)).await
+    }
+
+    /// Resize a shell, keeping the position of its window.
+    pub async fn resize(&mut self, id: Sid, rows: u16, cols: u16) -> Result<()> {
+        let Some(&winsize) = self.shells.get(&id) else {
+            return Ok(());
+        };
+        let winsize = WsWinsize {
+            rows,
+            cols,
+            ..winsize
+        };
+        self.send(WsClient::Move(id, Some(winsize))).await
+    }
+
+    /// Receive the next event from the session, or `None` once it closes.
+    pub async fn recv(&mut self) -> Result<Option<ViewerEvent>> {
+        loop {
+            let buf = match self.socket.next().await.transpose()? {
+                Some(Message::Binary(buf)) => buf,
+                Some(Message::Close(Some(frame))) if !frame.reason.is_empty() => {
+                    bail!("session closed: {}", frame.reason);
+                }
+                Some(Message::Close(_)) | None => return Ok(None),
+                Some(_) => continue,
+            };
+            let msg = match ws::decode(&buf) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    trace!(%err, "skipping message not handled by viewer");
+                    continue;
+                }
+            };
+            let event = match msg {
+                WsServer::Hello(uid) => {
+                    debug!(%uid, "connected to session");
+                    continue;
+                }
+                WsServer::InvalidAuth() => bail!("invalid encryption key or code"),
+                WsServer::TotpRequired() => ViewerEvent::TotpRequired,
+                WsServer::Users(_) => ViewerEvent::Joined,
+                WsServer::Shells(shells) => {
+                    self.shells = (shells.into_iter())
+                        .map(|(id, winsize, _)| (id, winsize))
+                        .collect();
+                    ViewerEvent::Shells(self.shells.keys().copied().collect())
+                }
+                WsServer::Chunks(id, mut offset, chunks) => {
+                    let Some(seq) = self.seqnums.get_mut(&id) else {
+                        continue;
+                    };
+                    let mut output = Vec::new();
+                    for chunk in chunks {
+                        let end = offset + chunk.len() as u64;
+                        if end > *seq {
+                            // Skip output that was already shown in a screen.
+                            let skip = seq.saturating_sub(offset);
+                            let stream = 0x100000000 | id.0 as u64;
+                            let data = &chunk[skip as usize..];
+                            output.extend(self.encrypt.segment(stream, offset + skip, data));
+                            *seq = end;
+                        }
+                        offset = end;
+                    }
+                    if output.is_empty() {
+                        continue;
+                    }
+                    ViewerEvent::Output(id, output)
+                }
+                WsServer::Screen(id, seq, offset, data) => {
+                    let Some(seqnum) = self.seqnums.get_mut(&id) else {
+                        continue;
+                    };
+                    *seqnum = seq;
+                    let stream = 0x300000000 | id.0 as u64;
+                    ViewerEvent::Output(id, self.encrypt.segment(stream, offset, &data))
+                }
+                WsServer::ShellExited(id, code) => ViewerEvent::ShellExited(id, code),
+                WsServer::Error(_, msg) => ViewerEvent::Error(msg),
+                WsServer::ReadOnly(_) => ViewerEvent::ReadOnly,
+                _ => continue,
+            };
+            return Ok(Some(event));
+        }
+    }
+}
+
+/// Open a connection to a server, with TLS if the link is secure.
+async fn connect_transport(authority: &str, secure: bool) -> Result<Box<dyn Transport>> {
+    let default_port = if secure { 443 } else { 80 };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.ends_with(']') => (host, port.parse()?),
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let tcp = TcpStream::connect((host, port)).await?;
+    tcp.set_nodelay(true)?;
+    if !secure {
+        return Ok(Box::new(tcp));
+    }
+
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(host).context("invalid server name")?;
+    let tls = TlsConnector::from(Arc::new(config))
+        .connect(name, tcp)
+        .await?;
+    Ok(Box::new(tls))
+}
+
+/// Puts the local terminal in raw mode, restoring it when dropped.
+struct RawMode(Termios);
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        let stdin = std::io::stdin();
+        let original = termios::tcgetattr(&stdin).context("standard input is not a terminal")?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(&stdin, SetArg::TCSADRAIN, &raw)?;
+        Ok(Self(original))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        termios::tcsetattr(std::io::stdin(), SetArg::TCSADRAIN, &self.0).ok();
+    }
+}
+
+/// Attach the local terminal to a shell in a session, until the shell closes
+/// or the user presses [`DETACH_KEY`].
+///
+/// Without a shell ID, this follows the first shell that is open.
+pub async fn attach(link: &str, shell: Option<Sid>, name: Option<&str>) -> Result<()> {
+    let mut viewer = Viewer::connect(link).await?;
+    let mut read_only = false;
+    let mut shells = Vec::new();
+    loop {
+        match viewer
+            .recv()
+            .await?
+            .context("session closed before joining")?
+        {
+            ViewerEvent::TotpRequired => {
+                eprint!("Authentication code: ");
+                let mut code = String::new();
+                std::io::stdin().read_line(&mut code)?;
+                viewer.send_totp(&code).await?;
+            }
+            ViewerEvent::Joined => break,
+            ViewerEvent::ReadOnly => read_only = true,
+            ViewerEvent::Shells(ids) => shells = ids,
+            ViewerEvent::Error(msg) => eprintln!("sshx: {msg}"),
+            _ => (),
+        }
+    }
+    if let Some(name) = name {
+        viewer.set_name(name).await?;
+    }
+
+    let id = match shell {
+        Some(id) => id,
+        None => loop {
+            if let Some(&id) = shells.first() {
+                break id;
+            }
+            eprintln!("sshx: waiting for a shell to open...");
+            match viewer.recv().await? {
+                Some(ViewerEvent::Shells(ids)) => shells = ids,
+                Some(ViewerEvent::ReadOnly) => read_only = true,
+                Some(_) => (),
+                None => return Ok(()),
+            }
+        },
+    };
+    let mode = if read_only { ", read-only" } else { "" };
+    eprintln!("sshx: attached to shell {id}{mode}, press Ctrl-] to detach\r");
+
+    let raw_mode = RawMode::enable()?;
+    viewer.subscribe(id).await?;
+    if let Some((rows, cols)) = local_winsize().filter(|_| !read_only) {
+        viewer.resize(id, rows, cols).await?;
+    }
+    let mut winch = signal(SignalKind::window_change())?;
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut buf = [0; 1024];
+    let reason = loop {
+        tokio::select! {
+            event = viewer.recv() => match event? {
+                Some(ViewerEvent::Output(_, data)) => {
+                    stdout.write_all(&data).await?;
+                    stdout.flush().await?;
+                }
+                Some(ViewerEvent::Shells(ids)) if !ids.contains(&id) => break "shell closed",
+                Some(ViewerEvent::ShellExited(_, code)) if code != 0 => {
+                    eprint!("\r\nsshx: shell exited with code {code}\r\n");
+                }
+                Some(_) => (),
+                None => break "session closed",
+            },
+            n = stdin.read(&mut buf) => {
+                let data = &buf[..n?];
+                let (data, detach) = match data.iter().position(|&b| b == DETACH_KEY) {
+                    Some(i) => (&data[..i], true),
+                    None => (data, data.is_empty()),
+                };
+                if !data.is_empty() && !read_only {
+                    viewer.send_input(id, data).await?;
+                }
+                if detach {
+                    break "detached";
+                }
+            }
+            _ = winch.recv(), if !read_only => {
+                if let Some((rows, cols)) = local_winsize() {
+                    viewer.resize(id, rows, cols).await?;
+                }
+            }
+        }
+    };
+    drop(raw_mode);
+    std::io::stdout().flush().ok();
+    eprintln!("\nsshx: {reason}");
+    Ok(())
+}
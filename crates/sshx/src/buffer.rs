@@ -0,0 +1,241 @@
+//! Local buffering of client messages while disconnected from the server.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+
+use anyhow::Result;
+use prost::Message;
+use sshx_core::proto::{client_update::ClientMessage, ClientUpdate};
+use tempfile::NamedTempFile;
+use tracing::{debug, warn};
+
+/// Keep at most this many bytes of buffered messages in memory.
+const MEMORY_LIMIT: usize = 1 << 20; // 1 MiB
+
+/// Spill at most this many bytes of terminal data to disk before dropping it.
+const SPILL_LIMIT: u64 = 64 << 20; // 64 MiB
+
+/// Bounded queue of client messages, waiting to be replayed on reconnection.
+///
+/// Messages are kept in memory up to a limit, then appended to a temporary
+/// file. Terminal data past the file limit is dropped, which the server later
+/// recovers through the periodic sequence number sync if it is still in the
+/// shell's rolling content buffer.
+pub struct OutputBuffer {
+    memory: VecDeque<ClientMessage>,
+    memory_bytes: usize,
+    memory_limit: usize,
+    spill: Option<Spill>,
+    spill_limit: u64,
+    dropped: bool,
+}
+
+/// Temporary file holding length-prefixed, encoded [`ClientUpdate`] messages.
+struct Spill {
+    writer: BufWriter<NamedTempFile>,
+    reader: Option<BufReader<File>>,
+    written: u64,
+    unread: usize,
+}
+
+impl Default for OutputBuffer {
+    fn default() -> Self {
+        Self::with_limits(MEMORY_LIMIT, SPILL_LIMIT)
+    }
+}
+
+impl OutputBuffer {
+    /// Construct a new, empty buffer with the default limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Construct a new, empty buffer with custom memory and disk limits.
+    pub fn with_limits(memory_limit: usize, spill_limit: u64) -> Self {
+        Self {
+            memory: VecDeque::new(),
+            memory_bytes: 0,
+            memory_limit,
+            spill: None,
+            spill_limit,
+            dropped: false,
+        }
+    }
+
+    /// Returns whether there are no buffered messages.
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty() && self.spill.as_ref().is_none_or(|s| s.unread == 0)
+    }
+
+    /// Add a message to the end of the buffer.
+    pub fn push(&mut self, msg: ClientMessage) -> Result<()> {
+        let update = ClientUpdate {
+            client_message: Some(msg),
+        };
+        let len = update.encoded_len();
+        if self.spill.is_none() && self.memory_bytes + len <= self.memory_limit {
+            self.memory_bytes += len;
+            self.memory.extend(update.client_message);
+            return Ok(());
+        }
+
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => {
+                debug!("spilling buffered output to a temporary file");
+                self.spill.insert(Spill {
+                    writer: BufWriter::new(NamedTempFile::new()?),
+                    reader: None,
+                    written: 0,
+                    unread: 0,
+                })
+            }
+        };
+        let is_data = matches!(update.client_message, Some(ClientMessage::Data(_)));
+        if is_data && spill.written + len as u64 > self.spill_limit {
+            if !self.dropped {
+                warn!("output buffer is full, dropping terminal data until reconnected");
+                self.dropped = true;
+            }
+            return Ok(());
+        }
+        spill.writer.write_all(&(len as u32).to_le_bytes())?;
+        spill.writer.write_all(&update.encode_to_vec())?;
+        spill.written += 4 + len as u64;
+        spill.unread += 1;
+        Ok(())
+    }
+
+    /// Return a message to the front of the buffer, ahead of everything else.
+    ///
+    /// This is for messages that were popped but could not be sent, so they
+    /// are kept in memory regardless of the limit to preserve their order.
+    pub fn push_front(&mut self, msg: ClientMessage) {
+        let update = ClientUpdate {
+            client_message: Some(msg),
+        };
+        self.memory_bytes += update.encoded_len();
+        if let Some(msg) = update.client_message {
+            self.memory.push_front(msg);
+        }
+    }
+
+    /// Remove and return the message at the front of the buffer.
+    pub fn pop(&mut self) -> Result<Option<ClientMessage>> {
+        if let Some(msg) = self.memory.pop_front() {
+            let update = ClientUpdate {
+                client_message: Some(msg),
+            };
+            self.memory_bytes -= update.encoded_len();
+            return Ok(update.client_message);
+        }
+
+        let Some(spill) = &mut self.spill else {
+            return Ok(None);
+        };
+        if spill.unread == 0 {
+            self.spill = None;
+            self.dropped = false;
+            return Ok(None);
+        }
+        spill.writer.flush()?;
+        let reader = match &mut spill.reader {
+            Some(reader) => reader,
+            None => spill
+                .reader
+                .insert(BufReader::new(spill.writer.get_ref().reopen()?)),
+        };
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let mut buf = vec![0; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut buf)?;
+        spill.unread -= 1;
+        match ClientUpdate::decode(&*buf) {
+            Ok(update) => Ok(update.client_message),
+            Err(err) => Err(std::io::Error::new(ErrorKind::InvalidData, err).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use sshx_core::proto::{client_update::ClientMessage, TerminalData};
+
+    use super::OutputBuffer;
+
+    fn data(seq: u64) -> ClientMessage {
+        ClientMessage::Data(TerminalData {
+            id: 1,
+            data: vec![b'x'; 100].into(),
+            seq,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn memory_only() -> Result<()> {
+        let mut buffer = OutputBuffer::new();
+        assert!(buffer.is_empty());
+        buffer.push(data(0))?;
+        buffer.push(ClientMessage::ClosedShell(1))?;
+        assert!(!buffer.is_empty());
+        assert_eq!(buffer.pop()?, Some(data(0)));
+        assert_eq!(buffer.pop()?, Some(ClientMessage::ClosedShell(1)));
+        assert_eq!(buffer.pop()?, None);
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn spill_to_disk() -> Result<()> {
+        let mut buffer = OutputBuffer::with_limits(500, 1 << 20);
+        for i in 0..20 {
+            buffer.push(data(i * 100))?;
+        }
+        for i in 0..10 {
+            assert_eq!(buffer.pop()?, Some(data(i * 100)));
+        }
+        // Pushes interleaved with reads are appended after the unread messages.
+        buffer.push(data(2000))?;
+        for i in 10..21 {
+            assert_eq!(buffer.pop()?, Some(data(i * 100)));
+        }
+        assert_eq!(buffer.pop()?, None);
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn drop_data_past_limit() -> Result<()> {
+        let mut buffer = OutputBuffer::with_limits(0, 500);
+        for i in 0..10 {
+            buffer.push(data(i * 100))?;
+        }
+        buffer.push(ClientMessage::ClosedShell(1))?;
+        let mut popped = Vec::new();
+        while let Some(msg) = buffer.pop()? {
+            popped.push(msg);
+        }
+        let mut expected: Vec<_> = (0..4).map(|i| data(i * 100)).collect();
+        expected.push(ClientMessage::ClosedShell(1));
+        assert_eq!(popped, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn push_front_after_spill() -> Result<()> {
+        let mut buffer = OutputBuffer::with_limits(0, 1 << 20);
+        for i in 0..3 {
+            buffer.push(data(i * 100))?;
+        }
+        let msg = buffer.pop()?.unwrap();
+        buffer.push_front(msg);
+        for i in 0..3 {
+            assert_eq!(buffer.pop()?, Some(data(i * 100)));
+        }
+        assert_eq!(buffer.pop()?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+//! Configuration file for running several standing sessions with `sshx up`,
+//! and templates of pre-arranged shells.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::controller::ShellLayout;
+
+/// Top-level contents of an `sshx.json` configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct UpConfig {
+    /// Address of the remote sshx server, if not the default.
+    #[serde(default)]
+    pub server: Option<String>,
+
+    /// Sessions to open and keep running.
+    pub sessions: Vec<SessionConfig>,
+
+    /// Templates that sessions can use, besides those in the user's
+    /// templates file.
+    #[serde(default)]
+    pub templates: HashMap<String, Template>,
+}
+
+/// Definition of a single named session in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Local name of the session, used in output and for resuming.
+    pub name: String,
+
+    /// Shell command to run in the session's terminals.
+    #[serde(default)]
+    pub shell: Option<String>,
+
+    /// Commands written to each new terminal when it starts.
+    #[serde(default)]
+    pub init: Vec<String>,
+
+    /// Name of a template for the shells that the session starts with.
+    #[serde(default)]
+    pub template: Option<String>,
+
+    /// Reclaim the same session URL when `sshx up` is restarted.
+    #[serde(default)]
+    pub resume: bool,
+
+    /// Batch output and reduce update rates, for very poor links.
+    #[serde(default)]
+    pub low_bandwidth: bool,
+
+    /// Collapse rapidly redrawn progress bars and spinners in output.
+    #[serde(default)]
+    pub collapse_redraws: bool,
+
+    /// Refuse all input from viewers, so they can only watch.
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Only run commands typed by viewers that start with these words.
+    #[serde(default)]
+    pub allow_commands: Vec<String>,
+
+    /// Block commands typed by viewers that start with these words.
+    #[serde(default)]
+    pub deny_commands: Vec<String>,
+
+    /// Require viewers to enter a TOTP code from an authenticator app.
+    #[serde(default)]
+    pub totp: bool,
+
+    /// Message shown to each viewer when they join.
+    #[serde(default)]
+    pub banner: Option<String>,
+
+    /// Overlay a watermark identifying each viewer on their view.
+    #[serde(default)]
+    pub watermark: bool,
+
+    /// Maximum bytes of output per second relayed from each shell.
+    #[serde(default)]
+    pub shell_bandwidth: Option<u32>,
+
+    /// Bytes of output each shell can send before it must be acknowledged.
+    #[serde(default)]
+    pub flow_window: Option<u64>,
+
+    /// Close the session after this long, like `"45m"` or `"2h"`.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub time_limit: Option<Duration>,
+
+    /// Run the session's terminals in a sandbox.
+    #[serde(default)]
+    pub sandbox: Option<SandboxConfig>,
+}
+
+/// Shells that a session starts with, arranged ahead of time.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Template {
+    /// Shells to open, in order.
+    pub shells: Vec<ShellTemplate>,
+}
+
+/// Definition of a single shell in a template.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ShellTemplate {
+    /// Title shown on the shell's window.
+    #[serde(default)]
+    pub title: Option<String>,
+
+    /// Horizontal position of the shell's window.
+    #[serde(default)]
+    pub x: i32,
+
+    /// Vertical position of the shell's window.
+    #[serde(default)]
+    pub y: i32,
+
+    /// Number of rows, 24 if not set.
+    #[serde(default)]
+    pub rows: Option<u16>,
+
+    /// Number of columns, 80 if not set.
+    #[serde(default)]
+    pub cols: Option<u16>,
+
+    /// Commands written to the shell when it starts.
+    #[serde(default)]
+    pub init: Vec<String>,
+}
+
+impl ShellTemplate {
+    /// Returns the layout for creating this shell.
+    pub fn layout(&self) -> ShellLayout {
+        let mut layout = ShellLayout::at((self.x, self.y));
+        layout.size = match (self.rows, self.cols) {
+            (None, None) => None,
+            (rows, cols) => Some((rows.unwrap_or(24), cols.unwrap_or(80))),
+        };
+        layout.title = self.title.clone();
+        layout.init_commands = self.init.clone();
+        layout
+    }
+}
+
+/// Read the templates defined in a file, keyed by name.
+///
+/// A missing file has no templates.
+pub fn load_templates(path: &Path) -> Result<HashMap<String, Template>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+    parse_templates(&text).with_context(|| format!("invalid templates {}", path.display()))
+}
+
+/// Parse and validate templates, keyed by name.
+pub fn parse_templates(text: &str) -> Result<HashMap<String, Template>> {
+    let templates: HashMap<String, Template> = serde_json::from_str(text)?;
+    for (name, template) in &templates {
+        template
+            .validate()
+            .with_context(|| format!("template {name:?}"))?;
+    }
+    Ok(templates)
+}
+
+impl Template {
+    fn validate(&self) -> Result<()> {
+        ensure!(!self.shells.is_empty(), "no shells are defined");
+        for shell in &self.shells {
+            ensure!(
+                shell.rows != Some(0) && shell.cols != Some(0),
+                "shell size must be positive",
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Returns the default location of the user's templates file.
+pub fn templates_path() -> Result<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME").context("could not find home directory")?;
+            PathBuf::from(home).join(".config")
+        }
+    };
+    Ok(config_dir.join("sshx/templates.json"))
+}
+
+/// Paths accessible to a sandboxed session, in addition to system paths.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// Extra paths that terminals can read.
+    #[serde(default)]
+    pub read: Vec<PathBuf>,
+
+    /// Paths that terminals can read and write.
+    #[serde(default)]
+    pub write: Vec<PathBuf>,
+}
+
+/// Parse a duration with a unit suffix, like `90s`, `45m`, or `2h`.
+///
+/// A bare number is interpreted as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = value.parse().context("duration must start with a number")?;
+    let scale = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => bail!("unknown duration unit {unit:?}, expected s, m, h, or d"),
+    };
+    let secs = value.checked_mul(scale).context("duration is too long")?;
+    ensure!(secs > 0, "duration must be positive");
+    Ok(Duration::from_secs(secs))
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    match Option::<String>::deserialize(d)? {
+        Some(s) => parse_duration(&s)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+impl UpConfig {
+    /// Read and validate a configuration file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Parse and validate configuration text.
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: Self = serde_json::from_str(text)?;
+        ensure!(!config.sessions.is_empty(), "no sessions are defined");
+        let mut names = HashSet::new();
+        for session in &config.sessions {
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            ensure!(
+                !session.name.is_empty() && session.name.chars().all(valid),
+                "session name {:?} must be alphanumeric, dashes or underscores",
+                session.name,
+            );
+            ensure!(
+                names.insert(&session.name),
+                "duplicate session name {:?}",
+                session.name,
+            );
+        }
+        for (name, template) in &config.templates {
+            template
+                .validate()
+                .with_context(|| format!("template {name:?}"))?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_duration, parse_templates, UpConfig};
+
+    #[test]
+    fn parse_config() {
+        let config = UpConfig::parse(
+            r#"{
+                "server": "https://example.com",
+                "sessions": [
+                    { "name": "build", "init": ["cd /src", "make watch"], "resume": true,
+                      "denyCommands": ["rm"], "collapseRedraws": true },
+                    { "name": "db", "shell": "psql", "lowBandwidth": true,
+                      "sandbox": { "write": ["/var/db"] }, "timeLimit": "2h" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.server.as_deref(), Some("https://example.com"));
+        assert_eq!(config.sessions.len(), 2);
+        assert_eq!(config.sessions[0].init, ["cd /src", "make watch"]);
+        assert!(config.sessions[0].resume);
+        assert_eq!(config.sessions[0].deny_commands, ["rm"]);
+        assert!(config.sessions[0].collapse_redraws);
+        assert!(!config.sessions[1].collapse_redraws);
+        assert_eq!(config.sessions[1].shell.as_deref(), Some("psql"));
+        assert!(config.sessions[1].low_bandwidth);
+        assert_eq!(config.sessions[0].time_limit, None);
+        assert_eq!(
+            config.sessions[1].time_limit,
+            Some(Duration::from_secs(7200))
+        );
+        assert!(config.sessions[0].sandbox.is_none());
+        let sandbox = config.sessions[1].sandbox.as_ref().unwrap();
+        assert!(sandbox.read.is_empty());
+        assert_eq!(sandbox.write, [std::path::Path::new("/var/db")]);
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        assert!(UpConfig::parse(r#"{ "sessions": [] }"#).is_err());
+        assert!(UpConfig::parse(r#"{ "sessions": [{ "name": "a b" }] }"#).is_err());
+        assert!(UpConfig::parse(r#"{ "sessions": [{ "name": "a" }, { "name": "a" }] }"#).is_err());
+        assert!(UpConfig::parse(r#"{ "sessions": [{ "name": "a", "shel": "sh" }] }"#).is_err());
+        let bad_limit = r#"{ "sessions": [{ "name": "a", "timeLimit": "1w" }] }"#;
+        assert!(UpConfig::parse(bad_limit).is_err());
+    }
+
+    #[test]
+    fn parse_template_files() {
+        let templates = parse_templates(
+            r#"{
+                "incident": { "shells": [
+                    { "title": "logs", "rows": 40, "cols": 120, "init": ["journalctl -f"] },
+                    { "title": "debug", "x": 800 }
+                ] }
+            }"#,
+        )
+        .unwrap();
+        let shells = &templates["incident"].shells;
+        let layout = shells[0].layout();
+        assert_eq!(layout.size, Some((40, 120)));
+        assert_eq!(layout.title.as_deref(), Some("logs"));
+        assert_eq!(layout.init_commands, ["journalctl -f"]);
+        let layout = shells[1].layout();
+        assert_eq!((layout.center, layout.size), ((800, 0), None));
+
+        assert!(parse_templates(r#"{ "empty": { "shells": [] } }"#).is_err());
+        assert!(parse_templates(r#"{ "a": { "shells": [{ "rows": 0 }] } }"#).is_err());
+        let config = r#"{ "sessions": [{ "name": "a", "template": "t" }],
+                          "templates": { "t": { "shells": [{ "titel": "x" }] } } }"#;
+        assert!(UpConfig::parse(config).is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("45m").unwrap(), Duration::from_secs(2700));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5 years").is_err());
+    }
+}
@@ -1,36 +1,322 @@
 //! Network gRPC client allowing server control of terminals.
 
 use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use sshx_core::feature::HOST_FEATURES;
 use sshx_core::proto::{
     client_update::ClientMessage, server_update::ServerMessage,
-    sshx_service_client::SshxServiceClient, ClientUpdate, CloseRequest, NewShell, OpenRequest,
+    sshx_service_client::SshxServiceClient, AccessEvent, AdoptRequest, ClientUpdate, CloseRequest,
+    ForkedSession, NewShell, OpenRequest, OpenResponse, SizePolicy, ViewerDecision,
 };
-use sshx_core::{rand_alphanumeric, Sid};
-use tokio::sync::mpsc;
-use tokio::task;
+use sshx_core::{rand_alphanumeric, telemetry, totp, ErrorCode, Sid, Uid, PROTOCOL_VERSION};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
 use tokio::time::{self, Duration, Instant, MissedTickBehavior};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tonic::transport::Channel;
-use tracing::{debug, error, warn};
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Status};
+use tracing::{debug, error, info, instrument, trace, warn};
 
+use crate::buffer::OutputBuffer;
 use crate::encrypt::Encrypt;
-use crate::runner::{Runner, ShellData};
+use crate::gatekeeper::{CommandFilter, LineGate};
+use crate::resume::SavedSession;
+use crate::runner::{Runner, ShellData, ShellOptions};
+use crate::sandbox::Sandbox;
+use crate::terminal::get_default_shell;
+use crate::throttle::{Rate, Throttle};
+use crate::transfer::{FileOptions, FileRequest, Transfers};
 
 /// Interval for sending empty heartbeat messages to the server.
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
 
+/// Interval for batching shell output in low-bandwidth mode.
+const LOW_BANDWIDTH_BATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest time that output is held to collapse redrawn progress bars.
+const COLLAPSE_REDRAWS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Longest time to wait for each shell's output when forking the session.
+const FORK_SCROLLBACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Environment variables describing the session, which the host can choose
+/// to set in each shell.
+pub const SESSION_VARIABLES: [&str; 4] = [
+    "SSHX_SESSION_URL",
+    "SSHX_SESSION_NAME",
+    "SSHX_WRITE_URL",
+    "SSHX_SHELL_ID",
+];
+
+/// Options that control how a session communicates with the server.
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub struct ControllerOptions {
+    /// Limit on the rate of terminal output uploaded to the server.
+    pub max_upload_rate: Option<Rate>,
+
+    /// Batch output and reduce update rates, for very poor links.
+    pub low_bandwidth: bool,
+
+    /// Collapse rapid redraws of progress bars and spinners before sending
+    /// output, so that they take up less of the session's history.
+    pub collapse_redraws: bool,
+
+    /// Commands written to each new shell when it starts.
+    pub init_commands: Vec<String>,
+
+    /// Program and arguments that shells run instead of the default shell,
+    /// like `tmux attach -t work`.
+    pub command: Option<Vec<String>>,
+
+    /// Environment variables set in each shell, either names from
+    /// [`SESSION_VARIABLES`] or `NAME=VALUE` pairs. Other names are ignored.
+    pub env: Vec<String>,
+
+    /// Refuse all input to shells, so that viewers can only watch.
+    ///
+    /// This is enforced by the client, so it holds even if the server or a
+    /// viewer's browser sends input anyway.
+    pub read_only: bool,
+
+    /// Sandbox profile applied to spawned shells.
+    pub sandbox: Option<Sandbox>,
+
+    /// Filter for command lines typed by viewers, blocking those not allowed.
+    pub command_filter: Option<CommandFilter>,
+
+    /// Require viewers to enter a TOTP code, using a newly generated secret.
+    pub totp: bool,
+
+    /// Maximum lifetime of the session, after which it is closed.
+    pub time_limit: Option<Duration>,
+
+    /// Message shown to each viewer when they join, like rules for the session.
+    pub banner: Option<String>,
+
+    /// Overlay a watermark identifying each viewer on their view of the session.
+    pub watermark: bool,
+
+    /// Maximum bytes of output per second relayed from each shell to viewers.
+    pub shell_bandwidth: Option<u32>,
+
+    /// Bytes of output each shell can send before the server acknowledges
+    /// it, so that a flood of output is slowed to what viewers consume.
+    pub flow_window: Option<u64>,
+
+    /// Reserve the session for the host to connect at a later time, such as
+    /// for a scheduled class. Viewers who join early wait for the host.
+    pub scheduled: Option<SystemTime>,
+
+    /// API key of the server namespace that owns the session.
+    pub api_key: Option<String>,
+
+    /// Make the session's link read-only, and create a separate link that
+    /// lets viewers write to shells.
+    pub write_link: bool,
+
+    /// Password that viewers must enter before writing to shells.
+    pub write_password: Option<String>,
+
+    /// Custom name for the session in its URL, if not already taken.
+    pub session_name: Option<String>,
+
+    /// Ask the server for a pronounceable session name, like
+    /// `calm-otter-42`, which is easier to read out over a call.
+    pub memorable_name: bool,
+
+    /// How the server picks the size of a shell when viewers resize it
+    /// differently.
+    pub size_policy: SizePolicy,
+
+    /// Let viewers upload files to the host and download files from it.
+    /// Transfers are refused if this is not set.
+    pub files: Option<FileOptions>,
+
+    /// Encryption key for the session, instead of a random one. It must be
+    /// alphanumeric, and long enough that it cannot be guessed.
+    pub encryption_key: Option<String>,
+
+    /// How to retry after losing the connection to the server.
+    pub reconnect: ReconnectPolicy,
+
+    /// Hold each new viewer until the host lets them in, so that a leaked
+    /// link does not grant access by itself. Viewers are turned away unless
+    /// they are admitted through [`Controller::confirm_viewers`].
+    pub knock: bool,
+}
+
+/// How the controller retries after losing its connection to the server.
+///
+/// Retries continue forever, with exponential backoff. To give up, stop
+/// polling [`Controller::run`] after some number of disconnections.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ReconnectPolicy {
+    /// Delay before the first retry, doubled after each failed one.
+    pub initial_delay: Duration,
+
+    /// Longest delay between retries.
+    pub max_delay: Duration,
+
+    /// Start over from the initial delay once a connection has stayed up for
+    /// this long.
+    pub reset_after: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(16),
+            reset_after: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns how long to wait after some number of failed retries.
+    pub fn delay(&self, retries: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(retries);
+        (self.initial_delay.saturating_mul(factor)).min(self.max_delay)
+    }
+}
+
+/// Something that happened in a session, reported to programs embedding the
+/// client through [`Controller::on_event`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ControllerEvent {
+    /// The controller connected to the server, and viewers can join at this
+    /// URL. This is sent again after each reconnection.
+    Ready {
+        /// URL of the session, including its encryption key.
+        url: String,
+    },
+
+    /// A shell was started, by the host or at a viewer's request.
+    ShellCreated {
+        /// ID of the shell.
+        id: Sid,
+    },
+
+    /// A shell was closed, or its process exited.
+    ShellClosed {
+        /// ID of the shell.
+        id: Sid,
+    },
+
+    /// The connection to the server was lost, and will be retried.
+    Disconnected {
+        /// Error that ended the connection.
+        error: String,
+        /// Delay before the next attempt to reconnect.
+        retry_in: Duration,
+    },
+
+    /// Another client adopted the session with `sshx adopt`. The controller
+    /// stops reconnecting, and closing it leaves the session open.
+    Replaced,
+}
+
+/// Callback for events in a session.
+type EventHandler = Arc<dyn Fn(&ControllerEvent) + Send + Sync>;
+
+/// Call each handler with an event.
+fn emit(handlers: &[EventHandler], event: ControllerEvent) {
+    for handler in handlers {
+        handler(&event);
+    }
+}
+
+/// A viewer waiting for the host to let them into a session, received from
+/// [`Controller::confirm_viewers`].
+#[derive(Debug)]
+pub struct ViewerRequest {
+    /// ID of the viewer.
+    pub uid: Uid,
+    /// IP address of the viewer, if the server knows it.
+    pub address: Option<String>,
+    reply: oneshot::Sender<bool>,
+}
+
+impl ViewerRequest {
+    /// Let the viewer join the session.
+    pub fn admit(self) {
+        self.reply.send(true).ok();
+    }
+
+    /// Turn the viewer away, disconnecting them.
+    pub fn deny(self) {
+        self.reply.send(false).ok();
+    }
+}
+
+/// Position, size, and startup commands for a shell created by the client.
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub struct ShellLayout {
+    /// Position of the shell's window in the session.
+    pub center: (i32, i32),
+
+    /// Number of rows and columns, if not the default size.
+    pub size: Option<(u16, u16)>,
+
+    /// Title shown on the shell's window, until the shell sets its own.
+    pub title: Option<String>,
+
+    /// Commands written to the shell when it starts, after those for every
+    /// shell in the session.
+    pub init_commands: Vec<String>,
+
+    /// Working directory of the shell, if not the current one.
+    pub cwd: Option<PathBuf>,
+
+    /// Program and arguments to run, if not the session's default.
+    pub argv: Option<Vec<String>>,
+}
+
+impl ShellLayout {
+    /// A shell with the default size, centered at a position.
+    pub fn at(center: (i32, i32)) -> Self {
+        Self {
+            center,
+            ..Default::default()
+        }
+    }
+}
+
+/// Interceptor that adds metadata to each outgoing gRPC request.
+type Interceptor = fn(Request<()>) -> Result<Request<()>, Status>;
+
+/// Client for the gRPC API, which sends trace context with each request.
+type GrpcClient = SshxServiceClient<InterceptedService<Channel, Interceptor>>;
+
 /// Handles a single session's communication with the remote server.
 pub struct Controller {
     origin: String,
     runner: Runner,
     encrypt: Encrypt,
     encryption_key: String,
+    totp_secret: Option<Vec<u8>>,
+    deadline: Option<SystemTime>,
 
     name: String,
     token: String,
     url: String,
+    write_url: Option<String>,
+    /// Identity of this client as the host, if it adopted the session.
+    host: Option<String>,
+    /// Set once another client adopts the session, which is no longer ours.
+    replaced: bool,
+    /// Whether this client runs some of the shells, beside the main host.
+    co_host: bool,
 
     /// Channels with backpressure routing messages to each shell task.
     shells_tx: HashMap<Sid, mpsc::Sender<ShellData>>,
@@ -38,40 +324,375 @@ pub struct Controller {
     output_tx: mpsc::Sender<ClientMessage>,
     /// Owned receiving end of the `output_tx` channel.
     output_rx: mpsc::Receiver<ClientMessage>,
+    /// Messages produced while disconnected, replayed after reconnecting.
+    buffer: OutputBuffer,
+    /// Optional limit on the rate of terminal data sent to the server.
+    throttle: Option<Throttle>,
+    /// Terminal data received from a shell but held back by the throttle.
+    paced: Option<ClientMessage>,
+    /// Options for spawned shells, such as output batching and sandboxing.
+    shell_options: ShellOptions,
+    /// Commands written to each new shell when it starts.
+    init_commands: Vec<String>,
+    /// Refuse all input to shells, so that viewers can only watch.
+    read_only: bool,
+    /// Filter for command lines typed by viewers.
+    command_filter: Option<CommandFilter>,
+    /// Input line being typed into each shell, tracked for the command filter.
+    line_gates: HashMap<Sid, LineGate>,
+    /// Channel for viewers waiting to be let in, if the host confirms them.
+    viewers_tx: Option<mpsc::UnboundedSender<ViewerRequest>>,
+    /// Channel for access log entries, if the host is watching them.
+    access_tx: Option<mpsc::UnboundedSender<AccessEvent>>,
+    /// Sequence number of the next access log entry to receive.
+    access_seq: u64,
+    /// Files being uploaded by viewers or downloaded from the host.
+    transfers: Transfers,
+    /// Options used to open this session, reused for forks of it.
+    options: ControllerOptions,
+    /// Output copied from another session, shown first in each new shell.
+    seeds: HashMap<Sid, String>,
+    /// Sessions forked from this one, with signals to close them.
+    forks: Mutex<Vec<(oneshot::Sender<()>, JoinHandle<()>)>>,
+    /// Callbacks for events in the session, from programs embedding it.
+    handlers: Vec<EventHandler>,
 }
 
 impl Controller {
     /// Construct a new controller, connecting to the remote server.
     pub async fn new(origin: &str, runner: Runner) -> Result<Self> {
+        Self::with_options(origin, runner, ControllerOptions::default()).await
+    }
+
+    /// Start building a controller for a server, for programs that embed the
+    /// client.
+    pub fn builder(origin: &str) -> ControllerBuilder {
+        ControllerBuilder {
+            origin: origin.into(),
+            runner: None,
+            options: ControllerOptions::default(),
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Construct a new controller with custom options.
+    pub async fn with_options(
+        origin: &str,
+        runner: Runner,
+        options: ControllerOptions,
+    ) -> Result<Self> {
+        Self::open(origin, runner, options, None).await
+    }
+
+    /// Open a new session, optionally copying the layout of another one.
+    #[instrument(skip_all, fields(%origin))]
+    async fn open(
+        origin: &str,
+        runner: Runner,
+        options: ControllerOptions,
+        fork_from: Option<String>,
+    ) -> Result<Self> {
         debug!(%origin, "connecting to server");
-        let encryption_key = rand_alphanumeric(14); // 83.3 bits of entropy
+        let encryption_key = match &options.encryption_key {
+            Some(key) => {
+                let valid = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric());
+                ensure!(valid, "encryption key must be alphanumeric");
+                key.clone()
+            }
+            None => rand_alphanumeric(14), // 83.3 bits of entropy
+        };
 
         let encryption_key2 = encryption_key.clone();
         let kdf_task = task::spawn_blocking(move || Encrypt::new(&encryption_key2));
 
         let mut client = Self::connect(origin).await?;
         let encrypt = kdf_task.await?;
+        let totp_secret = options.totp.then(totp::generate_secret);
+        let argv_offset = random_offset();
+        let argv = (options.command.as_ref())
+            .map(|argv| encrypt.segment(0x200000000, argv_offset, argv.join("\0").as_bytes()));
 
         let req = OpenRequest {
             origin: origin.into(),
             encrypted_zeros: encrypt.zeros().into(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol: PROTOCOL_VERSION,
+            low_bandwidth: options.low_bandwidth,
+            totp_secret: totp_secret.clone().unwrap_or_default().into(),
+            time_limit: (options.time_limit)
+                .map_or(0, |limit| limit.as_secs().clamp(1, u32::MAX.into()) as u32),
+            banner: options.banner.clone().unwrap_or_default(),
+            watermark: options.watermark,
+            shell_bandwidth: options.shell_bandwidth.unwrap_or(0),
+            flow_control: options.flow_window.is_some(),
+            fork_from: fork_from.unwrap_or_default(),
+            scheduled_ms: options.scheduled.map_or(0, |start| {
+                let since_epoch = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+                since_epoch.as_millis() as u64
+            }),
+            api_key: options.api_key.clone().unwrap_or_default(),
+            write_protected: options.write_link,
+            write_password: options.write_password.clone().unwrap_or_default(),
+            name: options.session_name.clone().unwrap_or_default(),
+            memorable_name: options.memorable_name,
+            argv: argv.unwrap_or_default().into(),
+            argv_offset,
+            size_policy: options.size_policy.into(),
+            features: HOST_FEATURES
+                .iter()
+                .map(|feature| feature.to_string())
+                .collect(),
+            knock: options.knock,
         };
         let mut resp = client.open(req).await?.into_inner();
+        check_server_version(&resp)?;
+        debug!(features = ?resp.features, "negotiated protocol features");
+        let write_url = (!resp.write_token.is_empty())
+            .then(|| write_link(&resp.url, &resp.write_token, &encryption_key));
         resp.url = resp.url + "#" + &encryption_key;
+        if options.low_bandwidth && !resp.low_bandwidth {
+            warn!("server does not support low-bandwidth mode, only batching output");
+        }
+        if options.watermark && !resp.watermark {
+            warn!("server does not support watermarks, viewers will not see them");
+        }
+        if options.size_policy != resp.size_policy() {
+            warn!("server does not support size policies, viewers will resize shells freely");
+        }
+        if options.shell_bandwidth.is_some() && resp.shell_bandwidth == 0 {
+            warn!("server does not support bandwidth caps, output will not be paced");
+        }
+        let flow_window = match options.flow_window {
+            Some(_) if !resp.flow_control => {
+                warn!("server does not support flow control, sending output without a window");
+                None
+            }
+            window => window,
+        };
+        let unsupported = if totp_secret.is_some() && !resp.totp {
+            Some("server does not support TOTP codes, refusing to share without them")
+        } else if options.scheduled.is_some() && !resp.scheduled {
+            Some("server does not support scheduled sessions")
+        } else if options.write_link && write_url.is_none() {
+            Some("server does not support read-only links, refusing to share a writable one")
+        } else if options.write_password.is_some() && !resp.write_password {
+            Some("server does not support write passwords, refusing to share without one")
+        } else if options.knock && !resp.knock {
+            Some("server does not support admitting viewers, refusing to share without it")
+        } else {
+            None
+        };
+        if let Some(msg) = unsupported {
+            // Closing is best-effort, the session is unusable either way.
+            let req = CloseRequest {
+                name: resp.name,
+                token: resp.token,
+                host: String::new(),
+            };
+            client.close(req).await.ok();
+            bail!(msg);
+        }
+        // The server may also impose a time limit of its own, from a quota.
+        let deadline = match (options.time_limit, resp.expires_ms) {
+            (None, 0) => None,
+            (Some(limit), 0) => {
+                warn!("server does not support time limits, only closing from the client");
+                Some(SystemTime::now() + limit)
+            }
+            (_, expires_ms) => Some(UNIX_EPOCH + Duration::from_millis(expires_ms)),
+        };
 
-        let (output_tx, output_rx) = mpsc::channel(64);
-        Ok(Self {
+        let saved = SavedSession {
             origin: origin.into(),
-            runner,
-            encrypt,
-            encryption_key,
             name: resp.name,
             token: resp.token,
             url: resp.url,
+            write_url,
+            encryption_key,
+            totp_secret: totp_secret.as_deref().map(totp::encode_base32),
+            deadline,
+            flow_window,
+            host: None,
+            co_host: false,
+        };
+        Ok(Self::from_saved(runner, options, encrypt, saved))
+    }
+
+    /// Reclaim a session saved by a previous process, if it still exists.
+    ///
+    /// Returns `None` if the server no longer knows about the session, for
+    /// instance because its grace period for disconnected clients expired.
+    #[instrument(skip_all, fields(name = %saved.name))]
+    pub async fn resume(
+        runner: Runner,
+        options: ControllerOptions,
+        saved: SavedSession,
+    ) -> Result<Option<Self>> {
+        debug!(origin = %saved.origin, name = %saved.name, "resuming session");
+        let encryption_key = saved.encryption_key.clone();
+        let kdf_task = task::spawn_blocking(move || Encrypt::new(&encryption_key));
+
+        // Check that the session is still alive by opening a channel to it.
+        let mut client = Self::connect(&saved.origin).await?;
+        let hello = hello(&saved.name, &saved.token, saved.host.as_deref());
+        let hello = ClientUpdate {
+            client_message: Some(hello),
+        };
+        match client.channel(tokio_stream::iter([hello])).await {
+            Ok(_) => (),
+            Err(status) if session_gone(&status) => {
+                debug!(%status, "saved session is no longer available");
+                return Ok(None);
+            }
+            Err(status) => return Err(status.into()),
+        }
+
+        let encrypt = kdf_task.await?;
+        Ok(Some(Self::from_saved(runner, options, encrypt, saved)))
+    }
+
+    /// Take over hosting a session from the client running it now, given
+    /// the session's link and its host token.
+    ///
+    /// The previous host is disconnected for good. Its open shells are
+    /// closed, and this client opens new shells in the same windows.
+    pub async fn adopt(
+        origin: &str,
+        runner: Runner,
+        options: ControllerOptions,
+        link: &str,
+        token: &str,
+    ) -> Result<Self> {
+        Self::add_host(origin, runner, options, link, token, false).await
+    }
+
+    /// Host some shells of a session, alongside the clients hosting it now,
+    /// given the session's link and its host token.
+    ///
+    /// The server asks this client to open one shell when it connects. Only
+    /// that shell's input comes here, and closing this controller closes just
+    /// the shells that it runs.
+    pub async fn join(
+        origin: &str,
+        runner: Runner,
+        options: ControllerOptions,
+        link: &str,
+        token: &str,
+    ) -> Result<Self> {
+        Self::add_host(origin, runner, options, link, token, true).await
+    }
+
+    #[instrument(skip_all, fields(%origin, join))]
+    async fn add_host(
+        origin: &str,
+        runner: Runner,
+        options: ControllerOptions,
+        link: &str,
+        token: &str,
+        join: bool,
+    ) -> Result<Self> {
+        let (name, encryption_key) = parse_link(link)?;
+        debug!(%origin, %name, join, "adding host to session");
+        let encryption_key2 = encryption_key.to_owned();
+        let kdf_task = task::spawn_blocking(move || Encrypt::new(&encryption_key2));
+
+        let mut client = Self::connect(origin).await?;
+        let encrypt = kdf_task.await?;
+        let req = AdoptRequest {
+            name: name.into(),
+            token: token.into(),
+            encrypted_zeros: encrypt.zeros().into(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            protocol: PROTOCOL_VERSION,
+            origin: origin.into(),
+        };
+        let resp = match join {
+            true => client.join(req).await?.into_inner(),
+            false => client.adopt(req).await?.into_inner(),
+        };
+        let write_url = (!resp.write_token.is_empty())
+            .then(|| write_link(&resp.url, &resp.write_token, encryption_key));
+        let flow_window = match options.flow_window {
+            Some(_) if !resp.flow_control => {
+                warn!("session does not use flow control, sending output without a window");
+                None
+            }
+            window => window,
+        };
+
+        let saved = SavedSession {
+            origin: origin.into(),
+            name: name.into(),
+            token: token.into(),
+            url: resp.url + "#" + encryption_key,
+            write_url,
+            encryption_key: encryption_key.into(),
+            // The secret stays with the previous host, but codes still work.
+            totp_secret: None,
+            deadline: (resp.expires_ms != 0)
+                .then(|| UNIX_EPOCH + Duration::from_millis(resp.expires_ms)),
+            flow_window,
+            host: Some(resp.host),
+            co_host: join,
+        };
+        Ok(Self::from_saved(runner, options, encrypt, saved))
+    }
+
+    fn from_saved(
+        runner: Runner,
+        options: ControllerOptions,
+        encrypt: Encrypt,
+        saved: SavedSession,
+    ) -> Self {
+        let (output_tx, output_rx) = mpsc::channel(64);
+        let saved_options = options.clone();
+        let transfers = Transfers::new(options.files, encrypt.clone(), output_tx.clone());
+        Self {
+            origin: saved.origin,
+            runner,
+            encrypt,
+            encryption_key: saved.encryption_key,
+            totp_secret: saved.totp_secret.as_deref().and_then(totp::decode_base32),
+            deadline: saved.deadline,
+            name: saved.name,
+            token: saved.token,
+            url: saved.url,
+            write_url: saved.write_url,
+            host: saved.host,
+            replaced: false,
+            co_host: saved.co_host,
             shells_tx: HashMap::new(),
             output_tx,
             output_rx,
-        })
+            buffer: OutputBuffer::new(),
+            throttle: options.max_upload_rate.map(Throttle::new),
+            paced: None,
+            shell_options: ShellOptions {
+                batch: options
+                    .low_bandwidth
+                    .then_some(LOW_BANDWIDTH_BATCH_INTERVAL),
+                collapse_redraws: options
+                    .collapse_redraws
+                    .then_some(COLLAPSE_REDRAWS_INTERVAL),
+                sandbox: options.sandbox.map(Arc::new),
+                flow_window: saved.flow_window,
+                cwd: None,
+                argv: options.command,
+                env: Vec::new(),
+            },
+            init_commands: options.init_commands,
+            read_only: options.read_only,
+            command_filter: options.command_filter,
+            line_gates: HashMap::new(),
+            viewers_tx: None,
+            access_tx: None,
+            access_seq: 0,
+            transfers,
+            options: saved_options,
+            seeds: HashMap::new(),
+            forks: Mutex::new(Vec::new()),
+            handlers: Vec::new(),
+        }
     }
 
     /// Create a new gRPC client to the HTTP(S) origin.
@@ -79,8 +700,12 @@ impl Controller {
     /// This is used on reconnection to the server, since some replicas may be
     /// gracefully shutting down, which means connected clients need to start a
     /// new TCP handshake.
-    async fn connect(origin: &str) -> Result<SshxServiceClient<Channel>, tonic::transport::Error> {
-        SshxServiceClient::connect(String::from(origin)).await
+    ///
+    /// Each request carries the trace context of the span it was made in.
+    pub(crate) async fn connect(origin: &str) -> Result<GrpcClient, tonic::transport::Error> {
+        let channel = Endpoint::new(String::from(origin))?.connect().await?;
+        let interceptor: Interceptor = telemetry::inject_context;
+        Ok(SshxServiceClient::with_interceptor(channel, interceptor))
     }
 
     /// Returns the name of the session.
@@ -93,23 +718,119 @@ impl Controller {
         &self.url
     }
 
+    /// Returns the token that lets another client adopt this session with
+    /// [`Controller::adopt`]. Anyone with it and the link can host the session.
+    pub fn host_token(&self) -> &str {
+        &self.token
+    }
+
+    /// Returns the URL that lets viewers write, if the main URL is read-only.
+    pub fn write_url(&self) -> Option<&str> {
+        self.write_url.as_deref()
+    }
+
     /// Returns the encryption key for this session, hidden from the server.
     pub fn encryption_key(&self) -> &str {
         &self.encryption_key
     }
 
+    /// Returns the credentials needed to resume this session later.
+    pub fn saved_session(&self) -> SavedSession {
+        SavedSession {
+            origin: self.origin.clone(),
+            name: self.name.clone(),
+            token: self.token.clone(),
+            url: self.url.clone(),
+            write_url: self.write_url.clone(),
+            encryption_key: self.encryption_key.clone(),
+            totp_secret: self.totp_secret.as_deref().map(totp::encode_base32),
+            deadline: self.deadline,
+            flow_window: self.shell_options.flow_window,
+            host: self.host.clone(),
+            co_host: self.co_host,
+        }
+    }
+
+    /// Returns when the session reaches its time limit, if it has one.
+    ///
+    /// The caller should close the session at this time. The server also
+    /// closes it shortly afterward.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+
+    /// Returns an `otpauth://` URI for enrolling the session's TOTP secret in
+    /// an authenticator app, if viewers must enter codes to join.
+    pub fn totp_uri(&self) -> Option<String> {
+        let secret = self.totp_secret.as_deref()?;
+        Some(totp::provisioning_uri(secret, &self.name))
+    }
+
+    /// Watch the session's access log, for viewers joining and leaving.
+    ///
+    /// Entries recorded before this call are sent first, and then new ones as
+    /// they happen, while the controller is running.
+    pub fn watch_access(&mut self) -> mpsc::UnboundedReceiver<AccessEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.access_tx = Some(tx);
+        rx
+    }
+
+    /// Confirm viewers waiting to join a session opened with
+    /// [`ControllerOptions::knock`], admitting or denying each request
+    /// received on the channel.
+    ///
+    /// Without this, waiting viewers are turned away.
+    pub fn confirm_viewers(&mut self) -> mpsc::UnboundedReceiver<ViewerRequest> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.viewers_tx = Some(tx);
+        rx
+    }
+
+    /// Confirm file transfers that viewers start, accepting or refusing each
+    /// request received on the channel.
+    ///
+    /// Without this, transfers are refused unless the options accept all of
+    /// them.
+    pub fn confirm_files(&mut self) -> mpsc::UnboundedReceiver<FileRequest> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.transfers.confirm_on(tx);
+        rx
+    }
+
+    /// Call a function for each event in the session, like the session being
+    /// ready for viewers or a shell being created.
+    ///
+    /// Handlers run inline on the controller's tasks, so they should return
+    /// quickly.
+    pub fn on_event(&mut self, handler: impl Fn(&ControllerEvent) + Send + Sync + 'static) {
+        self.handlers.push(Arc::new(handler));
+    }
+
     /// Run the controller forever, listening for requests from the server.
     pub async fn run(&mut self) -> ! {
+        let policy = self.options.reconnect.clone();
         let mut last_retry = Instant::now();
         let mut retries = 0;
         loop {
             if let Err(err) = self.try_channel().await {
-                if last_retry.elapsed() >= Duration::from_secs(10) {
+                if host_replaced(&err) {
+                    warn!("session was adopted by another host, disconnecting");
+                    self.replaced = true;
+                    emit(&self.handlers, ControllerEvent::Replaced);
+                    std::future::pending::<()>().await;
+                }
+                if last_retry.elapsed() >= policy.reset_after {
                     retries = 0;
                 }
-                let secs = 2_u64.pow(retries.min(4));
-                error!(%err, "disconnected, retrying in {secs}s...");
-                time::sleep(Duration::from_secs(secs)).await;
+                let delay = policy.delay(retries);
+                error!(%err, retries, "disconnected, retrying in {delay:?}...");
+                let event = ControllerEvent::Disconnected {
+                    error: err.to_string(),
+                    retry_in: delay,
+                };
+                emit(&self.handlers, event);
+                self.buffer_output(time::sleep(delay)).await;
                 retries += 1;
             }
             last_retry = Instant::now();
@@ -117,15 +838,50 @@ impl Controller {
     }
 
     /// Helper function used by `run()` that can return errors.
+    #[instrument(skip_all, fields(name = %self.name))]
     async fn try_channel(&mut self) -> Result<()> {
         let (tx, rx) = mpsc::channel(16);
 
-        let hello = ClientMessage::Hello(format!("{},{}", self.name, self.token));
-        send_msg(&tx, hello).await?;
+        send_msg(&tx, hello(&self.name, &self.token, self.host.as_deref())).await?;
+        if self.access_tx.is_some() {
+            // Resume from the last entry seen, in case this is a reconnection.
+            send_msg(&tx, ClientMessage::WatchAccess(self.access_seq)).await?;
+        }
 
-        let mut client = Self::connect(&self.origin).await?;
-        let resp = client.channel(ReceiverStream::new(rx)).await?;
+        let origin = self.origin.clone();
+        let resp = self
+            .buffer_output(async move {
+                let mut client = Self::connect(&origin).await?;
+                anyhow::Ok(client.channel(ReceiverStream::new(rx)).await?)
+            })
+            .await?;
         let mut messages = resp.into_inner(); // A stream of server messages.
+        debug!(name = %self.name, "established channel with server");
+        let url = self.url.clone();
+        emit(&self.handlers, ControllerEvent::Ready { url });
+
+        if let Some(msg) = self.paced.take() {
+            self.buffer.push_front(msg);
+        }
+        if !self.buffer.is_empty() {
+            debug!("replaying output buffered while disconnected");
+        }
+        loop {
+            match self.buffer.pop() {
+                Ok(Some(msg)) => {
+                    if let (Some(throttle), ClientMessage::Data(_)) = (&mut self.throttle, &msg) {
+                        throttle.ready().await;
+                    }
+                    self.send_or_buffer(&tx, msg).await?;
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    error!(%err, "discarding unreadable buffered output");
+                    self.buffer = OutputBuffer::new();
+                    break;
+                }
+            }
+        }
 
         let mut interval = time::interval(HEARTBEAT_INTERVAL);
         interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -135,9 +891,28 @@ impl Controller {
                     tx.send(ClientUpdate::default()).await?;
                     continue;
                 }
-                msg = self.output_rx.recv() => {
+                msg = async {
+                    let msg = match self.paced.take() {
+                        Some(msg) => msg,
+                        None => self.output_rx.recv().await?,
+                    };
+                    // Only terminal data waits on the throttle, and it is kept
+                    // aside in case the wait is cancelled.
+                    match (&mut self.throttle, msg) {
+                        (Some(throttle), msg @ ClientMessage::Data(_)) => {
+                            self.paced = Some(msg);
+                            throttle.ready().await;
+                            self.paced.take()
+                        }
+                        (_, msg) => Some(msg),
+                    }
+                } => {
                     let msg = msg.context("unreachable: output_tx was closed?")?;
-                    send_msg(&tx, msg).await?;
+                    if let ClientMessage::Data(data) = &msg {
+                        let bytes = data.data.len();
+                        trace!(id = data.id, seq = data.seq, bytes, "sending data");
+                    }
+                    self.send_or_buffer(&tx, msg).await?;
                     continue;
                 }
                 item = messages.next() => {
@@ -149,10 +924,28 @@ impl Controller {
 
             match message {
                 ServerMessage::Input(input) => {
-                    let data = self.encrypt.segment(0x200000000, input.offset, &input.data);
+                    trace!(%input.id, %input.offset, bytes = input.data.len(), "received input");
+                    if self.read_only {
+                        continue;
+                    }
+                    let mut data = self.encrypt.segment(0x200000000, input.offset, &input.data);
+                    if let Some(filter) = &self.command_filter {
+                        let gate = self.line_gates.entry(Sid(input.id)).or_default();
+                        let blocked;
+                        (data, blocked) = gate.process(filter, &data);
+                        for line in blocked {
+                            warn!(%input.id, ?line, "blocked command from viewer");
+                            let msg = format!("Command blocked in shell {}", input.id);
+                            send_msg(&tx, ClientMessage::Error(msg)).await?;
+                        }
+                    }
                     if let Some(sender) = self.shells_tx.get(&Sid(input.id)) {
+                        let data = match input.paste {
+                            true => ShellData::Paste(data),
+                            false => ShellData::Data(data),
+                        };
                         // This line applies backpressure if the shell task is overloaded.
-                        sender.send(ShellData::Data(data)).await.ok();
+                        sender.send(data).await.ok();
                     } else {
                         warn!(%input.id, "received data for non-existing shell");
                     }
@@ -160,18 +953,95 @@ impl Controller {
                 ServerMessage::CreateShell(new_shell) => {
                     let id = Sid(new_shell.id);
                     let center = (new_shell.x, new_shell.y);
-                    if !self.shells_tx.contains_key(&id) {
-                        self.spawn_shell_task(id, center);
+                    if matches!(self.runner, Runner::Command(_)) {
+                        // A single command runs once, so viewers cannot start more shells.
+                        warn!(%id, "ignoring request to create shell for a single command");
+                    } else if !self.shells_tx.contains_key(&id) {
+                        let mut layout = ShellLayout::at(center);
+                        let clamp = |n: u32| u16::try_from(n).unwrap_or(u16::MAX);
+                        layout.size = (new_shell.rows > 0 && new_shell.cols > 0)
+                            .then(|| (clamp(new_shell.rows), clamp(new_shell.cols)));
+                        if !new_shell.cwd.is_empty() {
+                            let cwd = (self.encrypt).segment(
+                                0x200000000,
+                                new_shell.cwd_offset,
+                                &new_shell.cwd,
+                            );
+                            layout.cwd = Some(String::from_utf8_lossy(&cwd).into_owned().into());
+                        }
+                        if !new_shell.command.is_empty() && !self.read_only {
+                            let mut command = (self.encrypt).segment(
+                                0x200000000,
+                                new_shell.command_offset,
+                                &new_shell.command,
+                            );
+                            command.push(b'\n');
+                            // Viewers' commands are checked like anything else they type.
+                            if let Some(filter) = &self.command_filter {
+                                let blocked;
+                                (command, blocked) = LineGate::new().process(filter, &command);
+                                for line in blocked {
+                                    warn!(%id, ?line, "blocked command from viewer");
+                                    let msg = format!("Command blocked in shell {id}");
+                                    send_msg(&tx, ClientMessage::Error(msg)).await?;
+                                }
+                            }
+                            let command = String::from_utf8_lossy(&command);
+                            let command = command.trim_end_matches('\n');
+                            if !command.is_empty() {
+                                layout.init_commands.push(command.into());
+                            }
+                        }
+                        if !new_shell.argv.is_empty() {
+                            let argv = (self.encrypt).segment(
+                                0x200000000,
+                                new_shell.argv_offset,
+                                &new_shell.argv,
+                            );
+                            let argv: Vec<String> = String::from_utf8_lossy(&argv)
+                                .split('\0')
+                                .map(String::from)
+                                .collect();
+                            // The session's own program comes from the open request.
+                            let custom = Some(&argv) != self.shell_options.argv.as_ref();
+                            if custom && (self.read_only || self.command_filter.is_some()) {
+                                // Viewers could otherwise run what they cannot type.
+                                warn!(%id, ?argv, "blocked program for shell from viewer");
+                                let msg = format!("Program blocked in shell {id}");
+                                send_msg(&tx, ClientMessage::Error(msg)).await?;
+                            } else {
+                                layout.argv = Some(argv);
+                            }
+                        }
+                        self.spawn_shell_task(id, layout);
                     } else {
                         warn!(%id, "server asked to create duplicate shell");
                     }
                 }
+                ServerMessage::Fork(req) => {
+                    if matches!(self.runner, Runner::Command(_)) {
+                        warn!("ignoring request to fork a single command");
+                        let msg = "Cannot fork a session running a single command".into();
+                        send_msg(&tx, ClientMessage::Error(msg)).await?;
+                        continue;
+                    }
+                    info!(scrollback = req.scrollback, "forking session");
+                    let seeds = match req.scrollback {
+                        true => self.scrollback().await,
+                        false => HashMap::new(),
+                    };
+                    let fork = self.spawn_fork(seeds);
+                    self.forks.lock().unwrap().push(fork);
+                }
                 ServerMessage::CloseShell(id) => {
+                    debug!(%id, "server closed shell");
                     // Closes the channel when it is dropped, notifying the task to shut down.
                     self.shells_tx.remove(&Sid(id));
+                    self.line_gates.remove(&Sid(id));
                     send_msg(&tx, ClientMessage::ClosedShell(id)).await?;
                 }
                 ServerMessage::Sync(seqnums) => {
+                    trace!(shells = seqnums.map.len(), "received sequence numbers");
                     for (id, seq) in seqnums.map {
                         if let Some(sender) = self.shells_tx.get(&Sid(id)) {
                             sender.send(ShellData::Sync(seq)).await.ok();
@@ -181,17 +1051,79 @@ impl Controller {
                         }
                     }
                 }
+                ServerMessage::ResyncShell(resync) => {
+                    if let Some(sender) = self.shells_tx.get(&Sid(resync.id)) {
+                        sender.send(ShellData::Resync(resync.seq)).await.ok();
+                    }
+                }
+                ServerMessage::Ack(seqnums) => {
+                    for (id, seq) in seqnums.map {
+                        if let Some(sender) = self.shells_tx.get(&Sid(id)) {
+                            sender.send(ShellData::Ack(seq)).await.ok();
+                        }
+                    }
+                }
                 ServerMessage::Resize(msg) => {
+                    debug!(%msg.id, %msg.rows, %msg.cols, "server resized shell");
                     if let Some(sender) = self.shells_tx.get(&Sid(msg.id)) {
                         sender.send(ShellData::Size(msg.rows, msg.cols)).await.ok();
                     } else {
                         warn!(%msg.id, "received resize for non-existing shell");
                     }
                 }
+                ServerMessage::Access(event) => {
+                    if let Some(access_tx) = &self.access_tx {
+                        if event.seq >= self.access_seq {
+                            self.access_seq = event.seq + 1;
+                            access_tx.send(event).ok();
+                        }
+                    }
+                }
+                ServerMessage::Knock(knock) => {
+                    let uid = Uid(knock.uid);
+                    let address = (!knock.address.is_empty()).then_some(knock.address);
+                    info!(%uid, ?address, "viewer is waiting to be let in");
+                    let (reply, decision) = oneshot::channel();
+                    let request = ViewerRequest {
+                        uid,
+                        address,
+                        reply,
+                    };
+                    if let Some(viewers_tx) = &self.viewers_tx {
+                        viewers_tx.send(request).ok();
+                    }
+                    // Viewers are denied if the request is dropped unanswered.
+                    let output_tx = self.output_tx.clone();
+                    tokio::spawn(async move {
+                        let admitted = decision.await.unwrap_or(false);
+                        let decision = ViewerDecision {
+                            uid: uid.0,
+                            admitted,
+                        };
+                        output_tx.send(ClientMessage::Admit(decision)).await.ok();
+                    });
+                }
+                ServerMessage::Upload(upload) => {
+                    self.transfers.upload(upload).await;
+                }
+                ServerMessage::Download(download) => {
+                    self.transfers.download(download).await;
+                }
                 ServerMessage::Ping(ts) => {
                     // Echo back the timestamp, for stateless latency measurement.
                     send_msg(&tx, ClientMessage::Pong(ts)).await?;
                 }
+                ServerMessage::TimeLeft(secs) => match secs {
+                    0 => info!("session has reached its time limit"),
+                    1..=59 => info!("session reaches its time limit in {secs} seconds"),
+                    _ => info!("session reaches its time limit in {} minutes", secs / 60),
+                },
+                ServerMessage::ServerShuttingDown(_) => {
+                    // Reconnect now, so the session moves to another server
+                    // before this one stops.
+                    info!("server is shutting down, reconnecting");
+                    return Ok(());
+                }
                 ServerMessage::Error(err) => {
                     error!(?err, "error received from server");
                 }
@@ -199,47 +1131,350 @@ impl Controller {
         }
     }
 
+    /// Buffer output from shells until a future completes.
+    ///
+    /// This keeps shells running while disconnected, instead of blocking them
+    /// on backpressure from the output channel.
+    async fn buffer_output<T>(&mut self, fut: impl Future<Output = T>) -> T {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                value = &mut fut => return value,
+                Some(msg) = self.output_rx.recv() => {
+                    if let Err(err) = self.buffer.push(msg) {
+                        error!(%err, "failed to buffer output while disconnected");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a message to the server, or buffer it if the channel is closed.
+    ///
+    /// Unsent messages go back to the front of the buffer, since they may have
+    /// just been replayed from it and must stay ahead of later output.
+    async fn send_or_buffer(
+        &mut self,
+        tx: &mpsc::Sender<ClientUpdate>,
+        message: ClientMessage,
+    ) -> Result<()> {
+        if let (Some(throttle), ClientMessage::Data(data)) = (&mut self.throttle, &message) {
+            throttle.consume(data.data.len());
+        }
+        let update = ClientUpdate {
+            client_message: Some(message),
+        };
+        if let Err(err) = tx.send(update).await {
+            if let Some(msg) = err.0.client_message {
+                self.buffer.push_front(msg);
+            }
+            bail!("failed to send message to server");
+        }
+        Ok(())
+    }
+
+    /// Collect recent output from every shell, to copy into a fork.
+    async fn scrollback(&self) -> HashMap<Sid, String> {
+        // Leave the alternate screen and reset colors after the copied output.
+        const SEPARATOR: &str =
+            "\x1b[?1049l\x1b[0m\r\n\x1b[2m[forked from another session]\x1b[0m\r\n";
+        let mut seeds = HashMap::new();
+        for (&id, sender) in &self.shells_tx {
+            let (tx, rx) = oneshot::channel();
+            if sender.send(ShellData::Scrollback(tx)).await.is_err() {
+                continue;
+            }
+            if let Ok(Ok(text)) = time::timeout(FORK_SCROLLBACK_TIMEOUT, rx).await {
+                seeds.insert(id, text + SEPARATOR);
+            }
+        }
+        seeds
+    }
+
+    /// Open a fork of this session in the background, with the same layout
+    /// and options but a new URL and encryption key.
+    ///
+    /// Viewers are sent the new URL, encrypted with this session's key.
+    fn spawn_fork(&self, seeds: HashMap<Sid, String>) -> (oneshot::Sender<()>, JoinHandle<()>) {
+        let origin = self.origin.clone();
+        let runner = self.runner.clone();
+        let mut options = self.options.clone();
+        options.encryption_key = None; // Each fork gets its own key.
+        let fork_from = format!("{},{}", self.name, self.token);
+        let encrypt = self.encrypt.clone();
+        let output_tx = self.output_tx.clone();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut fork = match Self::open(&origin, runner, options, Some(fork_from)).await {
+                Ok(fork) => fork,
+                Err(err) => {
+                    error!(?err, "failed to fork session");
+                    let msg = format!("Failed to fork session: {err}");
+                    output_tx.send(ClientMessage::Error(msg)).await.ok();
+                    return;
+                }
+            };
+            fork.seeds = seeds;
+            info!(name = %fork.name, url = %fork.url, "forked session");
+
+            let offset = random_offset();
+            let url = encrypt.segment(0x400000000, offset, fork.url.as_bytes());
+            let forked = ForkedSession {
+                url: url.into(),
+                offset,
+            };
+            output_tx.send(ClientMessage::Forked(forked)).await.ok();
+
+            tokio::select! {
+                _ = fork.run() => unreachable!(),
+                _ = shutdown_rx => (),
+            }
+            if let Err(err) = fork.close().await {
+                warn!(?err, name = %fork.name, "failed to close forked session");
+            }
+        });
+        (shutdown_tx, task)
+    }
+
+    /// Start a new shell from the client, without a request from the server.
+    ///
+    /// Returns a handle that resolves when the shell process has finished.
+    pub fn create_shell(&mut self, id: Sid, center: (i32, i32)) -> JoinHandle<()> {
+        self.spawn_shell_task(id, ShellLayout::at(center))
+    }
+
+    /// Start a new shell from the client with a custom layout, such as one
+    /// from a template.
+    ///
+    /// Returns a handle that resolves when the shell process has finished.
+    pub fn create_shell_with(&mut self, id: Sid, layout: ShellLayout) -> JoinHandle<()> {
+        self.spawn_shell_task(id, layout)
+    }
+
+    /// Returns the environment variables that the host chose to set in a shell.
+    fn shell_env(&self, id: Sid) -> Vec<(String, String)> {
+        let mut env = Vec::new();
+        for entry in &self.options.env {
+            if let Some((name, value)) = entry.split_once('=') {
+                env.push((name.into(), value.into()));
+                continue;
+            }
+            let value = match entry.as_str() {
+                "SSHX_SESSION_URL" => self.url.clone(),
+                "SSHX_SESSION_NAME" => self.name.clone(),
+                "SSHX_WRITE_URL" => match &self.write_url {
+                    Some(url) => url.clone(),
+                    None => continue,
+                },
+                "SSHX_SHELL_ID" => id.to_string(),
+                _ => continue,
+            };
+            env.push((entry.clone(), value));
+        }
+        env
+    }
+
     /// Entry point to start a new terminal task on the client.
-    fn spawn_shell_task(&mut self, id: Sid, center: (i32, i32)) {
+    fn spawn_shell_task(&mut self, id: Sid, layout: ShellLayout) -> JoinHandle<()> {
         let (shell_tx, shell_rx) = mpsc::channel(16);
-        let opt = self.shells_tx.insert(id, shell_tx);
+        let opt = self.shells_tx.insert(id, shell_tx.clone());
         debug_assert!(opt.is_none(), "shell ID cannot be in existing tasks");
 
+        let mut startup = Vec::new();
+        if let Some((rows, cols)) = layout.size {
+            startup.push(ShellData::Size(rows.into(), cols.into()));
+        }
+        let title = (layout.title.as_ref()).map(|title| {
+            let title: String = title.chars().filter(|c| !c.is_control()).collect();
+            format!("\x1b]0;{title}\x07")
+        });
+        let seed = self.seeds.remove(&id);
+        if title.is_some() || seed.is_some() {
+            let seed = seed.unwrap_or_default() + title.as_deref().unwrap_or_default();
+            startup.push(ShellData::Seed(seed));
+        }
+        for command in self.init_commands.iter().chain(&layout.init_commands) {
+            startup.push(ShellData::Data(format!("{command}\n").into_bytes()));
+        }
+        if !startup.is_empty() {
+            tokio::spawn(async move {
+                for data in startup {
+                    if shell_tx.send(data).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
         let runner = self.runner.clone();
         let encrypt = self.encrypt.clone();
         let output_tx = self.output_tx.clone();
+        let mut shell_options = self.shell_options.clone();
+        if let Some(cwd) = layout.cwd {
+            shell_options.cwd = Some(cwd);
+        }
+        if let Some(argv) = layout.argv {
+            shell_options.argv = Some(argv);
+        }
+        shell_options.env = self.shell_env(id);
+        let handlers = self.handlers.clone();
         tokio::spawn(async move {
             debug!(%id, "spawning new shell");
+            let (rows, cols) = layout.size.unwrap_or_default();
             let new_shell = NewShell {
                 id: id.0,
-                x: center.0,
-                y: center.1,
+                x: layout.center.0,
+                y: layout.center.1,
+                rows: rows.into(),
+                cols: cols.into(),
+                ..Default::default()
             };
             if let Err(err) = output_tx.send(ClientMessage::CreatedShell(new_shell)).await {
                 error!(%id, ?err, "failed to send shell creation message");
                 return;
             }
-            if let Err(err) = runner.run(id, encrypt, shell_rx, output_tx.clone()).await {
+            emit(&handlers, ControllerEvent::ShellCreated { id });
+            let result = runner.run(id, encrypt, &shell_options, shell_rx, output_tx.clone());
+            if let Err(err) = result.await {
                 let err = ClientMessage::Error(err.to_string());
                 output_tx.send(err).await.ok();
             }
             output_tx.send(ClientMessage::ClosedShell(id.0)).await.ok();
-        });
+            emit(&handlers, ControllerEvent::ShellClosed { id });
+        })
     }
 
-    /// Terminate this session gracefully.
+    /// Terminate this session gracefully, along with any forks of it.
+    #[instrument(skip_all, fields(name = %self.name))]
     pub async fn close(&self) -> Result<()> {
-        debug!("closing session");
+        let forks = std::mem::take(&mut *self.forks.lock().unwrap());
+        for (shutdown_tx, task) in forks {
+            shutdown_tx.send(()).ok();
+            task.await.ok();
+        }
+        if self.replaced {
+            debug!("leaving session open for the host that adopted it");
+            return Ok(());
+        }
         let req = CloseRequest {
             name: self.name.clone(),
             token: self.token.clone(),
+            host: match self.co_host {
+                true => self.host.clone().unwrap_or_default(),
+                false => String::new(),
+            },
         };
+        match self.co_host {
+            true => debug!("leaving session, closing only our shells"),
+            false => debug!("closing session"),
+        }
         let mut client = Self::connect(&self.origin).await?;
         client.close(req).await?;
         Ok(())
     }
 }
 
+/// Builder for a [`Controller`], for programs that embed terminal sharing.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use sshx::controller::{Controller, ControllerEvent};
+///
+/// let mut controller = Controller::builder("https://sshx.io")
+///     .on_event(|event| {
+///         if let ControllerEvent::Ready { url } = event {
+///             println!("sharing at {url}");
+///         }
+///     })
+///     .connect()
+///     .await?;
+/// controller.run().await
+/// # }
+/// ```
+pub struct ControllerBuilder {
+    origin: String,
+    runner: Option<Runner>,
+    options: ControllerOptions,
+    handlers: Vec<EventHandler>,
+}
+
+impl ControllerBuilder {
+    /// Set how shells are run, instead of the system's default shell.
+    pub fn runner(mut self, runner: Runner) -> Self {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Set options for the session, replacing any set before.
+    pub fn options(mut self, options: ControllerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Use a chosen encryption key, instead of a random one.
+    pub fn encryption_key(mut self, key: impl Into<String>) -> Self {
+        self.options.encryption_key = Some(key.into());
+        self
+    }
+
+    /// Set how to retry after losing the connection to the server.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.options.reconnect = policy;
+        self
+    }
+
+    /// Call a function for each event in the session, as in
+    /// [`Controller::on_event`].
+    pub fn on_event(mut self, handler: impl Fn(&ControllerEvent) + Send + Sync + 'static) -> Self {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// Open the session on the server.
+    ///
+    /// Call [`Controller::run`] afterward to start serving viewers.
+    pub async fn connect(self) -> Result<Controller> {
+        let runner = match self.runner {
+            Some(runner) => runner,
+            None => Runner::Shell(get_default_shell().await),
+        };
+        let mut controller = Controller::with_options(&self.origin, runner, self.options).await?;
+        controller.handlers = self.handlers;
+        Ok(controller)
+    }
+}
+
+/// Warn about or reject a server that is running a different version.
+fn check_server_version(resp: &OpenResponse) -> Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+    if resp.protocol == 0 {
+        warn!("server did not report its version, it may be outdated");
+    } else if resp.protocol > PROTOCOL_VERSION {
+        bail!(
+            "server uses protocol v{}, but this client only supports v{PROTOCOL_VERSION}; \
+             run `sshx upgrade` to update the client",
+            resp.protocol,
+        );
+    } else if resp.protocol < PROTOCOL_VERSION {
+        bail!(
+            "server uses protocol v{}, but this client requires v{PROTOCOL_VERSION}; \
+             the server needs to be updated",
+            resp.protocol,
+        );
+    } else if resp.version != version {
+        warn!(server = %resp.version, client = %version, "client and server versions differ");
+    }
+    Ok(())
+}
+
+/// Returns a random offset for encrypting a message on its own.
+pub(crate) fn random_offset() -> u64 {
+    let bytes: [u8; 8] = ring::rand::generate(&ring::rand::SystemRandom::new())
+        .expect("failed to generate random bytes")
+        .expose();
+    u64::from_be_bytes(bytes)
+}
+
 /// Attempt to send a client message over an update channel.
 async fn send_msg(tx: &mpsc::Sender<ClientUpdate>, message: ClientMessage) -> Result<()> {
     let update = ClientUpdate {
@@ -249,3 +1484,48 @@ async fn send_msg(tx: &mpsc::Sender<ClientUpdate>, message: ClientMessage) -> Re
         .await
         .context("failed to send message to server")
 }
+
+/// First message on a channel, identifying the session and its host.
+fn hello(name: &str, token: &str, host: Option<&str>) -> ClientMessage {
+    match host {
+        Some(host) => ClientMessage::Hello(format!("{name},{token},{host}")),
+        None => ClientMessage::Hello(format!("{name},{token}")),
+    }
+}
+
+/// Returns the link that lets viewers write to a write-protected session.
+fn write_link(url: &str, write_token: &str, encryption_key: &str) -> String {
+    // Links signed by the server already have a query string.
+    let sep = if url.contains('?') { '&' } else { '?' };
+    format!("{url}{sep}write={write_token}#{encryption_key}")
+}
+
+/// Split a session's link into the session name and its encryption key.
+pub(crate) fn parse_link(link: &str) -> Result<(&str, &str)> {
+    let (url, encryption_key) = link.split_once('#').context("link is missing its key")?;
+    let name = (url.rsplit_once("/s/"))
+        .and_then(|(_, rest)| rest.split(['?', '/']).next())
+        .filter(|name| !name.is_empty())
+        .context("link is not for a session")?;
+    Ok((name, encryption_key))
+}
+
+/// Returns whether an error means that another client adopted the session.
+fn host_replaced(err: &anyhow::Error) -> bool {
+    (err.downcast_ref::<Status>())
+        .is_some_and(|status| ErrorCode::from_status(status) == Some(ErrorCode::HostReplaced))
+}
+
+/// Returns whether a status means that a saved session can no longer be used.
+fn session_gone(status: &Status) -> bool {
+    matches!(
+        ErrorCode::from_status(status),
+        Some(
+            ErrorCode::NotFound
+                | ErrorCode::SessionClosed
+                | ErrorCode::SessionExpired
+                | ErrorCode::InvalidAuth
+                | ErrorCode::HostReplaced
+        )
+    )
+}
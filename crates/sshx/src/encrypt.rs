@@ -1,4 +1,21 @@
 //! Encryption of byte streams based on a random key.
+//!
+//! Sessions are end-to-end encrypted: the key is generated by the client and
+//! only shared in the fragment of the session URL, which browsers never send
+//! to the server. The server stores and relays ciphertext, and checks keys
+//! against the encrypted zero block without learning them.
+//!
+//! Each kind of data uses its own AES-CTR stream, selected by the high bits of
+//! the IV, so that keystreams are never reused between them:
+//!
+//! - `0x1_0000_0000 | id`: output of the shell with this ID.
+//! - `0x2_0000_0000`: input from viewers, at a random offset per message.
+//! - `0x3_0000_0000 | id`: screen renderings sent to viewers that join late.
+//! - `0x4_0000_0000`: URLs of sessions forked from this one.
+//! - `0x5_0000_0000`: files downloaded by viewers, at a random offset per file.
+//!
+//! Files uploaded by viewers, like shell directories and commands that they
+//! ask for, are encrypted as input.
 
 use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 
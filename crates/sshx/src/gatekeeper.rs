@@ -0,0 +1,265 @@
+//! Filtering of command lines typed by viewers, for supervised access.
+//!
+//! Keystrokes are forwarded to the shell as they are typed, so viewers still
+//! see their input echoed. The gatekeeper tracks the current line, and when
+//! Enter is pressed, either submits it or clears it with Ctrl-U if the command
+//! is not permitted. Editing keys that it cannot follow, like arrow keys, tab
+//! completion, or history search, are dropped.
+
+/// Shell syntax that could chain or substitute additional commands.
+const SHELL_METACHARACTERS: &[char] = &[';', '&', '|', '`', '$', '<', '>', '(', ')'];
+
+/// Rules for which command lines viewers are allowed to run.
+///
+/// A pattern matches a line that starts with the same words, so `git status`
+/// matches `git status -s` but not `git stash`. If any allow patterns are
+/// given, only matching lines are permitted, and they cannot contain shell
+/// metacharacters. Deny patterns always take precedence.
+///
+/// Quotes and backslash escapes are removed before matching, as the shell
+/// would. Lines whose words are only known once the shell expands them, like
+/// those with variables, are refused whenever there are any patterns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandFilter {
+    allow: Vec<Vec<String>>,
+    deny: Vec<Vec<String>>,
+}
+
+impl CommandFilter {
+    /// Create a new filter from allowlist and denylist patterns.
+    pub fn new(allow: &[String], deny: &[String]) -> Self {
+        let split = |patterns: &[String]| {
+            (patterns.iter())
+                .map(|pattern| pattern.split_whitespace().map(String::from).collect())
+                .filter(|words: &Vec<String>| !words.is_empty())
+                .collect()
+        };
+        Self {
+            allow: split(allow),
+            deny: split(deny),
+        }
+    }
+
+    /// Returns whether a command line is permitted to run.
+    pub fn allows(&self, line: &str) -> bool {
+        let Some(commands) = split_commands(line) else {
+            return self.allow.is_empty() && self.deny.is_empty();
+        };
+        // Deny patterns are checked against every command chained in the line.
+        if commands.iter().any(|words| matches_any(&self.deny, words)) {
+            return false;
+        }
+        if self.allow.is_empty() || line.trim().is_empty() {
+            return true;
+        }
+        matches!(&commands[..], [words] if matches_any(&self.allow, words))
+    }
+}
+
+/// Returns whether a command starts with the words of any pattern.
+fn matches_any(patterns: &[Vec<String>], words: &[String]) -> bool {
+    patterns.iter().any(|pattern| {
+        pattern.len() <= words.len() && pattern.iter().zip(words).all(|(p, w)| p == w)
+    })
+}
+
+/// Split a line into the words of each command chained in it, removing quotes
+/// and escapes like the shell does.
+///
+/// Returns `None` if the words depend on expansions, like `$HOME` or a command
+/// substitution inside double quotes, or if a quote or escape is left open and
+/// the shell would keep reading on the next line.
+fn split_commands(line: &str) -> Option<Vec<Vec<String>>> {
+    let mut commands = vec![Vec::new()];
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (Some(_), '$' | '`') => return None,
+            (Some(_), '\\') => {
+                let next = chars.next()?;
+                let word = word.get_or_insert_with(String::new);
+                if !matches!(next, '"' | '\\' | '$' | '`') {
+                    word.push('\\');
+                }
+                word.push(next);
+            }
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, '\\') => word.get_or_insert_with(String::new).push(chars.next()?),
+            // Command substitutions are checked as separate commands, but
+            // variables could expand to anything.
+            (None, '$') if chars.peek() != Some(&'(') => return None,
+            (None, c) if SHELL_METACHARACTERS.contains(&c) => {
+                commands.last_mut()?.extend(word.take());
+                commands.push(Vec::new());
+            }
+            (None, c) if c.is_whitespace() => commands.last_mut()?.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return None;
+    }
+    commands.last_mut()?.extend(word.take());
+    Some(commands)
+}
+
+/// Tracks the line being typed into one shell, filtering it on Enter.
+#[derive(Debug)]
+pub struct LineGate {
+    line: String,
+    partial: Vec<u8>,
+    in_escape: bool,
+}
+
+impl Default for LineGate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineGate {
+    /// Create a gate for a shell with an empty input line.
+    pub fn new() -> Self {
+        Self {
+            line: String::new(),
+            partial: Vec::new(),
+            in_escape: false,
+        }
+    }
+
+    /// Process input from a viewer, returning the bytes that should be written
+    /// to the shell, along with any command lines that were blocked.
+    pub fn process(&mut self, filter: &CommandFilter, data: &[u8]) -> (Vec<u8>, Vec<String>) {
+        let mut output = Vec::with_capacity(data.len());
+        let mut blocked = Vec::new();
+        for &byte in data {
+            if self.in_escape {
+                // Skip an escape sequence up to its final byte, like `ESC [ A`.
+                self.in_escape = !(byte.is_ascii_alphabetic() || byte == b'~');
+                continue;
+            }
+            match byte {
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    if filter.allows(&line) {
+                        output.push(b'\r');
+                    } else {
+                        output.push(0x15); // Ctrl-U, to clear the line
+                        blocked.push(line);
+                    }
+                }
+                0x7f | 0x08 => {
+                    self.line.pop();
+                    output.push(byte);
+                }
+                0x03 | 0x15 => {
+                    self.line.clear();
+                    output.push(byte);
+                }
+                0x04 if self.line.is_empty() => output.push(byte),
+                0x1b => self.in_escape = true,
+                0x20..=0x7e => {
+                    self.line.push(byte as char);
+                    output.push(byte);
+                }
+                0x80.. => {
+                    self.partial.push(byte);
+                    if let Ok(text) = std::str::from_utf8(&self.partial) {
+                        self.line.push_str(text);
+                        output.append(&mut self.partial);
+                    } else if self.partial.len() >= 4 {
+                        self.partial.clear(); // Invalid UTF-8 is dropped.
+                    }
+                }
+                _ => (), // Other control characters are dropped.
+            }
+        }
+        (output, blocked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandFilter, LineGate};
+
+    fn filter(allow: &[&str], deny: &[&str]) -> CommandFilter {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        CommandFilter::new(&strings(allow), &strings(deny))
+    }
+
+    #[test]
+    fn match_commands() {
+        let f = filter(&["git status", "ls"], &["ls /root"]);
+        assert!(f.allows("git status"));
+        assert!(f.allows("  git   status -s"));
+        assert!(!f.allows("git stash"));
+        assert!(f.allows("ls -la"));
+        assert!(!f.allows("ls /root"));
+        assert!(!f.allows("ls; rm -rf /"));
+        assert!(!f.allows("ls $(rm -rf /)"));
+        assert!(f.allows(""));
+
+        let f = filter(&[], &["rm", "sudo"]);
+        assert!(f.allows("echo hello > file"));
+        assert!(!f.allows("rm -rf /"));
+        assert!(!f.allows("sudo ls"));
+        assert!(!f.allows("echo hi; sudo ls"));
+        assert!(!f.allows("echo $(rm -rf /)"));
+    }
+
+    #[test]
+    fn match_quoted_commands() {
+        let f = filter(&[], &["rm", "sudo"]);
+        assert!(!f.allows("r\\m -rf /"));
+        assert!(!f.allows("'rm' -rf /"));
+        assert!(!f.allows("\"rm\" -rf /"));
+        assert!(!f.allows("r''m -rf /"));
+        assert!(!f.allows("echo hi; \"su\"do ls"));
+        assert!(!f.allows("echo \"$(rm -rf /)\""));
+        assert!(!f.allows("$'rm' -rf /"));
+        assert!(!f.allows("r${x}m -rf /"));
+        assert!(!f.allows("echo 'unfinished"));
+        assert!(f.allows("echo 'rm -rf /'"));
+        assert!(f.allows("echo \"it's; fine\" \\$HOME"));
+
+        let f = filter(&["git status", "ls"], &["ls /root"]);
+        assert!(f.allows("ls \"my files\" 'a;b'"));
+        assert!(f.allows("git \"status\""));
+        assert!(!f.allows("ls \"/root\""));
+        assert!(!f.allows("ls $HOME"));
+        assert!(!f.allows("ls;"));
+    }
+
+    #[test]
+    fn gate_input_lines() {
+        let f = filter(&["echo"], &[]);
+        let mut gate = LineGate::new();
+        assert_eq!(
+            gate.process(&f, b"echo hi\r"),
+            (b"echo hi\r".to_vec(), vec![])
+        );
+
+        let (output, blocked) = gate.process(&f, b"rm -rf /");
+        assert_eq!(output, b"rm -rf /");
+        assert!(blocked.is_empty());
+        let (output, blocked) = gate.process(&f, b"\r");
+        assert_eq!(output, b"\x15");
+        assert_eq!(blocked, ["rm -rf /"]);
+
+        // Backspace edits are tracked, while arrow keys and tabs are dropped.
+        let (output, blocked) = gate.process(&f, b"rx\x7fm\x1b[A\t\r");
+        assert_eq!(output, b"rx\x7fm\x15");
+        assert_eq!(blocked, ["rm"]);
+        let (output, blocked) = gate.process(&f, "echo h\u{e9}\r".as_bytes());
+        assert_eq!(output, "echo h\u{e9}\r".as_bytes());
+        assert!(blocked.is_empty());
+    }
+}
@@ -6,7 +6,19 @@
 #![deny(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod attach;
+pub mod buffer;
+pub mod config;
 pub mod controller;
 pub mod encrypt;
+pub mod gatekeeper;
+pub mod moderate;
+pub mod recording;
+pub mod resume;
 pub mod runner;
+pub mod sandbox;
+pub mod screen;
 pub mod terminal;
+pub mod throttle;
+pub mod transfer;
+pub mod upgrade;
@@ -1,34 +1,370 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use ansi_term::Color::{Cyan, Fixed, Green};
-use anyhow::Result;
-use clap::Parser;
-use sshx::{controller::Controller, runner::Runner, terminal::get_default_shell};
-use tokio::signal;
-use tracing::error;
+use anyhow::{ensure, Context, Result};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueHint};
+use clap_complete::Shell;
+use sshx::attach::attach;
+use sshx::config::{self, parse_duration, Template, UpConfig};
+use sshx::controller::{
+    Controller, ControllerEvent, ControllerOptions, ViewerRequest, SESSION_VARIABLES,
+};
+use sshx::encrypt::Encrypt;
+use sshx::gatekeeper::CommandFilter;
+use sshx::recording::decrypt_recording;
+use sshx::resume::{self, SavedSession};
+use sshx::sandbox::Sandbox;
+use sshx::throttle::Rate;
+use sshx::transfer::{Direction, FileOptions, FileRequest};
+use sshx::{moderate, runner::Runner, terminal::get_default_shell, upgrade};
+use sshx_core::proto::{AccessEvent, AccessKind, SizePolicy};
+use sshx_core::{telemetry, Sid, Uid};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::time::{self, Duration};
+use tokio::{signal, task::JoinSet};
+use tracing::{error, info};
+use tracing_subscriber::{prelude::*, EnvFilter};
 
 /// A secure web-based, collaborative terminal.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Address of the remote sshx server.
     #[clap(long, default_value = "https://sshx.io", env = "SSHX_SERVER")]
     server: String,
 
+    /// API key of the server namespace that the session belongs to, for
+    /// servers shared between several teams.
+    #[clap(long, value_name = "KEY", env = "SSHX_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
     /// Local shell command to run in the terminal.
     #[clap(long)]
     shell: Option<String>,
 
+    /// Program that each shell runs instead, given as one command line for
+    /// `sh -c` or as a program and its arguments. Must come after other
+    /// flags (e.g. --command "tmux attach -t work").
+    #[clap(
+        long = "command",
+        value_name = "COMMAND",
+        num_args = 1..,
+        allow_hyphen_values = true
+    )]
+    shell_command: Vec<String>,
+
+    /// Set an environment variable in each shell, can be repeated. Either
+    /// NAME=VALUE, or one of SSHX_SESSION_URL, SSHX_SESSION_NAME,
+    /// SSHX_WRITE_URL, or SSHX_SHELL_ID to tell scripts about the session.
+    #[clap(long = "env", value_name = "VAR", value_parser = parse_env)]
+    env: Vec<String>,
+
+    /// Maximum rate to upload terminal output, in bytes per second (e.g. 64K,
+    /// 1M).
+    #[clap(long, value_name = "RATE", env = "SSHX_MAX_UPLOAD_RATE")]
+    max_upload_rate: Option<Rate>,
+
+    /// Batch output and reduce update rates, for sharing over very poor links.
+    #[clap(long)]
+    low_bandwidth: bool,
+
+    /// Collapse rapidly redrawn progress bars and spinners before sending
+    /// output, which keeps long build logs small.
+    #[clap(long)]
+    collapse_redraws: bool,
+
+    /// Save the session locally, and reclaim it with the same URL if sshx is
+    /// restarted within the server's grace period.
+    #[clap(long, value_name = "PATH", num_args = 0..=1, value_hint = ValueHint::FilePath)]
+    resume: Option<Option<PathBuf>>,
+
+    /// Start with the shells of a template, defined in
+    /// ~/.config/sshx/templates.json, instead of an empty session.
+    #[clap(long, value_name = "NAME", env = "SSHX_TEMPLATE")]
+    template: Option<String>,
+
+    /// Run shells in a sandbox, with write access only to the current
+    /// directory and /tmp, and without privileged system calls (Linux only).
+    #[clap(long)]
+    sandbox: bool,
+
+    /// Extra path that sandboxed shells may read, can be repeated. Implies
+    /// --sandbox.
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::AnyPath)]
+    sandbox_read: Vec<PathBuf>,
+
+    /// Path that sandboxed shells may write, instead of the current directory,
+    /// can be repeated. Implies --sandbox.
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::AnyPath)]
+    sandbox_write: Vec<PathBuf>,
+
+    /// Refuse all input from viewers, so they can only watch. This is enforced
+    /// by the client, even if the server is compromised.
+    #[clap(long)]
+    read_only: bool,
+
+    /// Share a read-only link, and print a second link that lets viewers
+    /// write. Unlike --read-only, this is enforced by the server.
+    #[clap(long)]
+    write_link: bool,
+
+    /// Require viewers to enter this password before they can write. Others
+    /// can only watch.
+    #[clap(
+        long,
+        value_name = "PASSWORD",
+        env = "SSHX_WRITE_PASSWORD",
+        hide_env_values = true
+    )]
+    write_password: Option<String>,
+
+    /// Custom name for the session in its URL, using lowercase letters,
+    /// digits, and dashes. Fails if the name is already taken.
+    #[clap(long, value_name = "NAME", conflicts_with = "memorable_name")]
+    name: Option<String>,
+
+    /// Use a pronounceable session name like "calm-otter-42", which is easier
+    /// to dictate over a voice call.
+    #[clap(long)]
+    memorable_name: bool,
+
+    /// How to size a shell that viewers resize differently: "latest" uses the
+    /// most recent size, "smallest" fits every viewer, and "host" ignores
+    /// viewers' sizes.
+    #[clap(long, value_name = "POLICY", default_value = "latest", value_parser = parse_size_policy)]
+    size_policy: SizePolicy,
+
+    /// Only run commands typed by viewers that start with these words, can be
+    /// repeated (e.g. --allow-command "git status").
+    #[clap(long, value_name = "COMMAND")]
+    allow_command: Vec<String>,
+
+    /// Block commands typed by viewers that start with these words, can be
+    /// repeated.
+    #[clap(long, value_name = "COMMAND")]
+    deny_command: Vec<String>,
+
+    /// Let viewers upload files into the current directory and download files
+    /// from it, confirming each transfer on this terminal.
+    #[clap(long)]
+    files: bool,
+
+    /// Accept every file transfer without confirming it. Implies --files.
+    #[clap(long)]
+    accept_files: bool,
+
+    /// Require viewers to enter a rolling code from an authenticator app, in
+    /// addition to having the link. Prints a secret to enroll at startup.
+    #[clap(long)]
+    totp: bool,
+
+    /// Hold each new viewer until you let them in, confirming each one on
+    /// this terminal, so that a leaked link does not grant access by itself.
+    #[clap(long)]
+    knock: bool,
+
+    /// Message shown to each viewer when they join, such as rules for the
+    /// session (e.g. "read-only demo, recording in progress").
+    #[clap(long, value_name = "TEXT", env = "SSHX_BANNER")]
+    banner: Option<String>,
+
+    /// Overlay a faint watermark with each viewer's name and ID on their
+    /// view, so that leaked screenshots can be traced back to them.
+    #[clap(long)]
+    watermark: bool,
+
+    /// Maximum bytes of output per second relayed from each shell to each
+    /// viewer, so that one flooding shell does not starve the others.
+    #[clap(long, value_name = "BYTES")]
+    shell_bandwidth: Option<u32>,
+
+    /// Bytes of output each shell can send before the server acknowledges
+    /// it. Programs flooding output are paused until viewers catch up.
+    #[clap(long, value_name = "BYTES")]
+    flow_window: Option<u64>,
+
+    /// Close the session after this long (e.g. 90s, 45m, 2h), warning viewers
+    /// as the time limit approaches.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    time_limit: Option<Duration>,
+
+    /// Print viewers joining and leaving the session to standard error,
+    /// including their addresses.
+    #[clap(long)]
+    access_log: bool,
+
+    /// Print the host token, which lets another machine take over hosting
+    /// the session with `sshx adopt`, such as a jump host.
+    #[clap(long)]
+    host_token: bool,
+
     /// Quiet mode, only prints the URL to stdout.
     #[clap(short, long)]
     quiet: bool,
+
+    /// Verbose mode, repeat for more detail (-v for debug, -vv for trace).
+    #[clap(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Write diagnostic logs to a file instead of standard error.
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    log_file: Option<PathBuf>,
+
+    /// Export tracing spans to an OpenTelemetry collector at this OTLP/gRPC
+    /// endpoint, linked with the server's spans for the same requests.
+    #[clap(long, value_name = "URL", env = "SSHX_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
 }
 
-fn print_greeting(shell: &str, controller: &Controller) {
-    let version_str = match option_env!("CARGO_PKG_VERSION") {
+/// Additional commands, besides sharing a terminal.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Upgrade sshx to the latest release.
+    Upgrade {
+        /// Reinstall even if this version is already up to date.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Print a shell completion script to stdout.
+    Completions {
+        /// Shell to generate the completion script for.
+        shell: Shell,
+    },
+
+    /// Print the manual page in roff format to stdout.
+    Man,
+
+    /// Run a single command in a new session, ending it when the command exits.
+    Exec {
+        /// Allow viewers to type into the command's terminal.
+        #[clap(long)]
+        writable: bool,
+
+        /// Command to run, with its arguments.
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
+    /// Open and keep running all sessions defined in a configuration file.
+    Up {
+        /// Path to the JSON configuration file.
+        #[clap(short, long, default_value = "sshx.json", value_hint = ValueHint::FilePath)]
+        config: PathBuf,
+    },
+
+    /// Reserve a session that starts later, printing its link now. Start
+    /// hosting it with `sshx --resume`.
+    Schedule {
+        /// How long until the session starts (e.g. 30m, 2h, 1d).
+        #[clap(value_parser = parse_duration)]
+        start_in: Duration,
+
+        /// Where to save the session, instead of the default `--resume` path.
+        #[clap(short, long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+
+    /// Decrypt a shell recording downloaded from the server, printing a
+    /// playable asciicast file to stdout. Recordings of input also keep the
+    /// user who typed each event.
+    DecryptRecording {
+        /// Path to the recording.
+        #[clap(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+
+        /// Encryption key of the session, or its full link.
+        #[clap(long, env = "SSHX_KEY", hide_env_values = true)]
+        key: String,
+    },
+
+    /// Attach this terminal to a shell in another session, as a viewer. Press
+    /// Ctrl-] to detach.
+    Attach {
+        /// Link to the session, including its encryption key.
+        link: String,
+
+        /// ID of the shell to attach to, instead of the first open shell.
+        #[clap(long, value_name = "ID")]
+        shell: Option<u32>,
+
+        /// Name shown to other users of the session.
+        #[clap(long)]
+        name: Option<String>,
+    },
+
+    /// Take over hosting a session from the machine running it now, keeping
+    /// its link. The previous host is disconnected, and shells are reopened
+    /// here.
+    Adopt {
+        /// Link to the session, including its encryption key.
+        link: String,
+
+        /// Host token of the session, printed by `sshx --host-token`.
+        #[clap(long, env = "SSHX_HOST_TOKEN", hide_env_values = true)]
+        token: String,
+    },
+
+    /// Host some shells of a session from this machine, alongside the machine
+    /// running it now. A new shell opens here, and closes when you exit.
+    Join {
+        /// Link to the session, including its encryption key.
+        link: String,
+
+        /// Host token of the session, printed by `sshx --host-token`.
+        #[clap(long, env = "SSHX_HOST_TOKEN", hide_env_values = true)]
+        token: String,
+    },
+
+    /// List the viewers connected to a session, with their IDs and addresses.
+    Viewers {
+        /// Link to the session, including its encryption key.
+        link: String,
+
+        /// Host token of the session, printed by `sshx --host-token`.
+        #[clap(long, env = "SSHX_HOST_TOKEN", hide_env_values = true)]
+        token: String,
+    },
+
+    /// Disconnect a viewer from a session, by the ID shown in `sshx viewers`.
+    Kick {
+        /// Link to the session, including its encryption key.
+        link: String,
+
+        /// ID of the viewer to disconnect.
+        uid: u32,
+
+        /// Also turn away the viewer's address for the rest of the session.
+        #[clap(long)]
+        ban: bool,
+
+        /// Host token of the session, printed by `sshx --host-token`.
+        #[clap(long, env = "SSHX_HOST_TOKEN", hide_env_values = true)]
+        token: String,
+    },
+}
+
+/// Time to keep forwarding output after `sshx exec` finishes its command.
+const EXEC_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn version_str() -> String {
+    match option_env!("CARGO_PKG_VERSION") {
         Some(version) => format!("v{version}"),
         None => String::from("[dev]"),
-    };
+    }
+}
+
+fn print_greeting(shell: &str, controller: &Controller) {
+    let version_str = version_str();
 
     println!(
         r#"
@@ -43,45 +379,774 @@ fn print_greeting(shell: &str, controller: &Controller) {
         link_v = Cyan.underline().paint(controller.url()),
         shell_v = Fixed(8).paint(shell),
     );
+    print_write_url(controller);
+    if let Some(uri) = controller.totp_uri() {
+        print_totp(&uri);
+    }
+}
+
+fn print_host_token(controller: &Controller) {
+    println!(
+        "  {arr}  Host:  {token_v}\n         {note}\n",
+        arr = Green.paint("➜"),
+        token_v = Cyan.paint(controller.host_token()),
+        note = Fixed(8).paint("Run `sshx adopt <link> --token <token>` to host from elsewhere."),
+    );
+}
+
+/// Returns a signal for when another host adopts the session.
+fn adopted_signal(controller: &mut Controller) -> Arc<Notify> {
+    let adopted = Arc::new(Notify::new());
+    let adopted2 = Arc::clone(&adopted);
+    controller.on_event(move |event| {
+        if *event == ControllerEvent::Replaced {
+            adopted2.notify_one();
+        }
+    });
+    adopted
+}
+
+/// Print the link that lets viewers write, if the main link is read-only.
+fn print_write_url(controller: &Controller) {
+    if let Some(url) = controller.write_url() {
+        println!(
+            "  {arr}  Write: {link_v}\n",
+            arr = Green.paint("➜"),
+            link_v = Cyan.underline().paint(url),
+        );
+    }
+}
+
+/// Print access log entries to standard error as they arrive.
+fn print_access(mut access_rx: mpsc::UnboundedReceiver<AccessEvent>, session: Option<String>) {
+    tokio::spawn(async move {
+        while let Some(event) = access_rx.recv().await {
+            let secs = event.time_ms / 1000;
+            let time = format!(
+                "{:02}:{:02}:{:02}",
+                secs / 3600 % 24,
+                secs / 60 % 60,
+                secs % 60,
+            );
+            let action = match event.kind() {
+                AccessKind::AccessJoined => "joined",
+                AccessKind::AccessLeft => "left",
+                AccessKind::AccessRenamed => "renamed",
+                AccessKind::AccessRejected => "rejected",
+                AccessKind::AccessDenied => "denied",
+                AccessKind::AccessKicked => "kicked",
+                AccessKind::AccessBanned => "banned",
+                AccessKind::AccessBlocked => "blocked",
+            };
+            let mut line = match &session {
+                Some(name) => format!("[{time} UTC] {name}: {action}"),
+                None => format!("[{time} UTC] {action}"),
+            };
+            if event.uid != 0 {
+                line += &format!(" {:?} (user {})", event.name, event.uid);
+            }
+            if !event.address.is_empty() {
+                line += &format!(" from {}", event.address);
+            }
+            if let AccessKind::AccessKicked | AccessKind::AccessBanned = event.kind() {
+                line += &match event.actor {
+                    0 => " by the host".to_owned(),
+                    actor => format!(" by user {actor}"),
+                };
+            }
+            if event.kind() == AccessKind::AccessJoined {
+                line += if event.writable {
+                    ", read-write"
+                } else {
+                    ", read-only"
+                };
+            }
+            eprintln!("{}", Fixed(8).paint(line));
+        }
+    });
+}
+
+/// Ask on the terminal whether to accept each file transfer from viewers.
+fn confirm_files(mut requests: mpsc::UnboundedReceiver<FileRequest>) {
+    tokio::spawn(async move {
+        while let Some(request) = requests.recv().await {
+            let action = match request.direction {
+                Direction::Upload => "upload",
+                Direction::Download => "download",
+            };
+            eprint!(
+                "{} User {} wants to {action} {:?} ({} bytes). Accept? [y/N] ",
+                Green.paint("➜"),
+                request.uid,
+                request.path,
+                request.size,
+            );
+            let answer = tokio::task::spawn_blocking(|| {
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line).map(|_| line)
+            });
+            match answer.await {
+                Ok(Ok(line)) if line.trim().eq_ignore_ascii_case("y") => request.accept(),
+                _ => request.refuse(),
+            }
+        }
+    });
+}
+
+/// Ask on the terminal whether to let in each viewer waiting to join.
+fn confirm_viewers(mut requests: mpsc::UnboundedReceiver<ViewerRequest>) {
+    tokio::spawn(async move {
+        while let Some(request) = requests.recv().await {
+            let from = match &request.address {
+                Some(address) => format!(" from {address}"),
+                None => String::new(),
+            };
+            eprint!(
+                "{} User {}{from} wants to join. Let them in? [y/N] ",
+                Green.paint("➜"),
+                request.uid,
+            );
+            let answer = tokio::task::spawn_blocking(|| {
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line).map(|_| line)
+            });
+            match answer.await {
+                Ok(Ok(line)) if line.trim().eq_ignore_ascii_case("y") => request.admit(),
+                _ => request.deny(),
+            }
+        }
+    });
+}
+
+fn print_totp(uri: &str) {
+    println!(
+        "  {arr}  TOTP:  {uri_v}\n         {note}\n",
+        arr = Green.paint("➜"),
+        uri_v = Cyan.paint(uri),
+        note = Fixed(8).paint("Add this to an authenticator app, viewers will need its codes."),
+    );
 }
 
-#[tokio::main]
 async fn start(args: Args) -> Result<()> {
-    let shell = match args.shell {
-        Some(shell) => shell,
+    match args.command {
+        Some(Command::Upgrade { force }) => run_upgrade(force).await,
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Args::command(), "sshx", &mut io::stdout());
+            Ok(())
+        }
+        Some(Command::Man) => {
+            clap_mangen::Man::new(Args::command()).render(&mut io::stdout())?;
+            Ok(())
+        }
+        Some(Command::Exec {
+            writable,
+            ref command,
+        }) => exec(&args, command, writable).await,
+        Some(Command::Up { ref config }) => up(&args, config).await,
+        Some(Command::Schedule {
+            start_in,
+            ref output,
+        }) => schedule(&args, start_in, output.as_deref()).await,
+        Some(Command::DecryptRecording { ref file, ref key }) => {
+            // Links carry the key in their fragment, after the '#'.
+            let key = key.rsplit_once('#').map_or(&key[..], |(_, key)| key);
+            let cast = std::fs::read_to_string(file)
+                .with_context(|| format!("failed to read {}", file.display()))?;
+            print!("{}", decrypt_recording(&cast, &Encrypt::new(key))?);
+            Ok(())
+        }
+        Some(Command::Attach {
+            ref link,
+            shell,
+            ref name,
+        }) => attach(link, shell.map(Sid), name.as_deref()).await,
+        Some(Command::Adopt {
+            ref link,
+            ref token,
+        }) => add_host(&args, link, token, false).await,
+        Some(Command::Join {
+            ref link,
+            ref token,
+        }) => add_host(&args, link, token, true).await,
+        Some(Command::Viewers {
+            ref link,
+            ref token,
+        }) => print_viewers(&args, link, token).await,
+        Some(Command::Kick {
+            ref link,
+            uid,
+            ban,
+            ref token,
+        }) => moderate::kick(&args.server, link, token, Uid(uid), ban).await,
+        None => share(args).await,
+    }
+}
+
+/// Returns the sandbox profile requested on the command line, if any.
+fn sandbox_from_args(args: &Args) -> Result<Option<Sandbox>> {
+    if !args.sandbox && args.sandbox_read.is_empty() && args.sandbox_write.is_empty() {
+        return Ok(None);
+    }
+    let write = match args.sandbox_write.is_empty() {
+        true => vec![std::env::current_dir()?],
+        false => args.sandbox_write.clone(),
+    };
+    Ok(Some(Sandbox::new(args.sandbox_read.clone(), write)))
+}
+
+/// Returns a filter for viewer commands, if any patterns are given.
+fn command_filter(allow: &[String], deny: &[String]) -> Option<CommandFilter> {
+    match allow.is_empty() && deny.is_empty() {
+        true => None,
+        false => Some(CommandFilter::new(allow, deny)),
+    }
+}
+
+/// Parse an environment variable to set in shells, from the command line.
+fn parse_env(s: &str) -> Result<String> {
+    match s.split_once('=') {
+        Some((name, _)) => ensure!(!name.is_empty(), "missing variable name before `=`"),
+        None => ensure!(
+            SESSION_VARIABLES.contains(&s),
+            "unknown variable {s:?}, expected NAME=VALUE or one of {}",
+            SESSION_VARIABLES.join(", "),
+        ),
+    }
+    Ok(s.into())
+}
+
+/// Parse a policy for sizing shells, from the command line.
+fn parse_size_policy(s: &str) -> Result<SizePolicy> {
+    let name = format!("SIZE_{}", s.to_ascii_uppercase());
+    SizePolicy::from_str_name(&name)
+        .with_context(|| format!("unknown size policy {s:?}, expected latest, smallest, or host"))
+}
+
+async fn run_upgrade(force: bool) -> Result<()> {
+    let current = env!("CARGO_PKG_VERSION");
+    let latest = upgrade::latest_version().await?;
+    if !force && !upgrade::is_newer(&latest, current) {
+        println!("sshx {} is already up to date", version_str());
+        return Ok(());
+    }
+    println!("{} Upgrading sshx to v{latest}...", Green.paint("➜"));
+    let path = upgrade::install().await?;
+    println!(
+        "{} Installed sshx v{latest} at {}",
+        Green.paint("➜"),
+        Fixed(8).paint(path.display().to_string()),
+    );
+    Ok(())
+}
+
+async fn share(args: Args) -> Result<()> {
+    let shell = match &args.shell {
+        Some(shell) => shell.clone(),
         None => get_default_shell().await,
     };
+    let template = match &args.template {
+        Some(name) => Some(find_template(name, &HashMap::new())?),
+        None => None,
+    };
 
     let runner = Runner::Shell(shell.clone());
-    let mut controller = Controller::new(&args.server, runner).await?;
+    let options = share_options(&args)?;
+    let resume_path = match args.resume {
+        Some(Some(path)) => Some(path),
+        Some(None) => Some(resume::default_path()?),
+        None => None,
+    };
+    let (mut controller, resumed) =
+        open(&args.server, runner, options, resume_path.as_deref()).await?;
+    if let (Some(template), false) = (&template, resumed) {
+        start_template(&mut controller, template);
+    }
+    if args.access_log {
+        print_access(controller.watch_access(), None);
+    }
+    if args.files && !args.accept_files {
+        confirm_files(controller.confirm_files());
+    }
+    if args.knock {
+        confirm_viewers(controller.confirm_viewers());
+    }
     if args.quiet {
         println!("{}", controller.url());
+        if let Some(url) = controller.write_url() {
+            println!("{url}");
+        }
+        if let Some(uri) = controller.totp_uri() {
+            println!("{uri}");
+        }
     } else {
         print_greeting(&shell, &controller);
     }
+    if args.host_token {
+        print_host_token(&controller);
+    }
 
+    let adopted = adopted_signal(&mut controller);
     let exit_signal = signal::ctrl_c();
     tokio::pin!(exit_signal);
+    let deadline = deadline_reached(controller.deadline());
+    tokio::pin!(deadline);
     tokio::select! {
         _ = controller.run() => unreachable!(),
         Ok(()) = &mut exit_signal => (),
+        _ = &mut deadline => info!("session reached its time limit"),
+        _ = adopted.notified() => info!("session was adopted by another host"),
+    };
+    controller.close().await?;
+    if let Some(path) = &resume_path {
+        SavedSession::remove(path)?;
+    }
+
+    Ok(())
+}
+
+/// Adopt a session, or join it to host some of its shells.
+async fn add_host(args: &Args, link: &str, token: &str, join: bool) -> Result<()> {
+    let shell = match &args.shell {
+        Some(shell) => shell.clone(),
+        None => get_default_shell().await,
+    };
+    let runner = Runner::Shell(shell.clone());
+    let options = share_options(args)?;
+    let mut controller = match join {
+        true => Controller::join(&args.server, runner, options, link, token).await?,
+        false => Controller::adopt(&args.server, runner, options, link, token).await?,
+    };
+    if args.access_log {
+        print_access(controller.watch_access(), None);
+    }
+    if args.files && !args.accept_files {
+        confirm_files(controller.confirm_files());
+    }
+    if args.knock {
+        confirm_viewers(controller.confirm_viewers());
+    }
+    if args.quiet {
+        println!("{}", controller.url());
+    } else {
+        print_greeting(&shell, &controller);
+    }
+
+    let adopted = adopted_signal(&mut controller);
+    let exit_signal = signal::ctrl_c();
+    tokio::pin!(exit_signal);
+    let deadline = deadline_reached(controller.deadline());
+    tokio::pin!(deadline);
+    tokio::select! {
+        _ = controller.run() => unreachable!(),
+        Ok(()) = &mut exit_signal => (),
+        _ = &mut deadline => info!("session reached its time limit"),
+        _ = adopted.notified() => info!("session was adopted by another host"),
+    };
+    controller.close().await
+}
+
+/// Returns the options for sharing a shell, from the command line.
+fn share_options(args: &Args) -> Result<ControllerOptions> {
+    let mut options = ControllerOptions::default();
+    options.max_upload_rate = args.max_upload_rate;
+    options.low_bandwidth = args.low_bandwidth;
+    options.collapse_redraws = args.collapse_redraws;
+    options.sandbox = sandbox_from_args(args)?;
+    options.command_filter = command_filter(&args.allow_command, &args.deny_command);
+    options.read_only = args.read_only;
+    options.totp = args.totp;
+    options.knock = args.knock;
+    options.time_limit = args.time_limit;
+    options.banner = args.banner.clone();
+    options.watermark = args.watermark;
+    options.shell_bandwidth = args.shell_bandwidth;
+    options.flow_window = args.flow_window;
+    options.api_key = args.api_key.clone();
+    options.write_link = args.write_link;
+    options.write_password = args.write_password.clone();
+    options.session_name = args.name.clone();
+    options.memorable_name = args.memorable_name;
+    options.env = args.env.clone();
+    options.size_policy = args.size_policy;
+    options.command = match args.shell_command.as_slice() {
+        [] => None,
+        [line] => Some(vec!["sh".into(), "-c".into(), line.clone()]),
+        argv => Some(argv.to_vec()),
+    };
+    if args.files || args.accept_files {
+        let mut files = FileOptions::new(std::env::current_dir()?);
+        files.accept_all = args.accept_files;
+        options.files = Some(files);
+    }
+    Ok(options)
+}
+
+/// Print the viewers connected to a session, one per line.
+async fn print_viewers(args: &Args, link: &str, token: &str) -> Result<()> {
+    let viewers = moderate::list_viewers(&args.server, link, token).await?;
+    if viewers.is_empty() {
+        eprintln!("no viewers are connected");
+    }
+    for viewer in viewers {
+        let mut line = format!("{:>4}  {:?}", viewer.uid, viewer.name);
+        if !viewer.address.is_empty() {
+            line += &format!(" from {}", viewer.address);
+        }
+        println!("{line}");
+    }
+    Ok(())
+}
+
+async fn schedule(args: &Args, start_in: Duration, output: Option<&Path>) -> Result<()> {
+    let path = match output {
+        Some(path) => path.to_owned(),
+        None => resume::default_path()?,
+    };
+    let start = SystemTime::now() + start_in;
+    let mut options = share_options(args)?;
+    options.scheduled = Some(start);
+    // The shell is only started once the host resumes the session.
+    let runner = Runner::Shell(String::new());
+    let controller = Controller::with_options(&args.server, runner, options).await?;
+    controller.saved_session().save(&path)?;
+
+    if args.quiet {
+        println!("{}", controller.url());
+        if let Some(url) = controller.write_url() {
+            println!("{url}");
+        }
+        if let Some(uri) = controller.totp_uri() {
+            println!("{uri}");
+        }
+        return Ok(());
+    }
+    let resume_arg = match output {
+        Some(path) => format!("--resume {}", path.display()),
+        None => String::from("--resume"),
+    };
+    println!(
+        "\n  {arr}  Link:  {link_v}\n  {arr}  Start: {start_v}\n",
+        arr = Green.paint("➜"),
+        link_v = Cyan.underline().paint(controller.url()),
+        start_v = Fixed(8).paint(format!(
+            "in {} minutes, host it with `sshx {resume_arg}`",
+            start_in.as_secs().div_ceil(60),
+        )),
+    );
+    print_write_url(&controller);
+    if let Some(uri) = controller.totp_uri() {
+        print_totp(&uri);
+    }
+    Ok(())
+}
+
+async fn exec(args: &Args, command: &[String], writable: bool) -> Result<()> {
+    let runner = Runner::Command(command.to_vec());
+    let mut options = ControllerOptions::default();
+    options.max_upload_rate = args.max_upload_rate;
+    options.low_bandwidth = args.low_bandwidth;
+    options.collapse_redraws = args.collapse_redraws;
+    options.read_only = args.read_only || !writable;
+    options.sandbox = sandbox_from_args(args)?;
+    options.totp = args.totp;
+    options.knock = args.knock;
+    options.time_limit = args.time_limit;
+    options.banner = args.banner.clone();
+    options.watermark = args.watermark;
+    options.shell_bandwidth = args.shell_bandwidth;
+    options.flow_window = args.flow_window;
+    options.api_key = args.api_key.clone();
+    options.write_link = args.write_link;
+    options.write_password = args.write_password.clone();
+    options.session_name = args.name.clone();
+    options.memorable_name = args.memorable_name;
+    options.env = args.env.clone();
+    let mut controller = Controller::with_options(&args.server, runner, options).await?;
+    if args.access_log {
+        print_access(controller.watch_access(), None);
+    }
+    if args.knock {
+        confirm_viewers(controller.confirm_viewers());
+    }
+    if args.quiet {
+        println!("{}", controller.url());
+        if let Some(url) = controller.write_url() {
+            println!("{url}");
+        }
+        if let Some(uri) = controller.totp_uri() {
+            println!("{uri}");
+        }
+    } else {
+        print_greeting(&command.join(" "), &controller);
+    }
+
+    let mut task = controller.create_shell(Sid(1), (0, 0));
+    let exit_signal = signal::ctrl_c();
+    tokio::pin!(exit_signal);
+    let deadline = deadline_reached(controller.deadline());
+    tokio::pin!(deadline);
+    let finished = tokio::select! {
+        _ = controller.run() => unreachable!(),
+        _ = &mut task => true,
+        Ok(()) = &mut exit_signal => false,
+        _ = &mut deadline => {
+            info!("session reached its time limit");
+            false
+        }
     };
+    if finished {
+        // Give the last output a moment to reach the server before closing.
+        time::timeout(EXEC_FLUSH_TIMEOUT, controller.run())
+            .await
+            .ok();
+    }
     controller.close().await?;
 
     Ok(())
 }
 
+/// Find a template by name, in the configuration file or else the user's
+/// templates file.
+fn find_template(name: &str, configured: &HashMap<String, Template>) -> Result<Template> {
+    if let Some(template) = configured.get(name) {
+        return Ok(template.clone());
+    }
+    let path = config::templates_path()?;
+    let mut templates = config::load_templates(&path)?;
+    templates
+        .remove(name)
+        .with_context(|| format!("no template named {name:?} in {}", path.display()))
+}
+
+/// Create the shells of a template in a new session.
+fn start_template(controller: &mut Controller, template: &Template) {
+    for (i, shell) in template.shells.iter().enumerate() {
+        controller.create_shell_with(Sid(i as u32 + 1), shell.layout());
+    }
+}
+
+/// Open a new session, or resume a saved one if a path is given.
+///
+/// Also returns whether a saved session was resumed.
+async fn open(
+    server: &str,
+    runner: Runner,
+    options: ControllerOptions,
+    resume_path: Option<&Path>,
+) -> Result<(Controller, bool)> {
+    let saved = match resume_path {
+        Some(path) => SavedSession::load(path)?.filter(|saved| saved.origin == server),
+        None => None,
+    };
+    let resumed = match saved {
+        Some(saved) => Controller::resume(runner.clone(), options.clone(), saved).await?,
+        None => None,
+    };
+    let (controller, resumed) = match resumed {
+        Some(controller) => (controller, true),
+        None => (
+            Controller::with_options(server, runner, options).await?,
+            false,
+        ),
+    };
+    if let Some(path) = resume_path {
+        controller.saved_session().save(path)?;
+    }
+    Ok((controller, resumed))
+}
+
+async fn up(args: &Args, config_path: &Path) -> Result<()> {
+    if args.name.is_some() {
+        anyhow::bail!("--name cannot be used with several sessions");
+    }
+    let config = UpConfig::load(config_path)?;
+    let server = config.server.as_deref().unwrap_or(&args.server);
+    let default_shell = get_default_shell().await;
+    let default_sandbox = sandbox_from_args(args)?;
+
+    let mut sessions = Vec::new();
+    for session in config.sessions {
+        let template = match &session.template {
+            Some(name) => Some(find_template(name, &config.templates)?),
+            None => None,
+        };
+        let shell = session.shell.unwrap_or_else(|| default_shell.clone());
+        let mut options = ControllerOptions::default();
+        options.max_upload_rate = args.max_upload_rate;
+        options.low_bandwidth = args.low_bandwidth || session.low_bandwidth;
+        options.collapse_redraws = args.collapse_redraws || session.collapse_redraws;
+        options.init_commands = session.init;
+        options.sandbox = match session.sandbox {
+            Some(sandbox) => Some(Sandbox::new(sandbox.read, sandbox.write)),
+            None => default_sandbox.clone(),
+        };
+        options.command_filter = command_filter(
+            &[&args.allow_command[..], &session.allow_commands].concat(),
+            &[&args.deny_command[..], &session.deny_commands].concat(),
+        );
+        options.read_only = args.read_only || session.read_only;
+        options.totp = args.totp || session.totp;
+        options.time_limit = session.time_limit.or(args.time_limit);
+        options.banner = session.banner.or_else(|| args.banner.clone());
+        options.watermark = args.watermark || session.watermark;
+        options.shell_bandwidth = session.shell_bandwidth.or(args.shell_bandwidth);
+        options.flow_window = session.flow_window.or(args.flow_window);
+        options.api_key = args.api_key.clone();
+        options.write_link = args.write_link;
+        options.write_password = args.write_password.clone();
+        options.memorable_name = args.memorable_name;
+        let resume_path = match session.resume {
+            true => Some(resume::named_path(&session.name)?),
+            false => None,
+        };
+        let (mut controller, resumed) = open(
+            server,
+            Runner::Shell(shell),
+            options,
+            resume_path.as_deref(),
+        )
+        .await
+        .with_context(|| format!("failed to open session {:?}", session.name))?;
+        if let (Some(template), false) = (&template, resumed) {
+            start_template(&mut controller, template);
+        }
+        if args.access_log {
+            print_access(controller.watch_access(), Some(session.name.clone()));
+        }
+        if args.quiet {
+            println!("{} {}", session.name, controller.url());
+            if let Some(url) = controller.write_url() {
+                println!("{} {url}", session.name);
+            }
+            if let Some(uri) = controller.totp_uri() {
+                println!("{} {uri}", session.name);
+            }
+        } else {
+            println!(
+                "  {arr}  {name}: {link}",
+                arr = Green.paint("➜"),
+                name = Green.bold().paint(&session.name),
+                link = Cyan.underline().paint(controller.url()),
+            );
+            if let Some(url) = controller.write_url() {
+                println!(
+                    "     {} {}",
+                    Fixed(8).paint("write:"),
+                    Cyan.underline().paint(url)
+                );
+            }
+            if let Some(uri) = controller.totp_uri() {
+                print_totp(&uri);
+            }
+        }
+        sessions.push((controller, resume_path));
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut tasks = JoinSet::new();
+    for (mut controller, resume_path) in sessions {
+        let mut shutdown_rx = shutdown_rx.clone();
+        tasks.spawn(async move {
+            let name = controller.name().to_owned();
+            let deadline = controller.deadline();
+            tokio::select! {
+                _ = controller.run() => unreachable!(),
+                _ = shutdown_rx.changed() => (),
+                _ = deadline_reached(deadline) => info!(name, "session reached its time limit"),
+            }
+            if let Err(err) = controller.close().await {
+                error!(name, "failed to close session: {err:?}");
+            }
+            if let Some(path) = resume_path {
+                SavedSession::remove(&path)?;
+            }
+            anyhow::Ok(())
+        });
+    }
+
+    // Run until interrupted, or until every session reaches its time limit.
+    let exit_signal = signal::ctrl_c();
+    tokio::pin!(exit_signal);
+    loop {
+        tokio::select! {
+            result = tasks.join_next() => match result {
+                Some(result) => result??,
+                None => return Ok(()),
+            },
+            result = &mut exit_signal => break result?,
+        }
+    }
+    shutdown_tx.send_replace(true);
+    while let Some(result) = tasks.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Resolves at a session's deadline, or never if it has no time limit.
+async fn deadline_reached(deadline: Option<SystemTime>) {
+    match deadline {
+        Some(deadline) => {
+            let left = deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            time::sleep(left).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
 fn main() -> ExitCode {
     let args = Args::parse();
 
-    let default_level = if args.quiet { "error" } else { "info" };
+    let default_level = match args.verbose {
+        0 if args.quiet => "error",
+        0 => "info",
+        1 => "info,sshx=debug",
+        _ => "info,sshx=trace,tonic=debug",
+    };
 
-    tracing_subscriber::fmt()
-        .with_env_filter(std::env::var("RUST_LOG").unwrap_or(default_level.into()))
-        .with_writer(std::io::stderr)
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            eprintln!("failed to start runtime: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // The OTLP exporter sends batches of spans from a task on the runtime.
+    let _guard = runtime.enter();
+
+    let filter = EnvFilter::new(std::env::var("RUST_LOG").unwrap_or(default_level.into()));
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = match &args.log_file {
+        Some(path) => match File::create(path) {
+            Ok(file) => fmt.with_ansi(false).with_writer(Mutex::new(file)).boxed(),
+            Err(err) => {
+                eprintln!("failed to open log file {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => fmt.with_writer(std::io::stderr).boxed(),
+    };
+    let otlp = match &args.otlp_endpoint {
+        Some(endpoint) => match telemetry::layer("sshx", endpoint) {
+            Ok(layer) => Some(layer),
+            Err(err) => {
+                eprintln!("failed to export traces to {endpoint}: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .with(otlp)
         .init();
 
-    match start(args) {
+    let result = runtime.block_on(start(args));
+    telemetry::shutdown();
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
             error!("{err:?}");
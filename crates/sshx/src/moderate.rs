@@ -0,0 +1,34 @@
+//! Moderate the viewers of a running session from another process, given
+//! its link and host token.
+
+use anyhow::Result;
+use sshx_core::proto::{KickRequest, Viewer, ViewersRequest};
+use sshx_core::Uid;
+
+use crate::controller::{parse_link, Controller};
+
+/// List the viewers connected to a session, in the order they joined.
+pub async fn list_viewers(origin: &str, link: &str, token: &str) -> Result<Vec<Viewer>> {
+    let (name, _) = parse_link(link)?;
+    let mut client = Controller::connect(origin).await?;
+    let req = ViewersRequest {
+        name: name.into(),
+        token: token.into(),
+    };
+    Ok(client.viewers(req).await?.into_inner().viewers)
+}
+
+/// Disconnect a viewer from a session. If banning them, their address is
+/// also turned away for the rest of the session.
+pub async fn kick(origin: &str, link: &str, token: &str, uid: Uid, ban: bool) -> Result<()> {
+    let (name, _) = parse_link(link)?;
+    let mut client = Controller::connect(origin).await?;
+    let req = KickRequest {
+        name: name.into(),
+        token: token.into(),
+        uid: uid.0,
+        ban,
+    };
+    client.kick(req).await?;
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! Decryption of shell recordings saved by the server.
+//!
+//! The server records encrypted output in the asciicast v2 format, with an
+//! `sshx` field in the header giving the shell ID and the offset of the first
+//! output event. Decrypting a recording yields a standard asciicast file.
+//!
+//! Recordings of input have `input` set in that field instead. Each input
+//! event carries the user who typed it and its own offset, which is dropped
+//! once the event is decrypted.
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use serde_json::Value;
+
+use crate::encrypt::Encrypt;
+
+/// Decrypt a recording downloaded from the server, into a playable asciicast.
+pub fn decrypt_recording(cast: &str, encrypt: &Encrypt) -> Result<String> {
+    let mut lines = cast.lines();
+    let mut header: Value = serde_json::from_str(lines.next().context("recording is empty")?)?;
+    let info = (header.as_object_mut())
+        .and_then(|header| header.remove("sshx"))
+        .context("not an sshx recording")?;
+    let shell = info["shell"].as_u64().context("missing shell ID")?;
+    let mut output = header.to_string() + "\n";
+    if info["input"] == true {
+        for line in lines.filter(|line| !line.is_empty()) {
+            output += &decrypt_input(line, encrypt)?.to_string();
+            output += "\n";
+        }
+        return Ok(output);
+    }
+    let mut offset = info["offset"].as_u64().context("missing offset")?;
+
+    // Chunks may split multibyte characters, so hold back incomplete ones.
+    let mut pending = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let mut event: Value = serde_json::from_str(line)?;
+        if event[1] == "o" {
+            let Some(data) = event[2].as_str() else {
+                bail!("invalid output event: {line}");
+            };
+            let data = BASE64_STANDARD.decode(data)?;
+            pending.extend(encrypt.segment(0x100000000 | shell, offset, &data));
+            offset += data.len() as u64;
+
+            let complete = match std::str::from_utf8(&pending) {
+                Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                _ => pending.len(),
+            };
+            let text = String::from_utf8_lossy(&pending[..complete]).into_owned();
+            pending.drain(..complete);
+            event[2] = text.into();
+        }
+        output += &event.to_string();
+        output += "\n";
+    }
+    Ok(output)
+}
+
+/// Decrypt an input event, which viewers encrypt on their own streams.
+fn decrypt_input(line: &str, encrypt: &Encrypt) -> Result<Value> {
+    let mut event: Value = serde_json::from_str(line)?;
+    if event[1] != "i" {
+        return Ok(event);
+    }
+    let (Some(data), Some(offset)) = (event[2].as_str(), event[3]["offset"].as_u64()) else {
+        bail!("invalid input event: {line}");
+    };
+    let data = encrypt.segment(0x200000000, offset, &BASE64_STANDARD.decode(data)?);
+    event[2] = String::from_utf8_lossy(&data).into_owned().into();
+    if let Some(user) = event[3].as_object_mut() {
+        user.remove("offset");
+    }
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+    use serde_json::{json, Value};
+
+    use super::decrypt_recording;
+    use crate::encrypt::Encrypt;
+
+    #[test]
+    fn decrypt_events() -> Result<()> {
+        let encrypt = Encrypt::new("test");
+        let plaintext = "héllo\r\n".as_bytes();
+        // Split the output in the middle of the two-byte character.
+        let (first, second) = plaintext.split_at(2);
+        let chunk = |offset: u64, data: &[u8]| {
+            let data = encrypt.segment(0x100000002, offset, data);
+            BASE64_STANDARD.encode(data)
+        };
+
+        let header = json!({
+            "version": 2,
+            "width": 80,
+            "height": 24,
+            "timestamp": 1_700_000_000,
+            "sshx": { "shell": 2, "offset": 100, "encrypted": true },
+        });
+        let cast = [
+            header.to_string(),
+            json!([0.5, "o", chunk(100, first)]).to_string(),
+            json!([0.75, "r", "100x30"]).to_string(),
+            json!([1.0, "o", chunk(102, second)]).to_string(),
+        ]
+        .join("\n");
+
+        let decrypted = decrypt_recording(&cast, &encrypt)?;
+        let lines: Vec<Value> = (decrypted.lines())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines[0].get("sshx"), None);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[1], json!([0.5, "o", "h"]));
+        assert_eq!(lines[2], json!([0.75, "r", "100x30"]));
+        assert_eq!(lines[3], json!([1.0, "o", "éllo\r\n"]));
+        Ok(())
+    }
+
+    #[test]
+    fn decrypt_input_events() -> Result<()> {
+        let encrypt = Encrypt::new("test");
+        let chunk = |offset: u64, data: &[u8]| {
+            let data = encrypt.segment(0x200000000, offset, data);
+            BASE64_STANDARD.encode(data)
+        };
+
+        let header = json!({
+            "version": 2,
+            "width": 80,
+            "height": 24,
+            "timestamp": 1_700_000_000,
+            "sshx": { "shell": 1, "input": true, "encrypted": true },
+        });
+        // Each viewer encrypts input from their own offset.
+        let cast = [
+            header.to_string(),
+            json!([0.5, "i", chunk(7, b"ls\r"), { "user": 1, "name": "alice", "offset": 7 }])
+                .to_string(),
+            json!([1.0, "i", chunk(90, b"id\r"), { "user": 2, "name": "bob", "offset": 90 }])
+                .to_string(),
+        ]
+        .join("\n");
+
+        let decrypted = decrypt_recording(&cast, &encrypt)?;
+        let lines: Vec<Value> = (decrypted.lines())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines[0].get("sshx"), None);
+        assert_eq!(
+            lines[1],
+            json!([0.5, "i", "ls\r", { "user": 1, "name": "alice" }])
+        );
+        assert_eq!(
+            lines[2],
+            json!([1.0, "i", "id\r", { "user": 2, "name": "bob" }])
+        );
+        Ok(())
+    }
+}
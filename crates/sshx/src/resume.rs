@@ -0,0 +1,189 @@
+//! Local persistence of session credentials, for resuming after a restart.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+/// Credentials needed to reconnect to an existing session on the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedSession {
+    /// Origin of the server that the session was created on.
+    pub origin: String,
+    /// Name of the session.
+    pub name: String,
+    /// Signed verification token for the client.
+    pub token: String,
+    /// Public web URL to view the session, including the encryption key.
+    pub url: String,
+    /// Web URL that lets viewers write, if the main URL is read-only.
+    pub write_url: Option<String>,
+    /// Encryption key for this session, hidden from the server.
+    pub encryption_key: String,
+    /// Base32 secret for TOTP codes required to join, if enabled.
+    pub totp_secret: Option<String>,
+    /// Time when the session reaches its time limit, if it has one.
+    pub deadline: Option<SystemTime>,
+    /// Flow-control window for output, if the server acknowledges it.
+    pub flow_window: Option<u64>,
+    /// Identity of this client as the host, if it adopted the session.
+    pub host: Option<String>,
+    /// Whether this client runs some of the shells, beside the main host.
+    pub co_host: bool,
+}
+
+impl SavedSession {
+    /// Load a saved session from a file, if it exists.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("failed to read saved session"),
+        };
+        let field = |key: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+                .map(String::from)
+                .with_context(|| format!("saved session is missing {key}"))
+        };
+        Ok(Some(Self {
+            origin: field("origin")?,
+            name: field("name")?,
+            token: field("token")?,
+            url: field("url")?,
+            write_url: field("write_url").ok(),
+            encryption_key: field("encryption_key")?,
+            totp_secret: field("totp_secret").ok(),
+            deadline: match field("expires_ms") {
+                Ok(ms) => Some(UNIX_EPOCH + Duration::from_millis(ms.parse()?)),
+                Err(_) => None,
+            },
+            flow_window: match field("flow_window") {
+                Ok(bytes) => Some(bytes.parse()?),
+                Err(_) => None,
+            },
+            host: field("host").ok(),
+            co_host: field("co_host").is_ok(),
+        }))
+    }
+
+    /// Save the session to a file that is only readable by the current user.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        writeln!(file, "origin={}", self.origin)?;
+        writeln!(file, "name={}", self.name)?;
+        writeln!(file, "token={}", self.token)?;
+        writeln!(file, "url={}", self.url)?;
+        if let Some(write_url) = &self.write_url {
+            writeln!(file, "write_url={write_url}")?;
+        }
+        writeln!(file, "encryption_key={}", self.encryption_key)?;
+        if let Some(secret) = &self.totp_secret {
+            writeln!(file, "totp_secret={secret}")?;
+        }
+        if let Some(deadline) = self.deadline {
+            let expires_ms = deadline.duration_since(UNIX_EPOCH)?.as_millis();
+            writeln!(file, "expires_ms={expires_ms}")?;
+        }
+        if let Some(window) = self.flow_window {
+            writeln!(file, "flow_window={window}")?;
+        }
+        if let Some(host) = &self.host {
+            writeln!(file, "host={host}")?;
+        }
+        if self.co_host {
+            writeln!(file, "co_host=true")?;
+        }
+        Ok(())
+    }
+
+    /// Remove a saved session file, ignoring it if missing.
+    pub fn remove(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the default location of the saved session file.
+pub fn default_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("session"))
+}
+
+/// Returns the location of the saved session file for a named session.
+pub fn named_path(name: &str) -> Result<PathBuf> {
+    Ok(state_dir()?.join(format!("session-{name}")))
+}
+
+fn state_dir() -> Result<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME").context("could not find home directory")?;
+            PathBuf::from(home).join(".local/state")
+        }
+    };
+    Ok(state_dir.join("sshx"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use anyhow::Result;
+
+    use super::SavedSession;
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("nested/session");
+        assert_eq!(SavedSession::load(&path)?, None);
+
+        let saved = SavedSession {
+            origin: "https://sshx.io".into(),
+            name: "abc123".into(),
+            token: "dG9rZW4=".into(),
+            url: "https://sshx.io/s/abc123#key".into(),
+            write_url: None,
+            encryption_key: "key".into(),
+            totp_secret: None,
+            deadline: None,
+            flow_window: None,
+            host: None,
+            co_host: false,
+        };
+        saved.save(&path)?;
+        assert_eq!(SavedSession::load(&path)?, Some(saved.clone()));
+
+        let saved = SavedSession {
+            totp_secret: Some("JBSWY3DPEHPK3PXP".into()),
+            deadline: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_000)),
+            flow_window: Some(1 << 20),
+            host: Some("hostid".into()),
+            co_host: true,
+            write_url: Some("https://sshx.io/s/abc123?write=dG9r#key".into()),
+            ..saved
+        };
+        saved.save(&path)?;
+        assert_eq!(SavedSession::load(&path)?, Some(saved));
+
+        SavedSession::remove(&path)?;
+        SavedSession::remove(&path)?;
+        assert_eq!(SavedSession::load(&path)?, None);
+        Ok(())
+    }
+}
@@ -1,20 +1,30 @@
 //! Defines tasks that control the behavior of a single shell in the client.
 
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use anyhow::Result;
 use encoding_rs::{CoderResult, UTF_8};
-use sshx_core::proto::{client_update::ClientMessage, TerminalData};
+use sshx_core::proto::{client_update::ClientMessage, ShellExited, TerminalData, TerminalScreen};
 use sshx_core::Sid;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::mpsc,
+    sync::{mpsc, oneshot},
+    time::{self, Duration, Instant},
 };
+use tracing::{debug, trace, warn};
 
 use crate::encrypt::Encrypt;
+use crate::sandbox::Sandbox;
+use crate::screen::Screen;
 use crate::terminal::Terminal;
 
 const CONTENT_CHUNK_SIZE: usize = 1 << 16; // Send at most this many bytes at a time.
 const CONTENT_ROLLING_BYTES: usize = 8 << 20; // Store at least this much content.
 const CONTENT_PRUNE_BYTES: usize = 12 << 20; // Prune when we exceed this length.
+const SCREEN_SNAPSHOT_BYTES: usize = 1 << 16; // Send the screen after this much output.
+const SCROLLBACK_BYTES: usize = 1 << 20; // Copy at most this much output into forks.
+const BRACKETED_PASTE_MODE: u16 = 2004; // Private mode set by programs that want pastes marked.
 
 /// Variants of terminal behavior that are used by the controller.
 #[derive(Debug, Clone)]
@@ -22,18 +32,60 @@ pub enum Runner {
     /// Spawns the specified shell as a subprocess, forwarding PTYs.
     Shell(String),
 
+    /// Spawns a single command with arguments as a subprocess, forwarding PTYs.
+    Command(Vec<String>),
+
     /// Mock runner that only echos its input, useful for testing.
     Echo,
 }
 
+/// Options applied to every shell spawned by a runner.
+#[derive(Debug, Clone, Default)]
+pub struct ShellOptions {
+    /// If set, output is sent at most once per interval, and lines redrawn
+    /// within that interval are collapsed to their latest contents.
+    pub batch: Option<Duration>,
+
+    /// If set, output with lines redrawn by carriage returns, like progress
+    /// bars, is held for up to this long so intermediate frames are collapsed.
+    /// Other output is sent right away.
+    pub collapse_redraws: Option<Duration>,
+
+    /// Sandbox profile restricting what spawned processes can access.
+    pub sandbox: Option<Arc<Sandbox>>,
+
+    /// If set, stop reading output once this many bytes are unacknowledged
+    /// by the server, so that the process blocks until viewers catch up.
+    pub flow_window: Option<u64>,
+
+    /// Working directory of spawned processes, if not the current one.
+    pub cwd: Option<PathBuf>,
+
+    /// Program and arguments to spawn instead of the runner's shell.
+    pub argv: Option<Vec<String>>,
+
+    /// Extra environment variables set in spawned processes.
+    pub env: Vec<(String, String)>,
+}
+
 /// Internal message routed to shell runners.
 pub enum ShellData {
     /// Sequence of input bytes from the server.
     Data(Vec<u8>),
+    /// Text pasted by a viewer, written in one piece.
+    Paste(Vec<u8>),
     /// Information about the server's current sequence number.
     Sync(u64),
+    /// The server is missing output from this sequence number onward.
+    Resync(u64),
+    /// Output acknowledged by the server, for flow control.
+    Ack(u64),
     /// Resize the shell to a different number of rows and columns.
     Size(u32, u32),
+    /// Ask for the most recent output of the shell, to copy into a fork.
+    Scrollback(oneshot::Sender<String>),
+    /// Output from another shell, shown before this shell's own output.
+    Seed(String),
 }
 
 impl Runner {
@@ -42,11 +94,18 @@ impl Runner {
         &self,
         id: Sid,
         encrypt: Encrypt,
+        options: &ShellOptions,
         shell_rx: mpsc::Receiver<ShellData>,
         output_tx: mpsc::Sender<ClientMessage>,
     ) -> Result<()> {
         match self {
-            Self::Shell(shell) => shell_task(id, encrypt, shell, shell_rx, output_tx).await,
+            Self::Shell(shell) => {
+                let argv = [shell.clone()];
+                shell_task(id, encrypt, &argv, options, shell_rx, output_tx).await
+            }
+            Self::Command(argv) => {
+                shell_task(id, encrypt, argv, options, shell_rx, output_tx).await
+            }
             Self::Echo => echo_task(id, encrypt, shell_rx, output_tx).await,
         }
     }
@@ -56,25 +115,45 @@ impl Runner {
 async fn shell_task(
     id: Sid,
     encrypt: Encrypt,
-    shell: &str,
+    argv: &[String],
+    options: &ShellOptions,
     mut shell_rx: mpsc::Receiver<ShellData>,
     output_tx: mpsc::Sender<ClientMessage>,
 ) -> Result<()> {
-    let mut term = Terminal::new(shell).await?;
+    let batch = options.batch.or(options.collapse_redraws);
+    let sandbox = options.sandbox.as_deref();
+    let argv = options.argv.as_deref().unwrap_or(argv);
+    let mut term =
+        Terminal::with_args_in(argv, sandbox, options.cwd.as_deref(), &options.env).await?;
     term.set_winsize(24, 80)?;
+    debug!(%id, ?argv, "started shell process");
 
     let mut content = String::new(); // content from the terminal
     let mut content_offset = 0; // bytes before the first character of `content`
     let mut decoder = UTF_8.new_decoder(); // UTF-8 streaming decoder
     let mut seq = 0; // our log of the server's sequence number
     let mut seq_outdated = 0; // number of times seq has been outdated
+    let mut discontinuous = false; // set when output the server needs was pruned
+    let mut acked = 0; // output acknowledged by the server, for flow control
     let mut buf = [0u8; 4096]; // buffer for reading
     let mut finished = false; // set when this is done
+    let mut closed = false; // set when the server closed the shell, not the process
+    let mut next_flush = Instant::now(); // when batched output can next be sent
+    let mut screen = Screen::new(24, 80); // emulated screen, for late joiners
+    let mut screen_fed = 0; // bytes of content interpreted by `screen`
+    let mut screen_sent = 0; // value of `screen_fed` when the screen was last sent
+    let mut screen_offset = 0; // offset for encrypting the next screen
+    let mut pruned_lines = 0; // newlines in content before `content_offset`
+    let mut line_at = (0, 0); // newlines in content before some byte offset
 
     while !finished {
+        // Leave output in the pty while the flow-control window is full.
+        let read_bytes = (content_offset + content.len()) as u64;
+        let window_open = options.flow_window.is_none_or(|w| read_bytes < acked + w);
         tokio::select! {
-            result = term.read(&mut buf) => {
+            result = term.read(&mut buf), if window_open => {
                 let n = result?;
+                trace!(%id, bytes = n, "read output from pty");
                 if n == 0 {
                     finished = true;
                 } else {
@@ -86,59 +165,243 @@ async fn shell_task(
             item = shell_rx.recv() => {
                 match item {
                     Some(ShellData::Data(data)) => {
+                        trace!(%id, bytes = data.len(), "writing input to pty");
+                        term.write_all(&data).await?;
+                    }
+                    Some(ShellData::Paste(data)) => {
+                        trace!(%id, bytes = data.len(), "writing paste to pty");
+                        let data = match screen.mode(BRACKETED_PASTE_MODE) {
+                            true => bracket_paste(&data),
+                            false => data,
+                        };
                         term.write_all(&data).await?;
                     }
                     Some(ShellData::Sync(seq2)) => {
                         if seq2 < seq as u64 {
                             seq_outdated += 1;
                             if seq_outdated >= 3 {
+                                debug!(%id, seq, seq2, "rewinding to server sequence number");
                                 seq = seq2 as usize;
                             }
                         }
                     }
+                    Some(ShellData::Resync(seq2)) => {
+                        let seq2 = seq2 as usize;
+                        if seq2 >= content_offset && seq2 < seq {
+                            debug!(%id, seq, seq2, "resending output after gap");
+                            seq = seq2;
+                        } else if seq2 < content_offset {
+                            warn!(%id, seq2, content_offset, "output after gap was pruned");
+                            seq = content_offset;
+                            discontinuous = true;
+                        }
+                    }
+                    Some(ShellData::Ack(seq2)) => acked = seq2,
                     Some(ShellData::Size(rows, cols)) => {
+                        debug!(%id, rows, cols, "resizing pty");
                         term.set_winsize(rows as u16, cols as u16)?;
+                        screen.resize(rows as u16, cols as u16);
+                    }
+                    Some(ShellData::Scrollback(tx)) => {
+                        let start = content.len().saturating_sub(SCROLLBACK_BYTES);
+                        tx.send(content[prev_char_boundary(&content, start)..].into()).ok();
+                    }
+                    Some(ShellData::Seed(text)) => {
+                        if seq == 0 {
+                            content.insert_str(0, &text);
+                        } else {
+                            debug!(%id, "ignoring scrollback for shell with output");
+                        }
+                    }
+                    None => {
+                        // Server closed this shell.
+                        finished = true;
+                        closed = true;
                     }
-                    None => finished = true, // Server closed this shell.
                 }
             }
+            _ = time::sleep_until(next_flush),
+                if batch.is_some() && content_offset + content.len() > seq => {}
         }
 
         if finished {
+            debug!(%id, "shell process finished");
             content.reserve(decoder.max_utf8_buffer_length(0).unwrap());
             let (result, _, _) = decoder.decode_to_string(&[], &mut content, true);
             debug_assert!(result == CoderResult::InputEmpty);
         }
 
+        let unsent_start = seq.saturating_sub(content_offset).min(content.len());
+        let unsent = &content[prev_char_boundary(&content, unsent_start)..];
+        let flush = batch.is_none()
+            || finished
+            || Instant::now() >= next_flush
+            || (options.batch.is_none() && !has_redraws(unsent));
+        if let (Some(interval), true) = (batch, flush && content_offset + content.len() > seq) {
+            // Unsent content has no sequence numbers yet, so it can be rewritten.
+            let start = seq - content_offset;
+            let collapsed = collapse_redraws(&content[start..]);
+            content.truncate(start);
+            content.push_str(&collapsed);
+            next_flush = Instant::now() + interval;
+        }
+
         // Send data if the server has fallen behind.
-        if content_offset + content.len() > seq {
+        while flush && content_offset + content.len() > seq {
             let start = prev_char_boundary(&content, seq - content_offset);
             let end = prev_char_boundary(&content, (start + CONTENT_CHUNK_SIZE).min(content.len()));
             let data = encrypt.segment(
                 0x100000000 | id.0 as u64, // stream number
                 (content_offset + start) as u64,
-                content[start..end].as_bytes(),
+                &content.as_bytes()[start..end],
             );
+            if line_at.0 != content_offset + start {
+                line_at = (
+                    content_offset + start,
+                    pruned_lines + count_lines(&content[..start]),
+                );
+            }
             let data = TerminalData {
                 id: id.0,
                 data: data.into(),
                 seq: (content_offset + start) as u64,
+                line: line_at.1,
+                line_known: true,
+                discontinuous: std::mem::take(&mut discontinuous),
             };
             output_tx.send(ClientMessage::Data(data)).await?;
+            line_at = (
+                content_offset + end,
+                line_at.1 + count_lines(&content[start..end]),
+            );
+            if content_offset + end > screen_fed {
+                // Output may be sent again after rewinding, but is only interpreted once.
+                let from = screen_fed.max(content_offset + start) - content_offset;
+                screen.feed(&content[prev_char_boundary(&content, from)..end]);
+                screen_fed = content_offset + end;
+            }
             seq = content_offset + end;
             seq_outdated = 0;
+            if batch.is_none() {
+                break;
+            }
+        }
+
+        // Send the current screen once there is enough output that replaying
+        // all of it would be slow for viewers who join later.
+        if screen_fed - screen_sent >= SCREEN_SNAPSHOT_BYTES {
+            let rendered = screen.render();
+            let data = encrypt.segment(
+                0x300000000 | id.0 as u64, // stream number
+                screen_offset,
+                rendered.as_bytes(),
+            );
+            let data = TerminalScreen {
+                id: id.0,
+                seq: screen_fed as u64,
+                data: data.into(),
+                offset: screen_offset,
+            };
+            output_tx.send(ClientMessage::Screen(data)).await?;
+            screen_offset += rendered.len() as u64;
+            screen_sent = screen_fed;
         }
 
         if content.len() > CONTENT_PRUNE_BYTES && seq - CONTENT_ROLLING_BYTES > content_offset {
             let pruned = (seq - CONTENT_ROLLING_BYTES) - content_offset;
             let pruned = prev_char_boundary(&content, pruned);
+            pruned_lines += count_lines(&content[..pruned]);
             content_offset += pruned;
             content.drain(..pruned);
         }
     }
+
+    if !closed {
+        if let Some(code) = term.exit_code().await {
+            debug!(%id, code, "shell process exited");
+            let exited = ShellExited { id: id.0, code };
+            output_tx.send(ClientMessage::Exited(exited)).await?;
+        }
+    }
     Ok(())
 }
 
+/// Count the newlines in some output.
+fn count_lines(text: &str) -> u64 {
+    text.bytes().filter(|&b| b == b'\n').count() as u64
+}
+
+/// Wrap pasted text in bracketed paste markers, so that programs which ask
+/// for them can tell it apart from typed input.
+///
+/// End markers inside the text are removed, since they would let it break out
+/// of the paste and run as typed commands.
+fn bracket_paste(data: &[u8]) -> Vec<u8> {
+    const END: &[u8] = b"\x1b[201~";
+    let mut text = data.to_vec();
+    // Removing a marker can join the bytes around it into another one.
+    while let Some(i) = text.windows(END.len()).position(|w| w == END) {
+        text.drain(i..i + END.len());
+    }
+    [b"\x1b[200~", &text[..], END].concat()
+}
+
+/// Drop intermediate frames of lines redrawn with carriage returns, such as
+/// progress bars, when the final frame overwrites all of them.
+fn collapse_redraws(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let frames: Vec<&str> = line.split('\r').collect();
+        let collapse = match frames.as_slice() {
+            [_, middle @ .., last] if !middle.is_empty() => match display_width(last, true) {
+                Some(width) => {
+                    (middle.iter()).all(|f| display_width(f, false).is_some_and(|w| w <= width))
+                }
+                None => false,
+            },
+            _ => false,
+        };
+        if collapse {
+            out.push_str(frames[0]);
+            out.push('\r');
+            out.push_str(frames[frames.len() - 1]);
+        } else {
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+/// Returns whether text has carriage returns that redraw a line, rather than
+/// ending it.
+fn has_redraws(text: &str) -> bool {
+    (text.match_indices('\r')).any(|(i, _)| !text[i + 1..].starts_with('\n'))
+}
+
+/// Count the characters in a frame, or `None` if it has control characters.
+///
+/// If `escapes` is set, CSI escape sequences are allowed and not counted.
+fn display_width(frame: &str, escapes: bool) -> Option<usize> {
+    let mut width = 0;
+    let mut chars = frame.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && escapes {
+            if chars.next() != Some('[') {
+                return None;
+            }
+            chars.find(|c| ('\x40'..='\x7e').contains(c))?;
+        } else if c.is_control() {
+            return None;
+        } else {
+            width += 1;
+        }
+    }
+    Some(width)
+}
+
 /// Find the last char boundary before an index in O(1) time.
 fn prev_char_boundary(s: &str, i: usize) -> usize {
     (0..=i)
@@ -154,23 +417,70 @@ async fn echo_task(
     output_tx: mpsc::Sender<ClientMessage>,
 ) -> Result<()> {
     let mut seq = 0;
+    let mut content = String::new();
     while let Some(item) = shell_rx.recv().await {
-        match item {
-            ShellData::Data(data) => {
-                let msg = String::from_utf8_lossy(&data);
-                let term_data = TerminalData {
-                    id: id.0,
-                    data: encrypt
-                        .segment(0x100000000 | id.0 as u64, seq, msg.as_bytes())
-                        .into(),
-                    seq,
-                };
-                output_tx.send(ClientMessage::Data(term_data)).await?;
-                seq += msg.len() as u64;
+        let msg = match item {
+            ShellData::Data(data) | ShellData::Paste(data) => {
+                String::from_utf8_lossy(&data).into_owned()
             }
-            ShellData::Sync(_) => (),
-            ShellData::Size(_, _) => (),
-        }
+            ShellData::Seed(text) => text,
+            ShellData::Scrollback(tx) => {
+                tx.send(content.clone()).ok();
+                continue;
+            }
+            ShellData::Sync(_) | ShellData::Resync(_) | ShellData::Ack(_) | ShellData::Size(..) => {
+                continue
+            }
+        };
+        let term_data = TerminalData {
+            id: id.0,
+            data: encrypt
+                .segment(0x100000000 | id.0 as u64, seq, msg.as_bytes())
+                .into(),
+            seq,
+            ..Default::default()
+        };
+        output_tx.send(ClientMessage::Data(term_data)).await?;
+        seq += msg.len() as u64;
+        content.push_str(&msg);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{bracket_paste, collapse_redraws, has_redraws};
+
+    #[test]
+    fn bracketed_paste() {
+        assert_eq!(bracket_paste(b"ls\n"), b"\x1b[200~ls\n\x1b[201~");
+        let sneaky = b"x\x1b[201~rm -rf ~\n";
+        assert_eq!(bracket_paste(sneaky), b"\x1b[200~xrm -rf ~\n\x1b[201~");
+        let nested = b"\x1b[20\x1b[201~1~echo\n";
+        assert_eq!(bracket_paste(nested), b"\x1b[200~echo\n\x1b[201~");
+    }
+
+    #[test]
+    fn collapse_progress_bars() {
+        let text = "start\n[#  ] 10%\r[## ] 50%\r[###] 99%\n";
+        assert_eq!(collapse_redraws(text), "start\n[#  ] 10%\r[###] 99%\n");
+
+        let colored = "a\r1%\r2%\r\x1b[32mok\x1b[0m";
+        assert_eq!(collapse_redraws(colored), "a\r\x1b[32mok\x1b[0m");
+
+        // Longer intermediate frames would leave visible characters behind.
+        let text = "x\rloading...\rdone";
+        assert_eq!(collapse_redraws(text), text);
+
+        // Intermediate frames with escape sequences may change the terminal.
+        let text = "x\r\x1b[31m1\r2";
+        assert_eq!(collapse_redraws(text), text);
+    }
+
+    #[test]
+    fn detect_redraws() {
+        assert!(!has_redraws("plain output\r\nnext line\n"));
+        assert!(has_redraws("[#  ] 10%\r[## ] 50%"));
+        assert!(has_redraws("spinner |\r"));
+    }
+}
@@ -0,0 +1,355 @@
+//! Opt-in sandbox for shells, using Landlock and seccomp on Linux.
+//!
+//! Landlock scopes filesystem access to a set of read-only and writable paths,
+//! while a seccomp filter denies system calls that could be used to escape or
+//! tamper with the host, such as `ptrace`, `mount`, or loading kernel modules.
+
+#![allow(unsafe_code)]
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+/// System directories that are readable and executable inside the sandbox.
+const SYSTEM_READ_PATHS: &[&str] = &[
+    "/bin", "/sbin", "/usr", "/lib", "/lib32", "/lib64", "/etc", "/opt", "/proc", "/sys", "/dev",
+    "/run",
+];
+
+/// Device and scratch paths that are writable inside the sandbox.
+const SYSTEM_WRITE_PATHS: &[&str] = &[
+    "/tmp",
+    "/dev/pts",
+    "/dev/ptmx",
+    "/dev/tty",
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+];
+
+/// Profile restricting what spawned shells can access.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Create a sandbox with access to system paths, plus additional paths.
+    pub fn new(read: Vec<PathBuf>, write: Vec<PathBuf>) -> Self {
+        Self { read, write }
+    }
+
+    /// Returns the paths that are readable but not writable.
+    pub fn read_paths(&self) -> impl Iterator<Item = &Path> {
+        let system = SYSTEM_READ_PATHS.iter().map(Path::new);
+        system.chain(self.read.iter().map(PathBuf::as_path))
+    }
+
+    /// Returns the paths that are readable and writable.
+    pub fn write_paths(&self) -> impl Iterator<Item = &Path> {
+        let system = SYSTEM_WRITE_PATHS.iter().map(Path::new);
+        system.chain(self.write.iter().map(PathBuf::as_path))
+    }
+
+    /// Prepare the sandbox in the parent process, before forking a shell.
+    pub(crate) fn prepare(&self) -> Result<Prepared> {
+        imp::prepare(self)
+    }
+}
+
+pub(crate) use imp::Prepared;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs::File;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use anyhow::{bail, Context, Result};
+    use nix::errno::Errno;
+    use nix::libc::{self, sock_filter, sock_fprog};
+    use tracing::debug;
+
+    use super::Sandbox;
+
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// All filesystem rights from the first Landlock ABI version.
+    const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+    /// Rights that apply to regular files, rather than directories.
+    const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE;
+    const ACCESS_FS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// System calls that are denied inside the sandbox.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+    ];
+
+    /// Sandbox state created before forking, applied in the child process.
+    #[derive(Debug)]
+    pub struct Prepared {
+        ruleset: OwnedFd,
+        filter: Vec<sock_filter>,
+    }
+
+    pub fn prepare(sandbox: &Sandbox) -> Result<Prepared> {
+        // Safety: These system calls only read from the pointers passed in.
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            bail!("sandbox requires Landlock, which is not enabled in this kernel");
+        }
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_ALL,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        let fd = Errno::result(fd).context("failed to create Landlock ruleset")?;
+        // Safety: The file descriptor was just returned by the kernel.
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let paths = (sandbox.read_paths().map(|path| (path, ACCESS_FS_READ)))
+            .chain(sandbox.write_paths().map(|path| (path, ACCESS_FS_ALL)));
+        for (path, access) in paths {
+            let file = match File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(path)
+            {
+                Ok(file) => file,
+                Err(err) => {
+                    debug!(path = %path.display(), %err, "skipping sandbox path");
+                    continue;
+                }
+            };
+            let access = match file.metadata()?.is_dir() {
+                true => access,
+                false => access & ACCESS_FS_FILE,
+            };
+            let rule = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: file.as_raw_fd(),
+            };
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule,
+                    0,
+                )
+            };
+            Errno::result(ret)
+                .with_context(|| format!("failed to allow {} in sandbox", path.display()))?;
+        }
+
+        Ok(Prepared {
+            ruleset,
+            filter: seccomp_filter()?,
+        })
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn seccomp_filter() -> Result<Vec<sock_filter>> {
+        use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+        use libc::{SECCOMP_RET_ALLOW, SECCOMP_RET_DATA, SECCOMP_RET_ERRNO};
+
+        let stmt = |code: u32, k| sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        let jump = |op, k, jt, jf| sock_filter {
+            code: (BPF_JMP | op | BPF_K) as u16,
+            jt,
+            jf,
+            k,
+        };
+        let deny = stmt(
+            BPF_RET | BPF_K,
+            SECCOMP_RET_ERRNO | (libc::EPERM as u32 & SECCOMP_RET_DATA),
+        );
+
+        let mut filter = vec![
+            // Deny system calls from any other architecture or ABI.
+            stmt(BPF_LD | BPF_W | BPF_ABS, 4), // seccomp_data.arch
+            jump(BPF_JEQ, AUDIT_ARCH, 1, 0),
+            deny,
+            stmt(BPF_LD | BPF_W | BPF_ABS, 0), // seccomp_data.nr
+            jump(BPF_JGE, 0x4000_0000, 0, 1),  // x32 system calls
+            deny,
+        ];
+        for &nr in DENIED_SYSCALLS {
+            filter.push(jump(BPF_JEQ, nr as u32, 0, 1));
+            filter.push(deny);
+        }
+        filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        Ok(filter)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn seccomp_filter() -> Result<Vec<sock_filter>> {
+        bail!("sandbox is not supported on this architecture")
+    }
+
+    impl Prepared {
+        /// Restrict the current process. This is async-signal-safe, so it can
+        /// be called in a child process between `fork()` and `exec()`.
+        pub fn apply(&self) -> Result<(), Errno> {
+            // Safety: These system calls only read from the pointers passed in, which
+            // remain valid for the duration of the call.
+            unsafe {
+                Errno::result(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+                Errno::result(libc::syscall(
+                    libc::SYS_landlock_restrict_self,
+                    self.ruleset.as_raw_fd(),
+                    0,
+                ))?;
+                let prog = sock_fprog {
+                    len: self.filter.len() as u16,
+                    filter: self.filter.as_ptr() as *mut sock_filter,
+                };
+                Errno::result(libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &prog as *const sock_fprog,
+                ))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use anyhow::{bail, Result};
+    use nix::errno::Errno;
+
+    use super::Sandbox;
+
+    /// Placeholder for platforms without sandbox support.
+    #[derive(Debug)]
+    pub enum Prepared {}
+
+    pub fn prepare(_: &Sandbox) -> Result<Prepared> {
+        bail!("sandbox is only supported on Linux")
+    }
+
+    impl Prepared {
+        pub fn apply(&self) -> Result<(), Errno> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::path::PathBuf;
+
+    use anyhow::Result;
+    use tokio::io::AsyncReadExt;
+
+    use super::Sandbox;
+    use crate::terminal::Terminal;
+
+    /// Run a shell command in the sandbox and return its output.
+    async fn run(sandbox: &Sandbox, script: &str) -> Result<String> {
+        let argv = ["/bin/sh", "-c", script].map(String::from);
+        let mut terminal = Terminal::with_args(&argv, Some(sandbox)).await?;
+        let mut output = Vec::new();
+        let mut buf = [0; 4096];
+        // Reading fails with EIO once the child process exits.
+        while let Ok(n @ 1..) = terminal.read(&mut buf).await {
+            output.extend_from_slice(&buf[..n]);
+        }
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    #[tokio::test]
+    async fn restrict_filesystem() -> Result<()> {
+        // Use directories outside of /tmp, which is always writable.
+        let scratch = tempfile::tempdir_in(env!("CARGO_MANIFEST_DIR"))?;
+        let allowed = tempfile::tempdir_in(env!("CARGO_MANIFEST_DIR"))?;
+        let sandbox = Sandbox::new(vec![], vec![PathBuf::from(allowed.path())]);
+        if sandbox.prepare().is_err() {
+            return Ok(()); // Landlock is not available in this environment.
+        }
+        if sandbox
+            .write_paths()
+            .any(|path| scratch.path().starts_with(path))
+        {
+            return Ok(()); // The crate is checked out in a writable location.
+        }
+
+        let blocked = scratch.path().join("blocked");
+        let script = format!(
+            "echo ok > {}/file && echo wrote; echo no > {} || echo denied",
+            allowed.path().display(),
+            blocked.display(),
+        );
+        let output = run(&sandbox, &script).await?;
+        assert!(output.contains("wrote"), "output: {output}");
+        assert!(output.contains("denied"), "output: {output}");
+        assert!(!blocked.exists());
+        Ok(())
+    }
+}
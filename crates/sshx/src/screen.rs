@@ -0,0 +1,643 @@
+//! Lightweight terminal emulator that tracks the visible screen of a shell.
+//!
+//! Viewers that join a session late are sent a rendering of the current screen
+//! instead of replaying the shell's entire output history. Only the escape
+//! sequences that change what is displayed are interpreted, and every
+//! character is assumed to be one column wide.
+
+use std::fmt::Write;
+
+/// Private modes that are tracked and restored, but have no visible effect.
+///
+/// These are application cursor keys, mouse reporting, and bracketed paste,
+/// which change what viewers' terminals send as input.
+const TRACKED_MODES: &[u16] = &[1, 1000, 1002, 1003, 1005, 1006, 1015, 2004];
+
+/// Maximum number of parameter bytes in a control sequence.
+const MAX_PARAMS_LEN: usize = 256;
+
+/// Color of the text or background of a cell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Color {
+    #[default]
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// Graphic rendition of a cell, as set by SGR sequences.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Style {
+    /// Bit `n` is set if SGR attribute `n` (1 through 9) is enabled.
+    attrs: u16,
+    fg: Color,
+    bg: Color,
+}
+
+impl Style {
+    /// Style of cells cleared while this style is active.
+    fn erased(self) -> Self {
+        Self {
+            bg: self.bg,
+            ..Self::default()
+        }
+    }
+
+    /// Apply the parameters of an SGR sequence.
+    fn apply(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            *self = Self::default();
+        }
+        let mut iter = params.iter().copied();
+        while let Some(param) = iter.next() {
+            match param {
+                0 => *self = Self::default(),
+                1..=9 => self.attrs |= 1 << param,
+                21 => self.attrs &= !(1 << 1),
+                22 => self.attrs &= !(1 << 1 | 1 << 2),
+                23..=29 => self.attrs &= !(1 << (param - 20)),
+                30..=37 => self.fg = Color::Indexed((param - 30) as u8),
+                38 => self.fg = extended_color(&mut iter),
+                39 => self.fg = Color::Default,
+                40..=47 => self.bg = Color::Indexed((param - 40) as u8),
+                48 => self.bg = extended_color(&mut iter),
+                49 => self.bg = Color::Default,
+                90..=97 => self.fg = Color::Indexed((param - 90 + 8) as u8),
+                100..=107 => self.bg = Color::Indexed((param - 100 + 8) as u8),
+                _ => {}
+            }
+        }
+    }
+
+    /// Write an SGR sequence that sets this style from any previous style.
+    fn render(self, out: &mut String) {
+        out.push_str("\x1b[0");
+        for attr in 1..=9 {
+            if self.attrs & (1 << attr) != 0 {
+                write!(out, ";{attr}").unwrap();
+            }
+        }
+        for (color, base) in [(self.fg, 38), (self.bg, 48)] {
+            match color {
+                Color::Default => {}
+                Color::Indexed(n) => write!(out, ";{base};5;{n}").unwrap(),
+                Color::Rgb(r, g, b) => write!(out, ";{base};2;{r};{g};{b}").unwrap(),
+            }
+        }
+        out.push('m');
+    }
+}
+
+/// Parse the rest of a 256-color or true color SGR parameter.
+fn extended_color(iter: &mut impl Iterator<Item = u16>) -> Color {
+    match iter.next() {
+        Some(5) => Color::Indexed(iter.next().unwrap_or(0) as u8),
+        Some(2) => {
+            let mut next = || iter.next().unwrap_or(0) as u8;
+            Color::Rgb(next(), next(), next())
+        }
+        _ => Color::Default,
+    }
+}
+
+/// A single character on the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    style: Style,
+}
+
+impl Cell {
+    fn blank(style: Style) -> Self {
+        Self { ch: ' ', style }
+    }
+}
+
+/// State of the escape sequence parser.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    Escape,
+    /// Waiting for the character set designated by `ESC (` and similar.
+    Charset,
+    Csi(String),
+    /// Inside a string, like an OSC sequence, that ends with BEL or `ESC \`.
+    String,
+    StringEscape,
+}
+
+/// Tracks the contents of a terminal screen from the output written to it.
+#[derive(Debug, Clone)]
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    grid: Vec<Vec<Cell>>,
+    /// Contents of the primary screen while the alternate screen is active.
+    primary: Option<Vec<Vec<Cell>>>,
+    cursor: (usize, usize),
+    /// Set after writing to the last column, so the next character wraps.
+    pending_wrap: bool,
+    saved_cursor: ((usize, usize), Style),
+    style: Style,
+    scroll_region: (usize, usize),
+    cursor_hidden: bool,
+    no_autowrap: bool,
+    keypad_application: bool,
+    modes: Vec<u16>,
+    state: ParseState,
+}
+
+impl Screen {
+    /// Create a blank screen with a given size.
+    pub fn new(rows: u16, cols: u16) -> Self {
+        let (rows, cols) = (usize::from(rows.max(1)), usize::from(cols.max(1)));
+        Self {
+            rows,
+            cols,
+            grid: vec![vec![Cell::blank(Style::default()); cols]; rows],
+            primary: None,
+            cursor: (0, 0),
+            pending_wrap: false,
+            saved_cursor: ((0, 0), Style::default()),
+            style: Style::default(),
+            scroll_region: (0, rows - 1),
+            cursor_hidden: false,
+            no_autowrap: false,
+            keypad_application: false,
+            modes: Vec::new(),
+            state: ParseState::Ground,
+        }
+    }
+
+    /// Change the size of the screen, keeping the lines around the cursor.
+    ///
+    /// Lines are not reflowed, so text beyond the new width is cut off.
+    pub fn resize(&mut self, rows: u16, cols: u16) {
+        let (rows, cols) = (usize::from(rows.max(1)), usize::from(cols.max(1)));
+        let removed = (self.cursor.0 + 1).saturating_sub(rows);
+        for grid in std::iter::once(&mut self.grid).chain(&mut self.primary) {
+            grid.drain(..removed);
+            grid.resize(rows, vec![Cell::blank(Style::default()); cols]);
+            for line in grid.iter_mut() {
+                line.resize(cols, Cell::blank(Style::default()));
+            }
+        }
+        self.rows = rows;
+        self.cols = cols;
+        self.cursor = (
+            (self.cursor.0 - removed).min(rows - 1),
+            self.cursor.1.min(cols - 1),
+        );
+        self.pending_wrap = false;
+        self.scroll_region = (0, rows - 1);
+    }
+
+    /// Returns whether a private mode, like bracketed paste (2004), is set.
+    pub fn mode(&self, mode: u16) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    /// Interpret output written to the terminal.
+    pub fn feed(&mut self, text: &str) {
+        for c in text.chars() {
+            self.feed_char(c);
+        }
+    }
+
+    fn feed_char(&mut self, c: char) {
+        match std::mem::replace(&mut self.state, ParseState::Ground) {
+            ParseState::Ground => self.ground(c),
+            ParseState::Escape => self.escape(c),
+            ParseState::Charset => {}
+            ParseState::Csi(mut params) => match c {
+                '\x40'..='\x7e' => self.csi(&params, c),
+                '\x18' | '\x1a' => {} // CAN and SUB cancel the sequence.
+                '\x1b' => self.state = ParseState::Escape,
+                c if c.is_control() => {
+                    self.ground(c); // Control characters take effect immediately.
+                    self.state = ParseState::Csi(params);
+                }
+                _ => {
+                    if params.len() < MAX_PARAMS_LEN {
+                        params.push(c);
+                    }
+                    self.state = ParseState::Csi(params);
+                }
+            },
+            ParseState::String => match c {
+                '\x07' | '\x18' | '\x1a' => {}
+                '\x1b' => self.state = ParseState::StringEscape,
+                _ => self.state = ParseState::String,
+            },
+            ParseState::StringEscape => match c {
+                '\\' => {}
+                _ => self.state = ParseState::String,
+            },
+        }
+    }
+
+    fn ground(&mut self, c: char) {
+        match c {
+            '\x1b' => self.state = ParseState::Escape,
+            '\r' => self.carriage_return(),
+            '\n' | '\x0b' | '\x0c' => self.linefeed(),
+            '\x08' => {
+                self.cursor.1 = self.cursor.1.saturating_sub(1);
+                self.pending_wrap = false;
+            }
+            '\t' => {
+                self.cursor.1 = ((self.cursor.1 / 8 + 1) * 8).min(self.cols - 1);
+                self.pending_wrap = false;
+            }
+            c if c.is_control() => {}
+            c => self.print(c),
+        }
+    }
+
+    fn escape(&mut self, c: char) {
+        match c {
+            '[' => self.state = ParseState::Csi(String::new()),
+            ']' | 'P' | 'X' | '^' | '_' => self.state = ParseState::String,
+            '(' | ')' | '*' | '+' | '-' | '.' | '/' => self.state = ParseState::Charset,
+            '7' => self.saved_cursor = (self.cursor, self.style),
+            '8' => self.restore_cursor(),
+            'D' => self.linefeed(),
+            'E' => {
+                self.carriage_return();
+                self.linefeed();
+            }
+            'M' => self.reverse_index(),
+            'c' => *self = Self::new(self.rows as u16, self.cols as u16),
+            '=' => self.keypad_application = true,
+            '>' => self.keypad_application = false,
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, params: &str, action: char) {
+        let (private, params) = match params.as_bytes().first() {
+            Some(b'<'..=b'?') => (params.as_bytes()[0], &params[1..]),
+            _ => (0, params),
+        };
+        if params.bytes().any(|b| (0x20..0x30).contains(&b)) {
+            return; // Sequences with intermediate bytes do not affect the screen.
+        }
+        let args: Vec<u16> = params
+            .split([';', ':'])
+            .map(|p| p.parse().unwrap_or(0))
+            .collect();
+        let args = if params.is_empty() { &[][..] } else { &args };
+        let arg = |i: usize| args.get(i).copied().filter(|&n| n != 0).unwrap_or(1) as usize;
+
+        match (private, action) {
+            (b'?', 'h') => args.iter().for_each(|&mode| self.set_mode(mode, true)),
+            (b'?', 'l') => args.iter().for_each(|&mode| self.set_mode(mode, false)),
+            (0, 'A') => self.move_to(self.cursor.0.saturating_sub(arg(0)), self.cursor.1),
+            (0, 'B') => self.move_to(self.cursor.0 + arg(0), self.cursor.1),
+            (0, 'C') => self.move_to(self.cursor.0, self.cursor.1 + arg(0)),
+            (0, 'D') => self.move_to(self.cursor.0, self.cursor.1.saturating_sub(arg(0))),
+            (0, 'E') => self.move_to(self.cursor.0 + arg(0), 0),
+            (0, 'F') => self.move_to(self.cursor.0.saturating_sub(arg(0)), 0),
+            (0, 'G' | '`') => self.move_to(self.cursor.0, arg(0) - 1),
+            (0, 'H' | 'f') => self.move_to(arg(0) - 1, arg(1) - 1),
+            (0, 'd') => self.move_to(arg(0) - 1, self.cursor.1),
+            (0, 'J') => self.erase_display(args.first().copied().unwrap_or(0)),
+            (0, 'K') => self.erase_line(args.first().copied().unwrap_or(0)),
+            (0, 'L') => self.insert_lines(arg(0)),
+            (0, 'M') => self.delete_lines(arg(0)),
+            (0, '@') => self.insert_chars(arg(0)),
+            (0, 'P') => self.delete_chars(arg(0)),
+            (0, 'X') => {
+                let (row, col) = self.cursor;
+                let end = (col + arg(0)).min(self.cols);
+                self.grid[row][col..end].fill(Cell::blank(self.style.erased()));
+            }
+            (0, 'S') => self.scroll_up(arg(0)),
+            (0, 'T') => self.scroll_down(arg(0)),
+            (0, 'm') => self.style.apply(args),
+            (0, 'r') => {
+                let top = arg(0) - 1;
+                let bottom = args.get(1).copied().filter(|&n| n != 0);
+                let bottom = bottom.map_or(self.rows, usize::from).min(self.rows) - 1;
+                if top < bottom {
+                    self.scroll_region = (top, bottom);
+                    self.move_to(0, 0);
+                }
+            }
+            (0, 's') => self.saved_cursor = (self.cursor, self.style),
+            (0, 'u') => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn set_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            7 => self.no_autowrap = !enabled,
+            25 => self.cursor_hidden = !enabled,
+            47 | 1047 | 1049 => {
+                if mode == 1049 && enabled {
+                    self.saved_cursor = (self.cursor, self.style);
+                }
+                if enabled && self.primary.is_none() {
+                    let blank = vec![vec![Cell::blank(Style::default()); self.cols]; self.rows];
+                    self.primary = Some(std::mem::replace(&mut self.grid, blank));
+                } else if let (false, Some(primary)) = (enabled, self.primary.take()) {
+                    self.grid = primary;
+                }
+                if mode == 1049 && !enabled {
+                    self.restore_cursor();
+                }
+            }
+            mode if TRACKED_MODES.contains(&mode) => {
+                self.modes.retain(|&m| m != mode);
+                if enabled {
+                    self.modes.push(mode);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        if self.pending_wrap {
+            self.carriage_return();
+            self.linefeed();
+        }
+        let (row, col) = self.cursor;
+        self.grid[row][col] = Cell {
+            ch: c,
+            style: self.style,
+        };
+        if col + 1 < self.cols {
+            self.cursor.1 += 1;
+        } else {
+            self.pending_wrap = !self.no_autowrap;
+        }
+    }
+
+    fn carriage_return(&mut self) {
+        self.cursor.1 = 0;
+        self.pending_wrap = false;
+    }
+
+    fn linefeed(&mut self) {
+        self.pending_wrap = false;
+        if self.cursor.0 == self.scroll_region.1 {
+            self.scroll_up(1);
+        } else if self.cursor.0 + 1 < self.rows {
+            self.cursor.0 += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.pending_wrap = false;
+        if self.cursor.0 == self.scroll_region.0 {
+            self.scroll_down(1);
+        } else {
+            self.cursor.0 = self.cursor.0.saturating_sub(1);
+        }
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.cursor = (row.min(self.rows - 1), col.min(self.cols - 1));
+        self.pending_wrap = false;
+    }
+
+    fn restore_cursor(&mut self) {
+        let ((row, col), style) = self.saved_cursor;
+        self.move_to(row, col);
+        self.style = style;
+    }
+
+    fn blank_line(&self) -> Vec<Cell> {
+        vec![Cell::blank(self.style.erased()); self.cols]
+    }
+
+    /// Scroll lines in the scroll region up, adding blank lines at the bottom.
+    fn scroll_up(&mut self, n: usize) {
+        let (top, bottom) = self.scroll_region;
+        let n = n.min(bottom + 1 - top);
+        self.grid.drain(top..top + n);
+        let blank = self.blank_line();
+        self.grid.splice(
+            bottom + 1 - n..bottom + 1 - n,
+            std::iter::repeat_n(blank, n),
+        );
+    }
+
+    /// Scroll lines in the scroll region down, adding blank lines at the top.
+    fn scroll_down(&mut self, n: usize) {
+        let (top, bottom) = self.scroll_region;
+        let n = n.min(bottom + 1 - top);
+        self.grid.drain(bottom + 1 - n..bottom + 1);
+        let blank = self.blank_line();
+        self.grid.splice(top..top, std::iter::repeat_n(blank, n));
+    }
+
+    fn insert_lines(&mut self, n: usize) {
+        let (top, bottom) = self.scroll_region;
+        if (top..=bottom).contains(&self.cursor.0) {
+            self.scroll_region.0 = self.cursor.0;
+            self.scroll_down(n);
+            self.scroll_region.0 = top;
+            self.carriage_return();
+        }
+    }
+
+    fn delete_lines(&mut self, n: usize) {
+        let (top, bottom) = self.scroll_region;
+        if (top..=bottom).contains(&self.cursor.0) {
+            self.scroll_region.0 = self.cursor.0;
+            self.scroll_up(n);
+            self.scroll_region.0 = top;
+            self.carriage_return();
+        }
+    }
+
+    fn insert_chars(&mut self, n: usize) {
+        let (row, col) = self.cursor;
+        let blank = Cell::blank(self.style.erased());
+        let line = &mut self.grid[row];
+        let n = n.min(self.cols - col);
+        line.splice(col..col, std::iter::repeat_n(blank, n));
+        line.truncate(self.cols);
+        self.pending_wrap = false;
+    }
+
+    fn delete_chars(&mut self, n: usize) {
+        let (row, col) = self.cursor;
+        let blank = Cell::blank(self.style.erased());
+        let line = &mut self.grid[row];
+        let n = n.min(self.cols - col);
+        line.drain(col..col + n);
+        line.extend(std::iter::repeat_n(blank, n));
+        self.pending_wrap = false;
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let blank = Cell::blank(self.style.erased());
+        let row = self.cursor.0;
+        match mode {
+            0 => {
+                self.erase_line(0);
+                self.grid[row + 1..].iter_mut().for_each(|l| l.fill(blank));
+            }
+            1 => {
+                self.erase_line(1);
+                self.grid[..row].iter_mut().for_each(|l| l.fill(blank));
+            }
+            2 | 3 => self.grid.iter_mut().for_each(|l| l.fill(blank)),
+            _ => {}
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let blank = Cell::blank(self.style.erased());
+        let (row, col) = self.cursor;
+        match mode {
+            0 => self.grid[row][col..].fill(blank),
+            1 => self.grid[row][..=col].fill(blank),
+            2 => self.grid[row].fill(blank),
+            _ => {}
+        }
+    }
+
+    /// Render the screen as output that reproduces it on a blank terminal of
+    /// the same size, including the cursor position and terminal modes.
+    pub fn render(&self) -> String {
+        let mut out = String::from("\x1bc"); // Reset the terminal first.
+        match &self.primary {
+            Some(primary) => {
+                render_grid(primary, &mut out);
+                let (row, col) = self.saved_cursor.0;
+                write!(out, "\x1b[{};{}H\x1b[?1049h", row + 1, col + 1).unwrap();
+                render_grid(&self.grid, &mut out);
+            }
+            None => render_grid(&self.grid, &mut out),
+        }
+        if self.scroll_region != (0, self.rows - 1) {
+            let (top, bottom) = self.scroll_region;
+            write!(out, "\x1b[{};{}r", top + 1, bottom + 1).unwrap();
+        }
+        let (row, col) = self.cursor;
+        write!(out, "\x1b[{};{}H", row + 1, col + 1).unwrap();
+        self.style.render(&mut out);
+        for mode in &self.modes {
+            write!(out, "\x1b[?{mode}h").unwrap();
+        }
+        if self.no_autowrap {
+            out.push_str("\x1b[?7l");
+        }
+        if self.cursor_hidden {
+            out.push_str("\x1b[?25l");
+        }
+        if self.keypad_application {
+            out.push_str("\x1b=");
+        }
+        out
+    }
+}
+
+/// Write the contents of each line, skipping trailing blank cells.
+fn render_grid(grid: &[Vec<Cell>], out: &mut String) {
+    let mut style = Style::default();
+    for (i, line) in grid.iter().enumerate() {
+        let blank = Cell::blank(Style::default());
+        let len = line
+            .iter()
+            .rposition(|&cell| cell != blank)
+            .map_or(0, |n| n + 1);
+        if len == 0 {
+            continue;
+        }
+        write!(out, "\x1b[{}H", i + 1).unwrap();
+        for cell in &line[..len] {
+            if cell.style != style {
+                style = cell.style;
+                style.render(out);
+            }
+            out.push(cell.ch);
+        }
+    }
+    if style != Style::default() {
+        out.push_str("\x1b[0m");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Screen;
+
+    fn text(screen: &Screen) -> Vec<String> {
+        let lines = screen.grid.iter();
+        let lines = lines.map(|line| line.iter().map(|cell| cell.ch).collect::<String>());
+        lines.map(|line| line.trim_end().to_string()).collect()
+    }
+
+    fn roundtrip(screen: &Screen) -> Screen {
+        let mut copy = Screen::new(screen.rows as u16, screen.cols as u16);
+        copy.feed(&screen.render());
+        assert_eq!(copy.grid, screen.grid);
+        assert_eq!(copy.primary, screen.primary);
+        assert_eq!(copy.cursor, screen.cursor);
+        assert_eq!(copy.style, screen.style);
+        assert_eq!(copy.modes, screen.modes);
+        copy
+    }
+
+    #[test]
+    fn text_and_scrolling() {
+        let mut screen = Screen::new(3, 10);
+        screen.feed("one\r\ntwo\r\nthree\r\nfour");
+        assert_eq!(text(&screen), ["two", "three", "four"]);
+        assert_eq!(screen.cursor, (2, 4));
+
+        // Long lines wrap onto the next row.
+        screen.feed("\r\n0123456789abc");
+        assert_eq!(text(&screen), ["four", "0123456789", "abc"]);
+        roundtrip(&screen);
+    }
+
+    #[test]
+    fn progress_bar_redraws() {
+        let mut screen = Screen::new(2, 20);
+        screen.feed("$ build\r\n");
+        for i in 0..=100 {
+            screen.feed(&format!("\r\x1b[K\x1b[32m{i}%\x1b[0m"));
+        }
+        assert_eq!(text(&screen), ["$ build", "100%"]);
+        roundtrip(&screen);
+    }
+
+    #[test]
+    fn alternate_screen_and_modes() {
+        let mut screen = Screen::new(4, 20);
+        screen.feed("$ vim\r\n");
+        screen.feed("\x1b[?1049h\x1b[?1h\x1b[?2004h\x1b[2J\x1b[H\x1b[1;4mtitle\x1b[3;5Hbody");
+        screen.feed("\x1b]0;window title\x07\x1b[?25l");
+        assert_eq!(text(&screen), ["title", "", "    body", ""]);
+        let copy = roundtrip(&screen);
+        assert!(copy.cursor_hidden);
+
+        screen.feed("\x1b[?1049l\x1b[?1l");
+        assert_eq!(text(&screen), ["$ vim", "", "", ""]);
+        assert_eq!(screen.cursor, (1, 0));
+        assert_eq!(screen.modes, [2004]);
+        assert!(screen.mode(2004));
+        roundtrip(&screen);
+    }
+
+    #[test]
+    fn scroll_region_and_editing() {
+        let mut screen = Screen::new(4, 10);
+        screen.feed("header\r\na\r\nb\r\nfooter");
+        screen.feed("\x1b[2;3r\x1b[3;1H\nc");
+        assert_eq!(text(&screen), ["header", "b", "c", "footer"]);
+        screen.feed("\x1b[1;1H\x1b[2P\x1b[1@");
+        assert_eq!(text(&screen), [" ader", "b", "c", "footer"]);
+        roundtrip(&screen);
+
+        screen.resize(2, 4);
+        assert_eq!(text(&screen), [" ade", "b"]);
+    }
+}
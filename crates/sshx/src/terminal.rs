@@ -6,22 +6,28 @@ use std::convert::Infallible;
 use std::env;
 use std::ffi::{CStr, CString};
 use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use close_fds::CloseFdsBuilder;
 use nix::errno::Errno;
-use nix::libc::{login_tty, TIOCGWINSZ, TIOCSWINSZ};
+use nix::libc::{chdir, login_tty, TIOCGWINSZ, TIOCSWINSZ};
 use nix::pty::{self, Winsize};
 use nix::sys::signal::{kill, Signal::SIGKILL};
-use nix::sys::wait::waitpid;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::{execvp, fork, ForkResult, Pid};
 use pin_project::{pin_project, pinned_drop};
 use tokio::fs::{self, File};
 use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::time;
 use tracing::{instrument, trace};
 
+use crate::sandbox::{Prepared, Sandbox};
+
 /// Returns the default shell on this system.
 pub async fn get_default_shell() -> String {
     if let Ok(shell) = env::var("SHELL") {
@@ -41,6 +47,7 @@ pub async fn get_default_shell() -> String {
 #[pin_project(PinnedDrop)]
 pub struct Terminal {
     child: Pid,
+    reaped: bool,
     #[pin]
     master_read: File,
     #[pin]
@@ -49,12 +56,37 @@ pub struct Terminal {
 
 impl Terminal {
     /// Create a new terminal, with attached PTY.
-    #[instrument]
     pub async fn new(shell: &str) -> Result<Terminal> {
+        Self::with_args(&[shell.to_owned()], None).await
+    }
+
+    /// Create a new terminal running a program with arguments, optionally
+    /// restricted by a sandbox.
+    pub async fn with_args(argv: &[String], sandbox: Option<&Sandbox>) -> Result<Terminal> {
+        Self::with_args_in(argv, sandbox, None, &[]).await
+    }
+
+    /// Create a new terminal like [`Terminal::with_args`], starting in a
+    /// working directory. The current one is kept if it cannot be entered.
+    /// Extra environment variables are set for the program, after the ones
+    /// describing the terminal.
+    #[instrument]
+    pub async fn with_args_in(
+        argv: &[String],
+        sandbox: Option<&Sandbox>,
+        cwd: Option<&Path>,
+        env: &[(String, String)],
+    ) -> Result<Terminal> {
+        ensure!(!argv.is_empty(), "missing program to run in terminal");
+        let sandbox = sandbox.map(Sandbox::prepare).transpose()?;
+        let cwd = cwd
+            .map(|cwd| CString::new(cwd.as_os_str().as_bytes()))
+            .transpose()?;
         let result = pty::openpty(None, None)?;
 
         // The slave file descriptor was created by openpty() and is forked here.
-        let child = Self::fork_child(shell, result.slave.as_raw_fd())?;
+        let slave_port = result.slave.as_raw_fd();
+        let child = Self::fork_child(argv, sandbox.as_ref(), cwd.as_deref(), env, slave_port)?;
 
         // We need to clone the file object to prevent livelocks in Tokio, when multiple
         // reads and writes happen concurrently on the same file descriptor. This is a
@@ -67,29 +99,53 @@ impl Terminal {
 
         Ok(Self {
             child,
+            reaped: false,
             master_read,
             master_write,
         })
     }
 
     /// Entry point for the child process, which spawns a shell.
-    fn fork_child(shell: &str, slave_port: RawFd) -> Result<Pid> {
-        let shell = CString::new(shell.to_owned())?;
+    fn fork_child(
+        argv: &[String],
+        sandbox: Option<&Prepared>,
+        cwd: Option<&CStr>,
+        env: &[(String, String)],
+        slave_port: RawFd,
+    ) -> Result<Pid> {
+        let argv = argv
+            .iter()
+            .map(|arg| CString::new(arg.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
 
         // Safety: This does not use any async-signal-unsafe operations in the child
         // branch, such as memory allocation.
         match unsafe { fork() }? {
             ForkResult::Parent { child } => Ok(child),
-            ForkResult::Child => match Self::execv_child(&shell, slave_port) {
+            ForkResult::Child => match Self::execv_child(&argv, sandbox, cwd, env, slave_port) {
                 Ok(infallible) => match infallible {},
                 Err(_) => std::process::exit(1),
             },
         }
     }
 
-    fn execv_child(shell: &CStr, slave_port: RawFd) -> Result<Infallible, Errno> {
+    fn execv_child(
+        argv: &[CString],
+        sandbox: Option<&Prepared>,
+        cwd: Option<&CStr>,
+        env: &[(String, String)],
+        slave_port: RawFd,
+    ) -> Result<Infallible, Errno> {
         // Safety: The slave file descriptor was created by openpty().
         Errno::result(unsafe { login_tty(slave_port) })?;
+        if let Some(sandbox) = sandbox {
+            sandbox.apply()?;
+        }
+        // Entering the directory after the sandbox keeps it within its rules.
+        if let Some(cwd) = cwd {
+            // Safety: The path is a valid C string, and failures are ignored.
+            unsafe { chdir(cwd.as_ptr()) };
+        }
         // Safety: This is called immediately before an execv(), and there are no other
         // threads in this process to interact with its file descriptor table.
         unsafe { CloseFdsBuilder::new().closefrom(3) };
@@ -99,9 +155,35 @@ impl Terminal {
         env::set_var("COLORTERM", "truecolor");
         env::set_var("TERM_PROGRAM", "sshx");
         env::remove_var("TERM_PROGRAM_VERSION");
+        for (key, value) in env {
+            env::set_var(key, value);
+        }
 
         // Start the process.
-        execvp(shell, &[shell])
+        execvp(&argv[0], argv)
+    }
+
+    /// Wait briefly for the child process to exit, returning its exit code,
+    /// or 128 plus the signal that killed it like shells report.
+    ///
+    /// Returns `None` if the process is still running after the grace period,
+    /// for instance if it closed the terminal without exiting.
+    pub async fn exit_code(&mut self) -> Option<i32> {
+        for _ in 0..20 {
+            match waitpid(self.child, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_, code)) => {
+                    self.reaped = true;
+                    return Some(code);
+                }
+                Ok(WaitStatus::Signaled(_, signal, _)) => {
+                    self.reaped = true;
+                    return Some(128 + signal as i32);
+                }
+                Ok(_) => time::sleep(Duration::from_millis(50)).await,
+                Err(_) => return None,
+            }
+        }
+        None
     }
 
     /// Get the window size of the TTY.
@@ -130,7 +212,13 @@ impl AsyncRead for Terminal {
         cx: &mut Context<'_>,
         buf: &mut io::ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        self.project().master_read.poll_read(cx, buf)
+        match self.project().master_read.poll_read(cx, buf) {
+            // Linux reports a hangup as EIO once every process closes the TTY.
+            Poll::Ready(Err(err)) if err.raw_os_error() == Some(Errno::EIO as i32) => {
+                Poll::Ready(Ok(()))
+            }
+            poll => poll,
+        }
     }
 }
 
@@ -159,6 +247,9 @@ impl PinnedDrop for Terminal {
         let this = self.project();
         let child = *this.child;
         trace!(%child, "dropping terminal");
+        if *this.reaped {
+            return; // The process ID may already belong to another process.
+        }
 
         // Kill the child process on closure so that it doesn't keep running.
         kill(child, SIGKILL).ok();
@@ -170,6 +261,15 @@ impl PinnedDrop for Terminal {
     }
 }
 
+/// Get the window size of the local terminal on standard output, if any.
+pub fn local_winsize() -> Option<(u16, u16)> {
+    nix::ioctl_read_bad!(ioctl_get_winsize, TIOCGWINSZ, Winsize);
+    let mut winsize = make_winsize(0, 0);
+    // Safety: The ioctl only writes to the winsize struct passed to it.
+    unsafe { ioctl_get_winsize(std::io::stdout().as_raw_fd(), &mut winsize) }.ok()?;
+    Some((winsize.ws_row, winsize.ws_col)).filter(|&(rows, cols)| rows > 0 && cols > 0)
+}
+
 fn make_winsize(rows: u16, cols: u16) -> Winsize {
     Winsize {
         ws_row: rows,
@@ -181,7 +281,10 @@ fn make_winsize(rows: u16, cols: u16) -> Winsize {
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use anyhow::Result;
+    use tokio::io::AsyncReadExt;
 
     use super::Terminal;
 
@@ -193,4 +296,50 @@ mod tests {
         assert_eq!(terminal.get_winsize()?, (120, 72));
         Ok(())
     }
+
+    #[tokio::test]
+    async fn working_directory() -> Result<()> {
+        let argv = ["/bin/sh".into(), "-c".into(), "pwd".into()];
+        let mut terminal = Terminal::with_args_in(&argv, None, Some(Path::new("/")), &[]).await?;
+        let mut output = String::new();
+        let mut buf = [0; 256];
+        while !output.contains('\n') {
+            let n = terminal.read(&mut buf).await?;
+            assert!(n > 0, "terminal closed early");
+            output.push_str(std::str::from_utf8(&buf[..n])?);
+        }
+        assert_eq!(output.trim(), "/");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exit_code() -> Result<()> {
+        let argv = ["/bin/sh".into(), "-c".into(), "exit 3".into()];
+        let mut terminal = Terminal::with_args(&argv, None).await?;
+        let mut buf = [0; 256];
+        while terminal.read(&mut buf).await? > 0 {}
+        assert_eq!(terminal.exit_code().await, Some(3));
+
+        let argv = ["/bin/sh".into(), "-c".into(), "kill -9 $$".into()];
+        let mut terminal = Terminal::with_args(&argv, None).await?;
+        while terminal.read(&mut buf).await? > 0 {}
+        assert_eq!(terminal.exit_code().await, Some(128 + 9));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn environment() -> Result<()> {
+        let argv = ["/bin/sh".into(), "-c".into(), "echo $GREETING $TERM".into()];
+        let env = [("GREETING".into(), "hello".into())];
+        let mut terminal = Terminal::with_args_in(&argv, None, None, &env).await?;
+        let mut output = String::new();
+        let mut buf = [0; 256];
+        while !output.contains('\n') {
+            let n = terminal.read(&mut buf).await?;
+            assert!(n > 0, "terminal closed early");
+            output.push_str(std::str::from_utf8(&buf[..n])?);
+        }
+        assert_eq!(output.trim(), "hello xterm-256color");
+        Ok(())
+    }
 }
@@ -0,0 +1,121 @@
+//! Pacing of terminal output sent to the server over constrained links.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error};
+use tokio::time::{self, Duration, Instant};
+
+/// A transfer rate in bytes per second, parsed with an optional unit suffix.
+///
+/// Accepts plain numbers of bytes, or decimal (`K`, `M`, `G`) and binary
+/// (`Ki`, `Mi`, `Gi`) suffixes, optionally followed by `B`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_suffix(['B', 'b']).unwrap_or(s);
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: u64 = number.parse().context("invalid number in rate")?;
+        let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+            "" => 1,
+            "k" => 1_000,
+            "m" => 1_000_000,
+            "g" => 1_000_000_000,
+            "ki" => 1 << 10,
+            "mi" => 1 << 20,
+            "gi" => 1 << 30,
+            _ => bail!("unknown unit {unit:?} in rate"),
+        };
+        match number.checked_mul(multiplier) {
+            Some(0) => bail!("rate must be positive"),
+            Some(rate) => Ok(Rate(rate)),
+            None => bail!("rate is too large"),
+        }
+    }
+}
+
+/// Token bucket limiting the average rate of bytes sent.
+///
+/// Sends may briefly exceed the bucket capacity, in which case the debt is
+/// paid off by waiting longer before the next send. Only terminal data is
+/// paced, so control messages like resizes and closed shells go out at once.
+#[derive(Debug)]
+pub struct Throttle {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    /// Create a throttle with a one-second burst allowance.
+    pub fn new(Rate(rate): Rate) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = now;
+    }
+
+    /// Returns how long to wait before any more bytes can be sent.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        match self.tokens {
+            tokens if tokens > 0.0 => Duration::ZERO,
+            tokens => Duration::from_secs_f64(-tokens / self.rate as f64),
+        }
+    }
+
+    /// Wait until more bytes can be sent. This is cancel-safe.
+    pub async fn ready(&mut self) {
+        let delay = self.delay(Instant::now());
+        if !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+    }
+
+    /// Record that a number of bytes was sent.
+    pub fn consume(&mut self, bytes: usize) {
+        self.refill(Instant::now());
+        self.tokens -= bytes as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{Duration, Instant};
+
+    use super::{Rate, Throttle};
+
+    #[test]
+    fn parse_rate() {
+        assert_eq!("500".parse::<Rate>().unwrap(), Rate(500));
+        assert_eq!("64K".parse::<Rate>().unwrap(), Rate(64_000));
+        assert_eq!("64KiB".parse::<Rate>().unwrap(), Rate(65_536));
+        assert_eq!("2mb".parse::<Rate>().unwrap(), Rate(2_000_000));
+        assert!("0".parse::<Rate>().is_err());
+        assert!("fast".parse::<Rate>().is_err());
+        assert!("10X".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn throttle_delay() {
+        let mut throttle = Throttle::new(Rate(1000));
+        let now = Instant::now();
+        assert_eq!(throttle.delay(now), Duration::ZERO);
+        throttle.consume(3000);
+        let delay = throttle.delay(Instant::now());
+        assert!(delay > Duration::from_millis(1900) && delay <= Duration::from_secs(2));
+        assert_eq!(throttle.delay(now + Duration::from_secs(3)), Duration::ZERO);
+    }
+}
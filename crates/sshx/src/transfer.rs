@@ -0,0 +1,394 @@
+//! Files that viewers upload to, or download from, the host.
+//!
+//! Each transfer waits for the host to confirm it, unless the host chose to
+//! accept all of them. Uploads are written into the host's directory under
+//! their plain file name, and never replace a file that already exists.
+//! Downloads are limited to files inside the directory.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use sshx_core::proto::{
+    client_update::ClientMessage, FileData, FileDownload, FileState, FileStatus, FileUpload,
+};
+use sshx_core::Uid;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration};
+use tracing::{debug, info, warn};
+
+use crate::controller::random_offset;
+use crate::encrypt::Encrypt;
+
+/// Largest file that can be transferred in either direction.
+pub const MAX_FILE_BYTES: u64 = 256 << 20; // 256 MiB
+
+/// Size of each chunk of a downloaded file.
+const DOWNLOAD_CHUNK_BYTES: usize = 64 << 10; // 64 KiB
+
+/// Longest time to wait for the host to confirm a transfer.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Uploads are abandoned if no chunk arrives for this long.
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Settings for transferring files between viewers and the host.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileOptions {
+    /// Directory that uploads are written into, and downloads read from.
+    pub dir: PathBuf,
+
+    /// Start every transfer without asking the host to confirm it.
+    pub accept_all: bool,
+}
+
+impl FileOptions {
+    /// Transfer files in a directory, confirming each one.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            accept_all: false,
+        }
+    }
+}
+
+/// Direction of a file transfer, from the viewer's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The viewer sends a file to the host.
+    Upload,
+    /// The viewer receives a file from the host.
+    Download,
+}
+
+/// Transfer that a viewer started, waiting for the host to accept or refuse.
+///
+/// Dropping the request refuses it.
+#[derive(Debug)]
+pub struct FileRequest {
+    /// Whether the viewer is uploading or downloading the file.
+    pub direction: Direction,
+    /// Path of the file, relative to the directory for transfers.
+    pub path: PathBuf,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// ID of the viewer on the other end.
+    pub uid: Uid,
+    reply: oneshot::Sender<bool>,
+}
+
+impl FileRequest {
+    /// Let the transfer go ahead.
+    pub fn accept(self) {
+        self.reply.send(true).ok();
+    }
+
+    /// Refuse the transfer, telling the viewer.
+    pub fn refuse(self) {
+        self.reply.send(false).ok();
+    }
+}
+
+/// Transfers of a session in progress, driven by messages from the server.
+pub(crate) struct Transfers {
+    options: Option<FileOptions>,
+    confirm_tx: Option<mpsc::UnboundedSender<FileRequest>>,
+    encrypt: Encrypt,
+    output_tx: mpsc::Sender<ClientMessage>,
+    /// Chunks routed to each upload task, by viewer and transfer ID.
+    uploads: HashMap<(u32, u32), mpsc::Sender<FileUpload>>,
+}
+
+impl Transfers {
+    /// Handle transfers for a session, or refuse them if options are not set.
+    pub fn new(
+        options: Option<FileOptions>,
+        encrypt: Encrypt,
+        output_tx: mpsc::Sender<ClientMessage>,
+    ) -> Self {
+        Self {
+            options,
+            confirm_tx: None,
+            encrypt,
+            output_tx,
+            uploads: HashMap::new(),
+        }
+    }
+
+    /// Send requests for the host to confirm on a channel.
+    pub fn confirm_on(&mut self, confirm_tx: mpsc::UnboundedSender<FileRequest>) {
+        self.confirm_tx = Some(confirm_tx);
+    }
+
+    /// Handle part of a file uploaded by a viewer.
+    pub async fn upload(&mut self, msg: FileUpload) {
+        let key = (msg.uid, msg.id);
+        if !msg.name.is_empty() {
+            self.uploads.retain(|_, tx| !tx.is_closed());
+            let Some(task) = self.task(msg.id, msg.uid).await else {
+                return;
+            };
+            let (tx, rx) = mpsc::channel(16);
+            self.uploads.insert(key, tx);
+            tokio::spawn(async move {
+                if let Err(err) = task.receive(msg, rx).await {
+                    warn!(%err, id = task.id, uid = task.uid, "upload failed");
+                    task.status(FileState::FileFailed, 0, err.to_string()).await;
+                }
+            });
+        } else if let Some(tx) = self.uploads.get(&key) {
+            let done = msg.done;
+            // This applies backpressure if the file is written slowly.
+            if tx.send(msg).await.is_err() || done {
+                self.uploads.remove(&key);
+            }
+        }
+    }
+
+    /// Handle a viewer asking to download a file.
+    pub async fn download(&mut self, msg: FileDownload) {
+        let Some(task) = self.task(msg.id, msg.uid).await else {
+            return;
+        };
+        tokio::spawn(async move {
+            if let Err(err) = task.send(msg).await {
+                warn!(%err, id = task.id, uid = task.uid, "download failed");
+                task.status(FileState::FileFailed, 0, err.to_string()).await;
+            }
+        });
+    }
+
+    /// Returns a task for a new transfer, or refuses it if files are disabled.
+    async fn task(&self, id: u32, uid: u32) -> Option<TransferTask> {
+        let Some(options) = self.options.clone() else {
+            let status = FileStatus {
+                id,
+                uid,
+                state: FileState::FileFailed.into(),
+                size: 0,
+                error: "The host does not accept file transfers".into(),
+            };
+            let msg = ClientMessage::FileStatus(status);
+            self.output_tx.send(msg).await.ok();
+            return None;
+        };
+        Some(TransferTask {
+            id,
+            uid,
+            options,
+            confirm_tx: self.confirm_tx.clone(),
+            encrypt: self.encrypt.clone(),
+            output_tx: self.output_tx.clone(),
+        })
+    }
+}
+
+/// State of a single transfer, moved into its own task.
+struct TransferTask {
+    id: u32,
+    uid: u32,
+    options: FileOptions,
+    confirm_tx: Option<mpsc::UnboundedSender<FileRequest>>,
+    encrypt: Encrypt,
+    output_tx: mpsc::Sender<ClientMessage>,
+}
+
+impl TransferTask {
+    /// Tell the viewer about the progress of the transfer.
+    async fn status(&self, state: FileState, size: u64, error: String) {
+        let status = FileStatus {
+            id: self.id,
+            uid: self.uid,
+            state: state.into(),
+            size,
+            error,
+        };
+        self.output_tx
+            .send(ClientMessage::FileStatus(status))
+            .await
+            .ok();
+    }
+
+    /// Ask the host whether to go ahead with the transfer.
+    async fn confirm(&self, direction: Direction, path: &Path, size: u64) -> bool {
+        if self.options.accept_all {
+            return true;
+        }
+        let Some(confirm_tx) = &self.confirm_tx else {
+            return false;
+        };
+        let (reply, reply_rx) = oneshot::channel();
+        let request = FileRequest {
+            direction,
+            path: path.to_owned(),
+            size,
+            uid: Uid(self.uid),
+            reply,
+        };
+        if confirm_tx.send(request).is_err() {
+            return false;
+        }
+        matches!(time::timeout(CONFIRM_TIMEOUT, reply_rx).await, Ok(Ok(true)))
+    }
+
+    /// Decrypt a name or path sent by the viewer, encrypted as input.
+    fn decrypt_path(&self, data: &[u8], offset: u64) -> Result<String> {
+        let plaintext = self.encrypt.segment(0x200000000, offset, data);
+        String::from_utf8(plaintext).context("file name is not valid UTF-8")
+    }
+
+    /// Write an uploaded file, after the host accepts it.
+    async fn receive(&self, first: FileUpload, mut rx: mpsc::Receiver<FileUpload>) -> Result<()> {
+        let name = self.decrypt_path(&first.name, first.offset)?;
+        let name = upload_name(&name)?;
+        ensure!(first.size <= MAX_FILE_BYTES, "file is too large to upload");
+        let path = self.options.dir.join(name);
+        ensure!(
+            fs::symlink_metadata(&path).await.is_err(),
+            "a file named {name:?} already exists"
+        );
+        if !self
+            .confirm(Direction::Upload, Path::new(name), first.size)
+            .await
+        {
+            bail!("the host refused the file");
+        }
+
+        let mut file = (OpenOptions::new().write(true).create_new(true))
+            .open(&path)
+            .await
+            .with_context(|| format!("failed to create {name:?}"))?;
+        self.status(FileState::FileAccepted, 0, String::new()).await;
+        let result = async {
+            let mut written = 0;
+            loop {
+                let msg = time::timeout(UPLOAD_IDLE_TIMEOUT, rx.recv())
+                    .await
+                    .context("upload stalled")?
+                    .context("upload was interrupted")?;
+                if msg.done {
+                    break;
+                }
+                written += msg.data.len() as u64;
+                ensure!(written <= first.size, "file is larger than its stated size");
+                let data = self.encrypt.segment(0x200000000, msg.offset, &msg.data);
+                file.write_all(&data).await?;
+            }
+            ensure!(
+                written == first.size,
+                "file is smaller than its stated size"
+            );
+            file.sync_all().await?;
+            anyhow::Ok(())
+        };
+        if let Err(err) = result.await {
+            drop(file);
+            fs::remove_file(&path).await.ok();
+            return Err(err);
+        }
+        info!(
+            uid = self.uid,
+            ?name,
+            size = first.size,
+            "viewer uploaded a file"
+        );
+        self.status(FileState::FileDone, 0, String::new()).await;
+        Ok(())
+    }
+
+    /// Read a file and send it to the viewer, after the host accepts it.
+    async fn send(&self, request: FileDownload) -> Result<()> {
+        let path = self.decrypt_path(&request.path, request.offset)?;
+        let full_path = download_path(&self.options.dir, &path).await?;
+        let mut file = File::open(&full_path)
+            .await
+            .with_context(|| format!("failed to open {path:?}"))?;
+        let metadata = file.metadata().await?;
+        ensure!(metadata.is_file(), "{path:?} is not a file");
+        let size = metadata.len();
+        ensure!(size <= MAX_FILE_BYTES, "file is too large to download");
+        if !self
+            .confirm(Direction::Download, Path::new(&path), size)
+            .await
+        {
+            bail!("the host refused to send the file");
+        }
+
+        self.status(FileState::FileAccepted, size, String::new())
+            .await;
+        let base = random_offset();
+        let mut position = 0;
+        let mut buf = vec![0; DOWNLOAD_CHUNK_BYTES];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let offset = base.wrapping_add(position);
+            let data = FileData {
+                id: self.id,
+                uid: self.uid,
+                data: self.encrypt.segment(0x500000000, offset, &buf[..n]).into(),
+                offset,
+            };
+            // The output channel applies backpressure while the server is busy.
+            self.output_tx
+                .send(ClientMessage::FileData(data))
+                .await
+                .context("session was closed")?;
+            position += n as u64;
+        }
+        debug!(
+            uid = self.uid,
+            ?path,
+            size = position,
+            "viewer downloaded a file"
+        );
+        self.status(FileState::FileDone, 0, String::new()).await;
+        Ok(())
+    }
+}
+
+/// Check that an uploaded file has a plain name, without any directories.
+fn upload_name(name: &str) -> Result<&str> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\', '\0']) => Ok(name),
+        _ => bail!("invalid file name {name:?}"),
+    }
+}
+
+/// Resolve a path to download, checking that it stays inside the directory.
+async fn download_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let plain =
+        (relative.components()).all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    ensure!(plain && !path.contains('\0'), "invalid file path {path:?}");
+    let dir = fs::canonicalize(dir).await?;
+    // Symbolic links could point anywhere, so check where the path leads.
+    let full_path = fs::canonicalize(dir.join(relative))
+        .await
+        .with_context(|| format!("file {path:?} does not exist"))?;
+    ensure!(full_path.starts_with(&dir), "invalid file path {path:?}");
+    Ok(full_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::upload_name;
+
+    #[test]
+    fn upload_names() {
+        assert!(upload_name("notes.txt").is_ok());
+        assert!(upload_name(".bashrc").is_ok());
+        assert!(upload_name("").is_err());
+        assert!(upload_name(".").is_err());
+        assert!(upload_name("..").is_err());
+        assert!(upload_name("dir/notes.txt").is_err());
+        assert!(upload_name("/etc/passwd").is_err());
+        assert!(upload_name("..\\notes.txt").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Self-update of the `sshx` binary from the latest published release.
+//!
+//! Releases are downloaded from the same location as the install script, so
+//! this works with any binary originally installed through `curl | sh`.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+use tokio::{fs, process::Command};
+use tracing::{debug, warn};
+
+/// Base URL where release archives and their metadata are published.
+const RELEASE_URL: &str = "https://s3.amazonaws.com/sshx";
+
+/// Base64-encoded Ed25519 public key for release signatures, set at build time.
+///
+/// Development builds have no key, so signatures are not checked for them.
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("SSHX_RELEASE_PUBLIC_KEY");
+
+/// Returns the release target triple matching the running binary.
+pub fn target_triple() -> Result<&'static str> {
+    Ok(match (std::env::consts::ARCH, std::env::consts::OS) {
+        ("x86_64", "linux") => "x86_64-unknown-linux-musl",
+        ("aarch64", "linux") => "aarch64-unknown-linux-musl",
+        ("x86_64", "macos") => "x86_64-apple-darwin",
+        ("aarch64", "macos") => "aarch64-apple-darwin",
+        (arch, os) => bail!("no prebuilt releases are available for {arch}-{os}"),
+    })
+}
+
+/// Fetch the version string of the latest published release.
+pub async fn latest_version() -> Result<String> {
+    let body = fetch(&format!("{RELEASE_URL}/version.txt")).await?;
+    let version = String::from_utf8(body).context("release version is not UTF-8")?;
+    let version = version.trim().trim_start_matches('v').to_string();
+    ensure!(
+        parse_version(&version).is_some(),
+        "invalid release version {version:?}"
+    );
+    Ok(version)
+}
+
+/// Returns whether version `a` is strictly newer than version `b`.
+pub fn is_newer(a: &str, b: &str) -> bool {
+    match (parse_version(a), parse_version(b)) {
+        (Some(a), Some(b)) => a > b,
+        _ => false,
+    }
+}
+
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = version.split('.').map(|x| x.parse().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(version)
+}
+
+/// Download the latest release and atomically replace the running executable.
+///
+/// Returns the path of the executable that was replaced.
+pub async fn install() -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("could not locate the current executable")?;
+    let dir = exe.parent().context("executable has no parent directory")?;
+
+    let archive_url = format!("{RELEASE_URL}/sshx-{}.tar.gz", target_triple()?);
+    debug!(%archive_url, "downloading release");
+    let archive = fetch(&archive_url).await?;
+
+    let checksum = fetch(&format!("{archive_url}.sha256")).await?;
+    verify_checksum(&archive, &checksum)?;
+    match RELEASE_PUBLIC_KEY {
+        Some(key) => {
+            let signature = fetch(&format!("{archive_url}.sig")).await?;
+            verify_signature(&archive, &signature, key)?;
+        }
+        None => warn!("skipping signature check, this build has no release key"),
+    }
+
+    // Unpack next to the executable, so the final rename is on one filesystem.
+    let temp = tempfile::Builder::new()
+        .prefix(".sshx-upgrade")
+        .tempdir_in(dir)
+        .with_context(|| format!("could not write to {}, try using sudo", dir.display()))?;
+    let archive_path = temp.path().join("sshx.tar.gz");
+    fs::write(&archive_path, &archive).await?;
+    let status = Command::new("tar")
+        .arg("xzf")
+        .arg(&archive_path)
+        .arg("-C")
+        .arg(temp.path())
+        .status()
+        .await
+        .context("failed to run tar")?;
+    ensure!(status.success(), "failed to extract release archive");
+
+    let binary = temp.path().join("sshx");
+    ensure!(
+        fs::try_exists(&binary).await?,
+        "release archive is missing sshx"
+    );
+    fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).await?;
+    fs::rename(&binary, &exe)
+        .await
+        .with_context(|| format!("could not replace {}", exe.display()))?;
+    Ok(exe)
+}
+
+/// Download a file over HTTPS, using `curl` like the install script.
+async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(["-sSfL", url])
+        .output()
+        .await
+        .context("failed to run curl")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("failed to download {url}: {}", stderr.trim());
+    }
+    Ok(output.stdout)
+}
+
+/// Check an archive against a checksum file, in `sha256sum` output format.
+fn verify_checksum(archive: &[u8], checksum: &[u8]) -> Result<()> {
+    let checksum = std::str::from_utf8(checksum).context("checksum is not UTF-8")?;
+    let expected = checksum.split_whitespace().next().unwrap_or_default();
+    let actual: String = Sha256::digest(archive)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    ensure!(
+        expected.eq_ignore_ascii_case(&actual),
+        "checksum mismatch for downloaded release"
+    );
+    Ok(())
+}
+
+/// Check a base64-encoded Ed25519 signature of an archive.
+fn verify_signature(archive: &[u8], signature: &[u8], public_key: &str) -> Result<()> {
+    use ring::signature::{UnparsedPublicKey, ED25519};
+    let public_key = BASE64_STANDARD.decode(public_key)?;
+    let signature = BASE64_STANDARD.decode(signature.trim_ascii())?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(archive, &signature)
+        .ok()
+        .context("invalid signature for downloaded release")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_newer, verify_checksum};
+
+    #[test]
+    fn compare_versions() {
+        assert!(is_newer("0.2.3", "0.2.2"));
+        assert!(is_newer("0.10.0", "0.9.9"));
+        assert!(is_newer("1.0.0", "0.99.0"));
+        assert!(!is_newer("0.2.2", "0.2.2"));
+        assert!(!is_newer("0.2.1", "0.2.2"));
+        assert!(!is_newer("garbage", "0.2.2"));
+    }
+
+    #[test]
+    fn checksum_format() {
+        let digest = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        assert!(verify_checksum(b"hello world", digest.as_bytes()).is_ok());
+        let line = format!("{digest}  sshx-x86_64-unknown-linux-musl.tar.gz\n");
+        assert!(verify_checksum(b"hello world", line.as_bytes()).is_ok());
+        assert!(verify_checksum(b"hello there", line.as_bytes()).is_err());
+    }
+}
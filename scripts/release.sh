@@ -21,6 +21,11 @@ cargo build --release --target x86_64-apple-darwin
 
 cargo build --release --target aarch64-apple-darwin
 
+# Release metadata read by `sshx upgrade`. If SSHX_RELEASE_SIGNING_KEY points to
+# an Ed25519 private key, the binaries above should also have been built with
+# its public key in SSHX_RELEASE_PUBLIC_KEY so that signatures are checked.
+version=$(cargo pkgid -p sshx | cut -d@ -f2)
+
 temp=$(mktemp)
 targets=(
   x86_64-unknown-linux-musl
@@ -33,8 +38,15 @@ do
   echo "compress: target/$target/release/sshx"
   tar czf $temp -C target/$target/release sshx
   aws s3 cp $temp s3://sshx/sshx-$target.tar.gz
+  shasum -a 256 $temp | cut -d' ' -f1 | aws s3 cp - s3://sshx/sshx-$target.tar.gz.sha256
+  if [ -n "$SSHX_RELEASE_SIGNING_KEY" ]; then
+    openssl pkeyutl -sign -inkey "$SSHX_RELEASE_SIGNING_KEY" -rawin -in $temp \
+      | base64 | aws s3 cp - s3://sshx/sshx-$target.tar.gz.sig
+  fi
 
   echo "compress: target/$target/release/sshx-server"
   tar czf $temp -C target/$target/release sshx-server
   aws s3 cp $temp s3://sshx/sshx-server-$target.tar.gz
 done
+
+echo "$version" | aws s3 cp - s3://sshx/version.txt
@@ -0,0 +1,11 @@
+/** @file Saves data from the page as a file on the user's computer. */
+
+/** Prompt the browser to save a blob as a file with the given name. */
+export function saveBlob(blob: Blob, filename: string) {
+  const url = URL.createObjectURL(blob);
+  const link = document.createElement("a");
+  link.href = url;
+  link.download = filename;
+  link.click();
+  URL.revokeObjectURL(url);
+}
@@ -9,6 +9,16 @@ export type WsWinsize = {
   cols: number;
 };
 
+/** Options for creating a shell, see the Rust version. */
+export type WsNewShell = {
+  x: number;
+  y: number;
+  size: [number, number] | null;
+  cwd: [Uint8Array, bigint] | null;
+  command: [Uint8Array, bigint] | null;
+  argv: [Uint8Array, bigint] | null;
+};
+
 /** Information about a user, see the Rust version */
 export type WsUser = {
   name: string;
@@ -16,31 +26,102 @@ export type WsUser = {
   focus: number | null;
 };
 
+/** Full state of a session, see the Rust version. */
+export type WsSyncState = {
+  userId: Uid;
+  shells: [Sid, WsWinsize, string][];
+  users: [Uid, WsUser][];
+  seqnums: [Sid, number | bigint][];
+  history: [Sid, number | bigint][];
+  muted: boolean;
+};
+
+/** Machine-readable error category, see `ErrorCode` in the Rust version. */
+export type ErrorCode =
+  | "invalid_request"
+  | "invalid_auth"
+  | "permission_denied"
+  | "not_found"
+  | "name_taken"
+  | "session_closed"
+  | "session_expired"
+  | "rate_limited"
+  | "quota_exceeded"
+  | "incompatible"
+  | "client_reported"
+  | "shutting_down"
+  | "host_replaced"
+  | "idle_timeout"
+  | "shell_not_found"
+  | "session_full"
+  | "internal";
+
 /** Server message type, see the Rust version. */
 export type WsServer = {
   hello?: Uid;
+  features?: string[];
   invalidAuth?: [];
+  totpRequired?: [];
   users?: [Uid, WsUser][];
   userDiff?: [Uid, WsUser | null];
-  shells?: [Sid, WsWinsize][];
+  shells?: [Sid, WsWinsize, string][];
   chunks?: [Sid, number, Uint8Array[]];
-  hear?: [Uid, string, string];
+  shellExited?: [Sid, number];
+  hear?: [Uid, string, string, number | bigint];
   shellLatency?: number | bigint;
   pong?: number | bigint;
-  error?: string;
+  latency?: [number | bigint | null, number | bigint | null];
+  error?: [ErrorCode, string];
+  timeLeft?: number | bigint;
+  banner?: string;
+  watermark?: string;
+  sync?: WsSyncState;
+  historyStart?: [Sid, number];
+  lines?: [Sid, number, number, Uint8Array[]];
+  screen?: [Sid, number, number | bigint, Uint8Array];
+  forked?: [Uint8Array, number | bigint];
+  waiting?: number | bigint;
+  hostJoined?: [];
+  pending?: [];
+  removed?: boolean;
+  readOnly?: boolean;
+  writable?: [];
+  fileAccepted?: [number, number | bigint];
+  fileData?: [number, Uint8Array, number | bigint];
+  fileDone?: number;
+  fileFailed?: [number, string];
+  compressed?: Uint8Array; // only sent if asked for, not by browsers
 };
 
 /** Client message type, see the Rust version. */
 export type WsClient = {
   authenticate?: Uint8Array;
+  totp?: string;
+  authenticateWrite?: string;
   setName?: string;
   setCursor?: [number, number] | null;
   setFocus?: number | null;
   create?: [number, number];
+  createShell?: WsNewShell;
   close?: Sid;
+  rename?: [Sid, string];
   move?: [Sid, WsWinsize | null];
   data?: [Sid, Uint8Array, bigint];
+  paste?: [Sid, Uint8Array, bigint];
+  broadcast?: [Uint8Array, bigint];
   subscribe?: [Sid, number];
+  subscribeFrom?: [Sid, number];
+  subscribeScreen?: Sid;
+  fetchLines?: [Sid, number, number];
+  ack?: [Sid, number];
   chat?: string;
   ping?: bigint;
+  sync?: [];
+  fork?: boolean;
+  fileStart?: [number, Uint8Array, bigint, number];
+  fileChunk?: [number, Uint8Array, bigint];
+  fileEnd?: number;
+  fileRequest?: [number, Uint8Array, bigint];
+  kick?: number;
+  ban?: number;
 };
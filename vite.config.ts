@@ -16,7 +16,6 @@ export default defineConfig({
     proxy: {
       "/api": {
         target: "http://[::1]:8051",
-        changeOrigin: true,
         ws: true,
       },
     },